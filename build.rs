@@ -0,0 +1,9 @@
+fn main() {
+    // Use the bundled protoc unless one is given, so builds need no system install
+    if std::env::var_os("PROTOC").is_none() {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("bundled protoc");
+        std::env::set_var("PROTOC", protoc);
+    }
+    println!("cargo:rerun-if-changed=proto/control.proto");
+    tonic_prost_build::compile_protos("proto/control.proto").expect("failed to compile protos");
+}
@@ -0,0 +1,307 @@
+use crate::config::Config;
+use crate::models::AuthRequest;
+use crate::state::{AppState, CachedDecision};
+use crate::tenant;
+use crate::REQUEST_ID_HEADER;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tokio::time::Instant;
+
+/// Cache size at which expired decisions are swept
+const AUTH_CACHE_SWEEP_AT: usize = 10_000;
+
+const DENIED: (StatusCode, &str) = (StatusCode::FORBIDDEN, "Access denied");
+const INVALID_TOKEN: (StatusCode, &str) = (StatusCode::FORBIDDEN, "Invalid or expired token");
+
+/// Unix timestamp (seconds) a signed control request was made at
+pub const CONTROL_TIMESTAMP_HEADER: &str = "x-control-timestamp";
+/// `sha256=<hex HMAC>` over timestamp, method, path and body
+pub const CONTROL_SIGNATURE_HEADER: &str = "x-control-signature";
+/// Signed requests older or newer than this are rejected as replays
+const SIGNATURE_MAX_SKEW_SECS: i64 = 300;
+/// Number of remembered control signatures at which expired ones are swept
+const SEEN_SIGNATURES_SWEEP_AT: usize = 10_000;
+/// Largest control request body buffered for signature checks
+const MAX_SIGNED_BODY: usize = 16 * 1024 * 1024;
+
+/// Ask the auth callback (channel-level, else global) whether to admit a viewer.
+///
+/// With STREAM_TOKEN_SECRET set, the viewer's token must first be a valid
+/// signed token for this channel (see `verify_stream_token`). A tenant's
+/// channels use the tenant's callback and secret instead, with the channel
+/// identified by its id within the tenant.
+/// Returns Ok when no callback is configured or the callback answers 200.
+/// A non-200 answer is a denial (403); an unreachable callback fails closed (503).
+/// Decisions are cached per client IP + token so reconnect bursts don't hit
+/// the backend every time; backend failures are never cached.
+pub async fn authorize(
+    state: &AppState,
+    channel_id: &str,
+    addr: SocketAddr,
+    headers: &HeaderMap,
+    token: Option<&str>,
+) -> Result<(), (StatusCode, &'static str)> {
+    let (secret, realm_callback, realm_id) = match tenant::for_channel(&state.config, channel_id) {
+        Some((tenant, local_id)) => (
+            &tenant.stream_token_secret,
+            &tenant.auth_callback_url,
+            local_id,
+        ),
+        None => (
+            &state.config.stream_token_secret,
+            &state.config.auth_callback_url,
+            channel_id,
+        ),
+    };
+    if let Some(secret) = secret {
+        if !token.is_some_and(|t| verify_stream_token(secret, realm_id, t)) {
+            return Err(INVALID_TOKEN);
+        }
+    }
+
+    let url = state
+        .channel_routes
+        .load()
+        .get(channel_id)
+        .and_then(|r| r.auth_callback.clone())
+        .or_else(|| realm_callback.clone());
+    let Some(url) = url else {
+        return Ok(());
+    };
+
+    let cache_key = format!(
+        "{}\n{}\n{}\n{}",
+        url,
+        channel_id,
+        addr.ip(),
+        token.unwrap_or("")
+    );
+    if let Some(allowed) = cached_decision(state, &cache_key) {
+        return if allowed { Ok(()) } else { Err(DENIED) };
+    }
+
+    let body = AuthRequest {
+        channel_id: realm_id,
+        client_ip: addr.ip().to_string(),
+        user_agent: headers
+            .get(header::USER_AGENT)
+            .and_then(|v| v.to_str().ok()),
+        token,
+    };
+
+    let mut request = state.auth_client.post(&url).json(&body);
+    if let Some(request_id) = headers.get(REQUEST_ID_HEADER) {
+        request = request.header(REQUEST_ID_HEADER, request_id);
+    }
+
+    match request.send().await {
+        Ok(resp) if resp.status() == StatusCode::OK => {
+            cache_decision(state, cache_key, true);
+            Ok(())
+        }
+        Ok(resp) => {
+            tracing::info!(
+                "Channel {}: viewer {} denied by auth callback (HTTP {})",
+                channel_id,
+                addr,
+                resp.status()
+            );
+            cache_decision(state, cache_key, false);
+            Err(DENIED)
+        }
+        Err(e) => {
+            tracing::warn!("Channel {}: auth callback failed: {}", channel_id, e);
+            Err((StatusCode::SERVICE_UNAVAILABLE, "Auth backend unavailable"))
+        }
+    }
+}
+
+fn cached_decision(state: &AppState, key: &str) -> Option<bool> {
+    let entry = state.auth_cache.get(key)?;
+    if entry.expires > Instant::now() {
+        return Some(entry.allowed);
+    }
+    drop(entry);
+    state.auth_cache.remove(key);
+    None
+}
+
+fn cache_decision(state: &AppState, key: String, allowed: bool) {
+    let ttl = if allowed {
+        state.config.auth_cache_ttl
+    } else {
+        state.config.auth_cache_negative_ttl
+    };
+    if ttl.is_zero() {
+        return;
+    }
+
+    let now = Instant::now();
+    if state.auth_cache.len() >= AUTH_CACHE_SWEEP_AT {
+        state.auth_cache.retain(|_, d| d.expires > now);
+    }
+    state.auth_cache.insert(
+        key,
+        CachedDecision {
+            allowed,
+            expires: now + ttl,
+        },
+    );
+}
+
+/// Check a signed viewer token: `{expires}.{signature}`, where `expires` is a
+/// Unix timestamp and `signature` the hex HMAC-SHA256 of
+/// `"{channel_id}:{expires}"` keyed with the shared secret. Binding the
+/// channel and an expiry into the signature keeps shared URLs from working
+/// for other channels or for long.
+fn verify_stream_token(secret: &str, channel_id: &str, token: &str) -> bool {
+    let Some((expires, signature)) = token.split_once('.') else {
+        return false;
+    };
+    let unexpired = expires
+        .parse::<i64>()
+        .is_ok_and(|t| t > chrono::Utc::now().timestamp());
+    let Some(signature) = hex::decode(signature).ok().filter(|_| unexpired) else {
+        return false;
+    };
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}:{}", channel_id, expires).as_bytes());
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Check the `Authorization: Bearer` header against ADMIN_TOKEN.
+///
+/// Admin endpoints are disabled (404) when no token is configured.
+pub fn require_admin(state: &AppState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let Some(expected) = state.config.admin_token.as_deref() else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    match provided {
+        Some(token) if tokens_match(token, expected) => Ok(()),
+        Some(_) => Err(StatusCode::FORBIDDEN),
+        None => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// Marks requests that arrived on the control unix socket, which is
+/// protected by its file permissions instead of tokens
+#[derive(Clone, Copy)]
+pub struct LocalSocket;
+
+/// Middleware for `/control/v1/*`: admit requests carrying the control (or
+/// admin) bearer token, or a valid HMAC signature, and answer 401 otherwise.
+/// The control API is open when neither a token nor a secret is configured,
+/// and on the control socket (requests carrying [`LocalSocket`]).
+///
+/// A signature is `sha256=` followed by the hex HMAC-SHA256, keyed with
+/// CONTROL_HMAC_SECRET, of `"{timestamp}\n{METHOD}\n{path?query}\n{body}"`.
+/// Timestamps may be five minutes off, and each signature is accepted once,
+/// so a captured request can't be replayed.
+pub async fn require_control(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let config = &state.config;
+    let open = config.control_token.is_none() && config.control_hmac_secret.is_none();
+    if open || request.extensions().get::<LocalSocket>().is_some() {
+        return next.run(request).await;
+    }
+
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if bearer.is_some_and(|token| control_token_accepted(config, token)) {
+        return next.run(request).await;
+    }
+
+    if let Some(secret) = &config.control_hmac_secret {
+        if request.headers().contains_key(CONTROL_SIGNATURE_HEADER) {
+            let (parts, body) = request.into_parts();
+            let Ok(body) = axum::body::to_bytes(body, MAX_SIGNED_BODY).await else {
+                return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+            };
+            if verify_signature(&state, secret, &parts, &body) {
+                let request = Request::from_parts(parts, axum::body::Body::from(body));
+                return next.run(request).await;
+            }
+            return unauthorized();
+        }
+    }
+    unauthorized()
+}
+
+/// Whether `token` is the control (or admin) bearer token
+pub fn control_token_accepted(config: &Config, token: &str) -> bool {
+    [&config.control_token, &config.admin_token]
+        .into_iter()
+        .flatten()
+        .any(|expected| tokens_match(token, expected))
+}
+
+/// Compare a presented secret with the expected one in constant time
+pub fn tokens_match(provided: &str, expected: &str) -> bool {
+    provided.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+fn verify_signature(
+    state: &AppState,
+    secret: &str,
+    parts: &axum::http::request::Parts,
+    body: &[u8],
+) -> bool {
+    let header = |name| parts.headers.get(name).and_then(|v| v.to_str().ok());
+    let Some(timestamp) = header(CONTROL_TIMESTAMP_HEADER) else {
+        return false;
+    };
+    let now = chrono::Utc::now().timestamp();
+    let fresh = |t: &i64| (now - t).abs() <= SIGNATURE_MAX_SKEW_SECS;
+    let signed_at = timestamp.parse::<i64>().ok().filter(fresh);
+    let signature = header(CONTROL_SIGNATURE_HEADER)
+        .and_then(|v| v.strip_prefix("sha256="))
+        .and_then(|v| hex::decode(v).ok());
+    let (Some(signed_at), Some(signature)) = (signed_at, signature) else {
+        return false;
+    };
+    let path = parts
+        .uri
+        .path_and_query()
+        .map_or(parts.uri.path(), |p| p.as_str());
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}\n{}\n{}\n", timestamp, parts.method, path).as_bytes());
+    mac.update(body);
+    if mac.verify_slice(&signature).is_err() {
+        return false;
+    }
+
+    // Signatures outside the skew window are refused above, so only fresh
+    // ones need remembering
+    if state.seen_signatures.len() >= SEEN_SIGNATURES_SWEEP_AT {
+        state.seen_signatures.retain(|_, t| fresh(t));
+    }
+    state.seen_signatures.insert(signature, signed_at).is_none()
+}
+
+fn unauthorized() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, "Bearer")],
+        "Unauthorized",
+    )
+        .into_response()
+}
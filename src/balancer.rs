@@ -0,0 +1,93 @@
+use crate::state::AppState;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Spawn the background task that moves channels off accounts running near
+/// their limit onto equivalent URLs on lightly used accounts.
+pub fn spawn_balancer(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(state.config.rebalance_interval);
+        loop {
+            interval.tick().await;
+            rebalance(&state);
+        }
+    })
+}
+
+/// Schedule at most one switch per hot account per pass. Switches go through
+/// the upstream task's make-before-break handover, so they happen at a safe
+/// point without interrupting viewers.
+fn rebalance(state: &AppState) {
+    let high = state.config.rebalance_high_percent;
+    let low = state.config.rebalance_low_percent;
+
+    // (active, max) per limited, enabled account, adjusted as moves are planned
+    let mut load: HashMap<u64, (u32, u32)> = state
+        .accounts
+        .load()
+        .iter()
+        .filter(|a| a.enabled.load(Ordering::Relaxed))
+        .filter_map(|a| {
+            let max = a.max_connections.load(Ordering::Relaxed);
+            (max > 0).then(|| {
+                (
+                    *a.key(),
+                    (a.active_connections.load(Ordering::Relaxed), max),
+                )
+            })
+        })
+        .collect();
+    let percent = |(active, max): (u32, u32)| active as u64 * 100 / max as u64;
+
+    let hot: Vec<u64> = load
+        .iter()
+        .filter(|(_, &l)| percent(l) >= high as u64)
+        .map(|(&id, _)| id)
+        .collect();
+
+    for hot_id in hot {
+        let channels: Vec<_> = state
+            .active_channels
+            .iter()
+            .filter(|a| a.current_upstream().account_id == hot_id)
+            .filter(|a| a.pending_switch.lock().unwrap().is_none())
+            .map(|a| a.value().clone())
+            .collect();
+
+        // Coolest accounts first
+        let mut cool: Vec<u64> = load
+            .iter()
+            .filter(|(&id, &(active, max))| {
+                id != hot_id && percent((active + 1, max)) <= low as u64
+            })
+            .map(|(&id, _)| id)
+            .collect();
+        cool.sort_by_key(|id| percent(load[id]));
+
+        let planned = channels.iter().find_map(|active| {
+            let current = active.current_upstream();
+            cool.iter().find_map(|&account_id| {
+                let next = state.target_on_account(&active.channel_id, &current, account_id)?;
+                Some((active, next))
+            })
+        });
+        let Some((active, next)) = planned else {
+            continue;
+        };
+
+        tracing::info!(
+            "Balancer: moving channel {} from account {} to {}",
+            active.channel_id,
+            hot_id,
+            next.account_id
+        );
+        if let Some(l) = load.get_mut(&next.account_id) {
+            l.0 += 1;
+        }
+        if let Some(l) = load.get_mut(&hot_id) {
+            l.0 = l.0.saturating_sub(1);
+        }
+        *active.pending_switch.lock().unwrap() = Some(next);
+    }
+}
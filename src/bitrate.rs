@@ -0,0 +1,95 @@
+use crate::state::AppState;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{Instant, MissedTickBehavior};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Time constant of the moving average: a rate change is ~63% reflected
+/// after this long
+const AVERAGE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Exponential moving average of a byte counter's rate
+#[derive(Default)]
+pub struct RateMeter {
+    last: Option<(u64, Instant)>,
+    bits_per_sec: Option<f64>,
+}
+
+impl RateMeter {
+    /// Fold in the counter's current `total`. The first interval sets the
+    /// rate outright, so it doesn't start out ramping up from zero.
+    pub fn sample(&mut self, total: u64, now: Instant) {
+        if let Some((last_total, last_at)) = self.last {
+            let elapsed = now.duration_since(last_at).as_secs_f64();
+            if elapsed > 0.0 {
+                // Counters reset by the control API go backwards; count that as idle
+                let rate = total.saturating_sub(last_total) as f64 * 8.0 / elapsed;
+                let alpha = 1.0 - (-elapsed / AVERAGE_WINDOW.as_secs_f64()).exp();
+                self.bits_per_sec = Some(match self.bits_per_sec {
+                    Some(average) => average + alpha * (rate - average),
+                    None => rate,
+                });
+            }
+        }
+        self.last = Some((total, now));
+    }
+
+    /// Averaged rate in bits per second (None before two samples)
+    pub fn bits_per_sec(&self) -> Option<u64> {
+        self.bits_per_sec.map(|bps| bps.round() as u64)
+    }
+}
+
+/// Time spent sending to a client before its throughput is reported, so a
+/// few writes absorbed by socket buffers don't count as its bandwidth
+const MIN_BUSY_TIME: Duration = Duration::from_millis(200);
+
+/// A client's achievable throughput, measured only over writes made while
+/// data was queued for it: then the time a write takes is set by the
+/// client's connection rather than by the stream's own bitrate. Older
+/// writes fade out over `AVERAGE_WINDOW` of such sending time.
+#[derive(Default)]
+pub struct ThroughputEstimate {
+    bytes: f64,
+    secs: f64,
+}
+
+impl ThroughputEstimate {
+    pub fn record(&mut self, bytes: u64, elapsed: Duration) {
+        let elapsed = elapsed.as_secs_f64();
+        let decay = (-elapsed / AVERAGE_WINDOW.as_secs_f64()).exp();
+        self.bytes = self.bytes * decay + bytes as f64;
+        self.secs = self.secs * decay + elapsed;
+    }
+
+    /// Estimated bits per second (None until enough backlogged sending was seen)
+    pub fn bits_per_sec(&self) -> Option<u64> {
+        (self.secs >= MIN_BUSY_TIME.as_secs_f64())
+            .then(|| (self.bytes * 8.0 / self.secs).round() as u64)
+    }
+}
+
+/// Spawn the task that samples every active channel's byte counters once a
+/// second: upstream input and total output per channel, and each client's
+/// output.
+pub fn spawn_sampler(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let now = Instant::now();
+            for active in state.active_channels.iter() {
+                let input = active.bytes_transferred.load(Ordering::Relaxed);
+                active.input_rate.lock().unwrap().sample(input, now);
+                let output = active.counters.bytes_out.load(Ordering::Relaxed);
+                active.output_rate.lock().unwrap().sample(output, now);
+                for client in active.clients.iter() {
+                    let sent = client.bytes_sent.load(Ordering::Relaxed);
+                    client.send_rate.lock().unwrap().sample(sent, now);
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,55 @@
+use crate::state::AppState;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::time::Instant;
+
+/// Spawn the background task that samples account utilization and raises
+/// at-capacity warnings when an account stays near its limit for too long.
+pub fn spawn_monitor(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(state.config.account_sample_interval);
+        loop {
+            interval.tick().await;
+            sample_accounts(&state);
+        }
+    })
+}
+
+fn sample_accounts(state: &AppState) {
+    let threshold = state.config.account_warn_threshold;
+    let warn_after = state.config.account_warn_after;
+    let now = Instant::now();
+
+    for entry in state.accounts.load().iter() {
+        let account_id = *entry.key();
+        let account = entry.value();
+
+        let near_capacity = matches!(account.utilization_percent(), Some(pct) if pct >= threshold);
+        let mut since = account.near_capacity_since.lock().unwrap();
+
+        if !near_capacity {
+            if since.take().is_some() && account.capacity_warning.swap(false, Ordering::Relaxed) {
+                tracing::info!(
+                    "Account {}: utilization back below {}%",
+                    account_id,
+                    threshold
+                );
+            }
+            continue;
+        }
+
+        let started = *since.get_or_insert(now);
+        if now.duration_since(started) >= warn_after
+            && !account.capacity_warning.swap(true, Ordering::Relaxed)
+        {
+            tracing::warn!(
+                "Account {}: at {}/{} connections (>= {}%) for {}s, consider adding capacity",
+                account_id,
+                account.active_connections.load(Ordering::Relaxed),
+                account.max_connections.load(Ordering::Relaxed),
+                threshold,
+                now.duration_since(started).as_secs()
+            );
+        }
+    }
+}
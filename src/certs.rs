@@ -0,0 +1,32 @@
+use chrono::{DateTime, Utc};
+
+/// The leaf certificate an HTTPS upstream presented
+#[derive(Debug, Clone, PartialEq)]
+pub struct CertificateInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub not_after: DateTime<Utc>,
+}
+
+impl CertificateInfo {
+    /// Details of the certificate the response's TLS connection was made
+    /// with (None for plain HTTP or an unparseable certificate). Needs a
+    /// client built with `tls_info(true)`.
+    pub fn from_response(response: &reqwest::Response) -> Option<Self> {
+        let der = response
+            .extensions()
+            .get::<reqwest::tls::TlsInfo>()?
+            .peer_certificate()?;
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        Some(Self {
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            not_after: DateTime::from_timestamp(cert.validity().not_after.timestamp(), 0)?,
+        })
+    }
+
+    /// Whole days until the certificate expires (negative once expired)
+    pub fn days_remaining(&self) -> i64 {
+        (self.not_after - Utc::now()).num_days()
+    }
+}
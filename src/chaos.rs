@@ -0,0 +1,107 @@
+use crate::auth;
+use crate::models::{FaultConfig, FaultStatus};
+use crate::state::AppState;
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    Json,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Faults injected into one channel's upstream reads
+pub struct ChannelFaults {
+    /// Fail the upstream connection once this deadline passes (one-shot)
+    pub drop_at: Option<Instant>,
+    /// Chance (0-100) that any upstream read is turned into an error
+    pub read_error_percent: u8,
+    /// Added before each upstream read is processed
+    pub chunk_delay: Duration,
+}
+
+/// Apply any faults configured for a channel to an upstream read.
+///
+/// Returns an error to fail the read with, or the delay to wait before
+/// processing it.
+pub fn on_read(state: &AppState, channel_id: &str) -> Result<Duration, String> {
+    let Some(mut faults) = state.faults.get_mut(channel_id) else {
+        return Ok(Duration::ZERO);
+    };
+    if faults.drop_at.is_some_and(|at| Instant::now() >= at) {
+        faults.drop_at = None;
+        return Err("chaos: upstream dropped".to_string());
+    }
+    if faults.read_error_percent > 0 && fastrand::u8(0..100) < faults.read_error_percent {
+        return Err("chaos: injected read error".to_string());
+    }
+    Ok(faults.chunk_delay)
+}
+
+/// List channels with active fault injection (admin only).
+pub async fn list_faults(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<HashMap<String, FaultStatus>>, StatusCode> {
+    auth::require_admin(&state, &headers)?;
+    let faults = state
+        .faults
+        .iter()
+        .map(|e| (e.key().clone(), fault_status(e.value())))
+        .collect();
+    Ok(Json(faults))
+}
+
+/// Set the faults for a channel, replacing any already configured (admin only).
+pub async fn put_faults(
+    State(state): State<Arc<AppState>>,
+    Path(channel_id): Path<String>,
+    headers: HeaderMap,
+    Json(config): Json<FaultConfig>,
+) -> Result<Json<FaultStatus>, StatusCode> {
+    auth::require_admin(&state, &headers)?;
+    if config.read_error_percent > 100 {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let faults = ChannelFaults {
+        drop_at: config
+            .drop_in_secs
+            .map(|secs| Instant::now() + Duration::from_secs(secs)),
+        read_error_percent: config.read_error_percent,
+        chunk_delay: Duration::from_millis(config.chunk_delay_ms),
+    };
+    let status = fault_status(&faults);
+    tracing::warn!(
+        "Channel {}: chaos faults set (drop_in={:?}s, read_errors={}%, delay={}ms)",
+        channel_id,
+        config.drop_in_secs,
+        config.read_error_percent,
+        config.chunk_delay_ms
+    );
+    state.faults.insert(channel_id, faults);
+    Ok(Json(status))
+}
+
+/// Clear all faults for a channel (admin only).
+pub async fn delete_faults(
+    State(state): State<Arc<AppState>>,
+    Path(channel_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, StatusCode> {
+    auth::require_admin(&state, &headers)?;
+    if state.faults.remove(&channel_id).is_some() {
+        tracing::info!("Channel {}: chaos faults cleared", channel_id);
+    }
+    Ok(StatusCode::OK)
+}
+
+fn fault_status(faults: &ChannelFaults) -> FaultStatus {
+    FaultStatus {
+        drop_in_secs: faults
+            .drop_at
+            .map(|at| at.saturating_duration_since(Instant::now()).as_secs_f64()),
+        read_error_percent: faults.read_error_percent,
+        chunk_delay_ms: faults.chunk_delay.as_millis() as u64,
+    }
+}
@@ -0,0 +1,285 @@
+use crate::config::Config;
+use crate::state::{AccountState, AppState};
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Sync intervals an instance stays a cluster member after its last
+/// heartbeat, so a crashed instance's slots are reclaimed once it lapses
+const MEMBERSHIP_INTERVALS: u32 = 3;
+
+/// Shortest time between heartbeats
+const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Timeout for connecting to Redis and for each reply
+const REDIS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Take one slot of an account's cluster-wide limit for an instance, unless
+/// the live instances' slots already reach the limit. Slots of instances
+/// whose membership lapsed are dropped on the way.
+///
+/// KEYS: members (instance -> expiry ms), the account's slots (instance ->
+/// count). ARGV: instance, max_connections (0 = unlimited), now ms, the
+/// instance's new expiry ms. Returns 1 if the slot was taken.
+const CLAIM_SCRIPT: &str = r#"
+redis.call('ZADD', KEYS[1], ARGV[4], ARGV[1])
+local live = {}
+for _, id in ipairs(redis.call('ZRANGEBYSCORE', KEYS[1], ARGV[3], '+inf')) do
+    live[id] = true
+end
+local slots = redis.call('HGETALL', KEYS[2])
+local total = 0
+for i = 1, #slots, 2 do
+    if live[slots[i]] then
+        total = total + tonumber(slots[i + 1])
+    else
+        redis.call('HDEL', KEYS[2], slots[i])
+    end
+end
+local max = tonumber(ARGV[2])
+if max > 0 and total >= max then
+    return 0
+end
+redis.call('HINCRBY', KEYS[2], ARGV[1], 1)
+return 1
+"#;
+
+/// Give back slots an instance holds on an account.
+///
+/// KEYS: the account's slots. ARGV: instance, slots to release.
+const RELEASE_SCRIPT: &str = r#"
+local left = redis.call('HINCRBY', KEYS[1], ARGV[1], -tonumber(ARGV[2]))
+if left <= 0 then
+    redis.call('HDEL', KEYS[1], ARGV[1])
+end
+return left
+"#;
+
+/// This instance's share of the account limits of a cluster of proxies
+/// coordinating through Redis.
+///
+/// Each account has a hash `<prefix>:account:<id>` of the slots every
+/// instance holds on it, and `<prefix>:members` is a sorted set of the
+/// instances with the time their membership lapses. An instance takes a
+/// slot with a script that checks the live members' total against
+/// `max_connections` and counts the slot in one atomic step, before opening
+/// a provider connection; the local `active_connections` count and its
+/// limit still apply first. Slots are released as connections end, and a
+/// heartbeat every `cluster_sync_interval` renews membership and rewrites
+/// this instance's slots (repairing them after a Redis restart).
+///
+/// While Redis can't be reached, connections are admitted on the local
+/// count alone and their slots are published once it is back.
+pub struct Cluster {
+    client: redis::Client,
+    instance: String,
+    prefix: String,
+    interval: Duration,
+    claim: redis::Script,
+    release: redis::Script,
+    /// Held across every change to this instance's slots, so claims,
+    /// releases and heartbeats don't interleave. Connected on first use.
+    connection: tokio::sync::Mutex<Option<ConnectionManager>>,
+    /// Cleared while Redis is unreachable; claims then skip it until the
+    /// next heartbeat gets through
+    reachable: AtomicBool,
+}
+
+impl Cluster {
+    /// The cluster configured with CLUSTER_REDIS_URL, if any. Panics if the
+    /// URL is invalid, as the proxy would otherwise ignore limits silently.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let url = config.cluster_redis_url.as_deref()?;
+        let client =
+            redis::Client::open(url).unwrap_or_else(|e| panic!("invalid CLUSTER_REDIS_URL: {}", e));
+        Some(Self {
+            client,
+            instance: config.cluster_instance_id.clone(),
+            prefix: config.cluster_key_prefix.clone(),
+            interval: config.cluster_sync_interval.max(MIN_INTERVAL),
+            claim: redis::Script::new(CLAIM_SCRIPT),
+            release: redis::Script::new(RELEASE_SCRIPT),
+            connection: tokio::sync::Mutex::new(None),
+            reachable: AtomicBool::new(true),
+        })
+    }
+
+    /// Make sure every local connection slot on the account is also held in
+    /// the cluster, taking one more cluster slot if needed. False if the
+    /// cluster-wide limit is reached.
+    pub async fn claim(&self, account_id: u64, account: &AccountState) -> bool {
+        let mut connection = self.connection.lock().await;
+        let active = account.active_connections.load(Ordering::Relaxed);
+        if account.cluster_slots.load(Ordering::Relaxed) >= active {
+            return true;
+        }
+        if self.reachable.load(Ordering::Relaxed) {
+            let max = account.max_connections.load(Ordering::Relaxed);
+            let now = now_ms();
+            let claimed = match self.connected(&mut connection).await {
+                Ok(redis) => {
+                    self.claim
+                        .key(self.members_key())
+                        .key(self.account_key(account_id))
+                        .arg(&self.instance)
+                        .arg(max)
+                        .arg(now)
+                        .arg(now + self.membership_ttl().as_millis() as i64)
+                        .invoke_async::<bool>(redis)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            match claimed {
+                Ok(false) => return false,
+                Ok(true) => {}
+                Err(e) => self.unreachable(&e),
+            }
+        }
+        account.cluster_slots.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Give back the cluster slots held beyond the account's local
+    /// connections
+    pub async fn release(&self, account_id: u64, account: &AccountState) {
+        let mut connection = self.connection.lock().await;
+        let active = account.active_connections.load(Ordering::Relaxed);
+        let held = account.cluster_slots.load(Ordering::Relaxed);
+        if held <= active {
+            return;
+        }
+        // Counted down locally first: if Redis misses the release, the next
+        // heartbeat publishes the lower count
+        account
+            .cluster_slots
+            .fetch_sub(held - active, Ordering::Relaxed);
+        if !self.reachable.load(Ordering::Relaxed) {
+            return;
+        }
+        let released = match self.connected(&mut connection).await {
+            Ok(redis) => {
+                self.release
+                    .key(self.account_key(account_id))
+                    .arg(&self.instance)
+                    .arg(held - active)
+                    .invoke_async::<i64>(redis)
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = released {
+            self.unreachable(&e);
+        }
+    }
+
+    /// Renew membership, publish this instance's slots and read the other
+    /// members' into each account's `remote_connections`
+    async fn heartbeat(&self, state: &AppState) -> redis::RedisResult<()> {
+        let mut connection = self.connection.lock().await;
+        let redis = self.connected(&mut connection).await?;
+        let accounts: Vec<(u64, Arc<AccountState>)> = state
+            .accounts
+            .load()
+            .iter()
+            .map(|account| (*account.key(), account.value().clone()))
+            .collect();
+
+        let now = now_ms();
+        let members = self.members_key();
+        let mut publish = redis::pipe();
+        publish
+            .zadd(
+                &members,
+                &self.instance,
+                now + self.membership_ttl().as_millis() as i64,
+            )
+            .ignore()
+            .zrembyscore(&members, "-inf", format!("({}", now))
+            .ignore();
+        for (account_id, account) in &accounts {
+            let key = self.account_key(*account_id);
+            match account.cluster_slots.load(Ordering::Relaxed) {
+                0 => publish.hdel(key, &self.instance).ignore(),
+                slots => publish.hset(key, &self.instance, slots).ignore(),
+            };
+        }
+        publish.zrangebyscore(&members, now, "+inf");
+        let (live,): (Vec<String>,) = publish.query_async(redis).await?;
+
+        let mut read = redis::pipe();
+        for (account_id, _) in &accounts {
+            read.hgetall(self.account_key(*account_id));
+        }
+        let slots: Vec<HashMap<String, u32>> = read.query_async(redis).await?;
+        for ((_, account), slots) in accounts.iter().zip(slots) {
+            let remote = slots
+                .iter()
+                .filter(|(instance, _)| **instance != self.instance && live.contains(instance))
+                .map(|(_, count)| count)
+                .sum();
+            account.remote_connections.store(remote, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    async fn connected<'a>(
+        &self,
+        connection: &'a mut Option<ConnectionManager>,
+    ) -> redis::RedisResult<&'a mut ConnectionManager> {
+        if connection.is_none() {
+            let config = ConnectionManagerConfig::new()
+                .set_number_of_retries(1)
+                .set_connection_timeout(REDIS_TIMEOUT)
+                .set_response_timeout(REDIS_TIMEOUT);
+            *connection =
+                Some(ConnectionManager::new_with_config(self.client.clone(), config).await?);
+        }
+        Ok(connection.as_mut().expect("connected above"))
+    }
+
+    fn unreachable(&self, e: &redis::RedisError) {
+        if self.reachable.swap(false, Ordering::Relaxed) {
+            tracing::warn!(
+                "Cluster: Redis unreachable, enforcing account limits locally: {}",
+                e
+            );
+        }
+    }
+
+    fn membership_ttl(&self) -> Duration {
+        self.interval * MEMBERSHIP_INTERVALS
+    }
+
+    fn members_key(&self) -> String {
+        format!("{}:members", self.prefix)
+    }
+
+    fn account_key(&self, account_id: u64) -> String {
+        format!("{}:account:{}", self.prefix, account_id)
+    }
+}
+
+/// Spawn the cluster heartbeat, run every `cluster_sync_interval`
+pub fn spawn_heartbeat(state: Arc<AppState>, cluster: Arc<Cluster>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(cluster.interval);
+        loop {
+            interval.tick().await;
+            match cluster.heartbeat(&state).await {
+                Ok(()) => {
+                    if !cluster.reachable.swap(true, Ordering::Relaxed) {
+                        tracing::info!("Cluster: Redis reachable again, account slots republished");
+                    }
+                }
+                Err(e) => cluster.unreachable(&e),
+            }
+        }
+    })
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
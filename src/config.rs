@@ -0,0 +1,798 @@
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// A set of routes a listener can serve
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteGroup {
+    /// `/stream/*`
+    Stream,
+    /// `/control/v1/*`
+    Control,
+    /// `/status/v1/*` (except metrics)
+    Status,
+    /// `/status/v1/metrics`
+    Metrics,
+}
+
+impl RouteGroup {
+    pub const ALL: [RouteGroup; 4] = [
+        RouteGroup::Stream,
+        RouteGroup::Control,
+        RouteGroup::Status,
+        RouteGroup::Metrics,
+    ];
+
+    fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            "stream" => Some(RouteGroup::Stream),
+            "control" => Some(RouteGroup::Control),
+            "status" => Some(RouteGroup::Status),
+            "metrics" => Some(RouteGroup::Metrics),
+            _ => None,
+        }
+    }
+}
+
+/// One HTTP listener and the route groups it serves
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    pub addr: SocketAddr,
+    pub groups: Vec<RouteGroup>,
+    /// Serve HTTPS, with `cert_file`/`key_file` if set and the certificate
+    /// from `TLS_CERT_FILE`/`TLS_KEY_FILE` otherwise
+    pub tls: bool,
+    /// PEM certificate chain for this TLS listener
+    pub cert_file: Option<String>,
+    /// PEM private key for `cert_file`
+    pub key_file: Option<String>,
+}
+
+impl ListenerConfig {
+    /// Parse `[https://]addr[=group,group,...][?cert=path&key=path]`;
+    /// without groups the listener serves everything, with the `https://`
+    /// prefix it terminates TLS, with its own certificate if one is given.
+    fn parse(spec: &str) -> Option<Self> {
+        let spec = spec.trim();
+        let (spec, tls) = match spec.strip_prefix("https://") {
+            Some(rest) => (rest, true),
+            None => (spec.strip_prefix("http://").unwrap_or(spec), false),
+        };
+        let (mut cert_file, mut key_file) = (None, None);
+        let spec = match spec.split_once('?') {
+            Some((spec, certificate)) if tls => {
+                for param in certificate.split('&') {
+                    match param.split_once('=')? {
+                        ("cert", path) => cert_file = Some(path.to_string()),
+                        ("key", path) => key_file = Some(path.to_string()),
+                        _ => return None,
+                    }
+                }
+                if cert_file.is_none() || key_file.is_none() {
+                    return None;
+                }
+                spec
+            }
+            Some(_) => return None,
+            None => spec,
+        };
+        let (addr, groups) = match spec.split_once('=') {
+            Some((addr, groups)) => {
+                let mut unique = Vec::new();
+                for group in groups.split(',') {
+                    let group = RouteGroup::parse(group)?;
+                    if !unique.contains(&group) {
+                        unique.push(group);
+                    }
+                }
+                (addr, unique)
+            }
+            None => (spec, RouteGroup::ALL.to_vec()),
+        };
+        Some(Self {
+            addr: addr.trim().parse().ok()?,
+            groups,
+            tls,
+            cert_file,
+            key_file,
+        })
+    }
+}
+
+/// A customer lineup served on its own hostnames, from `TENANTS_FILE`
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct TenantConfig {
+    /// Namespace: the tenant's channels are the ones with ids `{name}:{id}`,
+    /// and its viewers address them as plain `{id}`
+    pub name: String,
+    /// Hostnames (`tv.example.com`, or `*.example.com`) whose requests and
+    /// TLS connections belong to the tenant
+    pub hosts: Vec<String>,
+    /// PEM certificate chain served on TLS listeners for the tenant's hosts
+    pub cert_file: Option<String>,
+    /// PEM private key for `cert_file`
+    pub key_file: Option<String>,
+    /// Auth callback for the tenant's channels, in place of AUTH_CALLBACK_URL
+    pub auth_callback_url: Option<String>,
+    /// Viewer token secret for the tenant's channels, in place of
+    /// STREAM_TOKEN_SECRET; tokens are signed over the tenant's own ids
+    pub stream_token_secret: Option<String>,
+}
+
+impl TenantConfig {
+    /// Whether `host` is one of the tenant's hostnames
+    pub fn serves_host(&self, host: &str) -> bool {
+        self.hosts
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                Some(domain) => host
+                    .len()
+                    .checked_sub(domain.len() + 1)
+                    .filter(|&dot| dot > 0 && host.as_bytes()[dot] == b'.')
+                    .is_some_and(|dot| host[dot + 1..].eq_ignore_ascii_case(domain)),
+                None => host.eq_ignore_ascii_case(pattern),
+            })
+    }
+}
+
+/// An IP network in CIDR notation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    /// Parse `addr/prefix`; a bare address is a single-host network.
+    pub fn parse(spec: &str) -> Option<Self> {
+        let (addr, prefix) = match spec.trim().split_once('/') {
+            Some((addr, prefix)) => (addr.parse().ok()?, Some(prefix.parse().ok()?)),
+            None => (spec.trim().parse().ok()?, None),
+        };
+        let max = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix = prefix.unwrap_or(max);
+        (prefix <= max).then_some(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 peers may show up as IPv4-mapped IPv6 on dual-stack sockets
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Runtime configuration, read from environment variables at startup.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Utilization (percent of max_connections) at which an account counts as near capacity
+    pub account_warn_threshold: u32,
+    /// How long an account must stay near capacity before a warning is raised
+    pub account_warn_after: Duration,
+    /// How often account utilization is sampled
+    pub account_sample_interval: Duration,
+    /// Maximum upstream connection attempts in flight across all channels (0 = unlimited)
+    pub max_concurrent_starts: usize,
+    /// Maximum upstream connection attempts in flight to any one provider
+    /// host (0 = unlimited); further starts queue
+    pub host_max_concurrent_starts: usize,
+    /// Persistent channels started per second during warm-up (0 = no ramp)
+    pub warmup_rate: u32,
+    /// Default viewer auth webhook; channels may override it
+    pub auth_callback_url: Option<String>,
+    /// Timeout for a single auth webhook call
+    pub auth_callback_timeout: Duration,
+    /// How long an admitted viewer's auth decision is reused (0 = no caching)
+    pub auth_cache_ttl: Duration,
+    /// How long a denial is reused (0 = don't cache denials)
+    pub auth_cache_negative_ttl: Duration,
+    /// How often account connection counters are reconciled against live usage
+    pub reconcile_interval: Duration,
+    /// Bearer token required for admin endpoints (unset = admin endpoints disabled)
+    pub admin_token: Option<String>,
+    /// Shared secret for signed viewer tokens; when set every stream request
+    /// needs a valid `?token=` for its channel
+    pub stream_token_secret: Option<String>,
+    /// Bearer token accepted on `/control/v1/*`
+    pub control_token: Option<String>,
+    /// Shared secret for HMAC-signed control requests; with neither this nor
+    /// `control_token` set the control API is unauthenticated
+    pub control_hmac_secret: Option<String>,
+    /// Unix socket the control API is also served on (None = off). Requests
+    /// on it skip the token and signature checks; access is governed by the
+    /// socket file's permissions.
+    pub control_socket: Option<String>,
+    /// Permissions the control socket file is created with
+    pub control_socket_mode: u32,
+    /// Address of the gRPC control service, mirroring the control API with
+    /// the same token (None = off; HMAC signatures don't apply to it)
+    pub grpc_listen: Option<SocketAddr>,
+    /// Tenants sharing this proxy, from the JSON list in `TENANTS_FILE`:
+    /// each gets a channel namespace, an auth realm and optionally its own
+    /// TLS certificate, selected by hostname
+    pub tenants: Vec<TenantConfig>,
+    /// Tokio worker threads for the main runtime (0 = one per core)
+    pub worker_threads: usize,
+    /// Upper bound on tokio's blocking thread pool
+    pub max_blocking_threads: usize,
+    /// Run upstream reader tasks on a separate runtime from client I/O
+    pub upstream_runtime: bool,
+    /// Worker threads for the dedicated upstream runtime (0 = one per core)
+    pub upstream_worker_threads: usize,
+    /// HTTP listeners, from `LISTENERS="addr=group,...;addr=group,..."`
+    /// (`https://addr=...` for a TLS listener, `https://addr=...?cert=path&key=path`
+    /// for one with its own certificate)
+    pub listeners: Vec<ListenerConfig>,
+    /// PEM certificate chain for TLS listeners without their own
+    pub tls_cert_file: Option<String>,
+    /// PEM private key for TLS listeners without their own
+    pub tls_key_file: Option<String>,
+    /// How often the TLS certificate files are checked for changes, so
+    /// rotated certs are picked up without a restart (0 = never)
+    pub tls_reload_interval: Duration,
+    /// TCP address that serves channels to other proxies over the framed
+    /// relay protocol (`relay://host:port/channel_id` sources; None = off)
+    pub relay_listen: Option<SocketAddr>,
+    /// Token a relay peer must present; required with `relay_listen`, since
+    /// relayed channels bypass viewer auth
+    pub relay_token: Option<String>,
+    /// Broadcast queue depth (chunks) at which upstream reading pauses (0 = never pause)
+    pub upstream_high_watermark: usize,
+    /// Queue depth the slowest client must drain to before reading resumes
+    pub upstream_low_watermark: usize,
+    /// Longest upstream reading stays paused; after that laggards are dropped instead
+    pub upstream_max_pause: Duration,
+    /// An upstream connection that delivers no bytes for this long counts as
+    /// failed and goes through failover (0 = wait indefinitely)
+    pub upstream_read_timeout: Duration,
+    /// Longest an upstream TCP/TLS connect may take (0 = no limit)
+    pub upstream_connect_timeout: Duration,
+    /// Longest an upstream request may wait for response headers once sent
+    /// (0 = no limit)
+    pub upstream_response_timeout: Duration,
+    /// Redirects followed per upstream request (0 = don't follow)
+    pub upstream_max_redirects: usize,
+    /// Accept invalid or self-signed upstream TLS certificates, for providers
+    /// with broken certs. Applies to every upstream request.
+    pub upstream_accept_invalid_certs: bool,
+    /// Routing entries not looked up for this long are hibernated to a
+    /// compact serialized form and hydrated again on first use; bulk syncs
+    /// also load unused channels hibernated (0 = keep everything hydrated)
+    pub routing_hibernate_after: Duration,
+    /// Longest upstream data waits in the chunk buffer before it is
+    /// broadcast, so low-bitrate channels don't wait for a full chunk
+    /// (0 = flush on size only)
+    pub chunk_flush_interval: Duration,
+    /// How often the account balancer runs (0 = disabled)
+    pub rebalance_interval: Duration,
+    /// How often failed-over channels probe their more preferred sources to
+    /// fail back (0 = disabled)
+    pub failback_interval: Duration,
+    /// How often one alternate source of an active channel is sampled in the
+    /// background to keep its score current (0 = disabled)
+    pub source_sample_interval: Duration,
+    /// How long each background source sample reads for
+    pub source_sample_duration: Duration,
+    /// Utilization (percent) at which the balancer moves channels off an account
+    pub rebalance_high_percent: u32,
+    /// Utilization a target account may reach after a move, keeping headroom for cold starts
+    pub rebalance_low_percent: u32,
+    /// Minimum time on a source before switching premium/standard tiers, so
+    /// viewer counts hovering around the threshold don't cause flapping
+    pub tier_switch_holdoff: Duration,
+    /// How long a channel keeps its upstream after the last client leaves, so
+    /// reconnecting players reattach without an upstream restart (0 = stop at once)
+    pub idle_grace: Duration,
+    /// Target length of HLS output segments
+    pub hls_segment_duration: Duration,
+    /// Segments listed in an HLS output playlist
+    pub hls_window_segments: usize,
+    /// HLS output stops after this long without playlist or segment requests
+    pub hls_output_idle: Duration,
+    /// How often encrypted HLS channels get a new segment key (0 = never)
+    pub hls_key_rotation: Duration,
+    /// Recent chunks burst to joining clients when no keyframe-aligned GOP
+    /// is cached (0 = disabled)
+    pub join_buffer_chunks: usize,
+    /// Oldest chunk kept in the join buffer (0 = no age limit)
+    pub join_buffer_max_age: Duration,
+    /// Per-client send queue (chunks) filled from the channel broadcast, so a
+    /// slow client loses only what overflows its own queue (0 = clients read
+    /// the broadcast directly)
+    pub client_queue_chunks: usize,
+    /// A client joining a channel with no data yet gets 504 if the first
+    /// chunk doesn't arrive within this long (0 = wait indefinitely)
+    pub stream_start_timeout: Duration,
+    /// A UDP/RTP input that delivers nothing for this long counts as failed
+    pub multicast_timeout: Duration,
+    /// Upstream failures on one account within `account_failure_window` that
+    /// suspend its retries everywhere (0 = never suspend)
+    pub account_failure_threshold: usize,
+    /// Sliding window over which account failures are counted
+    pub account_failure_window: Duration,
+    /// How long a failing account's retries stay suspended
+    pub account_retry_suspend: Duration,
+    /// How long source selection avoids a URL after it fails, doubled for
+    /// each further connect failure in a row (0 = no cooldown)
+    pub url_cooldown: Duration,
+    /// Upper bound for the doubled URL cooldown
+    pub url_cooldown_max: Duration,
+    /// Simultaneous stream connections allowed from one client IP across all
+    /// channels (0 = unlimited); channels can set a tighter limit of their own
+    pub max_clients_per_ip: usize,
+    /// New clients admitted per second on one channel (0 = unlimited);
+    /// joins beyond the burst wait their turn
+    pub join_rate: f64,
+    /// Clients that may join a channel at once before `join_rate` applies
+    pub join_burst: u32,
+    /// Longest a joining client waits for its turn before being turned away
+    pub join_queue_timeout: Duration,
+    /// Times a client may fall behind the broadcast buffer before its
+    /// connection is closed (0 = never); channels can override it
+    pub max_client_lag_events: u64,
+    /// Data a client may miss by falling behind before its connection is
+    /// closed (0 = unlimited); channels can override it
+    pub max_client_lag_bytes: u64,
+    /// Gap in a channel's data after which stream clients get TS null
+    /// packets as keepalive, repeated while it lasts (0 = never); channels
+    /// can override it
+    pub keepalive_after: Duration,
+    /// HTTPS upstreams whose certificate expires within this many days are
+    /// flagged in stream health
+    pub tls_expiry_warn_days: u32,
+    /// New upstream connections per second to any one provider host (0 = unlimited)
+    pub host_connect_rate: f64,
+    /// Per-host overrides of `host_connect_rate`, from
+    /// `HOST_CONNECT_RATES="host=rate;host=rate"`
+    pub host_connect_rates: Vec<(String, f64)>,
+    /// Reverse proxies and load balancers whose X-Forwarded-For / Forwarded
+    /// headers are believed for the client address, from
+    /// `TRUSTED_PROXIES="10.0.0.0/8,127.0.0.1"` (empty = use the socket peer)
+    pub trusted_proxies: Vec<IpNet>,
+    /// GeoLite2 Country database used to enrich client addresses in status
+    pub geoip_country_db: Option<String>,
+    /// GeoLite2 ASN database used to enrich client addresses in status
+    pub geoip_asn_db: Option<String>,
+    /// Recent channel and client events kept for `/status/v1/events/export`
+    /// (0 = keep none; followers still get new ones)
+    pub event_ring_size: usize,
+    /// Channel and client events are POSTed here as they happen (None = off)
+    pub webhook_url: Option<String>,
+    /// Key for the HMAC-SHA256 signature on webhook deliveries (None = unsigned)
+    pub webhook_secret: Option<String>,
+    /// Timeout for a single webhook delivery attempt
+    pub webhook_timeout: Duration,
+    /// Pushgateway group URL the `/metrics` exposition is PUT to
+    /// (e.g. `http://pgw:9091/metrics/job/proxy/instance/edge1`; None = off)
+    pub metrics_push_url: Option<String>,
+    /// Basic auth username for metrics pushes
+    pub metrics_push_username: Option<String>,
+    /// Basic auth password for metrics pushes
+    pub metrics_push_password: Option<String>,
+    /// Time between metrics pushes
+    pub metrics_push_interval: Duration,
+    /// PID that source changes are signalled on in-band, as a private
+    /// section carrying a JSON notice: `failover` after an upstream error,
+    /// `switch` for planned moves such as tier switches (0 = off)
+    pub failover_marker_pid: u16,
+    /// File the routing state (channels and accounts) is saved to after
+    /// every control change and restored from at startup (None = off)
+    pub state_file: Option<String>,
+    /// Orchestrator URL the full sync payload is fetched from before the
+    /// listeners start and then every `sync_interval` (None = push only)
+    pub sync_url: Option<String>,
+    /// Bearer token sent with sync pulls
+    pub sync_token: Option<String>,
+    /// Time between sync pulls after the first (0 = only at startup)
+    pub sync_interval: Duration,
+    /// Redis server shared by the proxy instances of a cluster, through
+    /// which account connection limits are enforced across all of them
+    /// (e.g. `redis://redis:6379/0`; None = each instance counts alone)
+    pub cluster_redis_url: Option<String>,
+    /// This instance's name in the cluster (random per process by default)
+    pub cluster_instance_id: String,
+    /// Prefix of the Redis keys the cluster uses, so several clusters can
+    /// share one server
+    pub cluster_key_prefix: String,
+    /// Time between cluster heartbeats; an instance missing three is
+    /// considered gone and its connection slots are reclaimed
+    pub cluster_sync_interval: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            account_warn_threshold: 90,
+            account_warn_after: Duration::from_secs(300),
+            account_sample_interval: Duration::from_secs(10),
+            max_concurrent_starts: 0,
+            host_max_concurrent_starts: 0,
+            warmup_rate: 2,
+            auth_callback_url: None,
+            auth_callback_timeout: Duration::from_secs(5),
+            auth_cache_ttl: Duration::from_secs(60),
+            auth_cache_negative_ttl: Duration::from_secs(10),
+            reconcile_interval: Duration::from_secs(60),
+            admin_token: None,
+            stream_token_secret: None,
+            control_token: None,
+            control_hmac_secret: None,
+            control_socket: None,
+            control_socket_mode: 0o660,
+            grpc_listen: None,
+            tenants: Vec::new(),
+            worker_threads: 0,
+            max_blocking_threads: 512,
+            upstream_runtime: false,
+            upstream_worker_threads: 0,
+            listeners: vec![ListenerConfig {
+                addr: SocketAddr::from(([0, 0, 0, 0], 8888)),
+                groups: RouteGroup::ALL.to_vec(),
+                tls: false,
+                cert_file: None,
+                key_file: None,
+            }],
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_reload_interval: Duration::from_secs(60),
+            relay_listen: None,
+            relay_token: None,
+            upstream_high_watermark: 48,
+            upstream_low_watermark: 16,
+            upstream_max_pause: Duration::from_secs(2),
+            upstream_read_timeout: Duration::from_secs(10),
+            upstream_connect_timeout: Duration::from_secs(10),
+            upstream_response_timeout: Duration::from_secs(15),
+            upstream_max_redirects: 10,
+            upstream_accept_invalid_certs: false,
+            routing_hibernate_after: Duration::ZERO,
+            chunk_flush_interval: Duration::from_millis(200),
+            rebalance_interval: Duration::ZERO,
+            failback_interval: Duration::ZERO,
+            source_sample_interval: Duration::ZERO,
+            source_sample_duration: Duration::from_secs(5),
+            rebalance_high_percent: 90,
+            rebalance_low_percent: 50,
+            tier_switch_holdoff: Duration::from_secs(30),
+            idle_grace: Duration::ZERO,
+            hls_segment_duration: Duration::from_secs(4),
+            hls_window_segments: 6,
+            hls_output_idle: Duration::from_secs(30),
+            hls_key_rotation: Duration::from_secs(300),
+            join_buffer_chunks: 4,
+            join_buffer_max_age: Duration::from_secs(5),
+            client_queue_chunks: 0,
+            stream_start_timeout: Duration::from_secs(10),
+            multicast_timeout: Duration::from_secs(5),
+            account_failure_threshold: 0,
+            account_failure_window: Duration::from_secs(60),
+            account_retry_suspend: Duration::from_secs(60),
+            url_cooldown: Duration::ZERO,
+            url_cooldown_max: Duration::from_secs(300),
+            max_clients_per_ip: 0,
+            join_rate: 0.0,
+            join_burst: 50,
+            join_queue_timeout: Duration::from_secs(5),
+            max_client_lag_events: 0,
+            max_client_lag_bytes: 0,
+            keepalive_after: Duration::from_secs(1),
+            tls_expiry_warn_days: 14,
+            host_connect_rate: 0.0,
+            host_connect_rates: Vec::new(),
+            trusted_proxies: Vec::new(),
+            geoip_country_db: None,
+            geoip_asn_db: None,
+            event_ring_size: 1000,
+            webhook_url: None,
+            webhook_secret: None,
+            webhook_timeout: Duration::from_secs(5),
+            metrics_push_url: None,
+            metrics_push_username: None,
+            metrics_push_password: None,
+            metrics_push_interval: Duration::from_secs(15),
+            failover_marker_pid: 0,
+            state_file: None,
+            sync_url: None,
+            sync_token: None,
+            sync_interval: Duration::from_secs(300),
+            cluster_redis_url: None,
+            cluster_instance_id: uuid::Uuid::new_v4().to_string(),
+            cluster_key_prefix: "dispatcharr-proxy".to_string(),
+            cluster_sync_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Config {
+    /// HTTP client settings shared by everything that talks to providers
+    pub fn upstream_client_builder(&self) -> reqwest::ClientBuilder {
+        let redirects = match self.upstream_max_redirects {
+            0 => reqwest::redirect::Policy::none(),
+            n => reqwest::redirect::Policy::limited(n),
+        };
+        let mut builder = reqwest::Client::builder()
+            .tls_info(true)
+            .redirect(redirects)
+            .danger_accept_invalid_certs(self.upstream_accept_invalid_certs);
+        if !self.upstream_connect_timeout.is_zero() {
+            builder = builder.connect_timeout(self.upstream_connect_timeout);
+        }
+        builder
+    }
+
+    /// New connections per second allowed to `host` (0 = unlimited)
+    pub fn connect_rate(&self, host: &str) -> f64 {
+        self.host_connect_rates
+            .iter()
+            .find(|(h, _)| h.eq_ignore_ascii_case(host))
+            .map_or(self.host_connect_rate, |(_, rate)| *rate)
+    }
+
+    pub fn from_env() -> Self {
+        let d = Self::default();
+        Self {
+            account_warn_threshold: env_parse("ACCOUNT_WARN_THRESHOLD", d.account_warn_threshold),
+            account_warn_after: env_secs("ACCOUNT_WARN_AFTER_SECS", d.account_warn_after),
+            account_sample_interval: env_secs(
+                "ACCOUNT_SAMPLE_INTERVAL_SECS",
+                d.account_sample_interval,
+            ),
+            max_concurrent_starts: env_parse("MAX_CONCURRENT_STARTS", d.max_concurrent_starts),
+            host_max_concurrent_starts: env_parse(
+                "HOST_MAX_CONCURRENT_STARTS",
+                d.host_max_concurrent_starts,
+            ),
+            warmup_rate: env_parse("WARMUP_RATE", d.warmup_rate),
+            auth_callback_url: env_string("AUTH_CALLBACK_URL"),
+            auth_callback_timeout: env_secs("AUTH_CALLBACK_TIMEOUT_SECS", d.auth_callback_timeout),
+            auth_cache_ttl: env_secs("AUTH_CACHE_TTL_SECS", d.auth_cache_ttl),
+            auth_cache_negative_ttl: env_secs(
+                "AUTH_CACHE_NEGATIVE_TTL_SECS",
+                d.auth_cache_negative_ttl,
+            ),
+            reconcile_interval: env_secs("RECONCILE_INTERVAL_SECS", d.reconcile_interval),
+            admin_token: env_string("ADMIN_TOKEN"),
+            stream_token_secret: env_string("STREAM_TOKEN_SECRET"),
+            control_token: env_string("CONTROL_TOKEN"),
+            control_hmac_secret: env_string("CONTROL_HMAC_SECRET"),
+            control_socket: env_string("CONTROL_SOCKET"),
+            control_socket_mode: env_octal("CONTROL_SOCKET_MODE", d.control_socket_mode),
+            grpc_listen: env_addr("GRPC_LISTEN"),
+            tenants: env_tenants("TENANTS_FILE"),
+            worker_threads: env_parse("WORKER_THREADS", d.worker_threads),
+            max_blocking_threads: env_parse("MAX_BLOCKING_THREADS", d.max_blocking_threads),
+            upstream_runtime: env_parse("UPSTREAM_RUNTIME", d.upstream_runtime),
+            upstream_worker_threads: env_parse(
+                "UPSTREAM_WORKER_THREADS",
+                d.upstream_worker_threads,
+            ),
+            listeners: env_listeners("LISTENERS", d.listeners),
+            tls_cert_file: env_string("TLS_CERT_FILE"),
+            tls_key_file: env_string("TLS_KEY_FILE"),
+            tls_reload_interval: env_secs("TLS_RELOAD_SECS", d.tls_reload_interval),
+            relay_listen: env_addr("RELAY_LISTEN"),
+            relay_token: env_string("RELAY_TOKEN"),
+            upstream_high_watermark: env_parse(
+                "UPSTREAM_HIGH_WATERMARK",
+                d.upstream_high_watermark,
+            ),
+            upstream_low_watermark: env_parse("UPSTREAM_LOW_WATERMARK", d.upstream_low_watermark),
+            upstream_max_pause: env_millis("UPSTREAM_MAX_PAUSE_MS", d.upstream_max_pause),
+            upstream_read_timeout: env_secs("UPSTREAM_READ_TIMEOUT_SECS", d.upstream_read_timeout),
+            upstream_connect_timeout: env_secs(
+                "UPSTREAM_CONNECT_TIMEOUT_SECS",
+                d.upstream_connect_timeout,
+            ),
+            upstream_response_timeout: env_secs(
+                "UPSTREAM_RESPONSE_TIMEOUT_SECS",
+                d.upstream_response_timeout,
+            ),
+            upstream_max_redirects: env_parse("UPSTREAM_MAX_REDIRECTS", d.upstream_max_redirects),
+            upstream_accept_invalid_certs: env_parse(
+                "UPSTREAM_ACCEPT_INVALID_CERTS",
+                d.upstream_accept_invalid_certs,
+            ),
+            routing_hibernate_after: env_secs("ROUTING_HIBERNATE_SECS", d.routing_hibernate_after),
+            chunk_flush_interval: env_millis("CHUNK_FLUSH_INTERVAL_MS", d.chunk_flush_interval),
+            rebalance_interval: env_secs("REBALANCE_INTERVAL_SECS", d.rebalance_interval),
+            failback_interval: env_secs("FAILBACK_INTERVAL_SECS", d.failback_interval),
+            source_sample_interval: env_secs(
+                "SOURCE_SAMPLE_INTERVAL_SECS",
+                d.source_sample_interval,
+            ),
+            source_sample_duration: env_secs("SOURCE_SAMPLE_SECS", d.source_sample_duration),
+            rebalance_high_percent: env_parse("REBALANCE_HIGH_PERCENT", d.rebalance_high_percent),
+            rebalance_low_percent: env_parse("REBALANCE_LOW_PERCENT", d.rebalance_low_percent),
+            tier_switch_holdoff: env_secs("TIER_SWITCH_HOLDOFF_SECS", d.tier_switch_holdoff),
+            idle_grace: env_secs("IDLE_GRACE_SECS", d.idle_grace),
+            hls_segment_duration: env_secs("HLS_SEGMENT_SECS", d.hls_segment_duration),
+            hls_window_segments: env_parse("HLS_WINDOW_SEGMENTS", d.hls_window_segments),
+            hls_output_idle: env_secs("HLS_OUTPUT_IDLE_SECS", d.hls_output_idle),
+            hls_key_rotation: env_secs("HLS_KEY_ROTATION_SECS", d.hls_key_rotation),
+            join_buffer_chunks: env_parse("JOIN_BUFFER_CHUNKS", d.join_buffer_chunks),
+            join_buffer_max_age: env_secs("JOIN_BUFFER_MAX_AGE_SECS", d.join_buffer_max_age),
+            client_queue_chunks: env_parse("CLIENT_QUEUE_CHUNKS", d.client_queue_chunks),
+            stream_start_timeout: env_secs("STREAM_START_TIMEOUT_SECS", d.stream_start_timeout),
+            multicast_timeout: env_millis("MULTICAST_TIMEOUT_MS", d.multicast_timeout),
+            account_failure_threshold: env_parse(
+                "ACCOUNT_FAILURE_THRESHOLD",
+                d.account_failure_threshold,
+            ),
+            account_failure_window: env_secs(
+                "ACCOUNT_FAILURE_WINDOW_SECS",
+                d.account_failure_window,
+            ),
+            account_retry_suspend: env_secs("ACCOUNT_RETRY_SUSPEND_SECS", d.account_retry_suspend),
+            url_cooldown: env_secs("URL_COOLDOWN_SECS", d.url_cooldown),
+            url_cooldown_max: env_secs("URL_COOLDOWN_MAX_SECS", d.url_cooldown_max),
+            max_clients_per_ip: env_parse("MAX_CLIENTS_PER_IP", d.max_clients_per_ip),
+            join_rate: env_parse("JOIN_RATE", d.join_rate),
+            join_burst: env_parse("JOIN_BURST", d.join_burst),
+            join_queue_timeout: env_secs("JOIN_QUEUE_SECS", d.join_queue_timeout),
+            max_client_lag_events: env_parse("MAX_CLIENT_LAG_EVENTS", d.max_client_lag_events),
+            max_client_lag_bytes: env_parse("MAX_CLIENT_LAG_BYTES", d.max_client_lag_bytes),
+            keepalive_after: env_millis("KEEPALIVE_AFTER_MS", d.keepalive_after),
+            tls_expiry_warn_days: env_parse("TLS_EXPIRY_WARN_DAYS", d.tls_expiry_warn_days),
+            host_connect_rate: env_parse("HOST_CONNECT_RATE", d.host_connect_rate),
+            host_connect_rates: env_host_rates("HOST_CONNECT_RATES", d.host_connect_rates),
+            trusted_proxies: env_networks("TRUSTED_PROXIES", d.trusted_proxies),
+            geoip_country_db: env_string("GEOIP_COUNTRY_DB"),
+            geoip_asn_db: env_string("GEOIP_ASN_DB"),
+            event_ring_size: env_parse("EVENT_RING_SIZE", d.event_ring_size),
+            webhook_url: env_string("WEBHOOK_URL"),
+            webhook_secret: env_string("WEBHOOK_SECRET"),
+            webhook_timeout: env_secs("WEBHOOK_TIMEOUT_SECS", d.webhook_timeout),
+            metrics_push_url: env_string("METRICS_PUSH_URL"),
+            metrics_push_username: env_string("METRICS_PUSH_USERNAME"),
+            metrics_push_password: env_string("METRICS_PUSH_PASSWORD"),
+            metrics_push_interval: env_secs("METRICS_PUSH_INTERVAL_SECS", d.metrics_push_interval),
+            failover_marker_pid: env_parse("FAILOVER_MARKER_PID", d.failover_marker_pid),
+            state_file: env_string("STATE_FILE"),
+            sync_url: env_string("SYNC_URL"),
+            sync_token: env_string("SYNC_TOKEN"),
+            sync_interval: env_secs("SYNC_INTERVAL_SECS", d.sync_interval),
+            cluster_redis_url: env_string("CLUSTER_REDIS_URL"),
+            cluster_instance_id: env_string("CLUSTER_INSTANCE_ID").unwrap_or(d.cluster_instance_id),
+            cluster_key_prefix: env_string("CLUSTER_KEY_PREFIX").unwrap_or(d.cluster_key_prefix),
+            cluster_sync_interval: env_millis("CLUSTER_SYNC_INTERVAL_MS", d.cluster_sync_interval),
+        }
+    }
+}
+
+/// Parse an environment variable, falling back to `default` if unset or invalid.
+fn env_parse<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(raw) => match raw.trim().parse() {
+            Ok(v) => v,
+            Err(_) => {
+                tracing::warn!("Ignoring invalid value for {}: {:?}", name, raw);
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+/// Read a duration given in whole seconds.
+fn env_secs(name: &str, default: Duration) -> Duration {
+    Duration::from_secs(env_parse(name, default.as_secs()))
+}
+
+/// Read a duration given in whole milliseconds.
+fn env_millis(name: &str, default: Duration) -> Duration {
+    Duration::from_millis(env_parse(name, default.as_millis() as u64))
+}
+
+/// Read an optional string, treating empty as unset.
+fn env_string(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.trim().is_empty())
+}
+
+/// Read file permissions given in octal (`660`, `0600`).
+fn env_octal(name: &str, default: u32) -> u32 {
+    let Some(raw) = env_string(name) else {
+        return default;
+    };
+    match u32::from_str_radix(raw.trim(), 8) {
+        Ok(mode) if mode <= 0o7777 => mode,
+        _ => {
+            tracing::warn!("Ignoring invalid value for {}: {:?}", name, raw);
+            default
+        }
+    }
+}
+
+/// Read the tenant list from the JSON file named by the variable. Unlike
+/// other settings an unreadable file is fatal: without it the tenants'
+/// channels would be reachable on every hostname.
+fn env_tenants(name: &str) -> Vec<TenantConfig> {
+    let Some(path) = env_string(name) else {
+        return Vec::new();
+    };
+    std::fs::read(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|data| serde_json::from_slice(&data).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| panic!("{} {}: {}", name, path, e))
+}
+
+/// Read an optional socket address.
+fn env_addr(name: &str) -> Option<SocketAddr> {
+    let raw = env_string(name)?;
+    match raw.trim().parse() {
+        Ok(addr) => Some(addr),
+        Err(_) => {
+            tracing::warn!("Ignoring invalid value for {}: {:?}", name, raw);
+            None
+        }
+    }
+}
+
+/// Read a `;`-separated list of listener specs.
+fn env_listeners(name: &str, default: Vec<ListenerConfig>) -> Vec<ListenerConfig> {
+    let Some(raw) = env_string(name) else {
+        return default;
+    };
+    let parsed: Option<Vec<ListenerConfig>> = raw
+        .split(';')
+        .filter(|s| !s.trim().is_empty())
+        .map(ListenerConfig::parse)
+        .collect();
+    match parsed {
+        Some(listeners) if !listeners.is_empty() => listeners,
+        _ => {
+            tracing::warn!("Ignoring invalid value for {}: {:?}", name, raw);
+            default
+        }
+    }
+}
+
+/// Read a `;`-separated list of `host=rate` pairs.
+fn env_host_rates(name: &str, default: Vec<(String, f64)>) -> Vec<(String, f64)> {
+    let Some(raw) = env_string(name) else {
+        return default;
+    };
+    let parsed: Option<Vec<(String, f64)>> = raw
+        .split(';')
+        .filter(|s| !s.trim().is_empty())
+        .map(|spec| {
+            let (host, rate) = spec.split_once('=')?;
+            Some((host.trim().to_string(), rate.trim().parse().ok()?))
+        })
+        .collect();
+    match parsed {
+        Some(rates) => rates,
+        None => {
+            tracing::warn!("Ignoring invalid value for {}: {:?}", name, raw);
+            default
+        }
+    }
+}
+
+/// Read a `,`-separated list of CIDR networks.
+fn env_networks(name: &str, default: Vec<IpNet>) -> Vec<IpNet> {
+    let Some(raw) = env_string(name) else {
+        return default;
+    };
+    let parsed: Option<Vec<IpNet>> = raw
+        .split(',')
+        .filter(|s| !s.trim().is_empty())
+        .map(IpNet::parse)
+        .collect();
+    match parsed {
+        Some(networks) => networks,
+        None => {
+            tracing::warn!("Ignoring invalid value for {}: {:?}", name, raw);
+            default
+        }
+    }
+}
@@ -1,37 +1,104 @@
 use crate::models::*;
+use crate::multicast;
 use crate::state::*;
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    extract::{Path, Query, State},
+    http::{HeaderName, HeaderValue, StatusCode},
     Json,
 };
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
+/// URL schemes an upstream can be fetched from
+const UPSTREAM_SCHEMES: [&str; 4] = ["http", "https", "udp", "rtp"];
+
 pub async fn put_channel(
     State(state): State<Arc<AppState>>,
     Path(channel_id): Path<String>,
     Json(config): Json<ChannelConfig>,
 ) -> StatusCode {
-    state.channel_routes.insert(
-        channel_id.clone(),
-        ChannelRouting {
-            streams: config.streams,
-        },
-    );
+    if let Some(group) = &config.multicast_group {
+        if let Err(e) = multicast::validate_url(&multicast::gateway_url(group)) {
+            tracing::warn!(
+                "Channel {}: invalid multicast_group {:?}: {}",
+                channel_id,
+                group,
+                e
+            );
+            return StatusCode::BAD_REQUEST;
+        }
+    }
+    let all_urls = config
+        .streams
+        .iter()
+        .chain(&config.premium_streams)
+        .chain(&config.quota_streams)
+        .flat_map(|s| &s.urls);
+    for url in all_urls {
+        if let Some(name) = invalid_header(&url.headers) {
+            tracing::warn!("Channel {}: invalid header {:?}", channel_id, name);
+            return StatusCode::BAD_REQUEST;
+        }
+    }
+    let enabled = config.enabled;
+    state
+        .channel_routes
+        .load()
+        .insert(channel_id.clone(), ChannelRouting::from(config));
+    if !enabled && stop_channel(&state, &channel_id) {
+        tracing::info!("Channel {} taken off-air", channel_id);
+    }
+    state.warmup.notify.notify_one();
+    state.routing_changed.notify_one();
     tracing::info!("Channel {} config updated", channel_id);
     StatusCode::OK
 }
 
+/// The first entry that can't be sent as an HTTP header, if any
+fn invalid_header(headers: &HashMap<String, String>) -> Option<&String> {
+    headers
+        .iter()
+        .find(|(name, value)| {
+            HeaderName::from_bytes(name.as_bytes()).is_err()
+                || HeaderValue::from_str(value).is_err()
+        })
+        .map(|(name, _)| name)
+}
+
+/// Why an account's `proxy_url` can't be used, if it can't
+fn invalid_proxy(proxy_url: &str) -> Option<String> {
+    let scheme = proxy_url.split_once("://").map(|(scheme, _)| scheme);
+    if !matches!(scheme, Some("http" | "https" | "socks5" | "socks5h")) {
+        return Some("scheme must be http, https, socks5 or socks5h".to_string());
+    }
+    reqwest::Proxy::all(proxy_url).err().map(|e| e.to_string())
+}
+
+/// Signal a channel's upstream to stop if it is running and disconnect its
+/// clients. Returns whether it was running. The upstream task releases the
+/// account slot and removes the channel as it exits.
+fn stop_channel(state: &AppState, channel_id: &str) -> bool {
+    let Some(active) = state.active_channels.get(channel_id).map(|a| a.clone()) else {
+        return false;
+    };
+    let was_running = !active.stop_tx.send_replace(true);
+    for client in active.clients.iter() {
+        client.kick.notify_one();
+    }
+    was_running
+}
+
 pub async fn delete_channel(
     State(state): State<Arc<AppState>>,
     Path(channel_id): Path<String>,
 ) -> StatusCode {
-    state.channel_routes.remove(&channel_id);
+    state.channel_routes.load().remove(&channel_id);
+    state.routing_changed.notify_one();
 
     // Stop active stream if running
-    if let Some((_, active)) = state.active_channels.remove(&channel_id) {
-        let _ = active.stop_tx.send(true);
-        state.decrement_connections(active.account_id);
+    if stop_channel(&state, &channel_id) {
         tracing::info!("Channel {} stopped and removed", channel_id);
     } else {
         tracing::info!("Channel {} config removed", channel_id);
@@ -40,106 +107,518 @@ pub async fn delete_channel(
     StatusCode::OK
 }
 
+/// Move an active channel onto another account. The new upstream is opened
+/// while the old one keeps streaming and takes over once connected.
+pub async fn switch_account(
+    State(state): State<Arc<AppState>>,
+    Path(channel_id): Path<String>,
+    Json(req): Json<SwitchAccountRequest>,
+) -> (StatusCode, &'static str) {
+    let Some(active) = state.active_channels.get(&channel_id).map(|a| a.clone()) else {
+        return (StatusCode::NOT_FOUND, "Channel not active");
+    };
+    let current = active.current_upstream();
+    if current.account_id == req.account_id {
+        return (StatusCode::OK, "Already on account");
+    }
+
+    let Some(next) = state.target_on_account(&channel_id, &current, req.account_id) else {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Channel has no stream on that account",
+        );
+    };
+    if !state.account_available(req.account_id) {
+        return (StatusCode::CONFLICT, "Account unavailable");
+    }
+
+    *active.pending_switch.lock().unwrap() = Some(next);
+    tracing::info!(
+        "Channel {}: switch from account {} to {} requested",
+        channel_id,
+        current.account_id,
+        req.account_id
+    );
+    (StatusCode::ACCEPTED, "Switch scheduled")
+}
+
+/// Move an active channel to the next available upstream, as if the
+/// current one had failed, without disconnecting its clients
+pub async fn force_failover(
+    State(state): State<Arc<AppState>>,
+    Path(channel_id): Path<String>,
+) -> (StatusCode, &'static str) {
+    let Some(active) = state.active_channels.get(&channel_id).map(|a| a.clone()) else {
+        return (StatusCode::NOT_FOUND, "Channel not active");
+    };
+    let current = active.current_upstream();
+    let Some((stream_id, account_id, url)) = state.select_next_stream(
+        &channel_id,
+        current.premium,
+        current.stream_id,
+        current.account_id,
+    ) else {
+        return (StatusCode::CONFLICT, "No other stream available");
+    };
+    tracing::info!(
+        "Channel {}: manual failover from stream={}, account={} to stream={}, account={}",
+        channel_id,
+        current.stream_id,
+        current.account_id,
+        stream_id,
+        account_id
+    );
+    *active.pending_switch.lock().unwrap() = Some(UpstreamTarget {
+        stream_id,
+        account_id,
+        url,
+        premium: current.premium,
+    });
+    (StatusCode::ACCEPTED, "Failover scheduled")
+}
+
+/// Reconnect an active channel's upstream on the same source, without
+/// disconnecting its clients
+pub async fn restart_channel(
+    State(state): State<Arc<AppState>>,
+    Path(channel_id): Path<String>,
+) -> (StatusCode, &'static str) {
+    let Some(active) = state.active_channels.get(&channel_id).map(|a| a.clone()) else {
+        return (StatusCode::NOT_FOUND, "Channel not active");
+    };
+    schedule_restart(&active);
+    tracing::info!("Channel {}: upstream restart requested", channel_id);
+    (StatusCode::ACCEPTED, "Restart scheduled")
+}
+
+/// Stop an active channel's upstream and disconnect its clients. The channel
+/// stays configured and starts again on its next viewer.
+pub async fn stop_active_channel(
+    State(state): State<Arc<AppState>>,
+    Path(channel_id): Path<String>,
+) -> (StatusCode, &'static str) {
+    if !state.active_channels.contains_key(&channel_id) {
+        return (StatusCode::NOT_FOUND, "Channel not active");
+    }
+    stop_channel(&state, &channel_id);
+    tracing::info!("Channel {}: stopped via control API", channel_id);
+    (StatusCode::OK, "Channel stopped")
+}
+
+/// A switch to the current source reconnects make-before-break
+fn schedule_restart(active: &ActiveChannel) {
+    *active.pending_switch.lock().unwrap() = Some(active.current_upstream());
+}
+
+/// Disconnect one viewer without touching the channel's other clients. The
+/// connection's own cleanup then runs as on a normal disconnect.
+pub async fn kick_client(
+    State(state): State<Arc<AppState>>,
+    Path((channel_id, client_id)): Path<(String, String)>,
+) -> (StatusCode, &'static str) {
+    let Some(active) = state.active_channels.get(&channel_id).map(|a| a.clone()) else {
+        return (StatusCode::NOT_FOUND, "Channel not active");
+    };
+    let Some(client) = active.clients.get(&client_id) else {
+        return (StatusCode::NOT_FOUND, "Client not connected");
+    };
+    client.kick.notify_one();
+    tracing::info!(
+        "Channel {}: client {} ({}) kicked",
+        channel_id,
+        client_id,
+        client.remote_addr
+    );
+    (StatusCode::OK, "Client disconnected")
+}
+
+/// Zero a channel's counters and the connection history of its upstream
+/// URLs, so dashboards start clean once a broken source is fixed. URL history
+/// is shared with other channels using the same URLs; quota usage is kept.
+pub async fn reset_stats(
+    State(state): State<Arc<AppState>>,
+    Path(channel_id): Path<String>,
+) -> (StatusCode, &'static str) {
+    let urls: Vec<String> = {
+        let routes = state.channel_routes.load();
+        let Some(routing) = routes.get(&channel_id) else {
+            return (StatusCode::NOT_FOUND, "Channel not found");
+        };
+        routing
+            .streams
+            .iter()
+            .chain(&routing.premium_streams)
+            .chain(&routing.quota_streams)
+            .flat_map(|s| s.urls.iter().map(|u| u.url.clone()))
+            .collect()
+    };
+
+    state.channel_counters(&channel_id).reset();
+    if let Some(active) = state.active_channels.get(&channel_id) {
+        active.bytes_transferred.store(0, Ordering::Relaxed);
+        active.backpressure_pauses.store(0, Ordering::Relaxed);
+        *active.quality.lock().unwrap() = Default::default();
+        *active.ts_health.lock().unwrap() = Default::default();
+        for client in active.clients.iter() {
+            client.lag_events.store(0, Ordering::Relaxed);
+            client.lag_bytes.store(0, Ordering::Relaxed);
+        }
+    }
+    for url in &urls {
+        state.url_health.remove(url);
+    }
+    tracing::info!("Channel {}: statistics reset", channel_id);
+    (StatusCode::OK, "Statistics reset")
+}
+
+/// Apply an operation to every channel tagged `tag`, so operators don't have
+/// to loop over thousands of channel ids.
+pub async fn tag_action(
+    State(state): State<Arc<AppState>>,
+    Path((tag, action)): Path<(String, TagAction)>,
+) -> Json<TagActionResponse> {
+    let mut tagged = Vec::new();
+    state.channel_routes.load().scan(|id, routing| {
+        if routing.tags.contains(&tag) {
+            tagged.push(id.to_string());
+        }
+    });
+
+    let mut channels = Vec::new();
+    for channel_id in tagged {
+        let changed = match action {
+            TagAction::Disable | TagAction::Enable => {
+                let enabled = action == TagAction::Enable;
+                let changed = state
+                    .channel_routes
+                    .load()
+                    .get_mut(&channel_id)
+                    .is_some_and(|mut r| std::mem::replace(&mut r.enabled, enabled) != enabled);
+                if !enabled {
+                    stop_channel(&state, &channel_id);
+                }
+                changed
+            }
+            TagAction::Stop => stop_channel(&state, &channel_id),
+            TagAction::Restart => match state.active_channels.get(&channel_id) {
+                Some(active) => {
+                    schedule_restart(&active);
+                    true
+                }
+                None => false,
+            },
+        };
+        if changed {
+            channels.push(channel_id);
+        }
+    }
+    if action == TagAction::Enable {
+        state.warmup.notify.notify_one();
+    }
+    if matches!(action, TagAction::Enable | TagAction::Disable) && !channels.is_empty() {
+        state.routing_changed.notify_one();
+    }
+    tracing::info!(
+        "Tag {}: {:?} applied to {} channels",
+        tag,
+        action,
+        channels.len()
+    );
+    Json(TagActionResponse {
+        tag,
+        action,
+        channels,
+    })
+}
+
 pub async fn put_account(
     State(state): State<Arc<AppState>>,
     Path(account_id): Path<u64>,
     Json(config): Json<AccountConfig>,
 ) -> StatusCode {
-    if let Some(existing) = state.accounts.get(&account_id) {
-        existing
-            .max_connections
-            .store(config.max_connections, std::sync::atomic::Ordering::Relaxed);
+    if let Some(name) = invalid_header(&config.headers) {
+        tracing::warn!("Account {}: invalid header {:?}", account_id, name);
+        return StatusCode::BAD_REQUEST;
+    }
+    if let Some(e) = config.proxy_url.as_deref().and_then(invalid_proxy) {
+        tracing::warn!("Account {}: invalid proxy_url: {}", account_id, e);
+        return StatusCode::BAD_REQUEST;
+    }
+    if let Some(existing) = state.accounts.load().get(&account_id) {
+        existing.apply(&config);
     } else {
-        state.accounts.insert(
-            account_id,
-            AccountState {
-                max_connections: std::sync::atomic::AtomicU32::new(config.max_connections),
-                active_connections: std::sync::atomic::AtomicU32::new(0),
-            },
-        );
+        state
+            .accounts
+            .load()
+            .insert(account_id, Arc::new(AccountState::from_config(&config)));
     }
+    state.routing_changed.notify_one();
     tracing::info!(
-        "Account {} limit set to {}",
+        "Account {} limit set to {}{}",
         account_id,
-        config.max_connections
+        config.max_connections,
+        if config.enabled { "" } else { " (disabled)" }
     );
     StatusCode::OK
 }
 
+/// Set a channel group's active-channel limit. Channels already active
+/// keep running if the new limit is lower; only new starts are refused.
+pub async fn put_group(
+    State(state): State<Arc<AppState>>,
+    Path(group): Path<String>,
+    Json(config): Json<GroupConfig>,
+) -> StatusCode {
+    tracing::info!(
+        "Group {} limited to {} active channels",
+        group,
+        config.max_active_channels
+    );
+    state.group_limits.insert(group, config.max_active_channels);
+    StatusCode::OK
+}
+
+/// Remove a channel group's limit
+pub async fn delete_group(
+    State(state): State<Arc<AppState>>,
+    Path(group): Path<String>,
+) -> StatusCode {
+    match state.group_limits.remove(&group) {
+        Some(_) => StatusCode::OK,
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
 pub async fn sync(
     State(state): State<Arc<AppState>>,
     Json(req): Json<SyncRequest>,
 ) -> StatusCode {
-    // Update routing table without stopping active channels.
-    // Remove channels no longer in the sync payload.
-    let new_ids: std::collections::HashSet<&String> = req.channels.keys().collect();
-    let old_ids: Vec<String> = state
-        .channel_routes
+    replace_routing(&state, "Sync", req.channels, req.accounts);
+    state.routing_changed.notify_one();
+    StatusCode::OK
+}
+
+/// Export the full routing state, for restoring with `/control/v1/restore`
+/// when the backend that normally pushes it is unavailable.
+pub async fn export(State(state): State<Arc<AppState>>) -> Json<Snapshot> {
+    Json(snapshot(&state))
+}
+
+/// The current routing state in export format
+pub(crate) fn snapshot(state: &AppState) -> Snapshot {
+    let mut channels = HashMap::new();
+    state.channel_routes.load().scan(|id, routing| {
+        channels.insert(id.to_string(), ChannelConfig::from(routing));
+    });
+    let accounts = state
+        .accounts
+        .load()
         .iter()
-        .map(|e| e.key().clone())
+        .map(|e| (e.key().to_string(), e.value().config()))
         .collect();
-    for id in &old_ids {
-        if !new_ids.contains(id) {
-            state.channel_routes.remove(id);
-            // Stop active stream for removed channel
-            if let Some((_, active)) = state.active_channels.remove(id) {
-                let _ = active.stop_tx.send(true);
-                state.decrement_connections(active.account_id);
-                tracing::info!("Sync: stopped removed channel {}", id);
-            }
-        }
+    Snapshot {
+        version: SNAPSHOT_VERSION,
+        exported_at: Some(chrono::Utc::now().to_rfc3339()),
+        channels,
+        accounts,
     }
+}
 
-    // Insert/update all channels from payload
-    for (id, config) in req.channels {
-        state.channel_routes.insert(
-            id,
-            ChannelRouting {
-                streams: config.streams,
-            },
+/// Replace the routing state with an exported snapshot. The snapshot is
+/// validated as a whole first and nothing is applied if any part is invalid
+/// (422); `?dry_run=true` only reports what would change.
+pub async fn restore(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<RestoreParams>,
+    Json(snapshot): Json<Snapshot>,
+) -> (StatusCode, Json<RestoreReport>) {
+    let errors = validate_snapshot(&snapshot);
+    let mut channels_added: Vec<String> = snapshot
+        .channels
+        .keys()
+        .filter(|id| !state.channel_routes.load().contains_key(id))
+        .cloned()
+        .collect();
+    let mut channels_removed: Vec<String> = state
+        .channel_routes
+        .load()
+        .ids()
+        .into_iter()
+        .filter(|id| !snapshot.channels.contains_key(id))
+        .collect();
+    channels_added.sort();
+    channels_removed.sort();
+    let mut report = RestoreReport {
+        dry_run: params.dry_run,
+        applied: false,
+        errors,
+        channels_updated: snapshot.channels.len() - channels_added.len(),
+        channels_added,
+        channels_removed,
+        accounts: snapshot.accounts.len(),
+    };
+
+    if !report.errors.is_empty() {
+        tracing::warn!(
+            "Restore rejected: {} validation errors",
+            report.errors.len()
+        );
+        return (StatusCode::UNPROCESSABLE_ENTITY, Json(report));
+    }
+    if !params.dry_run {
+        tracing::info!(
+            "Restoring snapshot exported at {}",
+            snapshot.exported_at.as_deref().unwrap_or("unknown time")
         );
+        replace_routing(&state, "Restore", snapshot.channels, snapshot.accounts);
+        state.routing_changed.notify_one();
+        report.applied = true;
     }
+    (StatusCode::OK, Json(report))
+}
 
-    // Update accounts, preserving active connection counts
-    let new_account_ids: std::collections::HashSet<u64> = req
-        .accounts
-        .iter()
-        .filter_map(|(id_str, _)| id_str.parse::<u64>().ok())
+/// Everything wrong with a snapshot (empty if it can be applied)
+pub(crate) fn validate_snapshot(snapshot: &Snapshot) -> Vec<String> {
+    let mut errors = Vec::new();
+    if snapshot.version != SNAPSHOT_VERSION {
+        errors.push(format!(
+            "unsupported snapshot version {} (expected {})",
+            snapshot.version, SNAPSHOT_VERSION
+        ));
+    }
+    for (id, config) in &snapshot.accounts {
+        if id.parse::<u64>().is_err() {
+            errors.push(format!("account {:?}: id is not a number", id));
+        }
+        if let Some(name) = invalid_header(&config.headers) {
+            errors.push(format!("account {}: invalid header {:?}", id, name));
+        }
+        if let Some(e) = config.proxy_url.as_deref().and_then(invalid_proxy) {
+            errors.push(format!("account {}: invalid proxy_url: {}", id, e));
+        }
+    }
+
+    let mut ids: Vec<&String> = snapshot.channels.keys().collect();
+    ids.sort();
+    for id in ids {
+        let config = &snapshot.channels[id];
+        if let Some(group) = &config.multicast_group {
+            if let Err(e) = multicast::validate_url(&multicast::gateway_url(group)) {
+                errors.push(format!(
+                    "channel {}: multicast_group {:?}: {}",
+                    id, group, e
+                ));
+            }
+        } else if config.streams.is_empty() {
+            errors.push(format!("channel {}: no streams", id));
+        }
+        let all_streams = config
+            .streams
+            .iter()
+            .chain(&config.premium_streams)
+            .chain(&config.quota_streams);
+        for stream in all_streams {
+            if stream.urls.is_empty() {
+                errors.push(format!("channel {}: stream {} has no URLs", id, stream.id));
+            }
+            for url in &stream.urls {
+                let valid = reqwest::Url::parse(&url.url)
+                    .is_ok_and(|u| UPSTREAM_SCHEMES.contains(&u.scheme()));
+                if !valid {
+                    errors.push(format!(
+                        "channel {}: stream {} has invalid URL {:?}",
+                        id, stream.id, url.url
+                    ));
+                }
+                if let Some(name) = invalid_header(&url.headers) {
+                    errors.push(format!(
+                        "channel {}: stream {} has invalid header {:?}",
+                        id, stream.id, name
+                    ));
+                }
+            }
+        }
+        if let (Some(high), Some(low)) = (config.high_watermark, config.low_watermark) {
+            if low > high {
+                errors.push(format!(
+                    "channel {}: low_watermark {} above high_watermark {}",
+                    id, low, high
+                ));
+            }
+        }
+    }
+    errors
+}
+
+/// Make the routing table and accounts match `channels` and `accounts`,
+/// stopping channels that were removed or taken off-air. Channels that stay
+/// keep streaming; accounts keep their live connection counts.
+pub(crate) fn replace_routing(
+    state: &AppState,
+    source: &str,
+    channels: HashMap<String, ChannelConfig>,
+    accounts: HashMap<String, AccountConfig>,
+) {
+    // Build the new tables off to the side and swap them in whole, so a
+    // concurrent stream request sees either the old or the new routing
+    let current_accounts = state.accounts.load();
+    let new_accounts: DashMap<u64, Arc<AccountState>> = accounts
+        .into_iter()
+        .filter_map(|(id_str, config)| {
+            let id = id_str.parse::<u64>().ok()?;
+            let account = match current_accounts.get(&id) {
+                // Update limits and flags but keep current active count
+                Some(existing) => {
+                    existing.apply(&config);
+                    existing.clone()
+                }
+                None => Arc::new(AccountState::from_config(&config)),
+            };
+            Some((id, account))
+        })
         .collect();
+    drop(current_accounts);
 
-    // Remove accounts no longer in payload
-    let old_account_ids: Vec<u64> =
-        state.accounts.iter().map(|e| *e.key()).collect();
-    for id in &old_account_ids {
-        if !new_account_ids.contains(id) {
-            state.accounts.remove(id);
+    let off_air: Vec<String> = channels
+        .iter()
+        .filter(|(_, config)| !config.enabled)
+        .map(|(id, _)| id.clone())
+        .collect();
+    // With hibernation on, channels nothing is using start out hibernated
+    let hibernate = !state.config.routing_hibernate_after.is_zero();
+    let new_routes = RoutingTable::default();
+    for (id, config) in channels {
+        if hibernate && !config.persistent && !state.active_channels.contains_key(&id) {
+            new_routes.insert_cold(id, &config);
+        } else {
+            new_routes.insert(id, ChannelRouting::from(config));
         }
     }
+    let channel_count = new_routes.len();
+    let account_count = new_accounts.len();
 
-    // Insert/update accounts, preserving active_connections for existing ones
-    for (id_str, config) in req.accounts {
-        if let Ok(id) = id_str.parse::<u64>() {
-            if let Some(existing) = state.accounts.get(&id) {
-                // Update max_connections but keep current active count
-                existing
-                    .max_connections
-                    .store(config.max_connections, std::sync::atomic::Ordering::Relaxed);
-            } else {
-                state.accounts.insert(
-                    id,
-                    AccountState {
-                        max_connections: std::sync::atomic::AtomicU32::new(
-                            config.max_connections,
-                        ),
-                        active_connections: std::sync::atomic::AtomicU32::new(0),
-                    },
-                );
-            }
+    state.accounts.store(Arc::new(new_accounts));
+    let old_routes = state.channel_routes.swap(Arc::new(new_routes));
+
+    // Stop active streams for removed channels and channels taken off-air;
+    // everything else keeps streaming
+    let routes = state.channel_routes.load();
+    for id in old_routes.ids() {
+        if !routes.contains_key(&id) && stop_channel(state, &id) {
+            tracing::info!("{}: stopped removed channel {}", source, id);
+        }
+    }
+    for id in &off_air {
+        if stop_channel(state, id) {
+            tracing::info!("{}: took channel {} off-air", source, id);
         }
     }
 
-    let channels = state.channel_routes.len();
-    let accounts = state.accounts.len();
-    tracing::info!("Sync complete: {} channels, {} accounts", channels, accounts);
-    StatusCode::OK
+    tracing::info!(
+        "{} complete: {} channels, {} accounts",
+        source,
+        channel_count,
+        account_count
+    );
+    state.warmup.notify.notify_one();
 }
@@ -0,0 +1,123 @@
+use crate::models::{EventExportParams, EventKind, ProxyEvent};
+use crate::state::AppState;
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    http::header,
+    response::Response,
+};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Events buffered for followers that read slower than events happen
+const FOLLOW_CAPACITY: usize = 256;
+
+/// Ring of recent proxy events, plus a live feed for export followers
+pub struct EventLog {
+    capacity: usize,
+    /// Recorded events, oldest first, and the next sequence number
+    ring: Mutex<(VecDeque<ProxyEvent>, u64)>,
+    live: broadcast::Sender<ProxyEvent>,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ring: Mutex::new((VecDeque::with_capacity(capacity), 1)),
+            live: broadcast::channel(FOLLOW_CAPACITY).0,
+        }
+    }
+
+    pub fn record(
+        &self,
+        kind: EventKind,
+        channel_id: &str,
+        client_id: Option<&str>,
+        request_id: Option<&str>,
+        message: String,
+    ) {
+        let mut ring = self.ring.lock().unwrap();
+        let (events, next_seq) = &mut *ring;
+        let event = ProxyEvent {
+            seq: *next_seq,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            kind,
+            channel_id: channel_id.to_string(),
+            client_id: client_id.map(str::to_string),
+            request_id: request_id.map(str::to_string),
+            message,
+        };
+        *next_seq += 1;
+        // Sent under the lock, so a follower sees each event exactly once:
+        // either in its snapshot or on its subscription
+        let _ = self.live.send(event.clone());
+        if self.capacity == 0 {
+            return;
+        }
+        if events.len() >= self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// Events recorded from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ProxyEvent> {
+        self.live.subscribe()
+    }
+
+    /// Kept events after sequence number `since`, and a subscription to the
+    /// ones recorded from now on
+    fn snapshot(&self, since: u64) -> (Vec<ProxyEvent>, broadcast::Receiver<ProxyEvent>) {
+        let ring = self.ring.lock().unwrap();
+        let events = ring.0.iter().filter(|e| e.seq > since).cloned().collect();
+        (events, self.live.subscribe())
+    }
+}
+
+/// Stream the event ring as NDJSON, one event per line, optionally staying
+/// open to tail new events (`?follow=true`).
+pub async fn export(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<EventExportParams>,
+) -> Response {
+    let (backlog, mut live) = state.events.snapshot(params.since.unwrap_or(0));
+    let wanted = move |event: &ProxyEvent| {
+        params
+            .channel
+            .as_ref()
+            .is_none_or(|channel| *channel == event.channel_id)
+    };
+
+    let body = async_stream::stream! {
+        for event in backlog.iter().filter(|e| wanted(e)) {
+            yield Ok::<_, std::io::Error>(ndjson_line(event));
+        }
+        if !params.follow {
+            return;
+        }
+        loop {
+            match live.recv().await {
+                Ok(event) if wanted(&event) => yield Ok(ndjson_line(&event)),
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("Event export follower fell behind, {} events skipped", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from_stream(body))
+        .unwrap()
+}
+
+fn ndjson_line(event: &ProxyEvent) -> Vec<u8> {
+    let mut line = serde_json::to_vec(event).expect("event serializes");
+    line.push(b'\n');
+    line
+}
@@ -0,0 +1,149 @@
+use crate::multicast;
+use crate::relay;
+use crate::session;
+use crate::state::{ActiveChannel, AppState, UpstreamTarget};
+use futures_util::future::join_all;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Longest a probe may take to connect and deliver its first bytes
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Spawn the background task that moves failed-over channels back to a more
+/// preferred source once it is healthy again.
+pub fn spawn_failback(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let client = state
+            .config
+            .upstream_client_builder()
+            .build()
+            .expect("failed to build fail-back probe client");
+        let mut interval = tokio::time::interval(state.config.failback_interval);
+        loop {
+            interval.tick().await;
+            let channels: Vec<Arc<ActiveChannel>> = state
+                .active_channels
+                .iter()
+                .filter(|a| a.pending_switch.lock().unwrap().is_none())
+                .map(|a| a.value().clone())
+                .collect();
+            join_all(
+                channels
+                    .iter()
+                    .map(|active| fail_back(&state, &client, active)),
+            )
+            .await;
+        }
+    })
+}
+
+/// Probe the sources preferred over the channel's current one, best first,
+/// and schedule a switch to the first that delivers data. The switch goes
+/// through the upstream task's make-before-break handover, which changes
+/// source at a chunk (and so TS packet) boundary.
+async fn fail_back(state: &AppState, client: &reqwest::Client, active: &ActiveChannel) {
+    let current = active.current_upstream();
+    for next in preferred_sources(state, &active.channel_id, &current) {
+        if !probe(state, client, &active.channel_id, &next).await {
+            continue;
+        }
+        tracing::info!(
+            "Channel {}: stream={}, account={} healthy again, failing back from stream={}, account={}",
+            active.channel_id,
+            next.stream_id,
+            next.account_id,
+            current.stream_id,
+            current.account_id
+        );
+        let mut pending = active.pending_switch.lock().unwrap();
+        if pending.is_none() {
+            *pending = Some(next);
+        }
+        return;
+    }
+}
+
+/// Available sources ordered before `current` in the channel's preference
+/// order, if its failover policy allows failing back
+fn preferred_sources(
+    state: &AppState,
+    channel_id: &str,
+    current: &UpstreamTarget,
+) -> Vec<UpstreamTarget> {
+    let over_quota = state.quota_exceeded(channel_id).is_some();
+    let routes = state.channel_routes.load();
+    let Some(routing) = routes.get(channel_id) else {
+        return Vec::new();
+    };
+    if !routing.failover.fail_back {
+        return Vec::new();
+    }
+    routing
+        .ordered_sources(current.premium, over_quota)
+        .into_iter()
+        .take_while(|(s, u)| !(s.id == current.stream_id && u.url == current.url))
+        .filter(|(_, u)| state.account_available(u.account_id))
+        .map(|(s, u)| UpstreamTarget {
+            stream_id: s.id,
+            account_id: u.account_id,
+            url: u.url.clone(),
+            premium: current.premium,
+        })
+        .collect()
+}
+
+/// Whether the target's URL connects and starts delivering data, recorded
+/// in its health
+async fn probe(
+    state: &AppState,
+    client: &reqwest::Client,
+    channel_id: &str,
+    target: &UpstreamTarget,
+) -> bool {
+    let url = target.url.as_str();
+    let multicast = multicast::is_multicast_url(url);
+    let relayed = relay::is_relay_url(url);
+    if !multicast && !relayed {
+        state.pace_host_connect(url).await;
+    }
+    let started = tokio::time::Instant::now();
+    let result = if multicast {
+        multicast::open(url, PROBE_TIMEOUT).await.map(drop)
+    } else if relayed {
+        relay::open(url, PROBE_TIMEOUT).await.map(drop)
+    } else {
+        probe_http(state, client, channel_id, target).await
+    };
+    match result {
+        Ok(()) => {
+            state.record_url_success(url, started.elapsed());
+            true
+        }
+        Err(e) => {
+            state.record_url_connect_failure(url, &format!("fail-back probe: {}", e));
+            false
+        }
+    }
+}
+
+async fn probe_http(
+    state: &AppState,
+    client: &reqwest::Client,
+    channel_id: &str,
+    target: &UpstreamTarget,
+) -> Result<(), String> {
+    let request = session::get(state, client, channel_id, target.account_id, &target.url).await?;
+    let mut response = request
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("HTTP {}", response.status()));
+    }
+    match response.chunk().await {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err("empty response".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
@@ -0,0 +1,119 @@
+use crate::state::Chunk;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::{broadcast, mpsc};
+
+/// What a client's send queue holds: chunks, and markers for chunks that
+/// overflowed it, in stream order
+pub enum QueueItem {
+    Chunk(Chunk),
+    Gap(u64),
+}
+
+/// Where a stream client reads the channel's chunks from. Both variants
+/// report lost data as `Lagged`, so the client handles either the same way.
+pub enum ClientFeed {
+    /// Straight from the channel broadcast
+    Broadcast(broadcast::Receiver<Chunk>),
+    /// From the client's own bounded queue, filled from the broadcast by a
+    /// pump task; a client that falls behind loses only what overflows it
+    Queue {
+        rx: mpsc::Receiver<QueueItem>,
+        pump: tokio::task::JoinHandle<()>,
+    },
+}
+
+impl ClientFeed {
+    /// Feed a client from `rx`, through a queue of `capacity` chunks (0 =
+    /// read the broadcast directly). The pump keeps `depth` current as it
+    /// fills the queue.
+    pub fn new(rx: broadcast::Receiver<Chunk>, capacity: usize, depth: Arc<AtomicUsize>) -> Self {
+        if capacity == 0 {
+            return Self::Broadcast(rx);
+        }
+        let (queue_tx, queue_rx) = mpsc::channel(capacity);
+        Self::Queue {
+            rx: queue_rx,
+            pump: tokio::spawn(pump(rx, queue_tx, depth)),
+        }
+    }
+
+    pub async fn recv(&mut self) -> Result<Chunk, RecvError> {
+        match self {
+            Self::Broadcast(rx) => rx.recv().await,
+            Self::Queue { rx, .. } => match rx.recv().await {
+                Some(QueueItem::Chunk(chunk)) => Ok(chunk),
+                Some(QueueItem::Gap(n)) => Err(RecvError::Lagged(n)),
+                None => Err(RecvError::Closed),
+            },
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<Chunk, TryRecvError> {
+        match self {
+            Self::Broadcast(rx) => rx.try_recv(),
+            Self::Queue { rx, .. } => match rx.try_recv() {
+                Ok(QueueItem::Chunk(chunk)) => Ok(chunk),
+                Ok(QueueItem::Gap(n)) => Err(TryRecvError::Lagged(n)),
+                Err(mpsc::error::TryRecvError::Empty) => Err(TryRecvError::Empty),
+                Err(mpsc::error::TryRecvError::Disconnected) => Err(TryRecvError::Closed),
+            },
+        }
+    }
+
+    /// Chunks waiting to be sent to the client
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Broadcast(rx) => rx.len(),
+            Self::Queue { rx, .. } => rx.len(),
+        }
+    }
+}
+
+impl Drop for ClientFeed {
+    fn drop(&mut self) {
+        if let Self::Queue { pump, .. } = self {
+            pump.abort();
+        }
+    }
+}
+
+/// Move chunks from the broadcast into a client's queue without ever
+/// waiting on the client. Chunks that don't fit are dropped and reported
+/// with a gap marker ahead of the next chunk that does.
+async fn pump(
+    mut rx: broadcast::Receiver<Chunk>,
+    queue: mpsc::Sender<QueueItem>,
+    depth: Arc<AtomicUsize>,
+) {
+    let mut gap: u64 = 0;
+    loop {
+        let chunk = match rx.recv().await {
+            Ok(chunk) => chunk,
+            Err(RecvError::Lagged(n)) => {
+                gap += n;
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+        let item = if gap > 0 {
+            QueueItem::Gap(gap)
+        } else {
+            QueueItem::Chunk(chunk.clone())
+        };
+        let sent = match queue.try_send(item) {
+            Ok(()) if gap > 0 => {
+                gap = 0;
+                queue.try_send(QueueItem::Chunk(chunk))
+            }
+            result => result,
+        };
+        match sent {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => gap += 1,
+            Err(mpsc::error::TrySendError::Closed(_)) => break,
+        }
+        depth.store(queue.max_capacity() - queue.capacity(), Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,184 @@
+use crate::config::IpNet;
+use crate::state::AppState;
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::{header, HeaderMap};
+use axum::middleware::Next;
+use axum::response::Response;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+/// Middleware for every route: when the connection comes from a trusted
+/// proxy, replace the request's connect info with the client address the
+/// proxy forwarded, so per-IP limits, auth callbacks, geo lookups and client
+/// status all see the viewer rather than the load balancer.
+pub async fn resolve_client_addr(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let trusted = &state.config.trusted_proxies;
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0);
+    if let Some(peer) = peer.filter(|_| !trusted.is_empty()) {
+        if let Some(client) = client_addr(peer, request.headers(), trusted) {
+            request.extensions_mut().insert(ConnectInfo(client));
+        }
+    }
+    next.run(request).await
+}
+
+/// The address behind the trusted proxies in front of `peer`: the forwarding
+/// chain is walked from the nearest hop back, stopping at the first hop that
+/// is not itself a trusted proxy. RFC 7239 `Forwarded` is used if present,
+/// else `X-Forwarded-For`. Hops without a port keep the peer's.
+fn client_addr(peer: SocketAddr, headers: &HeaderMap, trusted: &[IpNet]) -> Option<SocketAddr> {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(peer.ip()) {
+        return None;
+    }
+    let chain = match header_values(headers, header::FORWARDED.as_str()) {
+        Some(values) => forwarded_chain(&values),
+        None => header_values(headers, X_FORWARDED_FOR)?
+            .split(',')
+            .map(str::trim)
+            .map(str::to_string)
+            .collect(),
+    };
+    let mut client = peer;
+    for hop in chain.iter().rev() {
+        // An obfuscated or unknown hop hides everything before it
+        let Some((ip, port)) = parse_node(hop) else {
+            break;
+        };
+        client = SocketAddr::new(ip, port.unwrap_or(peer.port()));
+        if !is_trusted(ip) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+/// All values of a header, joined as one comma-separated list
+fn header_values(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    (!values.is_empty()).then(|| values.join(","))
+}
+
+/// The `for=` node of each `Forwarded` element, nearest hop last
+fn forwarded_chain(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(key, _)| key.eq_ignore_ascii_case("for"))
+                .map_or_else(String::new, |(_, node)| node.trim_matches('"').to_string())
+        })
+        .collect()
+}
+
+/// `ip`, `ip:port`, `[ipv6]` or `[ipv6]:port`
+fn parse_node(node: &str) -> Option<(IpAddr, Option<u16>)> {
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some((addr.ip(), Some(addr.port())));
+    }
+    let ip = node.trim_start_matches('[').trim_end_matches(']');
+    ip.parse().ok().map(|ip| (ip, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn trusted() -> Vec<IpNet> {
+        vec![IpNet::parse("10.0.0.0/8").unwrap()]
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn parse_node_forms() {
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(parse_node("192.0.2.1"), Some((v4, None)));
+        assert_eq!(parse_node("192.0.2.1:8080"), Some((v4, Some(8080))));
+        assert_eq!(parse_node("[2001:db8::1]"), Some((v6, None)));
+        assert_eq!(parse_node("[2001:db8::1]:443"), Some((v6, Some(443))));
+        assert_eq!(parse_node("unknown"), None);
+        assert_eq!(parse_node("_hidden"), None);
+    }
+
+    #[test]
+    fn forwarded_chain_takes_the_for_node_of_each_element() {
+        let chain = forwarded_chain(
+            r#"for=192.0.2.1;proto=https, by=10.0.0.2;FOR="[2001:db8::1]:443",proto=http"#,
+        );
+        assert_eq!(chain, ["192.0.2.1", "[2001:db8::1]:443", ""]);
+    }
+
+    #[test]
+    fn untrusted_peer_is_left_alone() {
+        let peer: SocketAddr = "198.51.100.7:5000".parse().unwrap();
+        let headers = headers(&[(X_FORWARDED_FOR, "192.0.2.1")]);
+        assert_eq!(client_addr(peer, &headers, &trusted()), None);
+    }
+
+    #[test]
+    fn walks_back_through_trusted_hops() {
+        let peer: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        // The spoofed first entry sits behind an untrusted hop and is ignored
+        let headers = headers(&[
+            (X_FORWARDED_FOR, "203.0.113.9, 192.0.2.1"),
+            (X_FORWARDED_FOR, "10.0.0.2"),
+        ]);
+        assert_eq!(
+            client_addr(peer, &headers, &trusted()),
+            Some("192.0.2.1:5000".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn forwarded_wins_over_x_forwarded_for() {
+        let peer: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let headers = headers(&[
+            ("forwarded", "for=192.0.2.1:1234"),
+            (X_FORWARDED_FOR, "198.51.100.7"),
+        ]);
+        assert_eq!(
+            client_addr(peer, &headers, &trusted()),
+            Some("192.0.2.1:1234".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn obfuscated_hop_stops_the_walk() {
+        let peer: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let headers = headers(&[("forwarded", "for=192.0.2.1, for=_proxy, for=10.0.0.2")]);
+        assert_eq!(
+            client_addr(peer, &headers, &trusted()),
+            Some("10.0.0.2:5000".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn trusted_peer_without_forwarding_headers_is_kept() {
+        let peer: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        assert_eq!(client_addr(peer, &HeaderMap::new(), &trusted()), None);
+    }
+}
@@ -0,0 +1,74 @@
+use crate::config::Config;
+use crate::models::GeoInfo;
+use dashmap::DashMap;
+use maxminddb::{geoip2, Reader};
+use std::net::IpAddr;
+
+/// Cached lookups kept before the cache is cleared and rebuilt
+const CACHE_MAX_ENTRIES: usize = 10_000;
+
+/// Country/ASN lookup of client addresses against local GeoLite databases
+pub struct GeoLookup {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+    cache: DashMap<IpAddr, GeoInfo>,
+}
+
+impl GeoLookup {
+    /// Open the configured databases; a missing or unreadable file disables
+    /// that half of the lookup.
+    pub fn open(config: &Config) -> Self {
+        Self {
+            country: open_db(config.geoip_country_db.as_deref()),
+            asn: open_db(config.geoip_asn_db.as_deref()),
+            cache: DashMap::new(),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.country.is_some() || self.asn.is_some()
+    }
+
+    /// Country and ASN for an address (None if no database is configured)
+    pub fn lookup(&self, ip: IpAddr) -> Option<GeoInfo> {
+        if !self.enabled() {
+            return None;
+        }
+        if let Some(cached) = self.cache.get(&ip) {
+            return Some(cached.clone());
+        }
+
+        let mut info = GeoInfo::default();
+        if let Some(reader) = &self.country {
+            if let Ok(record) = reader.lookup::<geoip2::Country>(ip) {
+                info.country = record.country.and_then(|c| c.iso_code).map(str::to_string);
+            }
+        }
+        if let Some(reader) = &self.asn {
+            if let Ok(record) = reader.lookup::<geoip2::Asn>(ip) {
+                info.asn = record.autonomous_system_number;
+                info.as_org = record.autonomous_system_organization.map(str::to_string);
+            }
+        }
+
+        if self.cache.len() >= CACHE_MAX_ENTRIES {
+            self.cache.clear();
+        }
+        self.cache.insert(ip, info.clone());
+        Some(info)
+    }
+}
+
+fn open_db(path: Option<&str>) -> Option<Reader<Vec<u8>>> {
+    let path = path?;
+    match Reader::open_readfile(path) {
+        Ok(reader) => {
+            tracing::info!("Loaded GeoIP database {}", path);
+            Some(reader)
+        }
+        Err(e) => {
+            tracing::warn!("Failed to open GeoIP database {}: {}", path, e);
+            None
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use crate::auth;
+use crate::control;
+use crate::models::{AccountConfig, ChannelConfig, ChannelDetailResponse};
+use crate::state::AppState;
+use crate::status;
+use axum::extract::{Json, Path, State};
+use axum::http::StatusCode;
+use futures_util::{Stream, StreamExt};
+use proto::control_server::{Control, ControlServer};
+use proto::{
+    ChannelStatusEvent, ControlReply, DeleteChannelRequest, PutAccountRequest, PutChannelRequest,
+    SyncRequest, WatchStatusRequest,
+};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status};
+
+/// Messages, server and client generated from `proto/control.proto`
+pub mod proto {
+    tonic::include_proto!("dispatcharr.proxy.control.v1");
+}
+
+/// Serve the gRPC control service on `listener`, admitting calls that
+/// carry the control (or admin) token as `authorization: Bearer` metadata
+pub fn spawn_server(state: Arc<AppState>, listener: TcpListener) -> tokio::task::JoinHandle<()> {
+    let service = ControlService {
+        state: state.clone(),
+    };
+    let service =
+        ControlServer::with_interceptor(service, move |request| authorize(&state, request));
+    tokio::spawn(async move {
+        let result = tonic::transport::Server::builder()
+            .add_service(service)
+            .serve_with_incoming(TcpIncoming::from(listener))
+            .await;
+        if let Err(e) = result {
+            tracing::error!("gRPC control service failed: {}", e);
+        }
+    })
+}
+
+/// Same rule as the HTTP control API, except that without a control token
+/// an HMAC-only setup admits nothing: there is no body signature to check
+fn authorize(state: &AppState, request: Request<()>) -> Result<Request<()>, Status> {
+    let config = &state.config;
+    if config.control_token.is_none() && config.control_hmac_secret.is_none() {
+        return Ok(request);
+    }
+    let bearer = request
+        .metadata()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if bearer.is_some_and(|token| auth::control_token_accepted(config, token)) {
+        Ok(request)
+    } else {
+        Err(Status::unauthenticated("control token required"))
+    }
+}
+
+struct ControlService {
+    state: Arc<AppState>,
+}
+
+#[tonic::async_trait]
+impl Control for ControlService {
+    async fn put_channel(
+        &self,
+        request: Request<PutChannelRequest>,
+    ) -> Result<Response<ControlReply>, Status> {
+        let request = request.into_inner();
+        let config: ChannelConfig = parse("config_json", &request.config_json)?;
+        let state = State(self.state.clone());
+        reply(control::put_channel(state, Path(request.channel_id), Json(config)).await)
+    }
+
+    async fn delete_channel(
+        &self,
+        request: Request<DeleteChannelRequest>,
+    ) -> Result<Response<ControlReply>, Status> {
+        let channel_id = request.into_inner().channel_id;
+        reply(control::delete_channel(State(self.state.clone()), Path(channel_id)).await)
+    }
+
+    async fn put_account(
+        &self,
+        request: Request<PutAccountRequest>,
+    ) -> Result<Response<ControlReply>, Status> {
+        let request = request.into_inner();
+        let config: AccountConfig = parse("config_json", &request.config_json)?;
+        let state = State(self.state.clone());
+        reply(control::put_account(state, Path(request.account_id), Json(config)).await)
+    }
+
+    async fn sync(&self, request: Request<SyncRequest>) -> Result<Response<ControlReply>, Status> {
+        let payload = parse("payload_json", &request.into_inner().payload_json)?;
+        reply(control::sync(State(self.state.clone()), Json(payload)).await)
+    }
+
+    type WatchStatusStream =
+        Pin<Box<dyn Stream<Item = Result<ChannelStatusEvent, Status>> + Send + 'static>>;
+
+    async fn watch_status(
+        &self,
+        request: Request<WatchStatusRequest>,
+    ) -> Result<Response<Self::WatchStatusStream>, Status> {
+        let channel_id = request.into_inner().channel_id;
+        let updates = status::watch(self.state.clone(), channel_id.clone())
+            .ok_or_else(|| Status::not_found("Channel not found"))?;
+        let events = updates.map(move |update| Ok(status_event(&channel_id, update)));
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+fn parse<T: serde::de::DeserializeOwned>(field: &str, json: &str) -> Result<T, Status> {
+    serde_json::from_str(json).map_err(|e| Status::invalid_argument(format!("{}: {}", field, e)))
+}
+
+/// The gRPC outcome of a control handler's HTTP status
+fn reply(status: StatusCode) -> Result<Response<ControlReply>, Status> {
+    let message = status.to_string();
+    match status {
+        s if s.is_success() => Ok(Response::new(ControlReply {})),
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => {
+            Err(Status::invalid_argument(message))
+        }
+        StatusCode::NOT_FOUND => Err(Status::not_found(message)),
+        StatusCode::CONFLICT => Err(Status::failed_precondition(message)),
+        _ => Err(Status::internal(message)),
+    }
+}
+
+fn status_event(channel_id: &str, update: Option<ChannelDetailResponse>) -> ChannelStatusEvent {
+    let Some(detail) = update else {
+        return ChannelStatusEvent {
+            channel_id: channel_id.to_string(),
+            deleted: true,
+            ..Default::default()
+        };
+    };
+    let upstream = detail.status.upstream.as_ref();
+    ChannelStatusEvent {
+        channel_id: channel_id.to_string(),
+        deleted: false,
+        state: detail.status.state.clone(),
+        clients: detail.status.clients,
+        stream_id: upstream.map(|u| u.stream_id),
+        account_id: upstream.map(|u| u.account_id),
+        upstream_url: upstream.map(|u| u.url.clone()),
+        detail_json: serde_json::to_string(&detail).expect("channel detail serializes"),
+    }
+}
@@ -0,0 +1,24 @@
+use crate::state::AppState;
+use std::sync::Arc;
+
+/// Spawn the task that hibernates routing entries nothing has looked up
+/// for `ROUTING_HIBERNATE_SECS`. Active and persistent channels stay
+/// hydrated.
+pub fn spawn_hibernator(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let idle = state.config.routing_hibernate_after;
+        let mut interval = tokio::time::interval(idle);
+        loop {
+            interval.tick().await;
+            let hibernated = state
+                .channel_routes
+                .load()
+                .hibernate_idle(idle, |id, routing| {
+                    routing.persistent || state.active_channels.contains_key(id)
+                });
+            if hibernated > 0 {
+                tracing::debug!("Hibernated {} idle routing entries", hibernated);
+            }
+        }
+    })
+}
@@ -0,0 +1,330 @@
+use crate::auth;
+use crate::hls_keys;
+use crate::models::StreamParams;
+use crate::session;
+use crate::state::{AppState, HlsResource};
+use crate::tenant;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use reqwest::Url;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::time::Instant;
+
+const MAX_ATTEMPTS: u32 = 10;
+/// Proxied playlist/segment URLs are forgotten after this long unused
+const RESOURCE_TTL: std::time::Duration = std::time::Duration::from_secs(600);
+const PLAYLIST_CONTENT_TYPE: &str = "application/vnd.apple.mpegurl";
+
+/// Per-request settings for rewriting a playlist
+struct Rewrite<'a> {
+    state: &'a AppState,
+    channel_id: &'a str,
+    base: &'a Url,
+    /// Viewer token carried on every proxied URI so it reaches the key endpoint
+    token: Option<&'a str>,
+    /// Key newly listed segments are encrypted with (None = no encryption)
+    key_id: Option<u64>,
+}
+
+/// Serve an HLS channel's top-level playlist with every URI rewritten to go
+/// through the proxy, so players keep the upstream ABR ladder without ever
+/// seeing provider URLs.
+pub async fn serve_playlist(
+    state: Arc<AppState>,
+    channel_id: String,
+    token: Option<String>,
+) -> Response {
+    state
+        .hls_resources
+        .retain(|_, r| r.last_used.elapsed() < RESOURCE_TTL);
+
+    let mut candidate = state.select_stream(&channel_id, false);
+    let mut attempts = 0;
+    while let Some((stream_id, account_id, url)) = candidate {
+        attempts += 1;
+        match fetch(&state, &channel_id, &url, token.as_deref(), None).await {
+            Ok(response) => return response,
+            Err(e) => tracing::warn!(
+                "HLS {}: stream={}, account={} failed: {}",
+                channel_id,
+                stream_id,
+                account_id,
+                e
+            ),
+        }
+        if attempts >= MAX_ATTEMPTS {
+            break;
+        }
+        candidate = state.select_next_stream(&channel_id, false, stream_id, account_id);
+    }
+
+    (StatusCode::SERVICE_UNAVAILABLE, "No streams available").into_response()
+}
+
+/// Serve a nested playlist or segment referenced by a rewritten playlist,
+/// to viewers the channel's playlist would still be served to.
+pub async fn serve_resource(
+    State(state): State<Arc<AppState>>,
+    Path((channel_id, resource_id)): Path<(String, String)>,
+    uri: Uri,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(channel_id) = tenant::channel_id(&state.config, &uri, &headers, &channel_id) else {
+        return (StatusCode::NOT_FOUND, "Unknown channel").into_response();
+    };
+    if state
+        .channel_routes
+        .load()
+        .get(&channel_id)
+        .is_some_and(|r| !r.enabled)
+    {
+        return (StatusCode::FORBIDDEN, "Channel is off-air").into_response();
+    }
+    if let Err(denied) =
+        auth::authorize(&state, &channel_id, addr, &headers, params.token.as_deref()).await
+    {
+        return denied.into_response();
+    }
+    let (url, key_id) = match state.hls_resources.get_mut(&resource_id) {
+        Some(mut resource) if resource.channel_id == channel_id => {
+            resource.last_used = Instant::now();
+            (resource.url.clone(), resource.key_id)
+        }
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let encryption = match key_id {
+        Some(key_id) => match hls_keys::key(&state, &channel_id, key_id) {
+            Some(key) => Some((key, segment_iv(&resource_id))),
+            // Key retired: the player is far behind the live edge
+            None => return StatusCode::GONE.into_response(),
+        },
+        None => None,
+    };
+    match fetch(
+        &state,
+        &channel_id,
+        &url,
+        params.token.as_deref(),
+        encryption,
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(e) => {
+            tracing::warn!("HLS {}: resource {} failed: {}", channel_id, resource_id, e);
+            (StatusCode::BAD_GATEWAY, "Upstream error").into_response()
+        }
+    }
+}
+
+/// Fetch an upstream URL, rewriting it if it is a playlist and streaming it
+/// through otherwise (encrypted with the given key and IV, if any).
+async fn fetch(
+    state: &AppState,
+    channel_id: &str,
+    url: &str,
+    token: Option<&str>,
+    encryption: Option<([u8; 16], [u8; 16])>,
+) -> Result<Response, String> {
+    let upstream = session::send(state, state.http_client.get(url))
+        .await
+        .map_err(|e| format!("connect error: {}", e))?;
+    if !upstream.status().is_success() {
+        return Err(format!("HTTP {}", upstream.status()));
+    }
+
+    let content_type = upstream
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    let base = upstream.url().clone();
+    if content_type.contains("mpegurl") || base.path().ends_with(".m3u8") {
+        let body = upstream
+            .text()
+            .await
+            .map_err(|e| format!("read error: {}", e))?;
+        let encrypt = state
+            .channel_routes
+            .load()
+            .get(channel_id)
+            .is_some_and(|r| r.hls_encryption)
+            && can_encrypt(&body);
+        let rewrite = Rewrite {
+            state,
+            channel_id,
+            base: &base,
+            token,
+            key_id: encrypt.then(|| hls_keys::current_key_id(state, channel_id)),
+        };
+        let playlist = rewrite.playlist(&body);
+        return Ok(Response::builder()
+            .header(header::CONTENT_TYPE, PLAYLIST_CONTENT_TYPE)
+            .header(header::CACHE_CONTROL, "no-cache")
+            .body(Body::from(playlist))
+            .unwrap());
+    }
+
+    if let Some((key, iv)) = encryption {
+        let body = upstream
+            .bytes()
+            .await
+            .map_err(|e| format!("read error: {}", e))?;
+        return Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "video/mp2t")
+            .body(Body::from(hls_keys::encrypt_segment(&key, &iv, &body)))
+            .unwrap());
+    }
+
+    let mut builder = Response::builder();
+    for name in [header::CONTENT_TYPE, header::CONTENT_LENGTH] {
+        if let Some(value) = upstream.headers().get(&name) {
+            builder = builder.header(name, value);
+        }
+    }
+    Ok(builder
+        .body(Body::from_stream(upstream.bytes_stream()))
+        .unwrap())
+}
+
+/// Only plain media playlists get encrypted: upstream-encrypted segments are
+/// passed through as they are, and fMP4 init sections are left alone.
+fn can_encrypt(body: &str) -> bool {
+    body.contains("#EXTINF")
+        && !body.contains("#EXT-X-MAP")
+        && !body
+            .lines()
+            .any(|l| l.starts_with("#EXT-X-KEY") && !l.contains("METHOD=NONE"))
+}
+
+/// IV for an encrypted segment, derived from its resource id so it is the
+/// same on every playlist refresh
+fn segment_iv(resource_id: &str) -> [u8; 16] {
+    let mut iv = [0u8; 16];
+    let id = u64::from_str_radix(resource_id, 16).unwrap_or_default();
+    iv[8..].copy_from_slice(&id.to_be_bytes());
+    iv
+}
+
+impl Rewrite<'_> {
+    /// Point every URI in a playlist (segment/variant lines and `URI="..."`
+    /// attributes of tags like EXT-X-KEY, EXT-X-MEDIA and EXT-X-MAP) at the
+    /// proxy, announcing the key of each encrypted segment.
+    fn playlist(&self, body: &str) -> String {
+        let mut out = String::with_capacity(body.len());
+        for line in body.lines() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                out.push_str(line);
+            } else if trimmed.starts_with('#') {
+                out.push_str(&self.uri_attributes(line));
+            } else if let Some(key_id) = self.key_id {
+                let (id, key_id) = self.register(trimmed, Some(key_id));
+                if let Some(key_id) = key_id {
+                    out.push_str(&format!(
+                        "#EXT-X-KEY:METHOD=AES-128,URI=\"{}\",IV=0x{}\n",
+                        self.path(&format!("key/{}", key_id)),
+                        segment_iv(&id)
+                            .iter()
+                            .map(|b| format!("{:02x}", b))
+                            .collect::<String>()
+                    ));
+                }
+                out.push_str(&self.path(&id));
+            } else {
+                out.push_str(&self.proxy_uri(trimmed));
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    fn uri_attributes(&self, line: &str) -> String {
+        const ATTR: &str = "URI=\"";
+        let mut out = String::with_capacity(line.len());
+        let mut rest = line;
+        while let Some(start) = rest.find(ATTR) {
+            let value_start = start + ATTR.len();
+            let Some(len) = rest[value_start..].find('"') else {
+                break;
+            };
+            out.push_str(&rest[..value_start]);
+            out.push_str(&self.proxy_uri(&rest[value_start..value_start + len]));
+            rest = &rest[value_start + len..];
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// Register an upstream URI (relative to `base`) and return its proxy path
+    fn proxy_uri(&self, uri: &str) -> String {
+        if self.base.join(uri).is_err() {
+            return uri.to_string();
+        }
+        let (id, _) = self.register(uri, None);
+        self.path(&id)
+    }
+
+    /// Register an upstream URI, returning its resource id and the key it is
+    /// encrypted with. Ids are derived from the URL so playlist refreshes
+    /// reuse them, and a segment keeps the key it was first listed with.
+    fn register(&self, uri: &str, key_id: Option<u64>) -> (String, Option<u64>) {
+        let url = self
+            .base
+            .join(uri)
+            .map_or_else(|_| uri.to_string(), String::from);
+        let mut hasher = DefaultHasher::new();
+        self.channel_id.hash(&mut hasher);
+        url.hash(&mut hasher);
+        let id = format!("{:016x}", hasher.finish());
+
+        let resource = self
+            .state
+            .hls_resources
+            .entry(id.clone())
+            .and_modify(|r| r.last_used = Instant::now())
+            .or_insert_with(|| HlsResource {
+                channel_id: self.channel_id.to_string(),
+                url,
+                last_used: Instant::now(),
+                key_id,
+            });
+        let key_id = resource.key_id;
+        (id, key_id)
+    }
+
+    /// Proxy path of a resource under this channel, carrying the viewer token
+    fn path(&self, resource: &str) -> String {
+        match self.token {
+            Some(token) => format!(
+                "/hls/{}/{}?token={}",
+                self.channel_id,
+                resource,
+                urlencode(token)
+            ),
+            None => format!("/hls/{}/{}", self.channel_id, resource),
+        }
+    }
+}
+
+/// Percent-encode everything but unreserved characters
+pub fn urlencode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
@@ -0,0 +1,88 @@
+use crate::auth;
+use crate::models::StreamParams;
+use crate::state::{AppState, HlsKey};
+use crate::tenant;
+use aes::cipher::{block_padding::Pkcs7, BlockEncryptMut, KeyIvInit};
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::time::Instant;
+
+type Aes128CbcEnc = cbc::Encryptor<aes::Aes128>;
+
+/// Superseded keys stay fetchable for this many rotation periods, so
+/// segments still listed in a player's playlist remain decryptable
+const KEY_RETAIN_PERIODS: u32 = 3;
+
+/// Id of the key new segments of a channel are encrypted with, generating or
+/// rotating it as needed.
+pub fn current_key_id(state: &AppState, channel_id: &str) -> u64 {
+    let rotation = state.config.hls_key_rotation;
+    let mut keys = state.hls_keys.entry(channel_id.to_string()).or_default();
+    if let Some(latest) = keys.back() {
+        if rotation.is_zero() || latest.created.elapsed() < rotation {
+            return latest.id;
+        }
+    }
+
+    let id = keys.back().map_or(1, |k| k.id + 1);
+    let mut key = [0u8; 16];
+    getrandom::getrandom(&mut key).expect("no system random source");
+    keys.push_back(HlsKey {
+        id,
+        key,
+        created: Instant::now(),
+    });
+    let retain = rotation * KEY_RETAIN_PERIODS;
+    while keys.len() > 1 && keys.front().is_some_and(|k| k.created.elapsed() > retain) {
+        keys.pop_front();
+    }
+    tracing::info!("Channel {}: HLS segment key {} generated", channel_id, id);
+    id
+}
+
+/// A channel key that is still retained
+pub fn key(state: &AppState, channel_id: &str, key_id: u64) -> Option<[u8; 16]> {
+    let keys = state.hls_keys.get(channel_id)?;
+    keys.iter().find(|k| k.id == key_id).map(|k| k.key)
+}
+
+/// Encrypt a whole segment with AES-128-CBC and PKCS#7 padding, as HLS
+/// `METHOD=AES-128` requires.
+pub fn encrypt_segment(key: &[u8; 16], iv: &[u8; 16], data: &[u8]) -> Vec<u8> {
+    Aes128CbcEnc::new(key.into(), iv.into()).encrypt_padded_vec_mut::<Pkcs7>(data)
+}
+
+/// Serve a segment key to a viewer the auth callback admits for the channel.
+pub async fn serve_key(
+    State(state): State<Arc<AppState>>,
+    Path((channel_id, key_id)): Path<(String, u64)>,
+    uri: Uri,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(channel_id) = tenant::channel_id(&state.config, &uri, &headers, &channel_id) else {
+        return (StatusCode::NOT_FOUND, "Unknown channel").into_response();
+    };
+    if let Err(denied) =
+        auth::authorize(&state, &channel_id, addr, &headers, params.token.as_deref()).await
+    {
+        return denied.into_response();
+    }
+    match key(&state, &channel_id, key_id) {
+        Some(key) => (
+            [
+                (header::CONTENT_TYPE, "application/octet-stream"),
+                (header::CACHE_CONTROL, "no-store"),
+            ],
+            key.to_vec(),
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
@@ -0,0 +1,468 @@
+use crate::auth;
+use crate::hls;
+use crate::hls_keys;
+use crate::models::StreamParams;
+use crate::state::{ActiveChannel, AppState, ClientState, HLS_OUTPUT_CLIENT_PREFIX};
+use crate::tenant;
+use crate::ts;
+use crate::upstream;
+use crate::REQUEST_ID_HEADER;
+use axum::{
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use dashmap::mapref::entry::Entry;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{broadcast, Notify};
+use tokio::time::Instant;
+
+/// Segments kept past the end of the playlist window, for players a little
+/// behind the live edge
+const EXTRA_SEGMENTS: usize = 2;
+/// How often the segmenter checks whether anyone is still watching
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// PCRs wrap after 2^33 ticks of the 90 kHz base clock
+const PCR_WRAP_SECS: f64 = (1u64 << 33) as f64 / 90_000.0;
+
+/// A finished segment of the sliding window
+pub struct Segment {
+    pub sequence: u64,
+    pub duration: f64,
+    /// Key the data is encrypted with (the IV is the sequence number)
+    pub key_id: Option<u64>,
+    pub data: Bytes,
+}
+
+/// Live HLS output of one channel, filled by a segmenter task subscribed to
+/// the channel's broadcast like any other client.
+pub struct HlsOutput {
+    pub segments: Mutex<VecDeque<Arc<Segment>>>,
+    /// The segmenter's entry in the channel's clients
+    pub client_id: String,
+    /// Signalled whenever a segment is added
+    ready: Notify,
+    created: Instant,
+    /// Milliseconds after `created` of the last playlist or segment request
+    last_request_ms: AtomicU64,
+}
+
+impl HlsOutput {
+    fn touch(&self) {
+        let ms = self.created.elapsed().as_millis() as u64;
+        self.last_request_ms.store(ms, Ordering::Relaxed);
+    }
+
+    fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_request_ms.load(Ordering::Relaxed));
+        self.created.elapsed().saturating_sub(last)
+    }
+
+    /// Wait until the window holds at least one segment (or `timeout` passes)
+    async fn wait_ready(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, async {
+            loop {
+                let notified = self.ready.notified();
+                tokio::pin!(notified);
+                notified.as_mut().enable();
+                if !self.segments.lock().unwrap().is_empty() {
+                    return;
+                }
+                notified.await;
+            }
+        })
+        .await;
+    }
+}
+
+/// Serve the channel's sliding-window playlist, starting its segmenter (and
+/// upstream) on first request.
+pub async fn serve_playlist(
+    State(state): State<Arc<AppState>>,
+    Path(channel_id): Path<String>,
+    uri: Uri,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(channel_id) = tenant::channel_id(&state.config, &uri, &headers, &channel_id) else {
+        return (StatusCode::NOT_FOUND, "Unknown channel").into_response();
+    };
+    if let Err(denied) = admit(&state, &channel_id, addr, &headers, &params).await {
+        return denied;
+    }
+    let request_id = headers.get(REQUEST_ID_HEADER).and_then(|v| v.to_str().ok());
+    let Some(output) = output_for(&state, &channel_id, request_id) else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "No streams available").into_response();
+    };
+    output.touch();
+    output
+        .wait_ready(state.config.hls_segment_duration * 3)
+        .await;
+
+    let segments = output.segments.lock().unwrap();
+    if segments.is_empty() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Stream not ready").into_response();
+    }
+    let window = segments.len().saturating_sub(EXTRA_SEGMENTS).max(1);
+    let listed: Vec<_> = segments.iter().skip(segments.len() - window).collect();
+    let playlist = render_playlist(&channel_id, &listed, params.token.as_deref());
+    (
+        [
+            (header::CONTENT_TYPE, "application/vnd.apple.mpegurl"),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        playlist,
+    )
+        .into_response()
+}
+
+/// Serve one segment of the window.
+pub async fn serve_segment(
+    State(state): State<Arc<AppState>>,
+    Path((channel_id, sequence)): Path<(String, u64)>,
+    uri: Uri,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(channel_id) = tenant::channel_id(&state.config, &uri, &headers, &channel_id) else {
+        return (StatusCode::NOT_FOUND, "Unknown channel").into_response();
+    };
+    if let Err(denied) = admit(&state, &channel_id, addr, &headers, &params).await {
+        return denied;
+    }
+    let Some(output) = state.hls_outputs.get(&channel_id).map(|o| o.clone()) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    output.touch();
+    let segment = output
+        .segments
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|s| s.sequence == sequence)
+        .cloned();
+    let Some(segment) = segment else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    if let Some(active) = state.active_channels.get(&channel_id) {
+        let len = segment.data.len() as u64;
+        active.counters.bytes_out.fetch_add(len, Ordering::Relaxed);
+        if let Some(client) = active.clients.get(&output.client_id) {
+            client.bytes_sent.fetch_add(len, Ordering::Relaxed);
+        }
+    }
+    ([(header::CONTENT_TYPE, "video/mp2t")], segment.data.clone()).into_response()
+}
+
+/// Same admission as the progressive stream: on-air, live, and authorized
+async fn admit(
+    state: &AppState,
+    channel_id: &str,
+    addr: SocketAddr,
+    headers: &HeaderMap,
+    params: &StreamParams,
+) -> Result<(), Response> {
+    match state.channel_routes.load().get(channel_id) {
+        Some(r) if !r.enabled => {
+            return Err((StatusCode::FORBIDDEN, "Channel is off-air").into_response())
+        }
+        Some(r) if r.vod || r.hls_passthrough => {
+            return Err((StatusCode::NOT_FOUND, "No HLS output for this channel").into_response())
+        }
+        _ => {}
+    }
+    auth::authorize(state, channel_id, addr, headers, params.token.as_deref())
+        .await
+        .map_err(IntoResponse::into_response)
+}
+
+/// The channel's HLS output, starting the channel and a segmenter if needed.
+/// An output whose channel has stopped is replaced, restarting the channel.
+fn output_for(
+    state: &Arc<AppState>,
+    channel_id: &str,
+    request_id: Option<&str>,
+) -> Option<Arc<HlsOutput>> {
+    if let Some(output) = state.hls_outputs.get(channel_id).map(|o| o.clone()) {
+        let live = state
+            .active_channels
+            .get(channel_id)
+            .is_some_and(|a| !a.stopping() && a.clients.contains_key(&output.client_id));
+        if live {
+            return Some(output);
+        }
+        state
+            .hls_outputs
+            .remove_if(channel_id, |_, o| Arc::ptr_eq(o, &output));
+    }
+    let active = upstream::get_or_start_channel(state, channel_id, request_id)?;
+    match state.hls_outputs.entry(channel_id.to_string()) {
+        Entry::Occupied(existing) => Some(existing.get().clone()),
+        Entry::Vacant(vacant) => {
+            let output = Arc::new(HlsOutput {
+                segments: Mutex::new(VecDeque::new()),
+                client_id: format!("{}{}", HLS_OUTPUT_CLIENT_PREFIX, uuid::Uuid::new_v4()),
+                ready: Notify::new(),
+                created: Instant::now(),
+                last_request_ms: AtomicU64::new(0),
+            });
+            vacant.insert(output.clone());
+            start_segmenter(state.clone(), active, output.clone());
+            Some(output)
+        }
+    }
+}
+
+/// Register the segmenter as a client of the channel and spawn it
+fn start_segmenter(state: Arc<AppState>, active: Arc<ActiveChannel>, output: Arc<HlsOutput>) {
+    let (rx, join_chunks) = {
+        let gop = active.gop_cache.lock().unwrap();
+        let join_chunks = active.join_chunks(&gop, state.config.join_buffer_max_age);
+        (active.sender.subscribe(), join_chunks)
+    };
+    let kick = Arc::new(Notify::new());
+    active.clients.insert(
+        output.client_id.clone(),
+        ClientState {
+            id: output.client_id.clone(),
+            conn_id: 0,
+            connected_since: Instant::now(),
+            bytes_sent: AtomicU64::new(0),
+            remote_addr: "hls-output".to_string(),
+            label: Some("HLS output".to_string()),
+            audio_only: false,
+            kick: kick.clone(),
+            lag_events: AtomicU64::new(0),
+            lag_bytes: AtomicU64::new(0),
+            lagging: AtomicBool::new(false),
+            queue_depth: Default::default(),
+            send_rate: Default::default(),
+            bandwidth: Default::default(),
+            playback: Mutex::new(None),
+            session_secret: None,
+        },
+    );
+    tracing::info!("Channel {}: HLS output started", active.channel_id);
+
+    tokio::spawn(async move {
+        let mut segmenter = Segmenter::new(&state, &active, &output);
+        for chunk in join_chunks {
+            segmenter.push(&chunk.data, chunk.keyframe);
+        }
+        segmenter.run(rx, &kick).await;
+
+        state
+            .hls_outputs
+            .remove_if(&active.channel_id, |_, o| Arc::ptr_eq(o, &output));
+        active.clients.remove(&output.client_id);
+        tracing::info!("Channel {}: HLS output stopped", active.channel_id);
+        if active.clients.is_empty() && !active.persistent {
+            let _ = active.stop_tx.send(true);
+        }
+    });
+}
+
+/// Cuts the broadcast into segments of about the target duration, at
+/// keyframes when the channel has detectable ones.
+struct Segmenter<'a> {
+    state: &'a AppState,
+    active: &'a ActiveChannel,
+    output: &'a HlsOutput,
+    data: Vec<u8>,
+    started: Instant,
+    first_pcr: Option<f64>,
+    last_pcr: Option<f64>,
+    sequence: u64,
+}
+
+impl<'a> Segmenter<'a> {
+    fn new(state: &'a AppState, active: &'a ActiveChannel, output: &'a HlsOutput) -> Self {
+        Self {
+            state,
+            active,
+            output,
+            data: Vec::new(),
+            started: Instant::now(),
+            first_pcr: None,
+            last_pcr: None,
+            sequence: 0,
+        }
+    }
+
+    async fn run(&mut self, mut rx: broadcast::Receiver<crate::state::Chunk>, kick: &Notify) {
+        let idle_timeout = self.state.config.hls_output_idle;
+        let mut idle_check = tokio::time::interval(IDLE_CHECK_INTERVAL);
+        // The segmenter keeps the channel's sender alive, so a dead upstream
+        // shows as its stop signal rather than a closed broadcast
+        let mut stopped = self.active.stop_tx.subscribe();
+        loop {
+            tokio::select! {
+                _ = kick.notified() => break,
+                _ = stopped.wait_for(|stop| *stop) => {
+                    tracing::info!("Channel {}: upstream stopped", self.active.channel_id);
+                    break;
+                }
+                _ = idle_check.tick() => {
+                    if self.output.idle_for() >= idle_timeout {
+                        tracing::info!(
+                            "Channel {}: no HLS requests for {}s",
+                            self.active.channel_id,
+                            idle_timeout.as_secs()
+                        );
+                        break;
+                    }
+                }
+                result = rx.recv() => match result {
+                    Ok(chunk) => self.push(&chunk.data, chunk.keyframe),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        tracing::warn!("Channel {}: HLS segmenter lagged {} chunks", self.active.channel_id, n);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+            }
+        }
+    }
+
+    /// Add broadcast data, finishing the current segment first if it is long
+    /// enough and this data starts a new keyframe (or there are none to wait for)
+    fn push(&mut self, data: &[u8], keyframe: bool) {
+        let target = self.state.config.hls_segment_duration.as_secs_f64();
+        let mut data = data;
+        if !self.data.is_empty() && self.duration(None) >= target {
+            let keyframes = self.active.keyframes_seen.load(Ordering::Relaxed);
+            let cut = match keyframe.then(|| ts::keyframe_offset(data)).flatten() {
+                Some(offset) => Some(offset),
+                // No keyframe in sight: don't let segments grow without bound
+                None if !keyframes || self.duration(None) >= target * 2.0 => Some(0),
+                None => None,
+            };
+            if let Some(offset) = cut {
+                self.append(&data[..offset]);
+                let next_pcr = ts::pcr_range(&data[offset..]).map(|(first, _)| first);
+                self.finish(next_pcr);
+                data = &data[offset..];
+            }
+        }
+        self.append(data);
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        if self.data.is_empty() {
+            self.started = Instant::now();
+        }
+        if let Some((first, last)) = ts::pcr_range(data) {
+            self.first_pcr.get_or_insert(first);
+            self.last_pcr = Some(last);
+        }
+        self.data.extend_from_slice(data);
+    }
+
+    /// Segment length by PCR (up to `next_pcr` if known), else wall clock
+    fn duration(&self, next_pcr: Option<f64>) -> f64 {
+        match (self.first_pcr, next_pcr.or(self.last_pcr)) {
+            (Some(first), Some(end)) if end != first => {
+                let span = end - first;
+                if span < 0.0 {
+                    span + PCR_WRAP_SECS
+                } else {
+                    span
+                }
+            }
+            _ => self.started.elapsed().as_secs_f64(),
+        }
+    }
+
+    fn finish(&mut self, next_pcr: Option<f64>) {
+        let duration = self.duration(next_pcr);
+        let sequence = self.sequence;
+        self.sequence += 1;
+        let data = std::mem::take(&mut self.data);
+        self.first_pcr = None;
+        self.last_pcr = None;
+
+        let encrypt = self
+            .state
+            .channel_routes
+            .load()
+            .get(&self.active.channel_id)
+            .is_some_and(|r| r.hls_encryption);
+        let key = encrypt
+            .then(|| {
+                let key_id = hls_keys::current_key_id(self.state, &self.active.channel_id);
+                hls_keys::key(self.state, &self.active.channel_id, key_id).map(|k| (key_id, k))
+            })
+            .flatten();
+        let (key_id, data) = match key {
+            Some((key_id, key)) => {
+                let iv = (sequence as u128).to_be_bytes();
+                (Some(key_id), hls_keys::encrypt_segment(&key, &iv, &data))
+            }
+            None => (None, data),
+        };
+
+        let keep = self.state.config.hls_window_segments.max(1) + EXTRA_SEGMENTS;
+        let mut segments = self.output.segments.lock().unwrap();
+        segments.push_back(Arc::new(Segment {
+            sequence,
+            duration,
+            key_id,
+            data: Bytes::from(data),
+        }));
+        while segments.len() > keep {
+            segments.pop_front();
+        }
+        drop(segments);
+        self.output.ready.notify_waiters();
+    }
+}
+
+fn render_playlist(channel_id: &str, segments: &[&Arc<Segment>], token: Option<&str>) -> String {
+    let query = token
+        .map(|t| format!("?token={}", hls::urlencode(t)))
+        .unwrap_or_default();
+    let target = segments
+        .iter()
+        .map(|s| s.duration.ceil() as u64)
+        .max()
+        .unwrap_or(1)
+        .max(1);
+
+    let mut out = String::new();
+    let _ = writeln!(out, "#EXTM3U");
+    let _ = writeln!(out, "#EXT-X-VERSION:3");
+    let _ = writeln!(out, "#EXT-X-TARGETDURATION:{}", target);
+    let _ = writeln!(out, "#EXT-X-MEDIA-SEQUENCE:{}", segments[0].sequence);
+    let mut key_id = None;
+    for segment in segments {
+        if segment.key_id != key_id {
+            match segment.key_id {
+                Some(id) => {
+                    let _ = writeln!(
+                        out,
+                        "#EXT-X-KEY:METHOD=AES-128,URI=\"/hls/{}/key/{}{}\"",
+                        channel_id, id, query
+                    );
+                }
+                None => {
+                    let _ = writeln!(out, "#EXT-X-KEY:METHOD=NONE");
+                }
+            }
+            key_id = segment.key_id;
+        }
+        let _ = writeln!(out, "#EXTINF:{:.3},", segment.duration);
+        let _ = writeln!(out, "segment/{}{}", segment.sequence, query);
+    }
+    out
+}
@@ -0,0 +1,61 @@
+mod auth;
+mod balancer;
+mod bitrate;
+mod capacity;
+mod certs;
+mod chaos;
+mod cluster;
+pub mod config;
+mod control;
+mod events;
+mod failback;
+mod feed;
+mod forwarded;
+mod geo;
+pub mod grpc;
+mod hibernate;
+mod hls;
+mod hls_keys;
+mod hls_output;
+mod metrics;
+pub mod models;
+mod multicast;
+mod persist;
+mod qoe;
+mod reaper;
+mod relay;
+mod sampling;
+mod server;
+mod session;
+pub mod state;
+mod status;
+mod stream;
+mod sync_pull;
+mod tenant;
+/// In-process test harness: a mock MPEG-TS upstream and a proxy bound to an
+/// ephemeral port, plus helpers to drive the control API.
+#[cfg(feature = "testing")]
+pub mod testing;
+mod tls;
+mod ts;
+mod ts_analyzer;
+mod ui;
+mod upstream;
+mod vod;
+mod warmup;
+mod webhooks;
+
+pub use config::{Config, IpNet, ListenerConfig, RouteGroup, TenantConfig};
+pub use server::{ProxyServer, ProxyServerBuilder, RunningServer};
+pub use state::AppState;
+
+/// Correlation header generated (or accepted from the caller) for every request
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+/// Header a client may use to label its session in status output
+pub const CLIENT_LABEL_HEADER: &str = "x-client-label";
+/// Response header carrying the client id a stream connection was registered
+/// under, for the session heartbeat API
+pub const CLIENT_ID_HEADER: &str = "x-client-id";
+/// Response header carrying the secret that resumes a stream session (as the
+/// `session_secret` query parameter or this request header)
+pub const SESSION_SECRET_HEADER: &str = "x-session-secret";
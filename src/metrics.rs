@@ -0,0 +1,335 @@
+use crate::state::{AccountState, AppState, ChannelCounters};
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::{Instant, MissedTickBehavior};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Samples kept for the max scheduler delay window
+const DELAY_WINDOW: usize = 60;
+/// Timeout for a single metrics push
+const PUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most recent tokio runtime sample
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeSnapshot {
+    pub workers: usize,
+    pub alive_tasks: usize,
+    pub global_queue_depth: usize,
+    /// Fraction of the last sample interval each worker spent busy (0.0-1.0)
+    pub worker_utilization: Vec<f64>,
+    /// How late the sampler's own timer fired on the last tick
+    pub scheduler_delay: Duration,
+    /// Worst scheduler delay over the last minute
+    pub scheduler_delay_max: Duration,
+}
+
+/// Spawn the task that samples runtime metrics once a second.
+///
+/// Scheduler delay is measured as the lateness of this task's own timer: when
+/// workers are saturated, ready tasks (including this one) wait to be polled.
+pub fn spawn_sampler(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let handle = tokio::runtime::Handle::current();
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_busy: Vec<Duration> = Vec::new();
+        let mut last_sample = Instant::now();
+        let mut delays: VecDeque<Duration> = VecDeque::with_capacity(DELAY_WINDOW);
+
+        loop {
+            let scheduled = interval.tick().await;
+            let now = Instant::now();
+            let delay = now.saturating_duration_since(scheduled);
+
+            let metrics = handle.metrics();
+            let workers = metrics.num_workers();
+            let wall = now.duration_since(last_sample).as_secs_f64();
+            let busy: Vec<Duration> = (0..workers)
+                .map(|w| metrics.worker_total_busy_duration(w))
+                .collect();
+            let worker_utilization = busy
+                .iter()
+                .enumerate()
+                .map(|(w, b)| {
+                    let prev = last_busy.get(w).copied().unwrap_or_default();
+                    if wall > 0.0 {
+                        (b.saturating_sub(prev).as_secs_f64() / wall).min(1.0)
+                    } else {
+                        0.0
+                    }
+                })
+                .collect();
+            last_busy = busy;
+            last_sample = now;
+
+            if delays.len() == DELAY_WINDOW {
+                delays.pop_front();
+            }
+            delays.push_back(delay);
+
+            *state.runtime_metrics.lock().unwrap() = RuntimeSnapshot {
+                workers,
+                alive_tasks: metrics.num_alive_tasks(),
+                global_queue_depth: metrics.global_queue_depth(),
+                worker_utilization,
+                scheduler_delay: delay,
+                scheduler_delay_max: delays.iter().max().copied().unwrap_or_default(),
+            };
+        }
+    })
+}
+
+/// Spawn the task that PUTs the `/metrics` exposition to a Prometheus
+/// Pushgateway every METRICS_PUSH_INTERVAL_SECS, for nodes that can't be
+/// scraped. PUT replaces the whole group, so channels that went away drop out
+/// of the gateway on the next push.
+pub fn spawn_pusher(state: Arc<AppState>, url: String) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(PUSH_TIMEOUT)
+            .build()
+            .expect("failed to build metrics push client");
+        let mut interval = tokio::time::interval(state.config.metrics_push_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let mut request = client
+                .put(&url)
+                .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(render_prometheus(&state));
+            if let Some(username) = &state.config.metrics_push_username {
+                request =
+                    request.basic_auth(username, state.config.metrics_push_password.as_deref());
+            }
+            match request.send().await {
+                Ok(response) if response.status().is_success() => {}
+                Ok(response) => tracing::warn!("Metrics push rejected: HTTP {}", response.status()),
+                Err(e) => tracing::warn!("Metrics push failed: {}", e),
+            }
+        }
+    })
+}
+
+/// Prometheus text exposition of channel, account and runtime metrics.
+pub async fn prometheus(State(state): State<Arc<AppState>>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render_prometheus(&state),
+    )
+        .into_response()
+}
+
+type ChannelCounterFn = fn(&ChannelCounters) -> u64;
+type AccountMetricFn = fn(&AccountState) -> f64;
+
+fn render_prometheus(state: &AppState) -> String {
+    let mut out = PromWriter::default();
+
+    let mut counters: Vec<_> = state
+        .channel_counters
+        .iter()
+        .map(|e| (e.key().clone(), e.value().clone()))
+        .collect();
+    counters.sort_by(|a, b| a.0.cmp(&b.0));
+    let channel_counters: [(&str, &str, ChannelCounterFn); 8] = [
+        (
+            "proxy_channel_upstream_bytes_total",
+            "Bytes read from upstream",
+            |c| c.bytes_in.load(Ordering::Relaxed),
+        ),
+        (
+            "proxy_channel_client_bytes_total",
+            "Bytes delivered to clients",
+            |c| c.bytes_out.load(Ordering::Relaxed),
+        ),
+        (
+            "proxy_channel_failovers_total",
+            "Switches to another stream after an upstream failure",
+            |c| c.failovers.load(Ordering::Relaxed),
+        ),
+        (
+            "proxy_channel_upstream_reconnects_total",
+            "Upstream connections opened after the first",
+            |c| c.reconnects.load(Ordering::Relaxed),
+        ),
+        (
+            "proxy_channel_lag_dropped_chunks_total",
+            "Chunks clients missed by falling behind the broadcast buffer",
+            |c| c.lag_drops.load(Ordering::Relaxed),
+        ),
+        (
+            "proxy_channel_lag_disconnects_total",
+            "Clients disconnected for falling behind too often or too far",
+            |c| c.lag_disconnects.load(Ordering::Relaxed),
+        ),
+        (
+            "proxy_channel_keepalive_packets_total",
+            "Keepalive null packets sent to clients during data gaps",
+            |c| c.keepalive_packets.load(Ordering::Relaxed),
+        ),
+        (
+            "proxy_channel_relay_lost_chunks_total",
+            "Chunks missing from a relay source's sequence",
+            |c| c.relay_lost_chunks.load(Ordering::Relaxed),
+        ),
+    ];
+    for (name, help, value) in channel_counters {
+        out.header(name, help, "counter");
+        for (channel_id, c) in &counters {
+            out.sample(name, &[("channel", channel_id)], value(c) as f64);
+        }
+    }
+
+    let mut active: Vec<_> = state
+        .active_channels
+        .iter()
+        .map(|e| e.value().clone())
+        .collect();
+    active.sort_by(|a, b| a.channel_id.cmp(&b.channel_id));
+    out.header("proxy_channel_clients", "Connected clients", "gauge");
+    for a in &active {
+        let account = a.current_upstream().account_id.to_string();
+        out.sample(
+            "proxy_channel_clients",
+            &[("channel", &a.channel_id), ("account", &account)],
+            a.clients.len() as f64,
+        );
+    }
+    out.header(
+        "proxy_channel_broadcast_queue_depth",
+        "Chunks queued for the slowest client",
+        "gauge",
+    );
+    for a in &active {
+        out.sample(
+            "proxy_channel_broadcast_queue_depth",
+            &[("channel", &a.channel_id)],
+            a.sender.len() as f64,
+        );
+    }
+    out.header(
+        "proxy_channel_upstream_idle_seconds",
+        "Time since upstream data last arrived",
+        "gauge",
+    );
+    for a in &active {
+        out.sample(
+            "proxy_channel_upstream_idle_seconds",
+            &[("channel", &a.channel_id)],
+            a.idle_for().as_secs_f64(),
+        );
+    }
+
+    let mut accounts: Vec<_> = state.accounts.load().iter().map(|e| *e.key()).collect();
+    accounts.sort_unstable();
+    let account_metrics: [(&str, &str, &str, AccountMetricFn); 5] = [
+        (
+            "proxy_account_active_connections",
+            "Upstream connections in use",
+            "gauge",
+            |a| a.active_connections.load(Ordering::Relaxed) as f64,
+        ),
+        (
+            "proxy_account_max_connections",
+            "Connection limit (0 = unlimited)",
+            "gauge",
+            |a| a.max_connections.load(Ordering::Relaxed) as f64,
+        ),
+        (
+            "proxy_account_utilization_ratio",
+            "Active connections as a fraction of the limit (0 if unlimited)",
+            "gauge",
+            |a| match a.max_connections.load(Ordering::Relaxed) {
+                0 => 0.0,
+                max => a.active_connections.load(Ordering::Relaxed) as f64 / max as f64,
+            },
+        ),
+        (
+            "proxy_account_enabled",
+            "Whether new selections may use the account",
+            "gauge",
+            |a| a.enabled.load(Ordering::Relaxed) as u8 as f64,
+        ),
+        (
+            "proxy_account_failovers_total",
+            "Upstream failures on the account that caused a failover",
+            "counter",
+            |a| a.failovers.load(Ordering::Relaxed) as f64,
+        ),
+    ];
+    for (name, help, kind, value) in account_metrics {
+        out.header(name, help, kind);
+        for id in &accounts {
+            if let Some(account) = state.accounts.load().get(id) {
+                out.sample(name, &[("account", &id.to_string())], value(&account));
+            }
+        }
+    }
+
+    let runtime = state.runtime_metrics.lock().unwrap().clone();
+    out.header("proxy_runtime_alive_tasks", "Tokio tasks alive", "gauge");
+    out.sample("proxy_runtime_alive_tasks", &[], runtime.alive_tasks as f64);
+    out.header(
+        "proxy_runtime_scheduler_delay_seconds",
+        "Lateness of the sampler timer on the last tick",
+        "gauge",
+    );
+    out.sample(
+        "proxy_runtime_scheduler_delay_seconds",
+        &[],
+        runtime.scheduler_delay.as_secs_f64(),
+    );
+    out.header(
+        "proxy_uptime_seconds",
+        "Time since the proxy started",
+        "gauge",
+    );
+    out.sample(
+        "proxy_uptime_seconds",
+        &[],
+        state.start_time.elapsed().as_secs_f64(),
+    );
+
+    out.0
+}
+
+/// Minimal writer for the Prometheus text format
+#[derive(Default)]
+struct PromWriter(String);
+
+impl PromWriter {
+    fn header(&mut self, name: &str, help: &str, kind: &str) {
+        let _ = writeln!(self.0, "# HELP {} {}", name, help);
+        let _ = writeln!(self.0, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            self.0.push('{');
+            for (i, (key, value)) in labels.iter().enumerate() {
+                if i > 0 {
+                    self.0.push(',');
+                }
+                let _ = write!(self.0, "{}=\"{}\"", key, escape_label(value));
+            }
+            self.0.push('}');
+        }
+        let _ = writeln!(self.0, " {}", value);
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
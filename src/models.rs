@@ -1,28 +1,341 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 // --- Control API models ---
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct StreamUrl {
     pub account_id: u64,
     pub url: String,
+    /// Tie-breaker between URLs of the same stream (higher is tried first)
+    #[serde(default)]
+    pub priority: i32,
+    /// Share of new connections among equally preferred URLs (0 = inherit
+    /// the stream's weight)
+    #[serde(default)]
+    pub weight: u32,
+    /// Headers sent with requests to this URL, overriding the account's
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct StreamConfig {
     pub id: u64,
     pub urls: Vec<StreamUrl>,
+    /// Streams with higher priority are used first; list order breaks ties
+    #[serde(default)]
+    pub priority: i32,
+    /// Spread new connections over equally preferred sources in proportion
+    /// to their weights (0 everywhere = always the first in list order)
+    #[serde(default)]
+    pub weight: u32,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct ChannelConfig {
+    #[serde(default)]
     pub streams: Vec<StreamConfig>,
+    /// Off-air channels keep their routing but refuse viewers
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Keep the upstream running even with no clients connected
+    #[serde(default)]
+    pub persistent: bool,
+    /// Warm-up order for persistent channels (higher starts first)
+    #[serde(default)]
+    pub priority: i32,
+    /// File-backed channel (recording/VOD): each client gets its own upstream
+    /// request so Range/seek can be honored
+    #[serde(default)]
+    pub vod: bool,
+    /// Viewer auth webhook for this channel (overrides AUTH_CALLBACK_URL)
+    #[serde(default)]
+    pub auth_callback: Option<String>,
+    /// Higher-cost sources used once viewers exceed `premium_threshold`
+    #[serde(default)]
+    pub premium_streams: Vec<StreamConfig>,
+    #[serde(default)]
+    pub premium_threshold: u32,
+    /// Per-channel override of UPSTREAM_HIGH_WATERMARK
+    #[serde(default)]
+    pub high_watermark: Option<usize>,
+    /// Per-channel override of UPSTREAM_LOW_WATERMARK
+    #[serde(default)]
+    pub low_watermark: Option<usize>,
+    /// Upstream bytes allowed per UTC day (for metered transit links)
+    #[serde(default)]
+    pub daily_quota_bytes: Option<u64>,
+    /// Upstream bytes allowed per UTC calendar month
+    #[serde(default)]
+    pub monthly_quota_bytes: Option<u64>,
+    /// Lower-bitrate variants used once a quota is exceeded (empty = stop the channel)
+    #[serde(default)]
+    pub quota_streams: Vec<StreamConfig>,
+    /// HLS upstream: proxy the playlists and segments to clients instead of
+    /// relaying a single TS stream
+    #[serde(default)]
+    pub hls_passthrough: bool,
+    /// Encrypt HLS segments served to clients with rotating AES-128 keys
+    #[serde(default)]
+    pub hls_encryption: bool,
+    /// Handling of DVB subtitle and teletext streams (None = pass through untouched)
+    #[serde(default)]
+    pub subtitles: Option<SubtitleConfig>,
+    /// ISO 639-2 audio languages to list first in the PMT, most preferred first
+    #[serde(default)]
+    pub audio_language_priority: Vec<String>,
+    /// Free-form labels for bulk operations (`/control/v1/tags/{tag}/...`)
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Licensing group whose active-channel limit this channel counts
+    /// against (`/control/v1/groups/{group}`)
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Simultaneous connections to this channel from one client IP
+    /// (None = only the global `MAX_CLIENTS_PER_IP` applies)
+    #[serde(default)]
+    pub max_clients_per_ip: Option<usize>,
+    /// Per-channel override of MAX_CLIENT_LAG_EVENTS (0 = never disconnect)
+    #[serde(default)]
+    pub max_lag_events: Option<u64>,
+    /// Per-channel override of MAX_CLIENT_LAG_BYTES (0 = unlimited)
+    #[serde(default)]
+    pub max_lag_bytes: Option<u64>,
+    /// Per-channel override of KEEPALIVE_AFTER_MS (0 = no keepalives)
+    #[serde(default)]
+    pub keepalive_after_ms: Option<u64>,
+    /// How the channel retries and moves between sources when one fails
+    #[serde(default)]
+    pub failover: FailoverPolicy,
+    /// Multicast gateway: relay this group (`239.1.1.1:5000`, or a `udp://` or
+    /// `rtp://` URL) to HTTP clients, with no streams or accounts involved.
+    /// Any `streams` given are ignored.
+    #[serde(default)]
+    pub multicast_group: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Per-channel upstream failure handling
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct FailoverPolicy {
+    /// Upstream failures (retries and failovers) before the channel gives up
+    pub max_attempts: u32,
+    /// Wait before reconnecting after a failure, doubled for each further
+    /// failure in a row (0 = reconnect at once)
+    pub retry_delay_ms: u64,
+    /// Upper bound for the doubled retry delay
+    pub max_retry_delay_ms: u64,
+    /// After the last source, continue from the top of the list
+    pub wrap_around: bool,
+    /// Reconnects to a failed URL before moving on to the next source
+    pub same_url_retries: u32,
+    /// Move back to a more preferred source once it is healthy again
+    /// (checked every `FAILBACK_INTERVAL_SECS`)
+    pub fail_back: bool,
+}
+
+impl Default for FailoverPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            retry_delay_ms: 0,
+            max_retry_delay_ms: 30_000,
+            wrap_around: false,
+            same_url_retries: 0,
+            fail_back: true,
+        }
+    }
+}
+
+impl FailoverPolicy {
+    /// Wait before the next connect after `failures` failures in a row
+    pub fn retry_delay(&self, failures: u32) -> std::time::Duration {
+        let doubled = self
+            .retry_delay_ms
+            .saturating_mul(1 << failures.saturating_sub(1).min(20));
+        std::time::Duration::from_millis(doubled.min(self.max_retry_delay_ms))
+    }
+}
+
+/// Per-channel rules for DVB subtitle and teletext PIDs
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct SubtitleConfig {
+    /// Applied to subtitle/teletext PIDs without a rule of their own
+    #[serde(default)]
+    pub default: SubtitleAction,
+    /// Rules by upstream PID
+    #[serde(default)]
+    pub pids: HashMap<u16, SubtitleAction>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubtitleAction {
+    #[default]
+    Keep,
+    /// Remove the stream and its PMT entry
+    Drop,
+    /// Move the stream to another PID (which must not already be in use)
+    Remap(u16),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct AccountConfig {
     pub max_connections: u32,
+    /// Disabled accounts (e.g. provider maintenance) are skipped for new selections
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// When disabled, move channels already on this account to other accounts
+    #[serde(default)]
+    pub migrate_active: bool,
+    /// Login the provider requires before streaming
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<SessionConfig>,
+    /// Headers sent with every upstream request on the account, e.g. the
+    /// `User-Agent` or `Referer` a provider expects
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    /// HTTP or SOCKS5 proxy the account's upstream requests go through
+    /// (`http://`, `https://`, `socks5://` or `socks5h://`, credentials in the
+    /// userinfo), so each account can have its own exit IP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_url: Option<String>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// A provider login performed before streaming on an account. `{username}`
+/// and `{password}` are substituted into the login URL (percent-encoded),
+/// headers and body;
+/// the token it yields replaces `{token}` in the account's stream URLs and
+/// `stream_headers`.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SessionConfig {
+    pub login_url: String,
+    #[serde(default = "default_login_method")]
+    pub method: String,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// JSONPath to the token in a JSON response (e.g. `$.data.token`)
+    #[serde(default)]
+    pub token_path: Option<String>,
+    /// Regex over the response body; the first capture group (or the whole
+    /// match) is the token. With neither, the whole body is the token.
+    #[serde(default)]
+    pub token_regex: Option<String>,
+    /// Seconds a token is used before logging in again
+    #[serde(default = "default_session_refresh_secs")]
+    pub refresh_secs: u64,
+    /// Headers added to stream requests
+    #[serde(default)]
+    pub stream_headers: HashMap<String, String>,
+}
+
+fn default_login_method() -> String {
+    "POST".to_string()
+}
+
+fn default_session_refresh_secs() -> u64 {
+    3600
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroupConfig {
+    /// Channels of the group that may be active at once
+    pub max_active_channels: u32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SwitchAccountRequest {
+    pub account_id: u64,
+}
+
+/// Operation applied to every channel carrying a tag
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TagAction {
+    /// Take the channels off-air, stopping their upstreams and disconnecting
+    /// their viewers
+    Disable,
+    /// Put the channels back on-air
+    Enable,
+    /// Stop running upstreams and disconnect their viewers, as the channel
+    /// stop endpoint does; they start again on the next viewer (or warm-up)
+    Stop,
+    /// Reconnect running upstreams without dropping viewers
+    Restart,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagActionResponse {
+    pub tag: String,
+    pub action: TagAction,
+    /// Channels the action changed
+    pub channels: Vec<String>,
+}
+
+/// What a proxy event reports
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    ChannelStarted,
+    ChannelStopped,
+    Failover,
+    /// The channel ran out of sources or failover attempts
+    FailoversExhausted,
+    ClientConnected,
+    ClientDisconnected,
+}
+
+/// A notable change in a channel's life, as kept in the event ring
+#[derive(Debug, Serialize, Clone)]
+pub struct ProxyEvent {
+    /// Increases by one per event; pass the last one seen as `?since=` to resume
+    pub seq: u64,
+    pub timestamp: String,
+    pub kind: EventKind,
+    pub channel_id: String,
+    pub client_id: Option<String>,
+    /// X-Request-Id of the request behind a client or channel-start event
+    pub request_id: Option<String>,
+    pub message: String,
+}
+
+/// Query of `/status/v1/events/export`
+#[derive(Debug, Default, Deserialize)]
+pub struct EventExportParams {
+    /// Only events after this sequence number
+    pub since: Option<u64>,
+    /// Only events of this channel
+    pub channel: Option<String>,
+    /// Keep the response open and stream new events as they happen
+    #[serde(default)]
+    pub follow: bool,
+}
+
+/// `?tag=&group=&state=&page=&per_page=&summary=` for channel listings.
+/// Without `per_page` every matching channel is returned; pages start at 1.
+#[derive(Debug, Default, Deserialize)]
+pub struct ChannelListParams {
+    pub tag: Option<String>,
+    /// Licensing group
+    pub group: Option<String>,
+    /// "active", "idle", "disabled" or "quota_exceeded"
+    pub state: Option<String>,
+    pub page: Option<usize>,
+    pub per_page: Option<usize>,
+    /// List only each channel's state and client count
+    #[serde(default)]
+    pub summary: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -31,6 +344,85 @@ pub struct SyncRequest {
     pub accounts: HashMap<String, AccountConfig>,
 }
 
+/// Snapshot format written by this version of the proxy
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Full routing state (channels and accounts) as exported by
+/// `/control/v1/export` and accepted by `/control/v1/restore`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Snapshot {
+    pub version: u32,
+    #[serde(default)]
+    pub exported_at: Option<String>,
+    pub channels: HashMap<String, ChannelConfig>,
+    pub accounts: HashMap<String, AccountConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RestoreParams {
+    /// Validate and report the changes without applying them
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RestoreReport {
+    pub dry_run: bool,
+    pub applied: bool,
+    /// Validation problems; any error rejects the whole snapshot
+    pub errors: Vec<String>,
+    pub channels_added: Vec<String>,
+    pub channels_removed: Vec<String>,
+    pub channels_updated: usize,
+    pub accounts: usize,
+}
+
+// --- Stream API models ---
+
+#[derive(Debug, Default, Deserialize)]
+pub struct StreamParams {
+    /// Client-supplied session token; reconnects with the same token (even
+    /// from a new IP) take over the existing client instead of adding one,
+    /// if they also present the secret issued with the stream
+    pub session: Option<String>,
+    /// Secret from the `X-Session-Secret` response header of the session's
+    /// first connection
+    pub session_secret: Option<String>,
+    /// Viewer credential forwarded to the auth callback
+    pub token: Option<String>,
+    /// Free-form client label shown in status (overrides the X-Client-Label header)
+    pub label: Option<String>,
+    /// "1"/"true" strips video for this client (audio-only fallback)
+    pub audio_only: Option<String>,
+    /// Output format: "ts" (default) or "hls"; overrides the Accept header.
+    /// "mp4" is refused with 406 as there is no fMP4 remuxer.
+    pub format: Option<String>,
+}
+
+/// Playback stats a custom player reports for its session
+#[derive(Debug, Deserialize)]
+pub struct HeartbeatRequest {
+    /// Media buffered ahead of the playhead
+    pub buffer_seconds: Option<f64>,
+    /// Frames dropped since playback started
+    pub dropped_frames: Option<u64>,
+}
+
+impl StreamParams {
+    pub fn audio_only(&self) -> bool {
+        matches!(self.audio_only.as_deref(), Some("1" | "true"))
+    }
+}
+
+/// Body POSTed to the viewer auth callback
+#[derive(Debug, Serialize)]
+pub struct AuthRequest<'a> {
+    pub channel_id: &'a str,
+    pub client_ip: String,
+    pub user_agent: Option<&'a str>,
+    pub token: Option<&'a str>,
+}
+
 // --- Status API models ---
 
 #[derive(Debug, Serialize, Clone)]
@@ -38,8 +430,13 @@ pub struct UpstreamStatus {
     pub stream_id: u64,
     pub account_id: u64,
     pub url: String,
+    pub premium: bool,
     pub connected_since: String,
     pub bytes_transferred: u64,
+    /// Upstream bitrate averaged over ~10s (None until sampled)
+    pub input_bitrate_bps: Option<u64>,
+    /// Bitrate sent to all clients together, averaged likewise
+    pub output_bitrate_bps: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -47,6 +444,66 @@ pub struct ChannelStatus {
     pub state: String,
     pub clients: u32,
     pub upstream: Option<UpstreamStatus>,
+    /// Upstream byte usage (None if the channel has no quota)
+    pub quota: Option<QuotaStatus>,
+    pub tags: Vec<String>,
+    /// Aggregated from recent session heartbeats (None if no client sends them)
+    pub qoe: Option<QoeStatus>,
+    /// Quality score over the last minute (None until a few seconds of samples)
+    pub quality: Option<QualityScore>,
+    /// Clients disconnected for exceeding the channel's lag limits
+    pub lag_disconnects: u64,
+    /// Keepalive null packets sent to clients during data gaps
+    pub keepalive_packets: u64,
+}
+
+/// A channel in the status listing
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum ChannelListing {
+    Full(Box<ChannelStatus>),
+    /// With `?summary=true`
+    Summary(ChannelSummary),
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelSummary {
+    pub state: String,
+    pub clients: u32,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct QualityScore {
+    /// 0 (unwatchable) to 100
+    pub score: u32,
+    /// Fewer upstream reconnects is higher (0.0-1.0)
+    pub stability: f64,
+    /// Less variation in the per-second bitrate is higher
+    pub steadiness: f64,
+    /// Fewer chunks missed by lagging clients is higher
+    pub delivery: f64,
+    /// Client buffer from heartbeats (None without heartbeats)
+    pub playback: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct QoeStatus {
+    /// Clients with a heartbeat in the last minute
+    pub reporting_clients: usize,
+    pub avg_buffer_seconds: Option<f64>,
+    pub min_buffer_seconds: Option<f64>,
+    /// Sum of the reporting clients' dropped frames
+    pub dropped_frames: u64,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct QuotaStatus {
+    pub daily_bytes: u64,
+    pub daily_limit: Option<u64>,
+    pub monthly_bytes: u64,
+    pub monthly_limit: Option<u64>,
+    /// "daily" or "monthly" once that quota is used up
+    pub exceeded: Option<String>,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -55,18 +512,69 @@ pub struct ClientInfo {
     pub connected_since: String,
     pub bytes_sent: u64,
     pub remote_addr: String,
+    pub label: Option<String>,
+    pub audio_only: bool,
+    /// Country/ASN of remote_addr (None unless GeoIP databases are configured)
+    pub geo: Option<GeoInfo>,
+    pub lag_events: u64,
+    /// Data missed by falling behind (estimated at full chunk size)
+    pub lag_bytes: u64,
+    /// Chunks waiting to be sent to this client
+    pub queue_depth: usize,
+    /// Bitrate sent to this client, averaged over ~10s (None until sampled)
+    pub bitrate_bps: Option<u64>,
+    /// Throughput the client's connection achieved while data was queued for
+    /// it (None if it has kept up with the stream, so its capacity is unknown
+    /// beyond the stream's bitrate)
+    pub bandwidth_bps: Option<u64>,
+    pub lagging: bool,
+    /// Latest session heartbeat (None if the player doesn't send them)
+    pub playback: Option<PlaybackInfo>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct PlaybackInfo {
+    pub buffer_seconds: Option<f64>,
+    pub dropped_frames: Option<u64>,
+    pub reported_at: String,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct GeoInfo {
+    /// ISO 3166-1 alpha-2 country code
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    pub as_org: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct AccountStatus {
     pub active_connections: u32,
+    /// Connections other instances of the cluster hold on the account
+    pub remote_connections: u32,
     pub max_connections: u32,
+    pub peak_connections: u32,
+    pub utilization_percent: Option<u32>,
+    pub near_capacity_since: Option<String>,
+    pub capacity_warning: bool,
+    pub enabled: bool,
+    /// Retries suspended by the account's failure budget until then
+    pub retry_suspended_until: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
 pub struct ChannelsResponse {
-    pub channels: HashMap<String, ChannelStatus>,
+    pub channels: HashMap<String, ChannelListing>,
+    /// Channels matching the filter, across all pages
+    pub total_channels: usize,
     pub accounts: HashMap<String, AccountStatus>,
+    pub groups: HashMap<String, GroupStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupStatus {
+    pub active_channels: u32,
+    pub max_active_channels: u32,
 }
 
 #[derive(Debug, Serialize)]
@@ -74,6 +582,123 @@ pub struct ChannelDetailResponse {
     #[serde(flatten)]
     pub status: ChannelStatus,
     pub clients: Vec<ClientInfo>,
+    /// MPEG-TS health of the data read from upstream (None when idle)
+    pub ts_health: Option<TsHealth>,
+}
+
+/// Transport stream health counters since the channel started
+#[derive(Debug, Serialize, Clone)]
+pub struct TsHealth {
+    pub packets: u64,
+    /// Times the stream lost TS packet sync
+    pub sync_losses: u64,
+    /// Packets whose continuity counter skipped, outside a flagged discontinuity
+    pub continuity_errors: u64,
+    /// Packets with the transport_scrambling_control bits set
+    pub scrambled_packets: u64,
+    /// Seconds since a PAT was seen (None if never)
+    pub seconds_since_pat: Option<f64>,
+    /// Seconds since a PMT was seen (None if never)
+    pub seconds_since_pmt: Option<f64>,
+    /// Per packet id
+    pub pids: BTreeMap<u16, PidStats>,
+}
+
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct PidStats {
+    pub packets: u64,
+    pub continuity_errors: u64,
+    pub scrambled_packets: u64,
+}
+
+/// What an active channel's upstream task has received since it started
+#[derive(Debug, Serialize)]
+pub struct UpstreamStatsResponse {
+    pub channel_id: String,
+    pub started_at: String,
+    /// Reads from upstream connections
+    pub chunks_received: u64,
+    pub bytes_received: u64,
+    pub average_chunk_bytes: u64,
+    /// Pauses of over a second between reads
+    pub gaps_over_1s: u64,
+    pub longest_gap_ms: u64,
+    pub reconnects: u64,
+    /// Bytes from each source used, in the order they were first used
+    pub sources: Vec<SourceBytes>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub struct SourceBytes {
+    pub stream_id: u64,
+    pub account_id: u64,
+    pub url: String,
+    pub bytes: u64,
+    /// The source currently being read
+    pub current: bool,
+}
+
+/// Health of one configured stream URL
+#[derive(Debug, Serialize)]
+pub struct StreamUrlStatus {
+    pub channel_id: String,
+    pub stream_id: u64,
+    pub account_id: u64,
+    pub url: String,
+    pub premium: bool,
+    /// One of the variants used once the channel's quota is exceeded
+    pub quota: bool,
+    /// The channel's upstream is currently pulling from this URL
+    pub in_use: bool,
+    /// "ok", "failing" (last connect failed) or "untested"
+    pub probe_status: String,
+    pub attempts: u64,
+    pub connect_failures: u64,
+    pub drops: u64,
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<String>,
+    pub last_success_at: Option<String>,
+    /// Selection avoids this URL until then after a recent failure
+    pub cooldown_until: Option<String>,
+    pub score: Option<u32>,
+    /// Connect time of the last successful connect
+    pub connect_latency_ms: Option<u64>,
+    /// Average bitrate over the last connection long enough to measure
+    pub bitrate_bps: Option<u64>,
+    /// Quality score a channel last achieved streaming from this URL
+    pub quality_score: Option<u32>,
+    /// Certificate of an HTTPS URL, from its last successful connect
+    pub certificate: Option<CertificateStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CertificateStatus {
+    pub subject: String,
+    pub issuer: String,
+    pub expires_at: String,
+    pub days_remaining: i64,
+    /// Expires within `TLS_EXPIRY_WARN_DAYS` (or already has)
+    pub expiring: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StreamsResponse {
+    pub streams: Vec<StreamUrlStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WarmupStatus {
+    pub in_progress: bool,
+    pub total: usize,
+    pub started: usize,
+    pub pending: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ReadyResponse {
+    pub ready: bool,
+    pub warmup: WarmupStatus,
 }
 
 #[derive(Debug, Serialize)]
@@ -82,4 +707,137 @@ pub struct HealthResponse {
     pub uptime_seconds: u64,
     pub active_channels: usize,
     pub total_clients: u32,
+    pub routed_channels: usize,
+    /// Routed channels currently held in hibernated form
+    pub hibernated_channels: usize,
+}
+
+// --- Metrics models ---
+
+#[derive(Debug, Serialize)]
+pub struct RuntimeMetrics {
+    pub workers: usize,
+    pub alive_tasks: usize,
+    pub global_queue_depth: usize,
+    pub worker_utilization: Vec<f64>,
+    pub mean_worker_utilization: f64,
+    pub scheduler_delay_ms: f64,
+    pub scheduler_delay_max_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelTaskHealth {
+    pub task_running: bool,
+    pub idle_seconds: f64,
+    pub clients: usize,
+    pub broadcast_queue_depth: usize,
+    /// Upstream reading is paused waiting for clients to drain the queue
+    pub backpressure_paused: bool,
+    pub backpressure_pauses: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MetricsResponse {
+    pub runtime: RuntimeMetrics,
+    pub channels: HashMap<String, ChannelTaskHealth>,
+}
+
+// --- Debug models ---
+
+#[derive(Debug, Serialize)]
+pub struct DebugRouting {
+    pub streams: Vec<StreamConfig>,
+    pub enabled: bool,
+    pub premium_streams: Vec<StreamConfig>,
+    pub premium_threshold: u32,
+    pub persistent: bool,
+    pub priority: i32,
+    pub vod: bool,
+    pub auth_callback: Option<String>,
+    pub high_watermark: Option<usize>,
+    pub low_watermark: Option<usize>,
+    pub daily_quota_bytes: Option<u64>,
+    pub monthly_quota_bytes: Option<u64>,
+    pub quota_streams: Vec<StreamConfig>,
+    pub hls_passthrough: bool,
+    pub hls_encryption: bool,
+    pub subtitles: Option<SubtitleConfig>,
+    pub audio_language_priority: Vec<String>,
+    pub tags: Vec<String>,
+    pub group: Option<String>,
+    pub max_clients_per_ip: Option<usize>,
+    pub max_lag_events: Option<u64>,
+    pub max_lag_bytes: Option<u64>,
+    pub keepalive_after_ms: Option<u64>,
+    pub failover: FailoverPolicy,
+    pub multicast_group: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DebugActiveChannel {
+    pub stream_id: u64,
+    pub account_id: u64,
+    pub url: String,
+    pub premium: bool,
+    pub persistent: bool,
+    pub connected_since: String,
+    pub bytes_transferred: u64,
+    pub clients: usize,
+    pub broadcast_receivers: usize,
+    /// Chunks queued in the broadcast buffer that the slowest receiver hasn't read
+    pub broadcast_queue_depth: usize,
+    pub high_watermark: usize,
+    pub low_watermark: usize,
+    pub backpressure_paused: bool,
+    pub backpressure_pauses: u64,
+    pub gop_cache_chunks: usize,
+    pub keyframes_seen: bool,
+    pub stop_signalled: bool,
+    pub task_running: bool,
+    /// Seconds since the upstream last delivered data
+    pub idle_seconds: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DebugAccount {
+    pub max_connections: u32,
+    pub active_connections: u32,
+    pub peak_connections: u32,
+    pub capacity_warning: bool,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DebugStateResponse {
+    pub uptime_seconds: u64,
+    pub routes: HashMap<String, DebugRouting>,
+    pub active_channels: HashMap<String, DebugActiveChannel>,
+    pub accounts: HashMap<String, DebugAccount>,
+    pub vod_sessions: HashMap<String, u64>,
+    pub start_permits_available: usize,
+    pub warmup: WarmupStatus,
+    pub auth_cache_entries: usize,
+}
+
+// --- Chaos models ---
+
+#[derive(Debug, Deserialize)]
+pub struct FaultConfig {
+    /// Drop the channel's upstream connection this many seconds from now
+    #[serde(default)]
+    pub drop_in_secs: Option<u64>,
+    /// Percentage (0-100) of upstream reads to fail
+    #[serde(default)]
+    pub read_error_percent: u8,
+    /// Delay added to every upstream read
+    #[serde(default)]
+    pub chunk_delay_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FaultStatus {
+    /// Seconds until the pending drop fires (None if none is pending)
+    pub drop_in_secs: Option<f64>,
+    pub read_error_percent: u8,
+    pub chunk_delay_ms: u64,
 }
@@ -0,0 +1,253 @@
+use bytes::Bytes;
+use futures_util::Stream;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// Large enough for any UDP datagram
+const DATAGRAM_BUFFER: usize = 65536;
+const TS_SYNC: u8 = 0x47;
+
+pub type ByteStream = Pin<Box<dyn Stream<Item = Result<Bytes, String>> + Send>>;
+
+/// Whether a stream URL is a UDP/RTP input rather than an HTTP one
+pub fn is_multicast_url(url: &str) -> bool {
+    url.starts_with("udp://") || url.starts_with("rtp://")
+}
+
+/// The input URL of a gateway channel's group, given either as a
+/// `udp://`/`rtp://` URL or as a bare `group:port` (received as plain UDP)
+pub fn gateway_url(group: &str) -> String {
+    if is_multicast_url(group) {
+        group.to_string()
+    } else {
+        format!("udp://@{}", group)
+    }
+}
+
+/// Check that a UDP/RTP URL names a group address and port
+pub fn validate_url(url: &str) -> Result<(), String> {
+    parse_url(url).map(drop)
+}
+
+/// The group address and port a UDP/RTP URL receives on
+pub fn group_address(url: &str) -> Option<SocketAddr> {
+    parse_url(url).ok().map(|(addr, _)| addr)
+}
+
+/// A bound (and, for group addresses, joined) UDP input that has already
+/// received its first datagram.
+pub struct MulticastSource {
+    socket: UdpSocket,
+    first: Bytes,
+    timeout: Duration,
+}
+
+/// Bind to a `udp://[@]group:port` or `rtp://[@]group:port` URL, joining the
+/// group if the address is multicast (on the interface given by `?iface=`,
+/// or the default one), and wait up to `timeout` for the feed to start.
+pub async fn open(url: &str, timeout: Duration) -> Result<MulticastSource, String> {
+    let (addr, iface) = parse_url(url)?;
+    let socket = bind(addr, iface).map_err(|e| format!("bind error: {}", e))?;
+
+    let mut buf = vec![0u8; DATAGRAM_BUFFER];
+    let first = match tokio::time::timeout(timeout, next_payload(&socket, &mut buf)).await {
+        Ok(result) => result?,
+        Err(_) => return Err(format!("no data within {:?}", timeout)),
+    };
+    Ok(MulticastSource {
+        socket,
+        first,
+        timeout,
+    })
+}
+
+impl MulticastSource {
+    /// TS payloads of the received datagrams; errors once the feed goes
+    /// quiet, so the channel fails over just like on an HTTP read error
+    pub fn into_stream(self) -> ByteStream {
+        let MulticastSource {
+            socket,
+            first,
+            timeout,
+        } = self;
+        Box::pin(async_stream::stream! {
+            yield Ok(first);
+            let mut buf = vec![0u8; DATAGRAM_BUFFER];
+            loop {
+                match tokio::time::timeout(timeout, next_payload(&socket, &mut buf)).await {
+                    Ok(Ok(payload)) => yield Ok(payload),
+                    Ok(Err(e)) => {
+                        yield Err(e);
+                        break;
+                    }
+                    Err(_) => {
+                        yield Err(format!("no data for {:?}", timeout));
+                        break;
+                    }
+                }
+            }
+        })
+    }
+}
+
+fn parse_url(url: &str) -> Result<(SocketAddr, Option<Ipv4Addr>), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("invalid URL: {}", e))?;
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| "missing group address".to_string())?;
+    let ip: IpAddr = host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse()
+        .map_err(|_| format!("invalid group address: {}", host))?;
+    let port = parsed.port().ok_or_else(|| "missing port".to_string())?;
+    let iface = match parsed.query_pairs().find(|(k, _)| k == "iface") {
+        Some((_, v)) => Some(
+            v.parse()
+                .map_err(|_| format!("invalid interface address: {}", v))?,
+        ),
+        None => None,
+    };
+    Ok((SocketAddr::new(ip, port), iface))
+}
+
+fn bind(addr: SocketAddr, iface: Option<Ipv4Addr>) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    // Several channels may listen on the same port with different groups
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    match addr.ip() {
+        IpAddr::V4(group) if group.is_multicast() => {
+            socket.join_multicast_v4(&group, &iface.unwrap_or(Ipv4Addr::UNSPECIFIED))?;
+        }
+        IpAddr::V6(group) if group.is_multicast() => socket.join_multicast_v6(&group, 0)?,
+        _ => {}
+    }
+    UdpSocket::from_std(socket.into())
+}
+
+/// Receive datagrams until one carries TS packets, returning just those
+async fn next_payload(socket: &UdpSocket, buf: &mut [u8]) -> Result<Bytes, String> {
+    loop {
+        let len = socket
+            .recv(buf)
+            .await
+            .map_err(|e| format!("receive error: {}", e))?;
+        if let Some(payload) = ts_payload(&buf[..len]) {
+            return Ok(Bytes::copy_from_slice(payload));
+        }
+    }
+}
+
+/// The whole TS packets in a datagram, with any RTP header (and padding)
+/// stripped. Returns None for datagrams with nothing usable.
+fn ts_payload(datagram: &[u8]) -> Option<&[u8]> {
+    let payload = if datagram.first() == Some(&TS_SYNC) {
+        datagram
+    } else {
+        rtp_payload(datagram)?
+    };
+    let whole = payload.len() / 188 * 188;
+    (whole > 0 && payload[0] == TS_SYNC).then(|| &payload[..whole])
+}
+
+/// Payload of an RTP (version 2) packet, per RFC 3550
+fn rtp_payload(packet: &[u8]) -> Option<&[u8]> {
+    if packet.len() < 12 || packet[0] >> 6 != 2 {
+        return None;
+    }
+    let csrc_count = (packet[0] & 0x0F) as usize;
+    let mut start = 12 + 4 * csrc_count;
+    if packet[0] & 0x10 != 0 {
+        // Header extension: 16-bit profile, 16-bit length in 32-bit words
+        let ext = packet.get(start..start + 4)?;
+        start += 4 + 4 * u16::from_be_bytes([ext[2], ext[3]]) as usize;
+    }
+    let mut end = packet.len();
+    if packet[0] & 0x20 != 0 {
+        end = end.checked_sub(*packet.last()? as usize)?;
+    }
+    packet.get(start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(packets: usize) -> Vec<u8> {
+        let mut data = vec![0xFF; 188 * packets];
+        for packet in data.chunks_mut(188) {
+            packet[0] = TS_SYNC;
+        }
+        data
+    }
+
+    fn rtp(first: u8, extra: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![first, 33, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1];
+        packet.extend_from_slice(extra);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn parses_group_port_and_interface() {
+        assert_eq!(
+            parse_url("udp://@239.1.1.1:1234?iface=10.0.0.5").unwrap(),
+            (
+                "239.1.1.1:1234".parse().unwrap(),
+                Some(Ipv4Addr::new(10, 0, 0, 5))
+            )
+        );
+        assert_eq!(
+            group_address("rtp://[ff15::1]:5004"),
+            Some("[ff15::1]:5004".parse().unwrap())
+        );
+        assert!(parse_url("udp://@239.1.1.1").is_err());
+        assert!(parse_url("udp://@example.com:1234").is_err());
+        assert!(parse_url("udp://@239.1.1.1:1234?iface=eth0").is_err());
+        assert_eq!(gateway_url("239.1.1.1:1234"), "udp://@239.1.1.1:1234");
+        assert_eq!(
+            gateway_url("rtp://@239.1.1.1:5004"),
+            "rtp://@239.1.1.1:5004"
+        );
+    }
+
+    #[test]
+    fn raw_udp_keeps_whole_packets_only() {
+        let mut datagram = ts(7);
+        datagram.extend_from_slice(&[0; 10]);
+        assert_eq!(ts_payload(&datagram), Some(&datagram[..188 * 7]));
+        assert_eq!(ts_payload(&datagram[..100]), None);
+    }
+
+    #[test]
+    fn strips_rtp_header_csrcs_extension_and_padding() {
+        let payload = ts(2);
+        let plain = rtp(0x80, &[], &payload);
+        assert_eq!(ts_payload(&plain), Some(&payload[..]));
+
+        // Two CSRCs, a one-word header extension and four bytes of padding
+        let mut extra = vec![0; 8];
+        extra.extend_from_slice(&[0xBE, 0xDE, 0, 1, 1, 2, 3, 4]);
+        let mut padded = rtp(0x80 | 0x20 | 0x10 | 2, &extra, &payload);
+        padded.extend_from_slice(&[0, 0, 0, 4]);
+        assert_eq!(rtp_payload(&padded), Some(&payload[..]));
+        assert_eq!(ts_payload(&padded), Some(&payload[..]));
+    }
+
+    #[test]
+    fn rejects_malformed_rtp() {
+        // Wrong version, truncated header, truncated extension, oversized padding
+        assert_eq!(rtp_payload(&rtp(0x40, &[], &ts(1))), None);
+        assert_eq!(rtp_payload(&[0x80; 8]), None);
+        assert_eq!(rtp_payload(&rtp(0x90, &[0xBE, 0xDE], &[])), None);
+        let mut padded = rtp(0xA0, &[], &[]);
+        padded.push(200);
+        assert_eq!(rtp_payload(&padded), None);
+        // RTP carrying something other than TS
+        assert_eq!(ts_payload(&rtp(0x80, &[], &[0; 188])), None);
+    }
+}
@@ -0,0 +1,69 @@
+use crate::control;
+use crate::models::Snapshot;
+use crate::state::AppState;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+/// Quiet period after a change before writing, so a burst of control calls
+/// results in one write
+const WRITE_DELAY: Duration = Duration::from_millis(200);
+
+/// Restore routing and accounts from the state file, so the proxy can serve
+/// streams before the backend's first sync. A missing file is a fresh start;
+/// an unreadable or invalid one is logged and ignored.
+pub fn load(state: &AppState, path: &str) {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+        Err(e) => {
+            tracing::warn!("State file {}: {}", path, e);
+            return;
+        }
+    };
+    let snapshot: Snapshot = match serde_json::from_slice(&data) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            tracing::warn!("State file {}: {}", path, e);
+            return;
+        }
+    };
+    let errors = control::validate_snapshot(&snapshot);
+    if !errors.is_empty() {
+        tracing::warn!("State file {} ignored: {}", path, errors.join("; "));
+        return;
+    }
+    control::replace_routing(state, "State file", snapshot.channels, snapshot.accounts);
+}
+
+/// Spawn the task that writes the state file after every routing change.
+pub fn spawn_writer(state: Arc<AppState>, path: String) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            state.routing_changed.notified().await;
+            tokio::time::sleep(WRITE_DELAY).await;
+            if let Err(e) = write(&state, &path).await {
+                tracing::warn!("Failed to write state file {}: {}", path, e);
+            }
+        }
+    })
+}
+
+/// Write the current routing state, replacing the file atomically so a
+/// crash mid-write leaves the previous state intact. The file holds account
+/// credentials, so only the proxy's user may read it.
+pub async fn write(state: &AppState, path: &str) -> std::io::Result<()> {
+    let data = serde_json::to_vec_pretty(&control::snapshot(state)).expect("snapshot serializes");
+    let tmp = format!("{}.tmp", path);
+    // A leftover temp file would keep its old permissions
+    let _ = tokio::fs::remove_file(&tmp).await;
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp).await?;
+    file.write_all(&data).await?;
+    file.sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&tmp, path).await
+}
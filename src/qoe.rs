@@ -0,0 +1,157 @@
+use crate::models::{QoeStatus, QualityScore};
+use crate::state::{ActiveChannel, AppState, PlaybackReport};
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+/// Samples (one per second) a score is computed over
+const WINDOW: usize = 60;
+/// Samples needed before a channel gets a score
+const MIN_SAMPLES: usize = 5;
+/// Heartbeats older than this no longer count towards a channel's QoE
+const HEARTBEAT_MAX_AGE: Duration = Duration::from_secs(60);
+/// Client buffer at which playback counts as fully healthy
+const TARGET_BUFFER_SECS: f64 = 5.0;
+
+// Weights of the score components; playback's share is spread over the
+// others when no client sends heartbeats
+const STABILITY_WEIGHT: f64 = 0.35;
+const STEADINESS_WEIGHT: f64 = 0.25;
+const DELIVERY_WEIGHT: f64 = 0.2;
+const PLAYBACK_WEIGHT: f64 = 0.2;
+
+/// Cumulative channel counters at one sample tick
+#[derive(Clone, Copy)]
+struct Totals {
+    bytes: u64,
+    reconnects: u64,
+    lag_drops: u64,
+}
+
+/// Rolling samples behind a channel's quality score
+#[derive(Default)]
+pub struct QualityWindow {
+    totals: VecDeque<Totals>,
+    pub score: Option<QualityScore>,
+}
+
+/// Spawn the task that samples every active channel once a second and
+/// updates its quality score.
+pub fn spawn_scorer(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            for active in state.active_channels.iter() {
+                let score = sample(&active);
+                if let Some(score) = score {
+                    let url = active.current_upstream().url;
+                    state.record_url_quality(&url, score.score);
+                }
+            }
+        }
+    })
+}
+
+/// Take a sample and rescore the channel
+fn sample(active: &ActiveChannel) -> Option<QualityScore> {
+    let totals = Totals {
+        bytes: active.bytes_transferred.load(Ordering::Relaxed),
+        reconnects: active.counters.reconnects.load(Ordering::Relaxed),
+        lag_drops: active.counters.lag_drops.load(Ordering::Relaxed),
+    };
+    let playback = playback_summary(active);
+    let mut window = active.quality.lock().unwrap();
+    if window.totals.len() > WINDOW {
+        window.totals.pop_front();
+    }
+    window.totals.push_back(totals);
+    window.score = score(&window.totals, playback.as_ref());
+    window.score.clone()
+}
+
+/// Score the sampled window from 0 (unwatchable) to 100. Components, each
+/// 0.0-1.0: stability (upstream reconnects), steadiness (variation of the
+/// per-second bitrate), delivery (chunks clients missed by lagging) and
+/// playback (client buffer from heartbeats, when present).
+fn score(totals: &VecDeque<Totals>, playback: Option<&QoeStatus>) -> Option<QualityScore> {
+    if totals.len() <= MIN_SAMPLES {
+        return None;
+    }
+    let first = totals.front()?;
+    let last = totals.back()?;
+    let reconnects = last.reconnects.saturating_sub(first.reconnects);
+    let lag_drops = last.lag_drops.saturating_sub(first.lag_drops);
+
+    let rates: Vec<f64> = totals
+        .iter()
+        .zip(totals.iter().skip(1))
+        .map(|(a, b)| b.bytes.saturating_sub(a.bytes) as f64)
+        .collect();
+    let steadiness = steadiness(&rates);
+    let stability = 1.0 / (1.0 + reconnects as f64);
+    let delivery = 1.0 / (1.0 + lag_drops as f64 / 10.0);
+    let playback = playback
+        .and_then(|p| p.avg_buffer_seconds)
+        .map(|buffer| (buffer / TARGET_BUFFER_SECS).clamp(0.0, 1.0));
+
+    let mut weighted =
+        stability * STABILITY_WEIGHT + steadiness * STEADINESS_WEIGHT + delivery * DELIVERY_WEIGHT;
+    let mut total_weight = STABILITY_WEIGHT + STEADINESS_WEIGHT + DELIVERY_WEIGHT;
+    if let Some(playback) = playback {
+        weighted += playback * PLAYBACK_WEIGHT;
+        total_weight += PLAYBACK_WEIGHT;
+    }
+    let round = |v: f64| (v * 100.0).round() / 100.0;
+    Some(QualityScore {
+        score: (weighted / total_weight * 100.0).round() as u32,
+        stability: round(stability),
+        steadiness: round(steadiness),
+        delivery: round(delivery),
+        playback: playback.map(round),
+    })
+}
+
+/// 1.0 minus the coefficient of variation of `rates` (0.0 if nothing flowed)
+fn steadiness(rates: &[f64]) -> f64 {
+    let mean = rates.iter().sum::<f64>() / rates.len().max(1) as f64;
+    if mean <= 0.0 {
+        return 0.0;
+    }
+    let variance = rates.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / rates.len() as f64;
+    1.0 - (variance.sqrt() / mean).min(1.0)
+}
+
+/// Score from 0 to 100 for a short background sample of a source that no
+/// channel is streaming from: the steadiness of its per-second byte `rates`,
+/// and its transport stream integrity from `stream_errors` (sync losses and
+/// continuity errors), weighted equally.
+pub fn sample_score(rates: &[f64], stream_errors: u64) -> u32 {
+    let integrity = 1.0 / (1.0 + stream_errors as f64 / 10.0);
+    ((steadiness(rates) + integrity) / 2.0 * 100.0).round() as u32
+}
+
+/// Player-reported quality across the channel's clients with a recent heartbeat
+pub fn playback_summary(active: &ActiveChannel) -> Option<QoeStatus> {
+    let reports: Vec<PlaybackReport> = active
+        .clients
+        .iter()
+        .filter_map(|c| c.playback.lock().unwrap().clone())
+        .filter(|p| p.at.elapsed() < HEARTBEAT_MAX_AGE)
+        .collect();
+    if reports.is_empty() {
+        return None;
+    }
+    let buffers: Vec<f64> = reports.iter().filter_map(|p| p.buffer_seconds).collect();
+    Some(QoeStatus {
+        reporting_clients: reports.len(),
+        avg_buffer_seconds: (!buffers.is_empty())
+            .then(|| buffers.iter().sum::<f64>() / buffers.len() as f64),
+        min_buffer_seconds: buffers.iter().copied().reduce(f64::min),
+        dropped_frames: reports.iter().filter_map(|p| p.dropped_frames).sum(),
+    })
+}
@@ -0,0 +1,63 @@
+use crate::state::AppState;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Spawn the task that reconciles account connection counters with actual usage.
+///
+/// Counters can drift (e.g. when a task dies without running its cleanup, or
+/// a stop races with a failover). A discrepancy is only
+/// corrected once it has been seen on two consecutive passes, so counts that
+/// are mid-update during a failover aren't "fixed" by mistake.
+pub fn spawn_reaper(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(state.config.reconcile_interval);
+        let mut previous: HashMap<u64, (u32, u32)> = HashMap::new();
+        loop {
+            interval.tick().await;
+            previous = reconcile(&state, &previous);
+        }
+    })
+}
+
+/// Returns the discrepancies seen on this pass as (counted, actual) per account.
+fn reconcile(state: &AppState, previous: &HashMap<u64, (u32, u32)>) -> HashMap<u64, (u32, u32)> {
+    let mut usage: HashMap<u64, u32> = HashMap::new();
+    for entry in state.active_channels.iter() {
+        *usage
+            .entry(entry.current_upstream().account_id)
+            .or_default() += 1;
+    }
+    for entry in state.vod_sessions.iter() {
+        *usage.entry(*entry.value()).or_default() += 1;
+    }
+
+    let mut drift = HashMap::new();
+    for entry in state.accounts.load().iter() {
+        let account_id = *entry.key();
+        let counted = entry.active_connections.load(Ordering::Relaxed);
+        let actual = usage.get(&account_id).copied().unwrap_or(0);
+        if counted == actual {
+            continue;
+        }
+
+        if previous.get(&account_id) == Some(&(counted, actual)) {
+            tracing::warn!(
+                "Account {}: connection counter drifted ({} counted, {} in use), correcting",
+                account_id,
+                counted,
+                actual
+            );
+            // Only correct if nothing changed underneath us
+            let _ = entry.active_connections.compare_exchange(
+                counted,
+                actual,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        } else {
+            drift.insert(account_id, (counted, actual));
+        }
+    }
+    drift
+}
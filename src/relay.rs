@@ -0,0 +1,367 @@
+use crate::multicast::ByteStream;
+use crate::state::{ActiveChannel, AppState, ChannelCounters, ClientState, RELAY_CLIENT_PREFIX};
+use crate::{auth, stream, upstream};
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, Notify};
+use tokio::time::Instant;
+
+const MAGIC: &[u8; 4] = b"DPR1";
+const FRAME_DATA: u8 = 0;
+const FRAME_HEARTBEAT: u8 = 1;
+const FRAME_ERROR: u8 = 2;
+/// Payload length, type and sequence number
+const HEADER_LEN: usize = 13;
+/// Larger frames mean a corrupt or foreign stream
+const MAX_FRAME: usize = 16 * 1024 * 1024;
+/// A quiet channel sends a heartbeat this often
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// The puller gives up on a connection with no frames for this long
+const PEER_TIMEOUT: Duration = Duration::from_secs(3);
+/// A connecting peer must send its hello within this long
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether a stream URL is another proxy's relay listener
+pub fn is_relay_url(url: &str) -> bool {
+    url.starts_with("relay://")
+}
+
+/// Accept relay connections on `listener`, serving each on its own task.
+///
+/// A peer pulls one channel per TCP connection. It opens with a hello —
+/// `DPR1`, then the channel id and the token, each as a big-endian u16
+/// length and the bytes — and is answered with frames of
+/// `u32 payload length | u8 type | u64 sequence | payload`. Data frames
+/// carry broadcast chunks numbered from 1; chunks skipped because the
+/// connection fell behind keep their numbers, so the peer sees the gap.
+/// Heartbeats carry the last number used and are sent while the channel is
+/// quiet, so a dead peer is noticed in seconds rather than at the TCP
+/// timeout. An error frame carries the reason a hello was refused.
+pub fn spawn_listener(state: Arc<AppState>, listener: TcpListener) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let (socket, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("Relay accept failed: {}", e);
+                    continue;
+                }
+            };
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(state, socket, addr).await {
+                    tracing::debug!("Relay peer {}: {}", addr, e);
+                }
+            });
+        }
+    })
+}
+
+/// Serve one peer: read its hello, then send the channel's broadcast until
+/// either side goes away
+async fn serve(
+    state: Arc<AppState>,
+    mut socket: TcpStream,
+    addr: SocketAddr,
+) -> Result<(), String> {
+    let _ = socket.set_nodelay(true);
+    let (channel_id, token) = tokio::time::timeout(HELLO_TIMEOUT, read_hello(&mut socket))
+        .await
+        .map_err(|_| "no hello".to_string())??;
+
+    // The listener only starts with a token configured
+    let expected = state.config.relay_token.as_deref().unwrap_or_default();
+    let refusal = if !auth::tokens_match(&token, expected) {
+        Some("invalid relay token")
+    } else {
+        match state.channel_routes.load().get(&channel_id) {
+            None => Some("unknown channel"),
+            Some(r) if !r.enabled => Some("channel is off-air"),
+            Some(r) if r.vod || r.hls_passthrough => Some("channel can't be relayed"),
+            Some(_) => None,
+        }
+    };
+    if let Some(reason) = refusal {
+        write_frame(&mut socket, FRAME_ERROR, 0, reason.as_bytes()).await?;
+        return Err(format!("refused channel {}: {}", channel_id, reason));
+    }
+    let Some(active) = upstream::get_or_start_channel(&state, &channel_id, None) else {
+        write_frame(&mut socket, FRAME_ERROR, 0, b"no stream available").await?;
+        return Err(format!("no stream available for channel {}", channel_id));
+    };
+
+    let client_id = format!("{}{}", RELAY_CLIENT_PREFIX, uuid::Uuid::new_v4());
+    let (rx, join_chunks) = {
+        let gop = active.gop_cache.lock().unwrap();
+        let join_chunks = active.join_chunks(&gop, state.config.join_buffer_max_age);
+        (active.sender.subscribe(), join_chunks)
+    };
+    let kick = Arc::new(Notify::new());
+    active.clients.insert(
+        client_id.clone(),
+        ClientState {
+            id: client_id.clone(),
+            conn_id: 0,
+            connected_since: Instant::now(),
+            bytes_sent: AtomicU64::new(0),
+            remote_addr: addr.to_string(),
+            label: Some("relay".to_string()),
+            audio_only: false,
+            kick: kick.clone(),
+            lag_events: AtomicU64::new(0),
+            lag_bytes: AtomicU64::new(0),
+            lagging: AtomicBool::new(false),
+            queue_depth: Default::default(),
+            send_rate: Default::default(),
+            bandwidth: Default::default(),
+            playback: Mutex::new(None),
+            session_secret: None,
+        },
+    );
+    tracing::info!("Channel {}: relaying to {}", channel_id, addr);
+
+    let result = send_broadcast(&mut socket, &active, &client_id, join_chunks, rx, &kick).await;
+
+    active.clients.remove(&client_id);
+    tracing::info!("Channel {}: relay to {} ended", channel_id, addr);
+    if active.clients.is_empty() {
+        stream::stop_if_idle(&active, state.config.idle_grace);
+    }
+    result
+}
+
+async fn send_broadcast(
+    socket: &mut TcpStream,
+    active: &ActiveChannel,
+    client_id: &str,
+    join_chunks: Vec<crate::state::Chunk>,
+    mut rx: broadcast::Receiver<crate::state::Chunk>,
+    kick: &Notify,
+) -> Result<(), String> {
+    let mut sequence = 0u64;
+    let sent = |len: usize| {
+        if let Some(client) = active.clients.get(client_id) {
+            client.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+        }
+    };
+    for chunk in join_chunks {
+        sequence += 1;
+        write_frame(socket, FRAME_DATA, sequence, &chunk.data).await?;
+        sent(chunk.data.len());
+    }
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    heartbeat.reset();
+    // The channel's sender outlives its upstream task, so a stopped channel
+    // shows as its stop signal rather than a closed broadcast
+    let mut stopped = active.stop_tx.subscribe();
+    loop {
+        tokio::select! {
+            _ = kick.notified() => return Ok(()),
+            _ = async { stopped.wait_for(|stop| *stop).await.map(|_| ()) } => break,
+            _ = heartbeat.tick() => {
+                write_frame(socket, FRAME_HEARTBEAT, sequence, &[]).await?;
+            }
+            result = rx.recv() => match result {
+                Ok(chunk) => {
+                    sequence += 1;
+                    write_frame(socket, FRAME_DATA, sequence, &chunk.data).await?;
+                    sent(chunk.data.len());
+                    heartbeat.reset();
+                }
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    // The skipped chunks keep their numbers so the peer sees the loss
+                    sequence += n;
+                    if let Some(client) = active.clients.get(client_id) {
+                        client.lag_events.fetch_add(1, Ordering::Relaxed);
+                    }
+                    tracing::warn!("Channel {}: relay lagged {} chunks", active.channel_id, n);
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+    write_frame(socket, FRAME_ERROR, sequence, b"channel stopped").await?;
+    Err(format!("channel {} stopped", active.channel_id))
+}
+
+/// A relay connection whose hello was accepted
+pub struct RelaySource {
+    socket: TcpStream,
+    url: String,
+    /// A data frame that arrived while waiting for the answer to the hello
+    first: Option<(u64, Bytes)>,
+    /// Sequence number of the last frame
+    sequence: u64,
+}
+
+/// Connect to a `relay://host:port/channel_id[?token=...]` URL, send the
+/// hello and wait up to `timeout` (0 = indefinitely) for the first frame
+pub async fn open(url: &str, timeout: Duration) -> Result<RelaySource, String> {
+    let (addr, channel_id, token) = parse_url(url)?;
+    let connect = async {
+        let mut socket = TcpStream::connect(&addr).await.map_err(|e| e.to_string())?;
+        let _ = socket.set_nodelay(true);
+        let mut hello = Vec::with_capacity(8 + channel_id.len() + token.len());
+        hello.extend_from_slice(MAGIC);
+        for field in [&channel_id, &token] {
+            hello.extend_from_slice(&(field.len() as u16).to_be_bytes());
+            hello.extend_from_slice(field.as_bytes());
+        }
+        socket.write_all(&hello).await.map_err(|e| e.to_string())?;
+        let (kind, sequence, payload) = read_frame(&mut socket).await?;
+        match kind {
+            FRAME_ERROR => Err(format!("refused: {}", String::from_utf8_lossy(&payload))),
+            FRAME_DATA => Ok((socket, Some((sequence, payload)), 0)),
+            _ => Ok((socket, None, sequence)),
+        }
+    };
+    let (socket, first, sequence) = if timeout.is_zero() {
+        connect.await?
+    } else {
+        tokio::time::timeout(timeout, connect)
+            .await
+            .map_err(|_| format!("no answer within {}s", timeout.as_secs()))??
+    };
+    Ok(RelaySource {
+        socket,
+        url: url.to_string(),
+        first,
+        sequence,
+    })
+}
+
+impl RelaySource {
+    /// Payloads of the data frames. Chunks missing from the sequence are
+    /// logged and counted in `counters`; an error frame, the connection
+    /// closing or going silent past the heartbeat ends the stream with an
+    /// error, so the channel reconnects or fails over.
+    pub fn into_stream(self, counters: Arc<ChannelCounters>) -> ByteStream {
+        let RelaySource {
+            mut socket,
+            url,
+            first,
+            mut sequence,
+        } = self;
+        let lost = move |sequence: &mut u64, next: u64, data: bool| {
+            let missing = next.saturating_sub(*sequence + u64::from(data));
+            if missing > 0 {
+                counters
+                    .relay_lost_chunks
+                    .fetch_add(missing, Ordering::Relaxed);
+                tracing::warn!("Relay {}: {} chunks lost before #{}", url, missing, next);
+            }
+            *sequence = next.max(*sequence);
+        };
+        Box::pin(async_stream::stream! {
+            if let Some((next, data)) = first {
+                lost(&mut sequence, next, true);
+                yield Ok(data);
+            }
+            loop {
+                let frame = match tokio::time::timeout(PEER_TIMEOUT, read_frame(&mut socket)).await {
+                    Ok(frame) => frame,
+                    Err(_) => Err(format!("relay silent for {}s", PEER_TIMEOUT.as_secs())),
+                };
+                match frame {
+                    Ok((FRAME_DATA, next, data)) => {
+                        lost(&mut sequence, next, true);
+                        yield Ok(data);
+                    }
+                    Ok((FRAME_ERROR, _, reason)) => {
+                        yield Err(format!("relay error: {}", String::from_utf8_lossy(&reason)));
+                        break;
+                    }
+                    Ok((_, next, _)) => lost(&mut sequence, next, false),
+                    Err(e) => {
+                        yield Err(e);
+                        break;
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Peer address, channel id and token of a relay URL
+fn parse_url(url: &str) -> Result<(String, String, String), String> {
+    let parsed = reqwest::Url::parse(url).map_err(|e| format!("invalid URL: {}", e))?;
+    let host = parsed
+        .host_str()
+        .ok_or_else(|| "missing relay host".to_string())?;
+    let port = parsed.port().ok_or_else(|| "missing port".to_string())?;
+    let channel_id = parsed.path().trim_matches('/');
+    if channel_id.is_empty() {
+        return Err("missing channel id".to_string());
+    }
+    let token = parsed
+        .query_pairs()
+        .find(|(k, _)| k == "token")
+        .map(|(_, v)| v.into_owned())
+        .unwrap_or_default();
+    Ok((format!("{}:{}", host, port), channel_id.to_string(), token))
+}
+
+async fn read_hello(socket: &mut TcpStream) -> Result<(String, String), String> {
+    let mut magic = [0u8; 4];
+    socket
+        .read_exact(&mut magic)
+        .await
+        .map_err(|e| e.to_string())?;
+    if &magic != MAGIC {
+        return Err("not a relay hello".to_string());
+    }
+    let mut fields = Vec::with_capacity(2);
+    for _ in 0..2 {
+        let len = socket.read_u16().await.map_err(|e| e.to_string())?;
+        let mut field = vec![0u8; len as usize];
+        socket
+            .read_exact(&mut field)
+            .await
+            .map_err(|e| e.to_string())?;
+        fields.push(String::from_utf8(field).map_err(|_| "hello is not UTF-8".to_string())?);
+    }
+    let token = fields.pop().unwrap_or_default();
+    let channel_id = fields.pop().unwrap_or_default();
+    Ok((channel_id, token))
+}
+
+async fn write_frame(
+    socket: &mut TcpStream,
+    kind: u8,
+    sequence: u64,
+    payload: &[u8],
+) -> Result<(), String> {
+    let mut header = [0u8; HEADER_LEN];
+    header[..4].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+    header[4] = kind;
+    header[5..].copy_from_slice(&sequence.to_be_bytes());
+    socket.write_all(&header).await.map_err(|e| e.to_string())?;
+    socket.write_all(payload).await.map_err(|e| e.to_string())
+}
+
+async fn read_frame(socket: &mut TcpStream) -> Result<(u8, u64, Bytes), String> {
+    let mut header = [0u8; HEADER_LEN];
+    match socket.read_exact(&mut header).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            return Err("relay closed the connection".to_string())
+        }
+        Err(e) => return Err(e.to_string()),
+    }
+    let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+    if len > MAX_FRAME {
+        return Err(format!("frame of {} bytes", len));
+    }
+    let sequence = u64::from_be_bytes(header[5..].try_into().unwrap());
+    let mut payload = vec![0u8; len];
+    socket
+        .read_exact(&mut payload)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok((header[4], sequence, Bytes::from(payload)))
+}
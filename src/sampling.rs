@@ -0,0 +1,179 @@
+use crate::multicast;
+use crate::qoe;
+use crate::relay;
+use crate::session;
+use crate::state::AppState;
+use crate::ts::{self, TS_PACKET_SIZE};
+use crate::ts_analyzer::TsAnalyzer;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Longest a sample may take to connect
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An alternate source to sample, and the account whose slot it uses
+struct Candidate {
+    channel_id: String,
+    account_id: u64,
+    url: String,
+}
+
+/// What a sample read
+struct Sample {
+    latency: Duration,
+    bytes: u64,
+    elapsed: Duration,
+    /// Bytes received in each second of the sample
+    rates: Vec<f64>,
+    /// Sync losses and continuity errors
+    stream_errors: u64,
+}
+
+/// Spawn the background task that keeps source rankings current: each
+/// interval it reads one alternate (not currently streaming) source of an
+/// active channel for a few seconds and records its latency, throughput and
+/// quality score, as a channel streaming from it would.
+pub fn spawn_source_sampler(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let client = state
+            .config
+            .upstream_client_builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()
+            .expect("failed to build source sampling client");
+        let mut sampled_at: HashMap<String, Instant> = HashMap::new();
+        let mut interval = tokio::time::interval(state.config.source_sample_interval);
+        loop {
+            interval.tick().await;
+            let Some(candidate) = next_candidate(&state, &sampled_at) else {
+                continue;
+            };
+            sampled_at.insert(candidate.url.clone(), Instant::now());
+            // Samples take an account connection like any other
+            if !state.try_acquire_connection(candidate.account_id) {
+                continue;
+            }
+            if !state.claim_cluster_slot(candidate.account_id).await {
+                state.decrement_connections(candidate.account_id);
+                continue;
+            }
+            let result = sample(&state, &client, &candidate).await;
+            state.decrement_connections(candidate.account_id);
+            // A full interval between samples, however long this one took
+            interval.reset();
+            match result {
+                Ok(sample) => {
+                    let score = qoe::sample_score(&sample.rates, sample.stream_errors);
+                    tracing::debug!(
+                        "Channel {}: sampled {}, score {}",
+                        candidate.channel_id,
+                        candidate.url,
+                        score
+                    );
+                    state.record_url_success(&candidate.url, sample.latency);
+                    state.record_url_throughput(&candidate.url, sample.bytes, sample.elapsed);
+                    state.record_url_quality(&candidate.url, score);
+                }
+                Err(e) => state
+                    .record_url_connect_failure(&candidate.url, &format!("source sample: {}", e)),
+            }
+        }
+    })
+}
+
+/// The alternate HTTP source of any active channel sampled longest ago
+/// (never-sampled first), skipping sources cooling down after a failure and
+/// those on unavailable accounts
+fn next_candidate(state: &AppState, sampled_at: &HashMap<String, Instant>) -> Option<Candidate> {
+    let routes = state.channel_routes.load();
+    let streaming: HashSet<String> = state
+        .active_channels
+        .iter()
+        .map(|a| a.current_upstream().url)
+        .collect();
+    let mut candidates = Vec::new();
+    for active in state.active_channels.iter() {
+        let Some(routing) = routes.get(&active.channel_id) else {
+            continue;
+        };
+        let current = active.current_upstream();
+        let over_quota = state.quota_exceeded(&active.channel_id).is_some();
+        for (_, source) in routing.ordered_sources(current.premium, over_quota) {
+            if streaming.contains(&source.url)
+                || multicast::is_multicast_url(&source.url)
+                || relay::is_relay_url(&source.url)
+                || state.url_cooling_down(&source.url)
+                || !state.account_available(source.account_id)
+            {
+                continue;
+            }
+            candidates.push(Candidate {
+                channel_id: active.channel_id.clone(),
+                account_id: source.account_id,
+                url: source.url.clone(),
+            });
+        }
+    }
+    candidates
+        .into_iter()
+        .min_by_key(|c| sampled_at.get(&c.url).copied())
+}
+
+/// Read the candidate's URL for the configured sample duration
+async fn sample(
+    state: &AppState,
+    client: &reqwest::Client,
+    candidate: &Candidate,
+) -> Result<Sample, String> {
+    let url = candidate.url.as_str();
+    state.pace_host_connect(url).await;
+    let started = Instant::now();
+    let request = session::get(
+        state,
+        client,
+        &candidate.channel_id,
+        candidate.account_id,
+        url,
+    )
+    .await?;
+    let mut response = session::send(state, request).await?;
+    if !response.status().is_success() {
+        return Err(format!("HTTP {}", response.status()));
+    }
+    let latency = started.elapsed();
+
+    let duration = state.config.source_sample_duration;
+    let reading_since = Instant::now();
+    let deadline = reading_since + duration;
+    let mut aligner = ts::PacketAligner::default();
+    let mut analyzer = TsAnalyzer::default();
+    let mut rates = vec![0.0; duration.as_secs_f64().ceil().max(1.0) as usize];
+    let mut bytes = 0u64;
+    loop {
+        let data = match tokio::time::timeout_at(deadline, response.chunk()).await {
+            Err(_) => break,
+            Ok(Ok(Some(data))) => data,
+            Ok(Ok(None)) => return Err("stream ended during sample".to_string()),
+            Ok(Err(e)) => return Err(e.to_string()),
+        };
+        if let Some(rate) = rates.get_mut(reading_since.elapsed().as_secs() as usize) {
+            *rate += data.len() as f64;
+        }
+        bytes += data.len() as u64;
+        let data = aligner.align(&data);
+        analyzer.inspect(&data, aligner.take_sync_losses());
+    }
+    if bytes < TS_PACKET_SIZE as u64 {
+        return Err("no data during sample".to_string());
+    }
+    let health = analyzer.health();
+    Ok(Sample {
+        latency,
+        bytes,
+        elapsed: duration,
+        rates,
+        stream_errors: health.sync_losses + health.continuity_errors,
+    })
+}
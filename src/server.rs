@@ -0,0 +1,562 @@
+use crate::config::{Config, RouteGroup};
+use crate::state::AppState;
+use crate::{
+    auth, balancer, bitrate, capacity, chaos, cluster, control, events, failback, forwarded, grpc,
+    hibernate, hls, hls_keys, hls_output, metrics, persist, qoe, reaper, relay, sampling, status,
+    stream, sync_pull, tls, ui, warmup, webhooks, REQUEST_ID_HEADER,
+};
+use axum::serve::ListenerExt;
+use axum::{middleware, routing::get, Router};
+use futures_util::future::{BoxFuture, FutureExt};
+use std::future::IntoFuture;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::ServerConfig;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::TraceLayer;
+
+/// Builder for an embeddable proxy instance.
+#[derive(Default)]
+pub struct ProxyServerBuilder {
+    config: Option<Config>,
+    state: Option<Arc<AppState>>,
+    routes: Option<Router<Arc<AppState>>>,
+    upstream_runtime: Option<tokio::runtime::Handle>,
+}
+
+impl ProxyServerBuilder {
+    /// Configuration to use (defaults to `Config::default()`, not the environment)
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Use an existing state instead of creating one from the config
+    pub fn state(mut self, state: Arc<AppState>) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Extra routes merged into every listener
+    pub fn routes(mut self, routes: Router<Arc<AppState>>) -> Self {
+        self.routes = Some(routes);
+        self
+    }
+
+    /// Run upstream reader tasks on this runtime (ignored if `state` is given)
+    pub fn upstream_runtime(mut self, handle: tokio::runtime::Handle) -> Self {
+        self.upstream_runtime = Some(handle);
+        self
+    }
+
+    pub fn build(self) -> ProxyServer {
+        let state = self.state.unwrap_or_else(|| {
+            let mut state = AppState::new(self.config.unwrap_or_default());
+            if let Some(handle) = self.upstream_runtime {
+                state = state.with_upstream_runtime(handle);
+            }
+            if let Some(path) = &state.config.state_file {
+                persist::load(&state, path);
+            }
+            Arc::new(state)
+        });
+        ProxyServer {
+            state,
+            extra_routes: self.routes,
+        }
+    }
+}
+
+/// The proxy: shared state plus the HTTP routes that serve it.
+pub struct ProxyServer {
+    state: Arc<AppState>,
+    extra_routes: Option<Router<Arc<AppState>>>,
+}
+
+/// A proxy serving on its configured listeners; dropping it stops the
+/// listeners, background tasks and channels' upstream tasks.
+pub struct RunningServer {
+    state: Arc<AppState>,
+    addrs: Vec<SocketAddr>,
+    relay_addr: Option<SocketAddr>,
+    grpc_addr: Option<SocketAddr>,
+    /// Control socket path this server bound, removed on drop
+    control_socket: Option<String>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl ProxyServer {
+    pub fn builder() -> ProxyServerBuilder {
+        ProxyServerBuilder::default()
+    }
+
+    pub fn state(&self) -> &Arc<AppState> {
+        &self.state
+    }
+
+    /// Router for the given route groups with state applied, for embedding
+    /// into another axum application. Serve it with connect info
+    /// (`into_make_service_with_connect_info::<SocketAddr>`).
+    pub fn router(&self, groups: &[RouteGroup]) -> Router {
+        let mut app = router(&self.state, groups);
+        if let Some(extra) = &self.extra_routes {
+            app = app.merge(extra.clone());
+        }
+        with_request_ids(app).with_state(self.state.clone())
+    }
+
+    /// Spawn the background tasks (capacity monitor, warm-up, reconciliation,
+    /// runtime sampler, quality scorer, bitrate sampler, optional balancer,
+    /// fail-back, source sampler, routing hibernator, webhook notifier,
+    /// metrics pusher, state file writer, sync puller and cluster heartbeat)
+    /// without binding any listener.
+    pub fn spawn_background_tasks(&self) -> Vec<JoinHandle<()>> {
+        let mut tasks = vec![
+            capacity::spawn_monitor(self.state.clone()),
+            warmup::spawn_warmup(self.state.clone()),
+            reaper::spawn_reaper(self.state.clone()),
+            metrics::spawn_sampler(self.state.clone()),
+            qoe::spawn_scorer(self.state.clone()),
+            bitrate::spawn_sampler(self.state.clone()),
+        ];
+        if !self.state.config.rebalance_interval.is_zero() {
+            tasks.push(balancer::spawn_balancer(self.state.clone()));
+        }
+        if !self.state.config.failback_interval.is_zero() {
+            tasks.push(failback::spawn_failback(self.state.clone()));
+        }
+        if !self.state.config.source_sample_interval.is_zero() {
+            tasks.push(sampling::spawn_source_sampler(self.state.clone()));
+        }
+        if !self.state.config.routing_hibernate_after.is_zero() {
+            tasks.push(hibernate::spawn_hibernator(self.state.clone()));
+        }
+        if let Some(url) = &self.state.config.webhook_url {
+            tasks.push(webhooks::spawn_notifier(self.state.clone(), url.clone()));
+        }
+        if let Some(url) = &self.state.config.metrics_push_url {
+            tasks.push(metrics::spawn_pusher(self.state.clone(), url.clone()));
+        }
+        if let Some(path) = &self.state.config.state_file {
+            tasks.push(persist::spawn_writer(self.state.clone(), path.clone()));
+        }
+        if let Some(url) = &self.state.config.sync_url {
+            if !self.state.config.sync_interval.is_zero() {
+                tasks.push(sync_pull::spawn_puller(self.state.clone(), url.clone()));
+            }
+        }
+        if let Some(cluster) = &self.state.cluster {
+            tasks.push(cluster::spawn_heartbeat(
+                self.state.clone(),
+                cluster.clone(),
+            ));
+        }
+        tasks
+    }
+
+    /// Bind every configured listener and start serving in the background.
+    /// With a sync URL configured, routing is pulled from it first, so the
+    /// first clients aren't turned away while waiting for a pushed sync.
+    pub async fn start(self) -> std::io::Result<RunningServer> {
+        if let Some(url) = &self.state.config.sync_url {
+            match sync_pull::pull(&self.state, &reqwest::Client::new(), url).await {
+                Ok(()) => tracing::info!("Initial sync pulled from {}", url),
+                Err(e) => tracing::warn!("Initial sync pull from {} failed: {}", url, e),
+            }
+        }
+        // Dropped on an error below, stopping whatever was started so far
+        let mut running = RunningServer {
+            state: self.state.clone(),
+            addrs: Vec::new(),
+            relay_addr: None,
+            grpc_addr: None,
+            control_socket: None,
+            tasks: Vec::new(),
+        };
+
+        let config = &self.state.config;
+        let serves_control = config
+            .listeners
+            .iter()
+            .any(|l| l.groups.contains(&RouteGroup::Control));
+        if serves_control && config.control_token.is_none() && config.control_hmac_secret.is_none()
+        {
+            tracing::warn!(
+                "Control API is unauthenticated; set CONTROL_TOKEN or CONTROL_HMAC_SECRET"
+            );
+        }
+        for listener in &config.listeners {
+            let groups = &listener.groups;
+            if groups.contains(&RouteGroup::Control)
+                && groups.contains(&RouteGroup::Stream)
+                && !listener.addr.ip().is_loopback()
+            {
+                tracing::warn!(
+                    "Control API is served on the public stream listener {}; use LISTENERS to \
+                     bind control on a separate address",
+                    listener.addr
+                );
+            }
+        }
+
+        // One TLS config per distinct default certificate, shared by the
+        // listeners serving it; tenant certificates are resolved by SNI in each
+        let mut tls_configs: Vec<(_, Arc<ServerConfig>)> = Vec::new();
+        let mut listener_tls = Vec::with_capacity(config.listeners.len());
+        for listener in &config.listeners {
+            if !listener.tls {
+                listener_tls.push(None);
+                continue;
+            }
+            let default = match (&listener.cert_file, &listener.key_file) {
+                (Some(cert_file), Some(key_file)) => Some((cert_file.as_str(), key_file.as_str())),
+                _ => match (&config.tls_cert_file, &config.tls_key_file) {
+                    (Some(cert_file), Some(key_file)) => {
+                        Some((cert_file.as_str(), key_file.as_str()))
+                    }
+                    _ if config.tenants.iter().any(|t| t.cert_file.is_some()) => None,
+                    _ => {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!(
+                                "TLS listener {} needs a cert and key, or TLS_CERT_FILE and \
+                                 TLS_KEY_FILE",
+                                listener.addr
+                            ),
+                        ))
+                    }
+                },
+            };
+            if let Some((_, tls_config)) = tls_configs.iter().find(|(d, _)| *d == default) {
+                listener_tls.push(Some(tls_config.clone()));
+                continue;
+            }
+            let resolver = tls::SniResolver::load(default, &config.tenants)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+            if !config.tls_reload_interval.is_zero() {
+                let interval = config.tls_reload_interval;
+                for store in resolver.stores() {
+                    running
+                        .tasks
+                        .push(tls::spawn_reloader(store.clone(), interval));
+                }
+            }
+            let tls_config = tls::server_config(resolver);
+            tls_configs.push((default, tls_config.clone()));
+            listener_tls.push(Some(tls_config));
+        }
+
+        for (listener, tls_config) in config.listeners.iter().zip(listener_tls) {
+            let app = self.router(&listener.groups);
+            let tcp = tokio::net::TcpListener::bind(listener.addr).await?;
+            let addr = tcp.local_addr()?;
+            let scheme = if listener.tls { "https" } else { "http" };
+            tracing::info!(
+                "Rust proxy listening on {}://{} ({:?})",
+                scheme,
+                addr,
+                listener.groups
+            );
+            running.addrs.push(addr);
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            let serve: BoxFuture<'static, std::io::Result<()>> = match tls_config {
+                Some(tls_config) => {
+                    // TapIo provides the peer address as connect info
+                    let tls = tls::TlsListener::new(tcp, tls_config)?.tap_io(|_| {});
+                    axum::serve(tls, app).into_future().boxed()
+                }
+                _ => axum::serve(tcp, app).into_future().boxed(),
+            };
+            running.tasks.push(tokio::spawn(async move {
+                if let Err(e) = serve.await {
+                    tracing::error!("Listener {} failed: {}", addr, e);
+                }
+            }));
+        }
+
+        match config.relay_listen {
+            Some(_) if config.relay_token.is_none() => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "RELAY_LISTEN requires RELAY_TOKEN",
+                ));
+            }
+            Some(addr) => {
+                let tcp = tokio::net::TcpListener::bind(addr).await?;
+                let addr = tcp.local_addr()?;
+                tracing::info!("Relay listening on {}", addr);
+                running
+                    .tasks
+                    .push(relay::spawn_listener(self.state.clone(), tcp));
+                running.relay_addr = Some(addr);
+            }
+            None => {}
+        }
+        if let Some(path) = &config.control_socket {
+            running.tasks.push(self.serve_control_socket(path)?);
+            running.control_socket = Some(path.clone());
+        }
+        if let Some(addr) = config.grpc_listen {
+            let tcp = tokio::net::TcpListener::bind(addr).await?;
+            let addr = tcp.local_addr()?;
+            tracing::info!("gRPC control service listening on {}", addr);
+            running
+                .tasks
+                .push(grpc::spawn_server(self.state.clone(), tcp));
+            running.grpc_addr = Some(addr);
+        }
+
+        // Only once everything is bound, so a failed start leaves none behind
+        running.tasks.extend(self.spawn_background_tasks());
+        Ok(running)
+    }
+
+    /// Serve the control API on a unix socket at `path` (replacing a socket
+    /// left behind by a previous run), created with `control_socket_mode`
+    #[cfg(unix)]
+    fn serve_control_socket(&self, path: &str) -> std::io::Result<JoinHandle<()>> {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+        match std::fs::symlink_metadata(path) {
+            Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("control socket {} exists and is not a socket", path),
+                ))
+            }
+            Err(_) => {}
+        }
+        let listener = tokio::net::UnixListener::bind(path)?;
+        let mode = self.state.config.control_socket_mode;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        tracing::info!("Control API listening on unix:{} (mode {:o})", path, mode);
+        let app = self
+            .router(&[RouteGroup::Control])
+            .layer(axum::Extension(auth::LocalSocket));
+        let path = path.to_string();
+        Ok(tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, app).await {
+                tracing::error!("Control socket {} failed: {}", path, e);
+            }
+        }))
+    }
+
+    #[cfg(not(unix))]
+    fn serve_control_socket(&self, _path: &str) -> std::io::Result<JoinHandle<()>> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "CONTROL_SOCKET needs a unix platform",
+        ))
+    }
+
+    /// Bind every configured listener and serve until they exit or the
+    /// process is asked to stop (Ctrl-C / SIGTERM), saving the state file
+    /// on the way out.
+    pub async fn run(self) -> std::io::Result<()> {
+        let mut running = self.start().await?;
+        let tasks = futures_util::future::join_all(running.tasks.drain(..));
+        tokio::select! {
+            _ = tasks => {}
+            _ = shutdown_signal() => tracing::info!("Shutting down"),
+        }
+        if let Some(path) = &running.state.config.state_file {
+            if let Err(e) = persist::write(&running.state, path).await {
+                tracing::warn!("Failed to write state file {}: {}", path, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RunningServer {
+    pub fn state(&self) -> &Arc<AppState> {
+        &self.state
+    }
+
+    /// Bound addresses, in listener config order (useful with port 0)
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Bound relay listener address, if `relay_listen` is set
+    pub fn relay_addr(&self) -> Option<SocketAddr> {
+        self.relay_addr
+    }
+
+    /// Bound gRPC control service address, if `grpc_listen` is set
+    pub fn grpc_addr(&self) -> Option<SocketAddr> {
+        self.grpc_addr
+    }
+}
+
+impl Drop for RunningServer {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+        self.state.abort_upstream_tasks();
+        if let Some(path) = &self.control_socket {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+/// Assign an X-Request-Id to every request (keeping one supplied by the
+/// caller), run the request in a span carrying it, and echo it in the response.
+fn with_request_ids(app: Router<Arc<AppState>>) -> Router<Arc<AppState>> {
+    let header = axum::http::HeaderName::from_static(REQUEST_ID_HEADER);
+    // Layers wrap in reverse: the last one added runs first
+    app.layer(PropagateRequestIdLayer::new(header.clone()))
+        .layer(
+            TraceLayer::new_for_http().make_span_with(|req: &axum::http::Request<_>| {
+                let request_id = req
+                    .headers()
+                    .get(REQUEST_ID_HEADER)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("-");
+                tracing::info_span!(
+                    "request",
+                    id = %request_id,
+                    method = %req.method(),
+                    uri = %req.uri()
+                )
+            }),
+        )
+        .layer(SetRequestIdLayer::new(header, MakeRequestUuid))
+}
+
+/// Build the routes for a listener from its route groups
+fn router(state: &Arc<AppState>, groups: &[RouteGroup]) -> Router<Arc<AppState>> {
+    let mut app = Router::new();
+    for group in groups {
+        app = match group {
+            RouteGroup::Control => app.merge(
+                Router::new()
+                    .route(
+                        "/control/v1/channels/{channel_id}",
+                        axum::routing::put(control::put_channel),
+                    )
+                    .route(
+                        "/control/v1/channels/{channel_id}",
+                        axum::routing::delete(control::delete_channel),
+                    )
+                    .route(
+                        "/control/v1/accounts/{account_id}",
+                        axum::routing::put(control::put_account),
+                    )
+                    .route(
+                        "/control/v1/groups/{group}",
+                        axum::routing::put(control::put_group).delete(control::delete_group),
+                    )
+                    .route(
+                        "/control/v1/channels/{channel_id}/clients/{client_id}",
+                        axum::routing::delete(control::kick_client),
+                    )
+                    .route(
+                        "/control/v1/channels/{channel_id}/failover",
+                        axum::routing::post(control::force_failover),
+                    )
+                    .route(
+                        "/control/v1/channels/{channel_id}/restart",
+                        axum::routing::post(control::restart_channel),
+                    )
+                    .route(
+                        "/control/v1/channels/{channel_id}/stop",
+                        axum::routing::post(control::stop_active_channel),
+                    )
+                    .route(
+                        "/control/v1/channels/{channel_id}/reset_stats",
+                        axum::routing::post(control::reset_stats),
+                    )
+                    .route(
+                        "/control/v1/channels/{channel_id}/switch_account",
+                        axum::routing::post(control::switch_account),
+                    )
+                    .route("/control/v1/sync", axum::routing::post(control::sync))
+                    .route("/control/v1/export", get(control::export))
+                    .route("/control/v1/restore", axum::routing::post(control::restore))
+                    .route(
+                        "/control/v1/tags/{tag}/{action}",
+                        axum::routing::post(control::tag_action),
+                    )
+                    .route("/control/v1/chaos", get(chaos::list_faults))
+                    .route(
+                        "/control/v1/chaos/{channel_id}",
+                        axum::routing::put(chaos::put_faults).delete(chaos::delete_faults),
+                    )
+                    .route_layer(middleware::from_fn_with_state(
+                        state.clone(),
+                        auth::require_control,
+                    )),
+            ),
+            RouteGroup::Stream => app
+                .route("/stream/{channel_id}", get(stream::stream_channel))
+                .route("/udp/{group}", get(stream::udpxy))
+                .route("/rtp/{group}", get(stream::udpxy))
+                .route(
+                    "/stream/v1/sessions/{client_id}/heartbeat",
+                    axum::routing::post(stream::heartbeat),
+                )
+                .route(
+                    "/stream/{channel_id}/index.m3u8",
+                    get(hls_output::serve_playlist),
+                )
+                .route(
+                    "/stream/{channel_id}/segment/{sequence}",
+                    get(hls_output::serve_segment),
+                )
+                .route("/hls/{channel_id}/{resource_id}", get(hls::serve_resource))
+                .route("/hls/{channel_id}/key/{key_id}", get(hls_keys::serve_key)),
+            RouteGroup::Status => app
+                .route("/status/v1/channels", get(status::channels_status))
+                .route(
+                    "/status/v1/channels/{channel_id}",
+                    get(status::channel_detail),
+                )
+                .route(
+                    "/status/v1/channels/{channel_id}/upstream_stats",
+                    get(status::upstream_stats),
+                )
+                .route(
+                    "/status/v1/channels/{channel_id}/watch",
+                    get(status::watch_channel),
+                )
+                .route("/status/v1/streams", get(status::streams_status))
+                .route("/status/v1/health", get(status::health))
+                .route("/status/v1/ready", get(status::ready))
+                .route("/status/v1/debug/state", get(status::debug_state))
+                .route("/status/v1/events/export", get(events::export))
+                .route("/ui", get(ui::dashboard)),
+            RouteGroup::Metrics => app
+                .route("/status/v1/metrics", get(status::metrics))
+                .route("/metrics", get(metrics::prometheus)),
+        };
+    }
+    app.layer(middleware::from_fn_with_state(
+        state.clone(),
+        forwarded::resolve_client_addr,
+    ))
+}
@@ -0,0 +1,249 @@
+use crate::hls;
+use crate::models::SessionConfig;
+use crate::state::AppState;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Method, RequestBuilder, Response};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Timeout for a login request
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A token obtained by logging in to an account
+pub struct Session {
+    /// The login settings the token was obtained with
+    config: SessionConfig,
+    token: String,
+    acquired: Instant,
+    /// The account's `session_generation` when the token was obtained
+    generation: u64,
+}
+
+/// A GET for one of `channel_id`'s sources on `account_id`, carrying the
+/// headers configured for it and sent through the account's outbound proxy
+/// (`client` is used for accounts without one). For accounts with a session
+/// login, the current token (logging in first if there is none, it has
+/// expired or the login settings changed) is substituted into the URL
+/// (percent-encoded) and stream headers.
+pub async fn get(
+    state: &AppState,
+    client: &Client,
+    channel_id: &str,
+    account_id: u64,
+    url: &str,
+) -> Result<RequestBuilder, String> {
+    let headers = state.upstream_headers(channel_id, account_id, url);
+    let Some(account) = state.accounts.load().get(&account_id).map(|a| a.clone()) else {
+        return Ok(client.get(url).headers(headers));
+    };
+    let proxied = account.proxy.lock().unwrap().as_mut().map(|p| {
+        p.client(&state.config)
+            .map_err(|e| format!("account {} proxy {}: {}", account_id, p.url, e))
+    });
+    let client = &match proxied {
+        Some(proxied) => proxied?,
+        None => client.clone(),
+    };
+    let Some(config) = account.session_config.lock().unwrap().clone() else {
+        return Ok(client.get(url).headers(headers));
+    };
+
+    // Held across the login so concurrent starts on the account share one
+    let mut session = account.session.lock().await;
+    let generation = account.session_generation.load(Ordering::Relaxed);
+    let fresh = session.as_ref().is_some_and(|s| {
+        s.config == config
+            && s.generation == generation
+            && s.acquired.elapsed() < Duration::from_secs(config.refresh_secs)
+    });
+    if !fresh {
+        let token = login(client, &config)
+            .await
+            .map_err(|e| format!("account {} login failed: {}", account_id, e))?;
+        tracing::info!("Account {}: session established", account_id);
+        *session = Some(Session {
+            config: config.clone(),
+            token,
+            acquired: Instant::now(),
+            // Read after the login: a rejection during it was of an older token
+            generation: account.session_generation.load(Ordering::Relaxed),
+        });
+    }
+    let token = &session.as_ref().expect("session set above").token;
+
+    let url = url.replace("{token}", &hls::urlencode(token));
+    let mut request = client.get(url).headers(headers);
+    for (name, value) in &config.stream_headers {
+        let value = HeaderValue::from_str(&value.replace("{token}", token))
+            .map_err(|_| format!("invalid value for stream header {}", name))?;
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid stream header {:?}", name))?;
+        // Replaces a configured header of the same name
+        let mut session_header = HeaderMap::new();
+        session_header.insert(name, value);
+        request = request.headers(session_header);
+    }
+    Ok(request)
+}
+
+/// Send a request to a provider, giving up if the response headers don't
+/// arrive within the configured response timeout
+pub async fn send(state: &AppState, request: RequestBuilder) -> Result<Response, String> {
+    let timeout = state.config.upstream_response_timeout;
+    if timeout.is_zero() {
+        return request.send().await.map_err(|e| e.to_string());
+    }
+    match tokio::time::timeout(timeout, request.send()).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(_) => Err(format!("no response within {}s", timeout.as_secs())),
+    }
+}
+
+/// Retire an account's token, e.g. after the provider rejected it, so the
+/// next request logs in again. Doesn't wait for a request holding the
+/// session; that request sees the newer generation.
+pub fn invalidate(state: &AppState, account_id: u64) {
+    let Some(account) = state.accounts.load().get(&account_id).map(|a| a.clone()) else {
+        return;
+    };
+    account.session_generation.fetch_add(1, Ordering::Relaxed);
+    tracing::info!(
+        "Account {}: session rejected, will log in again",
+        account_id
+    );
+}
+
+async fn login(client: &Client, config: &SessionConfig) -> Result<String, String> {
+    let username = config.username.as_deref().unwrap_or_default();
+    let password = config.password.as_deref().unwrap_or_default();
+    let fill = |template: &str| {
+        template
+            .replace("{username}", username)
+            .replace("{password}", password)
+    };
+    // Credentials go into the URL percent-encoded
+    let login_url = config
+        .login_url
+        .replace("{username}", &hls::urlencode(username))
+        .replace("{password}", &hls::urlencode(password));
+    let method = Method::from_bytes(config.method.to_uppercase().as_bytes())
+        .map_err(|_| format!("invalid method {:?}", config.method))?;
+    let mut request = client.request(method, login_url).timeout(LOGIN_TIMEOUT);
+    for (name, value) in &config.headers {
+        request = request.header(name, fill(value));
+    }
+    if let Some(body) = &config.body {
+        request = request.body(fill(body));
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("HTTP {}", response.status()));
+    }
+    let body = response.text().await.map_err(|e| e.to_string())?;
+    extract_token(config, &body)
+}
+
+/// Pull the token out of a login response body
+fn extract_token(config: &SessionConfig, body: &str) -> Result<String, String> {
+    let mut token = body.trim().to_string();
+    if let Some(path) = &config.token_path {
+        let json: serde_json::Value =
+            serde_json::from_str(body).map_err(|e| format!("response is not JSON: {}", e))?;
+        token = match json_path(&json, path) {
+            Some(serde_json::Value::String(s)) => s.clone(),
+            Some(serde_json::Value::Number(n)) => n.to_string(),
+            _ => return Err(format!("no token at {}", path)),
+        };
+    }
+    if let Some(pattern) = &config.token_regex {
+        let regex =
+            regex::Regex::new(pattern).map_err(|e| format!("invalid token_regex: {}", e))?;
+        let captures = regex
+            .captures(&token)
+            .ok_or_else(|| format!("token_regex {:?} did not match", pattern))?;
+        token = captures
+            .get(1)
+            .or_else(|| captures.get(0))
+            .map(|m| m.as_str().to_string())
+            .unwrap_or_default();
+    }
+    if token.is_empty() {
+        return Err("empty token".to_string());
+    }
+    Ok(token)
+}
+
+/// Resolve a simple JSONPath: `$` followed by `.field` and `[index]` steps
+fn json_path<'a>(value: &'a serde_json::Value, path: &str) -> Option<&'a serde_json::Value> {
+    let mut rest = path.strip_prefix('$').unwrap_or(path);
+    let mut current = value;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('[') {
+            let (index, tail) = after.split_once(']')?;
+            current = current.get(index.trim().parse::<usize>().ok()?)?;
+            rest = tail;
+        } else {
+            let after = rest.strip_prefix('.').unwrap_or(rest);
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            current = current.get(&after[..end])?;
+            rest = &after[end..];
+        }
+    }
+    Some(current)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(extra: serde_json::Value) -> SessionConfig {
+        let mut config = json!({ "login_url": "http://provider/login" });
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn json_path_follows_fields_and_indexes() {
+        let value = json!({ "data": { "tokens": [{ "value": "a" }, { "value": "b" }] } });
+        assert_eq!(
+            json_path(&value, "$.data.tokens[1].value"),
+            Some(&json!("b"))
+        );
+        assert_eq!(
+            json_path(&value, "data.tokens[ 0 ]"),
+            Some(&json!({ "value": "a" }))
+        );
+        assert_eq!(json_path(&value, "$"), Some(&value));
+
+        assert_eq!(json_path(&value, "$.data.missing"), None);
+        assert_eq!(json_path(&value, "$.data.tokens[2]"), None);
+        assert_eq!(json_path(&value, "$.data.tokens[x]"), None);
+        assert_eq!(json_path(&value, "$.data.tokens[0"), None);
+    }
+
+    #[test]
+    fn token_is_extracted_by_path_then_regex() {
+        let plain = config(json!({}));
+        assert_eq!(extract_token(&plain, " abc123\n"), Ok("abc123".to_string()));
+        assert!(extract_token(&plain, "  ").is_err());
+
+        let by_path = config(json!({ "token_path": "$.session.id" }));
+        let numeric = r#"{"session":{"id":42}}"#;
+        assert_eq!(extract_token(&by_path, numeric), Ok("42".to_string()));
+        assert!(extract_token(&by_path, r#"{"session":{}}"#).is_err());
+        assert!(extract_token(&by_path, "not json").is_err());
+
+        let both = config(json!({
+            "token_path": "$.auth",
+            "token_regex": "token=([0-9a-f]+)",
+        }));
+        let body = r#"{"auth":"Bearer token=00ff; expires=60"}"#;
+        assert_eq!(extract_token(&both, body), Ok("00ff".to_string()));
+        assert!(extract_token(&both, r#"{"auth":"denied"}"#).is_err());
+    }
+}
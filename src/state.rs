@@ -1,122 +1,1729 @@
+use crate::bitrate::{RateMeter, ThroughputEstimate};
+use crate::certs::CertificateInfo;
+use crate::chaos::ChannelFaults;
+use crate::cluster::Cluster;
+use crate::config::Config;
+use crate::events::EventLog;
+use crate::geo::GeoLookup;
+use crate::hls_output::HlsOutput;
+use crate::metrics::RuntimeSnapshot;
 use crate::models::*;
+use crate::multicast;
+use crate::qoe::QualityWindow;
+use crate::session::Session;
+use crate::ts::{PsiCache, SourceMarker};
+use crate::ts_analyzer::TsAnalyzer;
+use arc_swap::ArcSwap;
+use chrono::Datelike;
+use dashmap::mapref::one::{Ref, RefMut};
 use dashmap::DashMap;
-use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
-use std::sync::Arc;
-use tokio::sync::broadcast;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, Notify, OwnedSemaphorePermit, Semaphore};
 use tokio::time::Instant;
 
 /// Per-account connection tracking
 pub struct AccountState {
     pub max_connections: AtomicU32,
     pub active_connections: AtomicU32,
+    /// Connections other proxy instances of the cluster hold on the account,
+    /// as of the last cluster heartbeat
+    pub remote_connections: AtomicU32,
+    /// Slots this instance holds in the cluster-wide count
+    pub cluster_slots: AtomicU32,
+    /// Highest active_connections seen since the account was registered
+    pub peak_connections: AtomicU32,
+    /// When utilization first crossed the warning threshold (None while below it)
+    pub near_capacity_since: Mutex<Option<Instant>>,
+    /// Set once the account has stayed near capacity for the configured duration
+    pub capacity_warning: AtomicBool,
+    /// Cleared for maintenance: no new selections use this account
+    pub enabled: AtomicBool,
+    /// While disabled, channels on this account migrate to other accounts
+    pub migrate_active: AtomicBool,
+    /// Upstream failures on this account that made a channel fail over
+    pub failovers: AtomicU64,
+    /// Times of recent upstream failures, oldest first (the retry budget)
+    pub recent_failures: Mutex<VecDeque<Instant>>,
+    /// While set and in the future, no channel retries this account
+    pub retry_suspended_until: Mutex<Option<Instant>>,
+    /// Login the provider requires before streaming
+    pub session_config: Mutex<Option<SessionConfig>>,
+    /// Token from the last login (locked for the duration of a login)
+    pub session: tokio::sync::Mutex<Option<Session>>,
+    /// Bumped when the provider rejects the account's token; a session from
+    /// before the bump is not used again
+    pub session_generation: AtomicU64,
+    /// Headers sent with every upstream request on the account
+    pub headers: Mutex<HashMap<String, String>>,
+    /// Outbound proxy for the account's upstream requests
+    pub proxy: Mutex<Option<AccountProxy>>,
 }
 
+/// An account's outbound proxy and the HTTP client that goes through it
+pub struct AccountProxy {
+    pub url: String,
+    /// Built on first use with the upstream client settings. Error if the
+    /// proxy URL can't be used; requests then fail rather than going out
+    /// directly.
+    client: Option<Result<reqwest::Client, String>>,
+}
+
+impl AccountProxy {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+            client: None,
+        }
+    }
+
+    pub fn client(&mut self, config: &Config) -> Result<reqwest::Client, String> {
+        let url = &self.url;
+        self.client
+            .get_or_insert_with(|| {
+                reqwest::Proxy::all(url)
+                    .and_then(|proxy| config.upstream_client_builder().proxy(proxy).build())
+                    .map_err(|e| e.to_string())
+            })
+            .clone()
+    }
+}
+
+impl AccountState {
+    pub fn new(max_connections: u32) -> Self {
+        Self {
+            max_connections: AtomicU32::new(max_connections),
+            active_connections: AtomicU32::new(0),
+            remote_connections: AtomicU32::new(0),
+            cluster_slots: AtomicU32::new(0),
+            peak_connections: AtomicU32::new(0),
+            near_capacity_since: Mutex::new(None),
+            capacity_warning: AtomicBool::new(false),
+            enabled: AtomicBool::new(true),
+            migrate_active: AtomicBool::new(false),
+            failovers: AtomicU64::new(0),
+            recent_failures: Mutex::new(VecDeque::new()),
+            retry_suspended_until: Mutex::new(None),
+            session_config: Mutex::new(None),
+            session: tokio::sync::Mutex::new(None),
+            session_generation: AtomicU64::new(0),
+            headers: Mutex::new(HashMap::new()),
+            proxy: Mutex::new(None),
+        }
+    }
+
+    /// Whether the retry budget is spent (provider-wide outage suspected)
+    pub fn retry_suspended(&self) -> bool {
+        self.retry_suspended_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    pub fn from_config(config: &AccountConfig) -> Self {
+        let account = Self::new(config.max_connections);
+        account.apply(config);
+        account
+    }
+
+    /// Update limits and flags from a control push, keeping live counters
+    pub fn apply(&self, config: &AccountConfig) {
+        self.max_connections
+            .store(config.max_connections, Ordering::Relaxed);
+        self.enabled.store(config.enabled, Ordering::Relaxed);
+        self.migrate_active
+            .store(config.migrate_active, Ordering::Relaxed);
+        *self.session_config.lock().unwrap() = config.session.clone();
+        *self.headers.lock().unwrap() = config.headers.clone();
+        let mut proxy = self.proxy.lock().unwrap();
+        if proxy.as_ref().map(|p| &p.url) != config.proxy_url.as_ref() {
+            *proxy = config.proxy_url.as_deref().map(AccountProxy::new);
+        }
+    }
+
+    /// Current limits and flags, for snapshot export
+    pub fn config(&self) -> AccountConfig {
+        AccountConfig {
+            max_connections: self.max_connections.load(Ordering::Relaxed),
+            enabled: self.enabled.load(Ordering::Relaxed),
+            migrate_active: self.migrate_active.load(Ordering::Relaxed),
+            session: self.session_config.lock().unwrap().clone(),
+            headers: self.headers.lock().unwrap().clone(),
+            proxy_url: self.proxy.lock().unwrap().as_ref().map(|p| p.url.clone()),
+        }
+    }
+
+    /// Whether channels on this account should move elsewhere
+    pub fn wants_migration(&self) -> bool {
+        !self.enabled.load(Ordering::Relaxed) && self.migrate_active.load(Ordering::Relaxed)
+    }
+
+    /// Current utilization across the cluster as a percentage of
+    /// max_connections (None if unlimited)
+    pub fn utilization_percent(&self) -> Option<u32> {
+        let max = self.max_connections.load(Ordering::Relaxed);
+        if max == 0 {
+            return None;
+        }
+        let current = self.active_connections.load(Ordering::Relaxed)
+            + self.remote_connections.load(Ordering::Relaxed);
+        Some(((current as u64 * 100) / max as u64) as u32)
+    }
+}
+
+/// Shortest connection whose delivered bitrate is recorded for failover ranking
+const MIN_THROUGHPUT_SAMPLE: std::time::Duration = std::time::Duration::from_secs(5);
+/// Selections tried when the picked account's last slot is taken concurrently
+const RESERVE_ATTEMPTS: usize = 3;
+
+/// Client id prefix of a channel's HLS output segmenter
+pub const HLS_OUTPUT_CLIENT_PREFIX: &str = "hls-output-";
+/// Client id prefix of a relay peer
+pub const RELAY_CLIENT_PREFIX: &str = "relay-";
+
 /// Per-client state
 pub struct ClientState {
     pub id: String,
+    /// Identifies the HTTP connection currently serving this client; changes
+    /// when a session is resumed from another connection
+    pub conn_id: u64,
     pub connected_since: Instant,
     pub bytes_sent: AtomicU64,
     pub remote_addr: String,
+    /// Client-chosen label (e.g. "livingroom") to tell sessions apart in status
+    pub label: Option<String>,
+    /// Receives the channel with video stripped
+    pub audio_only: bool,
+    /// Signalled to terminate the serving connection (session taken over or
+    /// client kicked)
+    pub kick: Arc<Notify>,
+    /// Times this client fell behind the broadcast buffer
+    pub lag_events: AtomicU64,
+    /// Data this client missed by falling behind (skipped chunks are
+    /// counted at full chunk size when their exact size is unknown)
+    pub lag_bytes: AtomicU64,
+    /// Client is on the lagging tier (receives only the newest chunks)
+    pub lagging: AtomicBool,
+    /// Chunks waiting to be sent to this client
+    pub queue_depth: Arc<AtomicUsize>,
+    /// Rolling rate of `bytes_sent`
+    pub send_rate: Mutex<RateMeter>,
+    /// How fast the client takes data when it has some queued
+    pub bandwidth: Mutex<ThroughputEstimate>,
+    /// Latest stats from the player's session heartbeat
+    pub playback: Mutex<Option<PlaybackReport>>,
+    /// Issued to the viewer with the stream and needed to resume the session
+    /// or report on it (None for the proxy's own clients). Never shown in status.
+    pub session_secret: Option<String>,
+}
+
+impl ClientState {
+    /// Whether `id` belongs to one of the proxy's own clients, which viewers
+    /// can't use as a session id
+    pub fn is_internal_id(id: &str) -> bool {
+        id.starts_with(HLS_OUTPUT_CLIENT_PREFIX) || id.starts_with(RELAY_CLIENT_PREFIX)
+    }
+}
+
+/// Playback stats a player reported for its session
+#[derive(Clone)]
+pub struct PlaybackReport {
+    pub buffer_seconds: Option<f64>,
+    pub dropped_frames: Option<u64>,
+    pub at: Instant,
+}
+
+/// One open stream connection counted against its client IP; released on drop
+pub struct IpSlot {
+    state: Arc<AppState>,
+    ip: IpAddr,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        self.state
+            .clients_per_ip
+            .remove_if_mut(&self.ip, |_, open| {
+                *open = open.saturating_sub(1);
+                *open == 0
+            });
+    }
 }
 
 /// Routing config for a channel (from Django push)
 pub struct ChannelRouting {
     pub streams: Vec<StreamConfig>,
+    pub enabled: bool,
+    pub persistent: bool,
+    pub priority: i32,
+    pub vod: bool,
+    pub auth_callback: Option<String>,
+    pub premium_streams: Vec<StreamConfig>,
+    pub premium_threshold: u32,
+    pub high_watermark: Option<usize>,
+    pub low_watermark: Option<usize>,
+    pub daily_quota_bytes: Option<u64>,
+    pub monthly_quota_bytes: Option<u64>,
+    pub quota_streams: Vec<StreamConfig>,
+    pub hls_passthrough: bool,
+    pub hls_encryption: bool,
+    pub subtitles: Option<SubtitleConfig>,
+    pub audio_language_priority: Vec<String>,
+    pub tags: Vec<String>,
+    pub group: Option<String>,
+    pub max_clients_per_ip: Option<usize>,
+    pub max_lag_events: Option<u64>,
+    pub max_lag_bytes: Option<u64>,
+    pub keepalive_after_ms: Option<u64>,
+    pub failover: FailoverPolicy,
+    /// Set for multicast gateway channels, whose only stream is this group
+    pub multicast_group: Option<String>,
 }
 
-/// Live state for an active channel (upstream running)
-pub struct ActiveChannel {
+impl ChannelRouting {
+    /// Whether the premium tier should serve `clients` viewers
+    pub fn wants_premium(&self, clients: usize) -> bool {
+        !self.premium_streams.is_empty() && clients > self.premium_threshold as usize
+    }
+
+    pub fn has_quota(&self) -> bool {
+        self.daily_quota_bytes.is_some() || self.monthly_quota_bytes.is_some()
+    }
+
+    /// Which quota the given day/month usage has used up, if any
+    pub fn quota_exceeded(&self, daily_bytes: u64, monthly_bytes: u64) -> Option<&'static str> {
+        if self.daily_quota_bytes.is_some_and(|q| daily_bytes >= q) {
+            Some("daily")
+        } else if self.monthly_quota_bytes.is_some_and(|q| monthly_bytes >= q) {
+            Some("monthly")
+        } else {
+            None
+        }
+    }
+
+    /// Streams to pick from: the reduced variants once over quota, otherwise
+    /// the requested tier
+    pub fn candidate_streams(&self, premium: bool, over_quota: bool) -> &[StreamConfig] {
+        if over_quota {
+            &self.quota_streams
+        } else {
+            self.streams_for(premium)
+        }
+    }
+
+    /// Every (stream, URL) entry that may serve the channel, most preferred
+    /// first: by stream priority, then URL priority, then list order
+    pub fn ordered_sources(
+        &self,
+        premium: bool,
+        over_quota: bool,
+    ) -> Vec<(&StreamConfig, &StreamUrl)> {
+        let mut sources: Vec<_> = self
+            .candidate_streams(premium, over_quota)
+            .iter()
+            .flat_map(|s| s.urls.iter().map(move |u| (s, u)))
+            .collect();
+        sources.sort_by_key(|(s, u)| std::cmp::Reverse((s.priority, u.priority)));
+        sources
+    }
+
+    /// Headers configured on a source URL
+    pub fn url_headers(&self, account_id: u64, url: &str) -> Option<&HashMap<String, String>> {
+        self.streams
+            .iter()
+            .chain(&self.premium_streams)
+            .chain(&self.quota_streams)
+            .flat_map(|s| &s.urls)
+            .find(|u| u.account_id == account_id && u.url == url)
+            .map(|u| &u.headers)
+    }
+
+    /// Candidate streams for a tier (premium falls back to standard if unset)
+    pub fn streams_for(&self, premium: bool) -> &[StreamConfig] {
+        if premium && !self.premium_streams.is_empty() {
+            &self.premium_streams
+        } else {
+            &self.streams
+        }
+    }
+}
+
+impl From<ChannelConfig> for ChannelRouting {
+    fn from(config: ChannelConfig) -> Self {
+        // A gateway channel has a single source: its group, on no account
+        let streams = match &config.multicast_group {
+            Some(group) => vec![StreamConfig {
+                id: 0,
+                urls: vec![StreamUrl {
+                    account_id: 0,
+                    url: multicast::gateway_url(group),
+                    priority: 0,
+                    weight: 0,
+                    headers: Default::default(),
+                }],
+                priority: 0,
+                weight: 0,
+            }],
+            None => config.streams,
+        };
+        Self {
+            streams,
+            enabled: config.enabled,
+            persistent: config.persistent,
+            priority: config.priority,
+            vod: config.vod,
+            auth_callback: config.auth_callback,
+            premium_streams: config.premium_streams,
+            premium_threshold: config.premium_threshold,
+            high_watermark: config.high_watermark,
+            low_watermark: config.low_watermark,
+            daily_quota_bytes: config.daily_quota_bytes,
+            monthly_quota_bytes: config.monthly_quota_bytes,
+            quota_streams: config.quota_streams,
+            hls_passthrough: config.hls_passthrough,
+            hls_encryption: config.hls_encryption,
+            subtitles: config.subtitles,
+            audio_language_priority: config.audio_language_priority,
+            tags: config.tags,
+            group: config.group,
+            max_clients_per_ip: config.max_clients_per_ip,
+            max_lag_events: config.max_lag_events,
+            max_lag_bytes: config.max_lag_bytes,
+            keepalive_after_ms: config.keepalive_after_ms,
+            failover: config.failover,
+            multicast_group: config.multicast_group,
+        }
+    }
+}
+
+/// The config a routing entry was built from, for snapshot export
+impl From<&ChannelRouting> for ChannelConfig {
+    fn from(routing: &ChannelRouting) -> Self {
+        let streams = match routing.multicast_group {
+            Some(_) => Vec::new(),
+            None => routing.streams.clone(),
+        };
+        Self {
+            streams,
+            enabled: routing.enabled,
+            persistent: routing.persistent,
+            priority: routing.priority,
+            vod: routing.vod,
+            auth_callback: routing.auth_callback.clone(),
+            premium_streams: routing.premium_streams.clone(),
+            premium_threshold: routing.premium_threshold,
+            high_watermark: routing.high_watermark,
+            low_watermark: routing.low_watermark,
+            daily_quota_bytes: routing.daily_quota_bytes,
+            monthly_quota_bytes: routing.monthly_quota_bytes,
+            quota_streams: routing.quota_streams.clone(),
+            hls_passthrough: routing.hls_passthrough,
+            hls_encryption: routing.hls_encryption,
+            subtitles: routing.subtitles.clone(),
+            audio_language_priority: routing.audio_language_priority.clone(),
+            tags: routing.tags.clone(),
+            group: routing.group.clone(),
+            max_clients_per_ip: routing.max_clients_per_ip,
+            max_lag_events: routing.max_lag_events,
+            max_lag_bytes: routing.max_lag_bytes,
+            keepalive_after_ms: routing.keepalive_after_ms,
+            failover: routing.failover.clone(),
+            multicast_group: routing.multicast_group.clone(),
+        }
+    }
+}
+
+/// Channel routing entries in two tiers. Channels in use are kept hydrated;
+/// idle ones can be hibernated to their config serialized as JSON, which is
+/// far smaller than a `ChannelRouting`, and are hydrated again on first
+/// lookup.
+#[derive(Default)]
+pub struct RoutingTable {
+    hot: DashMap<String, ChannelRouting>,
+    cold: DashMap<String, Box<[u8]>>,
+    /// Last lookup of each hydrated entry
+    used: DashMap<String, Instant>,
+    /// Gateway channel of each multicast group address, for udpxy-style
+    /// requests
+    multicast_groups: DashMap<SocketAddr, String>,
+}
+
+impl RoutingTable {
+    /// A channel's routing, hydrating it if it is hibernated
+    pub fn get(&self, channel_id: &str) -> Option<Ref<'_, String, ChannelRouting>> {
+        self.touch(channel_id);
+        self.hot.get(channel_id)
+    }
+
+    pub fn get_mut(&self, channel_id: &str) -> Option<RefMut<'_, String, ChannelRouting>> {
+        self.touch(channel_id);
+        self.hot.get_mut(channel_id)
+    }
+
+    /// Look at a channel's routing without hydrating it, for scans that
+    /// would otherwise wake every entry
+    pub fn peek<R>(&self, channel_id: &str, f: impl FnOnce(&ChannelRouting) -> R) -> Option<R> {
+        if let Some(routing) = self.hot.get(channel_id) {
+            return Some(f(&routing));
+        }
+        let config = self.cold.get(channel_id).and_then(|c| decode(&c))?;
+        Some(f(&ChannelRouting::from(config)))
+    }
+
+    /// Call `f` for every channel, hibernated ones included
+    pub fn scan(&self, mut f: impl FnMut(&str, &ChannelRouting)) {
+        for id in self.ids() {
+            self.peek(&id, |routing| f(&id, routing));
+        }
+    }
+
+    pub fn contains_key(&self, channel_id: &str) -> bool {
+        self.hot.contains_key(channel_id) || self.cold.contains_key(channel_id)
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.hot.iter().map(|e| e.key().clone()).collect();
+        let hot: HashSet<&String> = ids.iter().collect();
+        let cold: Vec<String> = self
+            .cold
+            .iter()
+            .filter(|e| !hot.contains(e.key()))
+            .map(|e| e.key().clone())
+            .collect();
+        ids.extend(cold);
+        ids
+    }
+
+    pub fn len(&self) -> usize {
+        self.hot.len() + self.cold.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Channels currently hibernated
+    pub fn cold_len(&self) -> usize {
+        self.cold.len()
+    }
+
+    // Lookups prefer the hydrated entry, so the tiers are updated in an
+    // order where a concurrent hibernation can at worst leave a stale copy
+    // behind in `cold`, never lose the channel
+
+    pub fn insert(&self, channel_id: String, routing: ChannelRouting) {
+        self.index_multicast_group(&channel_id, routing.multicast_group.as_deref());
+        self.cold.remove(&channel_id);
+        self.used.insert(channel_id.clone(), Instant::now());
+        self.hot.insert(channel_id, routing);
+    }
+
+    /// Add a channel in hibernated form
+    pub fn insert_cold(&self, channel_id: String, config: &ChannelConfig) {
+        self.index_multicast_group(&channel_id, config.multicast_group.as_deref());
+        self.cold.insert(channel_id.clone(), encode(config));
+        self.hot.remove(&channel_id);
+        self.used.remove(&channel_id);
+    }
+
+    pub fn remove(&self, channel_id: &str) -> bool {
+        self.index_multicast_group(channel_id, None);
+        let hot = self.hot.remove(channel_id).is_some();
+        let cold = self.cold.remove(channel_id).is_some();
+        self.used.remove(channel_id);
+        hot || cold
+    }
+
+    /// The gateway channel for a multicast group address, without waking
+    /// hibernated entries
+    pub fn multicast_channel(&self, group: SocketAddr) -> Option<String> {
+        self.multicast_groups.get(&group).map(|id| id.clone())
+    }
+
+    fn index_multicast_group(&self, channel_id: &str, group: Option<&str>) {
+        self.multicast_groups.retain(|_, id| id != channel_id);
+        let address =
+            group.and_then(|group| multicast::group_address(&multicast::gateway_url(group)));
+        if let Some(address) = address {
+            self.multicast_groups
+                .insert(address, channel_id.to_string());
+        }
+    }
+
+    /// Hibernate hydrated entries not looked up for `idle`, except those
+    /// `keep` holds on to. Returns how many were hibernated.
+    pub fn hibernate_idle(
+        &self,
+        idle: std::time::Duration,
+        keep: impl Fn(&str, &ChannelRouting) -> bool,
+    ) -> usize {
+        let idle_ids: Vec<String> = self
+            .hot
+            .iter()
+            .filter(|e| {
+                self.used
+                    .get(e.key())
+                    .is_none_or(|used| used.elapsed() >= idle)
+                    && !keep(e.key(), e.value())
+            })
+            .map(|e| e.key().clone())
+            .collect();
+        let mut hibernated = 0;
+        for id in idle_ids {
+            // Under the entry's lock, so an update can't slip in between
+            // the copy and the removal
+            let removed = self.hot.remove_if(&id, |id, routing| {
+                // Looked up again since the scan
+                if self.used.get(id).is_some_and(|used| used.elapsed() < idle) {
+                    return false;
+                }
+                self.cold
+                    .insert(id.clone(), encode(&ChannelConfig::from(routing)));
+                true
+            });
+            if removed.is_some() {
+                self.used.remove(&id);
+                hibernated += 1;
+            }
+        }
+        hibernated
+    }
+
+    /// Hydrate the entry if needed and record the lookup. No `hot` lock is
+    /// held while `used` is updated, as hibernation takes them the other
+    /// way round.
+    fn touch(&self, channel_id: &str) {
+        self.hydrate(channel_id);
+        if self.hot.contains_key(channel_id) {
+            self.used.insert(channel_id.to_string(), Instant::now());
+        }
+    }
+
+    fn hydrate(&self, channel_id: &str) {
+        if self.hot.contains_key(channel_id) {
+            return;
+        }
+        // Can't fail to decode data we encoded; if it did the entry stays
+        // cold rather than the channel being lost
+        let Some(config) = self.cold.get(channel_id).and_then(|c| decode(&c)) else {
+            return;
+        };
+        self.hot
+            .entry(channel_id.to_string())
+            .or_insert_with(|| ChannelRouting::from(config));
+        self.cold.remove(channel_id);
+    }
+}
+
+fn encode(config: &ChannelConfig) -> Box<[u8]> {
+    serde_json::to_vec(config)
+        .expect("channel config serializes")
+        .into_boxed_slice()
+}
+
+fn decode(data: &[u8]) -> Option<ChannelConfig> {
+    serde_json::from_slice(data).ok()
+}
+
+/// Upstream bytes a channel has pulled in the current UTC day and month
+pub struct ChannelUsage {
+    pub day: chrono::NaiveDate,
+    pub day_bytes: u64,
+    pub month: (i32, u32),
+    pub month_bytes: u64,
+}
+
+impl ChannelUsage {
+    fn new(today: chrono::NaiveDate) -> Self {
+        Self {
+            day: today,
+            day_bytes: 0,
+            month: (today.year(), today.month()),
+            month_bytes: 0,
+        }
+    }
+
+    /// (day, month) byte totals as of `today` (zero for periods that have ended)
+    pub fn totals(&self, today: chrono::NaiveDate) -> (u64, u64) {
+        let day = if self.day == today { self.day_bytes } else { 0 };
+        let month = if self.month == (today.year(), today.month()) {
+            self.month_bytes
+        } else {
+            0
+        };
+        (day, month)
+    }
+
+    /// Reset counters whose period has ended
+    fn roll(&mut self, today: chrono::NaiveDate) {
+        if self.day != today {
+            self.day = today;
+            self.day_bytes = 0;
+        }
+        let month = (today.year(), today.month());
+        if self.month != month {
+            self.month = month;
+            self.month_bytes = 0;
+        }
+    }
+}
+
+/// The stream+account an active channel is currently pulling from
+#[derive(Debug, Clone)]
+pub struct UpstreamTarget {
     pub stream_id: u64,
     pub account_id: u64,
     pub url: String,
+    /// Selected from the channel's premium streams
+    pub premium: bool,
+}
+
+/// A unit of channel data as broadcast to clients
+#[derive(Clone)]
+pub struct Chunk {
+    pub data: bytes::Bytes,
+    /// Chunk contains the start of a video keyframe (a clean join point)
+    pub keyframe: bool,
+}
+
+/// Live state for an active channel (upstream running)
+/// Pauses between upstream reads longer than this count as gaps
+const RECEIVE_GAP: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Receive-side counters for an active channel's upstream task
+#[derive(Default)]
+pub struct ReceiveStats {
+    pub chunks: u64,
+    pub bytes: u64,
+    pub gaps: u64,
+    pub longest_gap: std::time::Duration,
+    pub reconnects: u64,
+    last_read: Option<Instant>,
+    /// (stream, account, URL) and bytes read from it
+    sources: Vec<((u64, u64, String), u64)>,
+}
+
+impl ReceiveStats {
+    /// Count one read of `len` bytes from `target`
+    pub fn record_read(&mut self, target: &UpstreamTarget, len: usize) {
+        let now = Instant::now();
+        if let Some(last) = self.last_read.replace(now) {
+            let gap = now - last;
+            if gap > RECEIVE_GAP {
+                self.gaps += 1;
+            }
+            self.longest_gap = self.longest_gap.max(gap);
+        }
+        self.chunks += 1;
+        self.bytes += len as u64;
+        let key = (target.stream_id, target.account_id, target.url.as_str());
+        match self
+            .sources
+            .iter_mut()
+            .find(|((s, a, u), _)| (*s, *a, u.as_str()) == key)
+        {
+            Some((_, bytes)) => *bytes += len as u64,
+            None => self.sources.push((
+                (target.stream_id, target.account_id, target.url.clone()),
+                len as u64,
+            )),
+        }
+    }
+
+    /// Bytes per source, flagging `current`
+    pub fn sources(&self, current: &UpstreamTarget) -> Vec<SourceBytes> {
+        self.sources
+            .iter()
+            .map(|((stream_id, account_id, url), bytes)| SourceBytes {
+                stream_id: *stream_id,
+                account_id: *account_id,
+                url: url.clone(),
+                bytes: *bytes,
+                current: *stream_id == current.stream_id
+                    && *account_id == current.account_id
+                    && *url == current.url,
+            })
+            .collect()
+    }
+}
+
+pub struct ActiveChannel {
+    pub channel_id: String,
+    /// Updated by the upstream task on failover and tier switches
+    pub upstream: Mutex<UpstreamTarget>,
+    /// Started by warm-up; keeps running with zero clients
+    pub persistent: bool,
+    /// X-Request-Id of the request that started the channel, sent on its
+    /// upstream connects
+    pub started_by: Option<String>,
     pub connected_since: Instant,
     pub bytes_transferred: AtomicU64,
-    pub sender: broadcast::Sender<bytes::Bytes>,
+    pub sender: broadcast::Sender<Chunk>,
+    /// Chunks since the most recent keyframe, replayed to joining clients.
+    /// Locked while broadcasting so a subscriber never misses or repeats a chunk.
+    pub gop_cache: Mutex<Vec<Chunk>>,
+    /// Most recent chunks with their arrival time, burst to joining clients
+    /// when the GOP cache is empty. Locked after `gop_cache`.
+    pub recent_chunks: Mutex<VecDeque<(Instant, Chunk)>>,
+    /// Keyframes have been detected in this channel's data
+    pub keyframes_seen: AtomicBool,
+    /// Milliseconds after `connected_since` at which upstream data last arrived
+    pub last_data_ms: AtomicU64,
+    /// Cleared when the upstream task exits
+    pub task_running: AtomicBool,
+    /// Upstream reading is paused until clients drain the broadcast queue
+    pub backpressure_paused: AtomicBool,
+    /// Times upstream reading has paused for backpressure
+    pub backpressure_pauses: AtomicU64,
+    /// Source requested via the switch_account API, picked up by the upstream task
+    pub pending_switch: Mutex<Option<UpstreamTarget>>,
+    /// Bumped each time the last client leaves; an idle-grace timer only stops
+    /// the channel if no client has come and gone since it was armed
+    pub idle_generation: AtomicU64,
+    /// Shared with `AppState::channel_counters`
+    pub counters: Arc<ChannelCounters>,
+    /// Samples and latest result of the quality scorer
+    pub quality: Mutex<QualityWindow>,
+    /// Rolling rate of data read from upstream
+    pub input_rate: Mutex<RateMeter>,
+    /// Rolling rate of data sent to all clients together
+    pub output_rate: Mutex<RateMeter>,
+    /// MPEG-TS health of the data read from upstream
+    pub ts_health: Mutex<TsAnalyzer>,
+    /// Current source's PAT and PMTs, sent ahead of a joining client's data
+    pub psi_tables: Mutex<PsiCache>,
+    /// Source change notice waiting to go out ahead of the next chunk
+    pub source_marker: Mutex<SourceMarker>,
+    /// What the upstream task has read, for `/upstream_stats`
+    pub receive_stats: Mutex<ReceiveStats>,
     pub clients: DashMap<String, ClientState>,
     pub stop_tx: tokio::sync::watch::Sender<bool>,
 }
 
+impl ActiveChannel {
+    /// Whether the upstream task was told to stop (or has exited)
+    pub fn stopping(&self) -> bool {
+        *self.stop_tx.borrow()
+    }
+
+    pub fn current_upstream(&self) -> UpstreamTarget {
+        self.upstream.lock().unwrap().clone()
+    }
+
+    pub fn mark_data(&self) {
+        let ms = self.connected_since.elapsed().as_millis() as u64;
+        self.last_data_ms.store(ms, Ordering::Relaxed);
+    }
+
+    /// Chunks to send a joining client before its live subscription: the
+    /// cached GOP if there is one, otherwise the recent-chunk buffer, led by
+    /// the cached PSI tables. The caller must hold the `gop_cache` lock so
+    /// this lines up with a subscribe.
+    pub fn join_chunks(&self, gop: &[Chunk], max_age: std::time::Duration) -> Vec<Chunk> {
+        let chunks: Vec<Chunk> = if !gop.is_empty() {
+            gop.to_vec()
+        } else {
+            self.recent_chunks
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(at, _)| max_age.is_zero() || at.elapsed() <= max_age)
+                .map(|(_, chunk)| chunk.clone())
+                .collect()
+        };
+        if chunks.is_empty() {
+            return chunks;
+        }
+        self.psi_chunk().into_iter().chain(chunks).collect()
+    }
+
+    /// The cached PAT and PMTs as a chunk of their own
+    pub fn psi_chunk(&self) -> Option<Chunk> {
+        let data = self.psi_tables.lock().unwrap().tables()?;
+        Some(Chunk {
+            data,
+            keyframe: false,
+        })
+    }
+
+    /// Chunks queued for the slowest client with its own send queue
+    pub fn deepest_client_queue(&self) -> usize {
+        self.clients
+            .iter()
+            .map(|c| c.queue_depth.load(Ordering::Relaxed))
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Time since the upstream last delivered data (or since start if it never has)
+    pub fn idle_for(&self) -> std::time::Duration {
+        let last = std::time::Duration::from_millis(self.last_data_ms.load(Ordering::Relaxed));
+        self.connected_since.elapsed().saturating_sub(last)
+    }
+}
+
+/// Progress of the persistent-channel warm-up pass
+pub struct WarmupState {
+    /// Wakes the warm-up task after routing changes
+    pub notify: Notify,
+    pub in_progress: AtomicBool,
+    pub total: AtomicUsize,
+    pub started: AtomicUsize,
+}
+
+/// Cumulative per-channel counters for metrics scraping; they outlive the
+/// active channel so restarts don't reset them
+#[derive(Default)]
+pub struct ChannelCounters {
+    /// Bytes read from upstream
+    pub bytes_in: AtomicU64,
+    /// Bytes delivered to clients
+    pub bytes_out: AtomicU64,
+    /// Switches to another stream after an upstream failure
+    pub failovers: AtomicU64,
+    /// Upstream connections opened after the first (resumes, failovers, switches)
+    pub reconnects: AtomicU64,
+    /// Chunks clients missed because they fell behind the broadcast buffer
+    pub lag_drops: AtomicU64,
+    /// Clients disconnected for exceeding the channel's lag limits
+    pub lag_disconnects: AtomicU64,
+    /// Keepalive null packets sent to clients during data gaps
+    pub keepalive_packets: AtomicU64,
+    /// Chunks missing from a `relay://` source's sequence numbers
+    pub relay_lost_chunks: AtomicU64,
+}
+
+impl ChannelCounters {
+    pub fn reset(&self) {
+        for counter in [
+            &self.bytes_in,
+            &self.bytes_out,
+            &self.failovers,
+            &self.reconnects,
+            &self.lag_drops,
+            &self.lag_disconnects,
+            &self.keepalive_packets,
+            &self.relay_lost_chunks,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Connection history of one upstream URL, shared by every channel using it
+#[derive(Default)]
+pub struct UrlHealth {
+    /// Connection attempts
+    pub attempts: u64,
+    /// Attempts that failed to connect or got a non-success status
+    pub connect_failures: u64,
+    /// Established connections that later errored or ended
+    pub drops: u64,
+    /// Connect failures since the last successful connect
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<Instant>,
+    pub last_success_at: Option<Instant>,
+    /// Certificate presented on the last successful HTTPS connect
+    pub certificate: Option<CertificateInfo>,
+    /// Time to the response (or first datagram) on the last successful connect
+    pub connect_latency: Option<std::time::Duration>,
+    /// Average bitrate delivered over the last connection long enough to measure
+    pub bitrate_bps: Option<u64>,
+    /// Latest quality score of a channel streaming from this URL
+    pub quality_score: Option<u32>,
+}
+
+impl UrlHealth {
+    /// 0-100 rating from the success ratio, penalised for a current failure
+    /// streak (None until the URL has been tried)
+    pub fn score(&self) -> Option<u32> {
+        if self.attempts == 0 {
+            return None;
+        }
+        let ok = self.attempts - self.connect_failures;
+        let ratio = (ok * 100 / self.attempts) as u32;
+        Some(ratio.saturating_sub(self.consecutive_failures * 20))
+    }
+
+    /// When the URL's cooldown ends, if its latest outcome was a failure:
+    /// `base` after it, doubled for each further connect failure in a row and
+    /// capped at `max`. None once it has connected again or for a zero `base`.
+    pub fn cooldown_until(
+        &self,
+        base: std::time::Duration,
+        max: std::time::Duration,
+    ) -> Option<Instant> {
+        let failed_at = self.last_error_at?;
+        if base.is_zero() || self.last_success_at.is_some_and(|ok| ok > failed_at) {
+            return None;
+        }
+        let doublings = self.consecutive_failures.saturating_sub(1).min(16);
+        Some(failed_at + (base * 2u32.pow(doublings)).min(max))
+    }
+}
+
+/// An upstream playlist or segment URL handed to HLS clients under an opaque id
+pub struct HlsResource {
+    pub channel_id: String,
+    pub url: String,
+    pub last_used: Instant,
+    /// Segment served encrypted with this channel key (None = as received)
+    pub key_id: Option<u64>,
+}
+
+/// An AES-128 key for a channel's encrypted HLS segments
+pub struct HlsKey {
+    pub id: u64,
+    pub key: [u8; 16],
+    pub created: Instant,
+}
+
+/// A cached viewer auth decision
+pub struct CachedDecision {
+    pub allowed: bool,
+    pub expires: Instant,
+}
+
 /// Top-level application state shared across all handlers
 pub struct AppState {
+    pub config: Config,
     pub start_time: Instant,
-    pub channel_routes: DashMap<String, ChannelRouting>,
+    /// Routing table; sync and restore build a new one and swap it in whole,
+    /// so readers never see a half-applied update
+    pub channel_routes: ArcSwap<RoutingTable>,
     pub active_channels: DashMap<String, Arc<ActiveChannel>>,
-    pub accounts: DashMap<u64, AccountState>,
+    /// Provider accounts, swapped like `channel_routes`; an account kept by a
+    /// sync keeps its `AccountState` (and live counters) in the new map
+    pub accounts: ArcSwap<DashMap<u64, Arc<AccountState>>>,
+    /// Limits how many upstream connection attempts may be in flight at once
+    pub start_permits: Semaphore,
+    pub warmup: WarmupState,
+    /// Wakes the state file writer after control changes to routing
+    pub routing_changed: Notify,
+    /// Shared account limits with other proxy instances (None = standalone)
+    pub cluster: Option<Arc<Cluster>>,
+    /// HTTP client for viewer auth callbacks
+    pub auth_client: reqwest::Client,
+    /// Recent auth decisions, keyed by callback URL + channel + client IP + token
+    pub auth_cache: DashMap<String, CachedDecision>,
+    /// Signatures of accepted signed control requests and their timestamps,
+    /// so a request can't be replayed within the allowed clock skew
+    pub seen_signatures: DashMap<Vec<u8>, i64>,
+    /// Channel each viewer's client id is connected to, for finding a
+    /// session by id
+    pub client_channels: DashMap<String, String>,
+    /// Open VOD client sessions (session id -> account id)
+    pub vod_sessions: DashMap<String, u64>,
+    /// Latest tokio runtime sample
+    pub runtime_metrics: Mutex<RuntimeSnapshot>,
+    /// Dedicated runtime for upstream reader tasks (None = spawn on the current runtime)
+    pub upstream_runtime: Option<tokio::runtime::Handle>,
+    /// Running upstream reader tasks, aborted when the server is dropped
+    pub upstream_tasks: Mutex<tokio::task::JoinSet<()>>,
+    /// Injected upstream faults per channel (chaos testing)
+    pub faults: DashMap<String, ChannelFaults>,
+    /// Country/ASN enrichment of client addresses
+    pub geo: GeoLookup,
+    /// Upstream connection outcomes keyed by URL
+    pub url_health: DashMap<String, UrlHealth>,
+    /// Upstream byte usage per channel, for quotas (outlives the active channel)
+    pub channel_usage: DashMap<String, ChannelUsage>,
+    /// HTTP client for HLS passthrough and VOD requests
+    pub http_client: reqwest::Client,
+    /// Proxied HLS URLs by resource id
+    pub hls_resources: DashMap<String, HlsResource>,
+    /// HLS output (segmented live broadcast) per channel
+    pub hls_outputs: DashMap<String, Arc<HlsOutput>>,
+    /// Segment encryption keys per channel, oldest first
+    pub hls_keys: DashMap<String, VecDeque<HlsKey>>,
+    /// Cumulative counters per channel
+    pub channel_counters: DashMap<String, Arc<ChannelCounters>>,
+    /// Earliest time the next new connection to each provider host may start
+    pub host_next_connect: DashMap<String, Instant>,
+    /// Start slots per provider host, when `host_max_concurrent_starts` is set
+    pub host_start_permits: DashMap<String, Arc<Semaphore>>,
+    /// Per channel, when the join token bucket would next be empty-handed
+    /// (the theoretical arrival time of the next join at `join_rate`)
+    pub join_next: DashMap<String, Instant>,
+    /// Open stream connections per client IP, across all channels
+    pub clients_per_ip: DashMap<IpAddr, usize>,
+    /// Recent channel and client events
+    pub events: EventLog,
+    /// Active-channel limit per channel group
+    pub group_limits: DashMap<String, u32>,
+    /// Held while starting a channel in a limited group, so concurrent
+    /// starts can't overshoot the limit
+    pub group_starts: Mutex<()>,
 }
 
 impl AppState {
-    pub fn new() -> Self {
+    pub fn new(config: Config) -> Self {
+        let start_permits = match config.max_concurrent_starts {
+            0 => Semaphore::MAX_PERMITS,
+            n => n,
+        };
+        let auth_client = reqwest::Client::builder()
+            .timeout(config.auth_callback_timeout)
+            .build()
+            .expect("failed to build auth HTTP client");
+        let http_client = config
+            .upstream_client_builder()
+            .build()
+            .expect("failed to build upstream HTTP client");
+        let geo = GeoLookup::open(&config);
+        let events = EventLog::new(config.event_ring_size);
+        let cluster = Cluster::from_config(&config).map(Arc::new);
         Self {
+            config,
             start_time: Instant::now(),
-            channel_routes: DashMap::new(),
+            channel_routes: ArcSwap::default(),
             active_channels: DashMap::new(),
-            accounts: DashMap::new(),
+            accounts: ArcSwap::default(),
+            start_permits: Semaphore::new(start_permits),
+            routing_changed: Notify::new(),
+            cluster,
+            warmup: WarmupState {
+                notify: Notify::new(),
+                in_progress: AtomicBool::new(false),
+                total: AtomicUsize::new(0),
+                started: AtomicUsize::new(0),
+            },
+            auth_client,
+            auth_cache: DashMap::new(),
+            seen_signatures: DashMap::new(),
+            client_channels: DashMap::new(),
+            vod_sessions: DashMap::new(),
+            runtime_metrics: Mutex::new(RuntimeSnapshot::default()),
+            upstream_runtime: None,
+            upstream_tasks: Mutex::new(tokio::task::JoinSet::new()),
+            faults: DashMap::new(),
+            geo,
+            url_health: DashMap::new(),
+            channel_usage: DashMap::new(),
+            http_client,
+            hls_resources: DashMap::new(),
+            hls_outputs: DashMap::new(),
+            hls_keys: DashMap::new(),
+            channel_counters: DashMap::new(),
+            host_next_connect: DashMap::new(),
+            host_start_permits: DashMap::new(),
+            join_next: DashMap::new(),
+            clients_per_ip: DashMap::new(),
+            events,
+            group_limits: DashMap::new(),
+            group_starts: Mutex::new(()),
         }
     }
 
-    /// Find first available stream+account for a channel, respecting limits.
-    pub fn select_stream(&self, channel_id: &str) -> Option<(u64, u64, String)> {
-        let routing = self.channel_routes.get(channel_id)?;
-        for stream in &routing.streams {
-            for url_entry in &stream.urls {
-                if let Some(account) = self.accounts.get(&url_entry.account_id) {
-                    let current = account.active_connections.load(Ordering::Relaxed);
-                    let max = account.max_connections.load(Ordering::Relaxed);
-                    if max == 0 || current < max {
-                        return Some((stream.id, url_entry.account_id, url_entry.url.clone()));
-                    }
-                } else {
-                    // Account not registered — allow (no limit)
-                    return Some((stream.id, url_entry.account_id, url_entry.url.clone()));
-                }
+    pub fn with_upstream_runtime(mut self, handle: tokio::runtime::Handle) -> Self {
+        self.upstream_runtime = Some(handle);
+        self
+    }
+
+    /// Spawn an upstream reader task on the dedicated runtime if configured.
+    pub fn spawn_upstream<F>(&self, task: F)
+    where
+        F: std::future::Future<Output = ()> + Send + 'static,
+    {
+        let mut tasks = self.upstream_tasks.lock().unwrap();
+        // Forget the ones that have exited
+        while tasks.try_join_next().is_some() {}
+        match &self.upstream_runtime {
+            Some(handle) => {
+                tasks.spawn_on(task, handle);
+            }
+            None => {
+                tasks.spawn(task);
             }
         }
-        None
     }
 
-    /// Try the next available stream after the current one fails.
+    /// Signal every channel to stop and abort the upstream tasks, for a
+    /// server going away
+    pub fn abort_upstream_tasks(&self) {
+        for active in self.active_channels.iter() {
+            active.stop_tx.send_replace(true);
+        }
+        self.upstream_tasks.lock().unwrap().abort_all();
+    }
+
+    /// High/low broadcast queue watermarks for a channel (channel overrides
+    /// fall back to the global config; high 0 = never pause).
+    pub fn watermarks(&self, channel_id: &str) -> (usize, usize) {
+        let routes = self.channel_routes.load();
+        let routing = routes.get(channel_id);
+        let high = routing
+            .as_ref()
+            .and_then(|r| r.high_watermark)
+            .unwrap_or(self.config.upstream_high_watermark);
+        let low = routing
+            .as_ref()
+            .and_then(|r| r.low_watermark)
+            .unwrap_or(self.config.upstream_low_watermark);
+        (high, low.min(high))
+    }
+
+    /// Lag events and missed bytes after which a client of `channel_id` is
+    /// disconnected (channel overrides fall back to the global config; 0 =
+    /// no limit)
+    pub fn lag_limits(&self, channel_id: &str) -> (u64, u64) {
+        let routes = self.channel_routes.load();
+        let routing = routes.get(channel_id);
+        let events = routing
+            .as_ref()
+            .and_then(|r| r.max_lag_events)
+            .unwrap_or(self.config.max_client_lag_events);
+        let bytes = routing
+            .as_ref()
+            .and_then(|r| r.max_lag_bytes)
+            .unwrap_or(self.config.max_client_lag_bytes);
+        (events, bytes)
+    }
+
+    /// Data gap after which clients of `channel_id` get keepalives (the
+    /// channel's override, else the global config; zero = never)
+    pub fn keepalive_after(&self, channel_id: &str) -> std::time::Duration {
+        self.channel_routes
+            .load()
+            .get(channel_id)
+            .and_then(|r| r.keepalive_after_ms)
+            .map_or(
+                self.config.keepalive_after,
+                std::time::Duration::from_millis,
+            )
+    }
+
+    /// The channel's group and that group's active-channel limit, if it has one
+    pub fn group_limit(&self, channel_id: &str) -> Option<(String, u32)> {
+        let group = self.channel_routes.load().get(channel_id)?.group.clone()?;
+        let limit = *self.group_limits.get(&group)?;
+        Some((group, limit))
+    }
+
+    /// Active channels belonging to `group`
+    pub fn group_active_channels(&self, group: &str) -> u32 {
+        let routes = self.channel_routes.load();
+        self.active_channels
+            .iter()
+            .filter(|a| {
+                !a.stopping()
+                    && routes
+                        .get(a.key())
+                        .is_some_and(|r| r.group.as_deref() == Some(group))
+            })
+            .count() as u32
+    }
+
+    /// Whether the channel's group already has as many active channels as
+    /// its limit allows
+    pub fn group_full(&self, channel_id: &str) -> bool {
+        self.group_limit(channel_id)
+            .is_some_and(|(group, limit)| self.group_active_channels(&group) >= limit)
+    }
+
+    /// Pick a stream+account for a channel, respecting limits and skipping
+    /// URLs in their failure cooldown: among the available sources of the
+    /// highest priority, a weighted random choice (or the first in list order
+    /// if none of them has a weight).
+    pub fn select_stream(&self, channel_id: &str, premium: bool) -> Option<(u64, u64, String)> {
+        let over_quota = self.quota_exceeded(channel_id).is_some();
+        let routes = self.channel_routes.load();
+        let routing = routes.get(channel_id)?;
+        if !routing.enabled {
+            return None;
+        }
+        let available = self.skip_cooling_down(
+            routing
+                .ordered_sources(premium, over_quota)
+                .into_iter()
+                .filter(|(_, u)| self.account_available(u.account_id))
+                .collect(),
+        );
+        let (first_stream, first_url) = *available.first()?;
+        let top = (first_stream.priority, first_url.priority);
+        let tier: Vec<_> = available
+            .into_iter()
+            .take_while(|(s, u)| (s.priority, u.priority) == top)
+            .collect();
+
+        let weight = |(s, u): &(&StreamConfig, &StreamUrl)| match u.weight {
+            0 => s.weight as u64,
+            w => w as u64,
+        };
+        let total: u64 = tier.iter().map(weight).sum();
+        let (stream, url) = if total == 0 {
+            tier[0]
+        } else {
+            let mut pick = fastrand::u64(0..total);
+            *tier.iter().find(|source| {
+                let w = weight(source);
+                if pick < w {
+                    return true;
+                }
+                pick -= w;
+                false
+            })?
+        };
+        Some((stream.id, url.account_id, url.url.clone()))
+    }
+
+    /// `select_stream`, with a connection slot reserved on the chosen account.
+    /// The caller owns the slot and releases it with `decrement_connections`.
+    pub fn reserve_stream(&self, channel_id: &str, premium: bool) -> Option<(u64, u64, String)> {
+        (0..RESERVE_ATTEMPTS).find_map(|_| {
+            let (stream_id, account_id, url) = self.select_stream(channel_id, premium)?;
+            // Losing the slot to a concurrent start makes the next pick skip it
+            self.try_acquire_connection(account_id)
+                .then_some((stream_id, account_id, url))
+        })
+    }
+
+    /// `select_next_stream`, with a connection slot reserved on the chosen
+    /// account as in `reserve_stream`
+    pub fn reserve_next_stream(
+        &self,
+        channel_id: &str,
+        premium: bool,
+        failed_stream_id: u64,
+        failed_account_id: u64,
+    ) -> Option<(u64, u64, String)> {
+        (0..RESERVE_ATTEMPTS).find_map(|_| {
+            let (stream_id, account_id, url) =
+                self.select_next_stream(channel_id, premium, failed_stream_id, failed_account_id)?;
+            self.try_acquire_connection(account_id)
+                .then_some((stream_id, account_id, url))
+        })
+    }
+
+    /// Pick a stream after the current one fails: of the available sources
+    /// ordered after it (or any other source, if the channel's failover
+    /// policy wraps around) that aren't cooling down, the highest priority ones, and of those the
+    /// one with the best recent bitrate and latency (order breaks ties).
     pub fn select_next_stream(
         &self,
         channel_id: &str,
+        premium: bool,
         failed_stream_id: u64,
         failed_account_id: u64,
     ) -> Option<(u64, u64, String)> {
-        let routing = self.channel_routes.get(channel_id)?;
-        let mut past_failed = false;
-        for stream in &routing.streams {
-            for url_entry in &stream.urls {
-                if stream.id == failed_stream_id && url_entry.account_id == failed_account_id {
-                    past_failed = true;
-                    continue;
-                }
-                if !past_failed {
-                    continue;
-                }
-                if let Some(account) = self.accounts.get(&url_entry.account_id) {
-                    let current = account.active_connections.load(Ordering::Relaxed);
-                    let max = account.max_connections.load(Ordering::Relaxed);
-                    if max == 0 || current < max {
-                        return Some((stream.id, url_entry.account_id, url_entry.url.clone()));
-                    }
-                } else {
-                    return Some((stream.id, url_entry.account_id, url_entry.url.clone()));
+        let over_quota = self.quota_exceeded(channel_id).is_some();
+        let routes = self.channel_routes.load();
+        let routing = routes.get(channel_id)?;
+        let sources = routing.ordered_sources(premium, over_quota);
+        let failed = sources
+            .iter()
+            .position(|(s, u)| s.id == failed_stream_id && u.account_id == failed_account_id)?;
+        let earlier = if routing.failover.wrap_around {
+            &sources[..failed]
+        } else {
+            &[]
+        };
+        let candidates = self.skip_cooling_down(
+            sources[failed + 1..]
+                .iter()
+                .chain(earlier)
+                .copied()
+                .filter(|(_, u)| self.account_available(u.account_id))
+                .collect(),
+        );
+        let (stream, url) = candidates.into_iter().min_by_key(|(s, u)| {
+            (
+                std::cmp::Reverse((s.priority, u.priority)),
+                self.failover_rank(&u.url),
+            )
+        })?;
+        Some((stream.id, url.account_id, url.url.clone()))
+    }
+
+    /// The source `channel_id` would use on `account_id` in place of
+    /// `current`: the same stream if it has a URL on that account, else any
+    /// stream of the same tier that does.
+    pub fn target_on_account(
+        &self,
+        channel_id: &str,
+        current: &UpstreamTarget,
+        account_id: u64,
+    ) -> Option<UpstreamTarget> {
+        let routes = self.channel_routes.load();
+        let routing = routes.get(channel_id)?;
+        let streams = routing.streams_for(current.premium);
+        let mut candidates = streams
+            .iter()
+            .filter(|s| s.id == current.stream_id)
+            .chain(streams.iter().filter(|s| s.id != current.stream_id));
+        candidates.find_map(|s| {
+            let url = s.urls.iter().find(|u| u.account_id == account_id)?;
+            Some(UpstreamTarget {
+                stream_id: s.id,
+                account_id,
+                url: url.url.clone(),
+                premium: current.premium,
+            })
+        })
+    }
+
+    /// Whether a new connection may use this account: enabled, under its
+    /// limit and not suspended by its retry budget. Unregistered accounts
+    /// have no limit.
+    pub fn account_available(&self, account_id: u64) -> bool {
+        let Some(account) = self.accounts.load().get(&account_id).map(|a| a.clone()) else {
+            return true;
+        };
+        let current = account.active_connections.load(Ordering::Relaxed);
+        let max = account.max_connections.load(Ordering::Relaxed);
+        account.enabled.load(Ordering::Relaxed)
+            && (max == 0 || current < max)
+            && !account.retry_suspended()
+    }
+
+    /// Count an upstream failure against the account's retry budget. Once
+    /// `account_failure_threshold` failures fall within the window, every
+    /// channel stops retrying the account for `account_retry_suspend`, so a
+    /// provider-wide outage isn't amplified by per-channel retries.
+    pub fn record_account_failure(&self, account_id: u64) {
+        let threshold = self.config.account_failure_threshold;
+        if threshold == 0 {
+            return;
+        }
+        let Some(account) = self.accounts.load().get(&account_id).map(|a| a.clone()) else {
+            return;
+        };
+        let now = Instant::now();
+        let mut failures = account.recent_failures.lock().unwrap();
+        failures.push_back(now);
+        while failures
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > self.config.account_failure_window)
+        {
+            failures.pop_front();
+        }
+        if failures.len() < threshold || account.retry_suspended() {
+            return;
+        }
+        failures.clear();
+        let suspend = self.config.account_retry_suspend;
+        *account.retry_suspended_until.lock().unwrap() = Some(now + suspend);
+        tracing::warn!(
+            "Account {}: {} upstream failures within {:?}, suspending retries for {:?}",
+            account_id,
+            threshold,
+            self.config.account_failure_window,
+            suspend
+        );
+    }
+
+    /// Headers for a request to a channel's source: the account's, with the
+    /// URL's own taking precedence. Entries that aren't valid headers are
+    /// skipped (the control API rejects them).
+    pub fn upstream_headers(
+        &self,
+        channel_id: &str,
+        account_id: u64,
+        url: &str,
+    ) -> reqwest::header::HeaderMap {
+        let mut headers = reqwest::header::HeaderMap::new();
+        let mut add = |entries: &HashMap<String, String>| {
+            for (name, value) in entries {
+                if let (Ok(name), Ok(value)) = (
+                    reqwest::header::HeaderName::from_bytes(name.as_bytes()),
+                    reqwest::header::HeaderValue::from_str(value),
+                ) {
+                    headers.insert(name, value);
                 }
             }
+        };
+        if let Some(account) = self.accounts.load().get(&account_id) {
+            add(&account.headers.lock().unwrap());
+        }
+        if let Some(route) = self.channel_routes.load().get(channel_id) {
+            if let Some(url_headers) = route.url_headers(account_id, url) {
+                add(url_headers);
+            }
+        }
+        headers
+    }
+
+    pub fn record_url_success(&self, url: &str, latency: std::time::Duration) {
+        let mut health = self.url_health.entry(url.to_string()).or_default();
+        health.attempts += 1;
+        health.consecutive_failures = 0;
+        health.last_success_at = Some(Instant::now());
+        health.connect_latency = Some(latency);
+    }
+
+    /// Record what a connection to `url` delivered before it ended; short
+    /// connections are ignored as too noisy to rank sources by
+    pub fn record_url_throughput(&self, url: &str, bytes: u64, elapsed: std::time::Duration) {
+        if elapsed < MIN_THROUGHPUT_SAMPLE || bytes == 0 {
+            return;
+        }
+        let bps = (bytes as f64 * 8.0 / elapsed.as_secs_f64()) as u64;
+        let mut health = self.url_health.entry(url.to_string()).or_default();
+        health.bitrate_bps = Some(bps);
+    }
+
+    /// Whether `url` failed recently enough that selection should avoid it
+    pub fn url_cooling_down(&self, url: &str) -> bool {
+        self.url_health.get(url).is_some_and(|health| {
+            health
+                .cooldown_until(self.config.url_cooldown, self.config.url_cooldown_max)
+                .is_some_and(|until| until > Instant::now())
+        })
+    }
+
+    /// `sources` without those cooling down after a failure, unless that
+    /// would leave none
+    fn skip_cooling_down<'a>(
+        &self,
+        sources: Vec<(&'a StreamConfig, &'a StreamUrl)>,
+    ) -> Vec<(&'a StreamConfig, &'a StreamUrl)> {
+        let ready: Vec<_> = sources
+            .iter()
+            .copied()
+            .filter(|(_, u)| !self.url_cooling_down(&u.url))
+            .collect();
+        if ready.is_empty() {
+            sources
+        } else {
+            ready
+        }
+    }
+
+    /// Remember the quality score a channel streaming from `url` achieved
+    pub fn record_url_quality(&self, url: &str, score: u32) {
+        if let Some(mut health) = self.url_health.get_mut(url) {
+            health.quality_score = Some(score);
+        }
+    }
+
+    /// Failover preference for a source (lower is better): sources not
+    /// currently failing, then higher quality score, higher measured bitrate
+    /// and lower connect latency. Unmeasured sources rank after measured ones.
+    fn failover_rank(
+        &self,
+        url: &str,
+    ) -> (
+        bool,
+        std::cmp::Reverse<u32>,
+        std::cmp::Reverse<u64>,
+        std::time::Duration,
+    ) {
+        let Some(health) = self.url_health.get(url) else {
+            return (
+                false,
+                std::cmp::Reverse(0),
+                std::cmp::Reverse(0),
+                std::time::Duration::MAX,
+            );
+        };
+        (
+            health.consecutive_failures > 0,
+            std::cmp::Reverse(health.quality_score.unwrap_or(0)),
+            std::cmp::Reverse(health.bitrate_bps.unwrap_or(0)),
+            health.connect_latency.unwrap_or(std::time::Duration::MAX),
+        )
+    }
+
+    /// Remember the certificate an HTTPS URL presented, warning when a newly
+    /// seen one is close to expiry
+    pub fn record_url_certificate(&self, url: &str, certificate: CertificateInfo) {
+        let mut health = self.url_health.entry(url.to_string()).or_default();
+        if health.certificate.as_ref() == Some(&certificate) {
+            return;
+        }
+        let days = certificate.days_remaining();
+        if days < self.config.tls_expiry_warn_days as i64 {
+            tracing::warn!(
+                "Upstream {} certificate expires in {} days ({}, issued by {})",
+                url,
+                days,
+                certificate.not_after.to_rfc3339(),
+                certificate.issuer
+            );
+        }
+        health.certificate = Some(certificate);
+    }
+
+    pub fn record_url_connect_failure(&self, url: &str, error: &str) {
+        let mut health = self.url_health.entry(url.to_string()).or_default();
+        health.attempts += 1;
+        health.connect_failures += 1;
+        health.consecutive_failures += 1;
+        health.last_error = Some(error.to_string());
+        health.last_error_at = Some(Instant::now());
+    }
+
+    /// Record an established upstream connection failing mid-stream
+    pub fn record_url_drop(&self, url: &str, error: &str) {
+        let mut health = self.url_health.entry(url.to_string()).or_default();
+        health.drops += 1;
+        health.last_error = Some(error.to_string());
+        health.last_error_at = Some(Instant::now());
+    }
+
+    /// Wait until the URL's host may take another new connection under its
+    /// configured rate, reserving that slot. Pacing is shared across all
+    /// accounts, since providers ban by source IP.
+    pub async fn pace_host_connect(&self, url: &str) {
+        let Some(host) = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(str::to_string))
+        else {
+            return;
+        };
+        let rate = self.config.connect_rate(&host);
+        if rate <= 0.0 {
+            return;
+        }
+        let now = Instant::now();
+        let slot = {
+            let mut next = self.host_next_connect.entry(host.clone()).or_insert(now);
+            let slot = (*next).max(now);
+            *next = slot + std::time::Duration::from_secs_f64(1.0 / rate);
+            slot
+        };
+        if slot > now {
+            tracing::debug!("Pacing connection to {}: waiting {:?}", host, slot - now);
+            tokio::time::sleep_until(slot).await;
+        }
+    }
+
+    /// Wait for a start slot on the URL's host if `host_max_concurrent_starts`
+    /// limits them; the slot is held until the permit is dropped
+    pub async fn host_start_permit(&self, url: &str) -> Option<OwnedSemaphorePermit> {
+        let limit = self.config.host_max_concurrent_starts;
+        if limit == 0 {
+            return None;
+        }
+        let host = reqwest::Url::parse(url).ok()?.host_str()?.to_string();
+        let permits = self
+            .host_start_permits
+            .entry(host.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(limit)))
+            .clone();
+        if permits.available_permits() == 0 {
+            tracing::debug!("Upstream start queued, waiting for a free slot on {}", host);
+        }
+        permits.acquire_owned().await.ok()
+    }
+
+    /// Wait for a token from the channel's join bucket (`join_rate` per
+    /// second, holding up to `join_burst`). Returns false, without taking a
+    /// token, if the wait would exceed `join_queue_timeout`.
+    pub async fn pace_join(&self, channel_id: &str) -> bool {
+        let rate = self.config.join_rate;
+        if rate <= 0.0 {
+            return true;
+        }
+        let interval = std::time::Duration::from_secs_f64(1.0 / rate);
+        let burst = interval * self.config.join_burst.saturating_sub(1);
+        let now = Instant::now();
+        let slot = {
+            let mut next = self.join_next.entry(channel_id.to_string()).or_insert(now);
+            let due = (*next).max(now);
+            let slot = due.checked_sub(burst).unwrap_or(now).max(now);
+            if slot - now > self.config.join_queue_timeout {
+                return false;
+            }
+            *next = due + interval;
+            slot
+        };
+        if slot > now {
+            tracing::debug!("Channel {}: join queued for {:?}", channel_id, slot - now);
+            tokio::time::sleep_until(slot).await;
+        }
+        true
+    }
+
+    /// Count a new stream connection from `ip`, or None if that IP already
+    /// has `MAX_CLIENTS_PER_IP` open. `enforce: false` always counts it (a
+    /// resumed session replacing its own connection).
+    pub fn acquire_ip_slot(self: &Arc<Self>, ip: IpAddr, enforce: bool) -> Option<IpSlot> {
+        let limit = self.config.max_clients_per_ip;
+        let mut open = self.clients_per_ip.entry(ip).or_insert(0);
+        if enforce && limit > 0 && *open >= limit {
+            return None;
         }
-        None
+        *open += 1;
+        Some(IpSlot {
+            state: self.clone(),
+            ip,
+        })
+    }
+
+    /// The channel's cumulative counters, created on first use
+    pub fn channel_counters(&self, channel_id: &str) -> Arc<ChannelCounters> {
+        if let Some(counters) = self.channel_counters.get(channel_id) {
+            return counters.clone();
+        }
+        self.channel_counters
+            .entry(channel_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Count upstream bytes against the channel's quotas
+    pub fn record_upstream_bytes(&self, channel_id: &str, bytes: u64) {
+        let today = chrono::Utc::now().date_naive();
+        let mut usage = match self.channel_usage.get_mut(channel_id) {
+            Some(usage) => usage,
+            None => self
+                .channel_usage
+                .entry(channel_id.to_string())
+                .or_insert_with(|| ChannelUsage::new(today)),
+        };
+        usage.roll(today);
+        usage.day_bytes += bytes;
+        usage.month_bytes += bytes;
     }
 
-    pub fn increment_connections(&self, account_id: u64) {
-        if let Some(account) = self.accounts.get(&account_id) {
-            account.active_connections.fetch_add(1, Ordering::Relaxed);
+    /// Which of the channel's quotas is used up ("daily"/"monthly"), if any
+    pub fn quota_exceeded(&self, channel_id: &str) -> Option<&'static str> {
+        let routes = self.channel_routes.load();
+        let routing = routes.get(channel_id)?;
+        if !routing.has_quota() {
+            return None;
+        }
+        let usage = self.channel_usage.get(channel_id)?;
+        let (daily, monthly) = usage.totals(chrono::Utc::now().date_naive());
+        routing.quota_exceeded(daily, monthly)
+    }
+
+    /// Take a connection slot on the account if `account_available` allows
+    /// it, checking the limit and counting the connection in one atomic step
+    /// so concurrent starts can't both take the last slot. Unregistered
+    /// accounts always succeed.
+    pub fn try_acquire_connection(&self, account_id: u64) -> bool {
+        let Some(account) = self.accounts.load().get(&account_id).map(|a| a.clone()) else {
+            return true;
+        };
+        if !account.enabled.load(Ordering::Relaxed) || account.retry_suspended() {
+            return false;
+        }
+        let max = account.max_connections.load(Ordering::Relaxed);
+        match account.active_connections.fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |current| (max == 0 || current < max).then_some(current + 1),
+        ) {
+            Ok(previous) => {
+                account
+                    .peak_connections
+                    .fetch_max(previous + 1, Ordering::Relaxed);
+                true
+            }
+            Err(_) => false,
         }
     }
 
     pub fn decrement_connections(&self, account_id: u64) {
-        if let Some(account) = self.accounts.get(&account_id) {
+        if let Some(account) = self.accounts.load().get(&account_id) {
             // Use fetch_update to prevent underflow (sync replaces accounts with fresh 0 counters
             // while upstream tasks still hold references and decrement on cleanup)
-            let _ = account.active_connections.fetch_update(
+            let released = account.active_connections.fetch_update(
                 Ordering::Relaxed,
                 Ordering::Relaxed,
                 |current| {
@@ -127,6 +1734,25 @@ impl AppState {
                     }
                 },
             );
+            if let (Ok(_), Some(cluster)) = (released, &self.cluster) {
+                let cluster = cluster.clone();
+                let account = account.clone();
+                tokio::spawn(async move { cluster.release(account_id, &account).await });
+            }
+        }
+    }
+
+    /// In a cluster, take the cluster-wide slot for a connection about to be
+    /// opened on a slot already reserved locally. False if the account's
+    /// limit is reached across the cluster; the caller then releases its
+    /// local slot.
+    pub async fn claim_cluster_slot(&self, account_id: u64) -> bool {
+        let Some(cluster) = &self.cluster else {
+            return true;
+        };
+        match self.accounts.load().get(&account_id).map(|a| a.clone()) {
+            Some(account) => cluster.claim(account_id, &account).await,
+            None => true,
         }
     }
 }
@@ -1,61 +1,216 @@
+use crate::auth;
+use crate::certs::CertificateInfo;
 use crate::models::*;
-use crate::state::AppState;
+use crate::qoe;
+use crate::state::{ActiveChannel, AppState, ChannelCounters, ChannelRouting};
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
     Json,
 };
+use futures_util::{Stream, StreamExt};
 use std::collections::HashMap;
-use std::sync::atomic::Ordering;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-pub async fn channels_status(State(state): State<Arc<AppState>>) -> Json<ChannelsResponse> {
-    let mut channels = HashMap::new();
+/// How often a watched channel is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
 
-    // Include all routed channels (active or idle)
-    for entry in state.channel_routes.iter() {
-        let channel_id = entry.key().clone();
-        let status = if let Some(active) = state.active_channels.get(&channel_id) {
-            ChannelStatus {
-                state: "active".to_string(),
-                clients: active.clients.len() as u32,
-                upstream: Some(UpstreamStatus {
-                    stream_id: active.stream_id,
-                    account_id: active.account_id,
-                    url: active.url.clone(),
-                    connected_since: format_instant(active.connected_since),
-                    bytes_transferred: active.bytes_transferred.load(Ordering::Relaxed),
-                }),
-            }
-        } else {
-            ChannelStatus {
-                state: "idle".to_string(),
-                clients: 0,
-                upstream: None,
-            }
-        };
-        channels.insert(channel_id, status);
+pub async fn channels_status(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ChannelListParams>,
+) -> Json<ChannelsResponse> {
+    // Include all routed channels (active or idle), in id order so pages
+    // are stable. Hibernated channels are read without hydrating them.
+    let routes = state.channel_routes.load();
+    let mut ids = routes.ids();
+    ids.sort();
+    if params.tag.is_some() || params.group.is_some() || params.state.is_some() {
+        ids.retain(|id| routes.peek(id, |r| listed(&state, &params, id, r)) == Some(true));
+    }
+    let total_channels = ids.len();
+    if let Some(per_page) = params.per_page {
+        let page = params.page.unwrap_or(1).max(1);
+        ids = ids
+            .into_iter()
+            .skip((page - 1).saturating_mul(per_page))
+            .take(per_page)
+            .collect();
     }
+    let channels = ids
+        .into_iter()
+        .filter_map(|id| {
+            let listing = routes.peek(&id, |routing| {
+                if params.summary {
+                    ChannelListing::Summary(ChannelSummary {
+                        state: channel_state(&state, &id, routing),
+                        clients: state
+                            .active_channels
+                            .get(&id)
+                            .map_or(0, |a| a.clients.len() as u32),
+                    })
+                } else {
+                    ChannelListing::Full(Box::new(channel_status(&state, &id, routing)))
+                }
+            })?;
+            Some((id, listing))
+        })
+        .collect();
 
     let mut accounts = HashMap::new();
-    for entry in state.accounts.iter() {
+    for entry in state.accounts.load().iter() {
+        let account = entry.value();
+        let near_capacity_since = account
+            .near_capacity_since
+            .lock()
+            .unwrap()
+            .map(format_instant);
+        let retry_suspended_until = account
+            .retry_suspended_until
+            .lock()
+            .unwrap()
+            .filter(|until| tokio::time::Instant::now() < *until)
+            .map(format_instant);
         accounts.insert(
             entry.key().to_string(),
             AccountStatus {
-                active_connections: entry.value().active_connections.load(Ordering::Relaxed),
-                max_connections: entry.value().max_connections.load(Ordering::Relaxed),
+                active_connections: account.active_connections.load(Ordering::Relaxed),
+                remote_connections: account.remote_connections.load(Ordering::Relaxed),
+                max_connections: account.max_connections.load(Ordering::Relaxed),
+                peak_connections: account.peak_connections.load(Ordering::Relaxed),
+                utilization_percent: account.utilization_percent(),
+                near_capacity_since,
+                capacity_warning: account.capacity_warning.load(Ordering::Relaxed),
+                enabled: account.enabled.load(Ordering::Relaxed),
+                retry_suspended_until,
             },
         );
     }
 
-    Json(ChannelsResponse { channels, accounts })
+    let groups = state
+        .group_limits
+        .iter()
+        .map(|entry| {
+            let status = GroupStatus {
+                active_channels: state.group_active_channels(entry.key()),
+                max_active_channels: *entry.value(),
+            };
+            (entry.key().clone(), status)
+        })
+        .collect();
+
+    Json(ChannelsResponse {
+        channels,
+        total_channels,
+        accounts,
+        groups,
+    })
 }
 
 pub async fn channel_detail(
     State(state): State<Arc<AppState>>,
     Path(channel_id): Path<String>,
 ) -> Result<Json<ChannelDetailResponse>, StatusCode> {
-    if let Some(active) = state.active_channels.get(&channel_id) {
+    detail(&state, &channel_id)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Stream a channel's detail as server-sent events: the current detail
+/// first, then again whenever its state, upstream, clients, quota or tags
+/// change. A `deleted` event ends the stream if the channel is removed.
+pub async fn watch_channel(
+    State(state): State<Arc<AppState>>,
+    Path(channel_id): Path<String>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    let updates = watch(state, channel_id.clone()).ok_or(StatusCode::NOT_FOUND)?;
+    let events = updates.map(move |update| {
+        Ok(match update {
+            Some(detail) => detail_event(&detail),
+            None => Event::default().event("deleted").data(channel_id.as_str()),
+        })
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// A channel's detail now and after every change worth a watch event, then
+/// None once if the channel is removed. None if there is no such channel.
+pub fn watch(
+    state: Arc<AppState>,
+    channel_id: String,
+) -> Option<impl Stream<Item = Option<ChannelDetailResponse>>> {
+    let first = detail(&state, &channel_id)?;
+    Some(async_stream::stream! {
+        let mut last = WatchKey::of(&first);
+        yield Some(first);
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let Some(detail) = detail(&state, &channel_id) else {
+                yield None;
+                break;
+            };
+            let key = WatchKey::of(&detail);
+            if key != last {
+                last = key;
+                yield Some(detail);
+            }
+        }
+    })
+}
+
+/// The parts of a channel's detail whose change is worth a watch event;
+/// counters and bitrates move all the time and are left out
+#[derive(PartialEq)]
+struct WatchKey {
+    state: String,
+    upstream: Option<(u64, u64, String, bool)>,
+    clients: Vec<(String, bool)>,
+    quota_exceeded: Option<String>,
+    tags: Vec<String>,
+}
+
+impl WatchKey {
+    fn of(detail: &ChannelDetailResponse) -> Self {
+        let status = &detail.status;
+        let mut clients: Vec<(String, bool)> = detail
+            .clients
+            .iter()
+            .map(|c| (c.id.clone(), c.lagging))
+            .collect();
+        clients.sort();
+        Self {
+            state: status.state.clone(),
+            upstream: status
+                .upstream
+                .as_ref()
+                .map(|u| (u.stream_id, u.account_id, u.url.clone(), u.premium)),
+            clients,
+            quota_exceeded: status.quota.as_ref().and_then(|q| q.exceeded.clone()),
+            tags: status.tags.clone(),
+        }
+    }
+}
+
+fn detail_event(detail: &ChannelDetailResponse) -> Event {
+    let data = serde_json::to_string(detail).expect("channel detail serializes");
+    Event::default().event("status").data(data)
+}
+
+fn detail(state: &AppState, channel_id: &str) -> Option<ChannelDetailResponse> {
+    let routes = state.channel_routes.load();
+    let routing = routes.get(channel_id);
+    let quota = routing
+        .as_ref()
+        .and_then(|r| quota_status(state, channel_id, r));
+    let tags = routing.as_ref().map(|r| r.tags.clone()).unwrap_or_default();
+    let lag_disconnects = channel_counter(state, channel_id, |c| &c.lag_disconnects);
+    let keepalive_packets = channel_counter(state, channel_id, |c| &c.keepalive_packets);
+    if let Some(active) = state.active_channels.get(channel_id) {
         let clients: Vec<ClientInfo> = active
             .clients
             .iter()
@@ -64,40 +219,495 @@ pub async fn channel_detail(
                 connected_since: format_instant(c.connected_since),
                 bytes_sent: c.bytes_sent.load(Ordering::Relaxed),
                 remote_addr: c.remote_addr.clone(),
+                label: c.label.clone(),
+                audio_only: c.audio_only,
+                geo: c
+                    .remote_addr
+                    .parse::<std::net::SocketAddr>()
+                    .ok()
+                    .and_then(|a| state.geo.lookup(a.ip())),
+                lag_events: c.lag_events.load(Ordering::Relaxed),
+                lag_bytes: c.lag_bytes.load(Ordering::Relaxed),
+                queue_depth: c.queue_depth.load(Ordering::Relaxed),
+                bitrate_bps: c.send_rate.lock().unwrap().bits_per_sec(),
+                bandwidth_bps: c.bandwidth.lock().unwrap().bits_per_sec(),
+                lagging: c.lagging.load(Ordering::Relaxed),
+                playback: c.playback.lock().unwrap().as_ref().map(|p| PlaybackInfo {
+                    buffer_seconds: p.buffer_seconds,
+                    dropped_frames: p.dropped_frames,
+                    reported_at: format_instant(p.at),
+                }),
             })
             .collect();
 
-        Ok(Json(ChannelDetailResponse {
+        return Some(ChannelDetailResponse {
             status: ChannelStatus {
                 state: "active".to_string(),
                 clients: active.clients.len() as u32,
-                upstream: Some(UpstreamStatus {
-                    stream_id: active.stream_id,
-                    account_id: active.account_id,
-                    url: active.url.clone(),
-                    connected_since: format_instant(active.connected_since),
-                    bytes_transferred: active.bytes_transferred.load(Ordering::Relaxed),
-                }),
+                upstream: Some(upstream_status(&active)),
+                quota,
+                tags,
+                qoe: qoe::playback_summary(&active),
+                quality: active.quality.lock().unwrap().score.clone(),
+                lag_disconnects,
+                keepalive_packets,
             },
             clients,
-        }))
-    } else if state.channel_routes.contains_key(&channel_id) {
-        Ok(Json(ChannelDetailResponse {
-            status: ChannelStatus {
-                state: "idle".to_string(),
-                clients: 0,
-                upstream: None,
+            ts_health: Some(active.ts_health.lock().unwrap().health()),
+        });
+    }
+    let routing = routing?;
+    Some(ChannelDetailResponse {
+        status: ChannelStatus {
+            state: idle_state(&routing, quota.as_ref()),
+            clients: 0,
+            upstream: None,
+            quota,
+            tags,
+            qoe: None,
+            quality: None,
+            lag_disconnects,
+            keepalive_packets,
+        },
+        clients: vec![],
+        ts_health: None,
+    })
+}
+
+/// Receive counters of an active channel's upstream since it started
+pub async fn upstream_stats(
+    State(state): State<Arc<AppState>>,
+    Path(channel_id): Path<String>,
+) -> Result<Json<UpstreamStatsResponse>, StatusCode> {
+    let active = state
+        .active_channels
+        .get(&channel_id)
+        .map(|a| a.clone())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let stats = active.receive_stats.lock().unwrap();
+    Ok(Json(UpstreamStatsResponse {
+        channel_id,
+        started_at: format_instant(active.connected_since),
+        chunks_received: stats.chunks,
+        bytes_received: stats.bytes,
+        average_chunk_bytes: stats.bytes.checked_div(stats.chunks).unwrap_or(0),
+        gaps_over_1s: stats.gaps,
+        longest_gap_ms: stats.longest_gap.as_millis() as u64,
+        reconnects: stats.reconnects,
+        sources: stats.sources(&active.current_upstream()),
+    }))
+}
+
+/// Every configured stream URL across all channels with its connection health.
+pub async fn streams_status(State(state): State<Arc<AppState>>) -> Json<StreamsResponse> {
+    let mut streams = Vec::new();
+    let warn_days = state.config.tls_expiry_warn_days as i64;
+    let (cooldown, cooldown_max) = (state.config.url_cooldown, state.config.url_cooldown_max);
+    let now = tokio::time::Instant::now();
+    state.channel_routes.load().scan(|channel_id, entry| {
+        let current = state
+            .active_channels
+            .get(channel_id)
+            .map(|a| a.current_upstream());
+        let tiers = [
+            (false, false, &entry.streams),
+            (true, false, &entry.premium_streams),
+            (false, true, &entry.quota_streams),
+        ];
+        for (premium, quota, configs) in tiers {
+            for stream in configs.iter() {
+                for url in &stream.urls {
+                    let in_use = current.as_ref().is_some_and(|t| {
+                        t.stream_id == stream.id
+                            && t.account_id == url.account_id
+                            && t.url == url.url
+                    });
+                    let health = state.url_health.get(&url.url);
+                    let health = health.as_deref();
+                    let probe_status = match health {
+                        Some(h) if h.consecutive_failures > 0 => "failing",
+                        Some(h) if h.last_success_at.is_some() => "ok",
+                        _ => "untested",
+                    };
+                    streams.push(StreamUrlStatus {
+                        channel_id: channel_id.to_string(),
+                        stream_id: stream.id,
+                        account_id: url.account_id,
+                        url: url.url.clone(),
+                        premium,
+                        quota,
+                        in_use,
+                        probe_status: probe_status.to_string(),
+                        attempts: health.map_or(0, |h| h.attempts),
+                        connect_failures: health.map_or(0, |h| h.connect_failures),
+                        drops: health.map_or(0, |h| h.drops),
+                        consecutive_failures: health.map_or(0, |h| h.consecutive_failures),
+                        last_error: health.and_then(|h| h.last_error.clone()),
+                        last_error_at: health.and_then(|h| h.last_error_at).map(format_instant),
+                        last_success_at: health.and_then(|h| h.last_success_at).map(format_instant),
+                        cooldown_until: health
+                            .and_then(|h| h.cooldown_until(cooldown, cooldown_max))
+                            .filter(|until| *until > now)
+                            .map(format_instant),
+                        score: health.and_then(|h| h.score()),
+                        connect_latency_ms: health
+                            .and_then(|h| h.connect_latency)
+                            .map(|d| d.as_millis() as u64),
+                        bitrate_bps: health.and_then(|h| h.bitrate_bps),
+                        quality_score: health.and_then(|h| h.quality_score),
+                        certificate: health
+                            .and_then(|h| h.certificate.as_ref())
+                            .map(|c| certificate_status(c, warn_days)),
+                    });
+                }
+            }
+        }
+    });
+    streams.sort_by(|a, b| (&a.channel_id, a.stream_id).cmp(&(&b.channel_id, b.stream_id)));
+    Json(StreamsResponse { streams })
+}
+
+pub async fn health(State(state): State<Arc<AppState>>) -> Json<HealthResponse> {
+    let elapsed = state.start_time.elapsed().as_secs();
+    let active = state.active_channels.len();
+    let clients: u32 = state
+        .active_channels
+        .iter()
+        .map(|c| c.clients.len() as u32)
+        .sum();
+    let routes = state.channel_routes.load();
+
+    Json(HealthResponse {
+        status: "ok".to_string(),
+        uptime_seconds: elapsed,
+        active_channels: active,
+        total_clients: clients,
+        routed_channels: routes.len(),
+        hibernated_channels: routes.cold_len(),
+    })
+}
+
+/// Readiness probe: 503 until the persistent-channel warm-up has finished.
+pub async fn ready(State(state): State<Arc<AppState>>) -> (StatusCode, Json<ReadyResponse>) {
+    let warmup = warmup_status(&state);
+    let code = if warmup.in_progress {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (
+        code,
+        Json(ReadyResponse {
+            ready: !warmup.in_progress,
+            warmup,
+        }),
+    )
+}
+
+/// Raw internal state dump for diagnosing stuck channels (admin only).
+pub async fn debug_state(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+) -> Result<Json<DebugStateResponse>, StatusCode> {
+    auth::require_admin(&state, &headers)?;
+
+    let mut routes = HashMap::new();
+    state.channel_routes.load().scan(|id, r| {
+        routes.insert(
+            id.to_string(),
+            DebugRouting {
+                streams: r.streams.clone(),
+                enabled: r.enabled,
+                premium_streams: r.premium_streams.clone(),
+                premium_threshold: r.premium_threshold,
+                persistent: r.persistent,
+                priority: r.priority,
+                vod: r.vod,
+                auth_callback: r.auth_callback.clone(),
+                high_watermark: r.high_watermark,
+                low_watermark: r.low_watermark,
+                daily_quota_bytes: r.daily_quota_bytes,
+                monthly_quota_bytes: r.monthly_quota_bytes,
+                quota_streams: r.quota_streams.clone(),
+                hls_passthrough: r.hls_passthrough,
+                hls_encryption: r.hls_encryption,
+                subtitles: r.subtitles.clone(),
+                audio_language_priority: r.audio_language_priority.clone(),
+                tags: r.tags.clone(),
+                group: r.group.clone(),
+                max_clients_per_ip: r.max_clients_per_ip,
+                max_lag_events: r.max_lag_events,
+                max_lag_bytes: r.max_lag_bytes,
+                keepalive_after_ms: r.keepalive_after_ms,
+                failover: r.failover.clone(),
+                multicast_group: r.multicast_group.clone(),
             },
-            clients: vec![],
-        }))
+        );
+    });
+
+    let active_channels = state
+        .active_channels
+        .iter()
+        .map(|e| {
+            let active = e.value();
+            let target = active.current_upstream();
+            let (high_watermark, low_watermark) = state.watermarks(e.key());
+            (
+                e.key().clone(),
+                DebugActiveChannel {
+                    stream_id: target.stream_id,
+                    account_id: target.account_id,
+                    url: target.url,
+                    premium: target.premium,
+                    persistent: active.persistent,
+                    connected_since: format_instant(active.connected_since),
+                    bytes_transferred: active.bytes_transferred.load(Ordering::Relaxed),
+                    clients: active.clients.len(),
+                    broadcast_receivers: active.sender.receiver_count(),
+                    broadcast_queue_depth: active.sender.len(),
+                    high_watermark,
+                    low_watermark,
+                    backpressure_paused: active.backpressure_paused.load(Ordering::Relaxed),
+                    backpressure_pauses: active.backpressure_pauses.load(Ordering::Relaxed),
+                    gop_cache_chunks: active.gop_cache.lock().unwrap().len(),
+                    keyframes_seen: active.keyframes_seen.load(Ordering::Relaxed),
+                    stop_signalled: *active.stop_tx.borrow(),
+                    task_running: active.task_running.load(Ordering::Relaxed),
+                    idle_seconds: active.idle_for().as_secs_f64(),
+                },
+            )
+        })
+        .collect();
+
+    let accounts = state
+        .accounts
+        .load()
+        .iter()
+        .map(|e| {
+            let a = e.value();
+            (
+                e.key().to_string(),
+                DebugAccount {
+                    max_connections: a.max_connections.load(Ordering::Relaxed),
+                    active_connections: a.active_connections.load(Ordering::Relaxed),
+                    peak_connections: a.peak_connections.load(Ordering::Relaxed),
+                    capacity_warning: a.capacity_warning.load(Ordering::Relaxed),
+                    enabled: a.enabled.load(Ordering::Relaxed),
+                },
+            )
+        })
+        .collect();
+
+    let vod_sessions = state
+        .vod_sessions
+        .iter()
+        .map(|e| (e.key().clone(), *e.value()))
+        .collect();
+
+    Ok(Json(DebugStateResponse {
+        uptime_seconds: state.start_time.elapsed().as_secs(),
+        routes,
+        active_channels,
+        accounts,
+        vod_sessions,
+        start_permits_available: state.start_permits.available_permits(),
+        warmup: warmup_status(&state),
+        auth_cache_entries: state.auth_cache.len(),
+    }))
+}
+
+/// Tokio runtime metrics and per-channel upstream task health.
+pub async fn metrics(State(state): State<Arc<AppState>>) -> Json<MetricsResponse> {
+    let snapshot = state.runtime_metrics.lock().unwrap().clone();
+    let mean_worker_utilization = if snapshot.worker_utilization.is_empty() {
+        0.0
+    } else {
+        snapshot.worker_utilization.iter().sum::<f64>() / snapshot.worker_utilization.len() as f64
+    };
+
+    let channels = state
+        .active_channels
+        .iter()
+        .map(|e| {
+            let active = e.value();
+            (
+                e.key().clone(),
+                ChannelTaskHealth {
+                    task_running: active.task_running.load(Ordering::Relaxed),
+                    idle_seconds: active.idle_for().as_secs_f64(),
+                    clients: active.clients.len(),
+                    broadcast_queue_depth: active.sender.len(),
+                    backpressure_paused: active.backpressure_paused.load(Ordering::Relaxed),
+                    backpressure_pauses: active.backpressure_pauses.load(Ordering::Relaxed),
+                },
+            )
+        })
+        .collect();
+
+    Json(MetricsResponse {
+        runtime: RuntimeMetrics {
+            workers: snapshot.workers,
+            alive_tasks: snapshot.alive_tasks,
+            global_queue_depth: snapshot.global_queue_depth,
+            worker_utilization: snapshot.worker_utilization,
+            mean_worker_utilization,
+            scheduler_delay_ms: snapshot.scheduler_delay.as_secs_f64() * 1000.0,
+            scheduler_delay_max_ms: snapshot.scheduler_delay_max.as_secs_f64() * 1000.0,
+        },
+        channels,
+    })
+}
+
+fn warmup_status(state: &AppState) -> WarmupStatus {
+    let warmup = &state.warmup;
+    let total = warmup.total.load(Ordering::Relaxed);
+    let started = warmup.started.load(Ordering::Relaxed);
+    WarmupStatus {
+        in_progress: warmup.in_progress.load(Ordering::Relaxed),
+        total,
+        started,
+        pending: total.saturating_sub(started),
+    }
+}
+
+/// Whether a channel passes the listing's tag, group and state filters
+fn listed(
+    state: &AppState,
+    params: &ChannelListParams,
+    channel_id: &str,
+    routing: &ChannelRouting,
+) -> bool {
+    params.tag.as_ref().is_none_or(|t| routing.tags.contains(t))
+        && params
+            .group
+            .as_ref()
+            .is_none_or(|g| routing.group.as_ref() == Some(g))
+        && params
+            .state
+            .as_ref()
+            .is_none_or(|s| *s == channel_state(state, channel_id, routing))
+}
+
+fn channel_state(state: &AppState, channel_id: &str, routing: &ChannelRouting) -> String {
+    if state.active_channels.contains_key(channel_id) {
+        "active".to_string()
+    } else {
+        idle_state(routing, quota_status(state, channel_id, routing).as_ref())
+    }
+}
+
+fn channel_status(state: &AppState, channel_id: &str, routing: &ChannelRouting) -> ChannelStatus {
+    let tags = routing.tags.clone();
+    let quota = quota_status(state, channel_id, routing);
+    let lag_disconnects = channel_counter(state, channel_id, |c| &c.lag_disconnects);
+    let keepalive_packets = channel_counter(state, channel_id, |c| &c.keepalive_packets);
+    if let Some(active) = state.active_channels.get(channel_id) {
+        ChannelStatus {
+            state: "active".to_string(),
+            clients: active.clients.len() as u32,
+            upstream: Some(upstream_status(&active)),
+            quota,
+            tags,
+            qoe: qoe::playback_summary(&active),
+            quality: active.quality.lock().unwrap().score.clone(),
+            lag_disconnects,
+            keepalive_packets,
+        }
     } else {
-        Err(StatusCode::NOT_FOUND)
+        ChannelStatus {
+            state: idle_state(routing, quota.as_ref()),
+            clients: 0,
+            upstream: None,
+            quota,
+            tags,
+            qoe: None,
+            quality: None,
+            lag_disconnects,
+            keepalive_packets,
+        }
+    }
+}
+
+/// State reported for a routed channel with no upstream running
+fn idle_state(routing: &ChannelRouting, quota: Option<&QuotaStatus>) -> String {
+    let state = if !routing.enabled {
+        "disabled"
+    } else if quota.is_some_and(|q| q.exceeded.is_some()) && routing.quota_streams.is_empty() {
+        "quota_exceeded"
+    } else {
+        "idle"
+    };
+    state.to_string()
+}
+
+/// Upstream byte usage against the channel's quotas (None if it has none)
+fn quota_status(
+    state: &AppState,
+    channel_id: &str,
+    routing: &ChannelRouting,
+) -> Option<QuotaStatus> {
+    if !routing.has_quota() {
+        return None;
+    }
+    let (daily_bytes, monthly_bytes) = state
+        .channel_usage
+        .get(channel_id)
+        .map_or((0, 0), |u| u.totals(chrono::Utc::now().date_naive()));
+    Some(QuotaStatus {
+        daily_bytes,
+        daily_limit: routing.daily_quota_bytes,
+        monthly_bytes,
+        monthly_limit: routing.monthly_quota_bytes,
+        exceeded: routing
+            .quota_exceeded(daily_bytes, monthly_bytes)
+            .map(str::to_string),
+    })
+}
+
+fn upstream_status(active: &ActiveChannel) -> UpstreamStatus {
+    let target = active.current_upstream();
+    UpstreamStatus {
+        stream_id: target.stream_id,
+        account_id: target.account_id,
+        url: target.url,
+        premium: target.premium,
+        connected_since: format_instant(active.connected_since),
+        bytes_transferred: active.bytes_transferred.load(Ordering::Relaxed),
+        input_bitrate_bps: active.input_rate.lock().unwrap().bits_per_sec(),
+        output_bitrate_bps: active.output_rate.lock().unwrap().bits_per_sec(),
+    }
+}
+
+/// One of the channel's cumulative counters, since they were last reset
+fn channel_counter(
+    state: &AppState,
+    channel_id: &str,
+    counter: fn(&ChannelCounters) -> &AtomicU64,
+) -> u64 {
+    state
+        .channel_counters
+        .get(channel_id)
+        .map_or(0, |c| counter(&c).load(Ordering::Relaxed))
+}
+
+fn certificate_status(certificate: &CertificateInfo, warn_days: i64) -> CertificateStatus {
+    let days_remaining = certificate.days_remaining();
+    CertificateStatus {
+        subject: certificate.subject.clone(),
+        issuer: certificate.issuer.clone(),
+        expires_at: certificate.not_after.to_rfc3339(),
+        days_remaining,
+        expiring: days_remaining < warn_days,
     }
 }
 
 fn format_instant(instant: tokio::time::Instant) -> String {
-    let elapsed = instant.elapsed();
-    let system_time = std::time::SystemTime::now() - elapsed;
+    let now = tokio::time::Instant::now();
+    let system_time = if instant <= now {
+        std::time::SystemTime::now() - (now - instant)
+    } else {
+        std::time::SystemTime::now() + (instant - now)
+    };
     let datetime: chrono::DateTime<chrono::Utc> = system_time.into();
     datetime.to_rfc3339()
 }
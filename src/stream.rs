@@ -1,18 +1,36 @@
-use crate::state::{AppState, ClientState};
+use crate::auth;
+use crate::feed::ClientFeed;
+use crate::hls;
+use crate::models::{EventKind, HeartbeatRequest, StreamParams};
+use crate::state::{AppState, ClientState, IpSlot, PlaybackReport};
+use crate::tenant;
+use crate::ts;
 use crate::upstream;
+use crate::vod;
+use crate::{CLIENT_ID_HEADER, CLIENT_LABEL_HEADER, REQUEST_ID_HEADER, SESSION_SECRET_HEADER};
 use axum::{
     body::Body,
-    extract::{ConnectInfo, Path, State},
-    http::{header, StatusCode},
-    response::{IntoResponse, Response},
+    extract::{ConnectInfo, Path, Query, RawQuery, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    Json,
 };
 use bytes::Bytes;
-use std::net::SocketAddr;
-use std::sync::atomic::{AtomicU64, Ordering};
+use dashmap::mapref::entry::Entry;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, Notify};
 use tokio::time::Instant;
 
+/// Lag events after which a client is moved to the lagging tier
+const LAG_TIER_THRESHOLD: u32 = 3;
+/// Consecutive caught-up chunks before a lagging client returns to normal delivery
+const LAG_RECOVERY_CHUNKS: u32 = 50;
+
+/// Longest client label kept, in characters
+const MAX_LABEL_CHARS: usize = 64;
+
 /// TS null packet (188 bytes) used as keepalive
 fn ts_null_packet() -> Bytes {
     let mut pkt = vec![0u8; 188];
@@ -23,111 +41,596 @@ fn ts_null_packet() -> Bytes {
     Bytes::from(pkt)
 }
 
+/// Source of per-connection ids, used to tell a resumed session's new
+/// connection apart from the one it replaced
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
 /// Guard that cleans up client state when dropped (i.e. when client disconnects)
 struct ClientGuard {
     channel_id: String,
     client_id: String,
+    conn_id: u64,
+    request_id: Option<String>,
     active: Arc<crate::state::ActiveChannel>,
-    state: Arc<AppState>,
     bytes_sent: Arc<AtomicU64>,
+    idle_grace: std::time::Duration,
+    state: Arc<AppState>,
+    _ip_slot: IpSlot,
 }
 
 impl Drop for ClientGuard {
     fn drop(&mut self) {
-        self.active.clients.remove(&self.client_id);
+        // A resumed session belongs to a newer connection now — leave it alone
+        let removed = self
+            .active
+            .clients
+            .remove_if(&self.client_id, |_, c| c.conn_id == self.conn_id);
+        if removed.is_none() {
+            tracing::info!(
+                "Channel {}: client {} previous connection closed (session resumed elsewhere)",
+                self.channel_id,
+                self.client_id
+            );
+            return;
+        }
+        self.state
+            .client_channels
+            .remove_if(&self.client_id, |_, channel_id| {
+                *channel_id == self.channel_id
+            });
+        let bytes_sent = self.bytes_sent.load(Ordering::Relaxed);
         tracing::info!(
             "Channel {}: client {} disconnected (sent {} bytes)",
             self.channel_id,
             self.client_id,
-            self.bytes_sent.load(Ordering::Relaxed)
+            bytes_sent
+        );
+        self.state.events.record(
+            EventKind::ClientDisconnected,
+            &self.channel_id,
+            Some(&self.client_id),
+            self.request_id.as_deref(),
+            format!("sent {} bytes", bytes_sent),
         );
 
-        // If last client, stop the channel immediately
         if self.active.clients.is_empty() {
-            tracing::info!("Channel {}: no clients remaining, stopping", self.channel_id);
-            let _ = self.active.stop_tx.send(true);
+            stop_if_idle(&self.active, self.idle_grace);
         }
     }
 }
 
+/// Stop a channel its last client just left (persistent channels keep
+/// running), after the grace period if one is configured
+pub fn stop_if_idle(active: &Arc<crate::state::ActiveChannel>, grace: std::time::Duration) {
+    if active.persistent {
+        return;
+    }
+    if grace.is_zero() {
+        tracing::info!(
+            "Channel {}: no clients remaining, stopping",
+            active.channel_id
+        );
+        let _ = active.stop_tx.send(true);
+        return;
+    }
+
+    let generation = active.idle_generation.fetch_add(1, Ordering::Relaxed) + 1;
+    tracing::info!(
+        "Channel {}: no clients remaining, stopping in {}s unless one reattaches",
+        active.channel_id,
+        grace.as_secs()
+    );
+    let active = active.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(grace).await;
+        if active.clients.is_empty() && active.idle_generation.load(Ordering::Relaxed) == generation
+        {
+            tracing::info!(
+                "Channel {}: idle grace expired, stopping",
+                active.channel_id
+            );
+            let _ = active.stop_tx.send(true);
+        }
+    });
+}
+
+/// The connection currently holding a session id
+struct SessionHolder {
+    channel_id: String,
+    ip: Option<IpAddr>,
+    secret: Option<String>,
+}
+
+fn session_holder(state: &AppState, client_id: &str) -> Option<SessionHolder> {
+    let channel_id = state.client_channels.get(client_id)?.clone();
+    let active = state.active_channels.get(&channel_id)?;
+    let client = active.clients.get(client_id)?;
+    Some(SessionHolder {
+        ip: client
+            .remote_addr
+            .parse::<SocketAddr>()
+            .ok()
+            .map(|a| a.ip()),
+        secret: client.session_secret.clone(),
+        channel_id,
+    })
+}
+
+/// Clients currently watching the channel from `ip`
+fn channel_clients_from(state: &AppState, channel_id: &str, ip: IpAddr) -> usize {
+    state.active_channels.get(channel_id).map_or(0, |active| {
+        active
+            .clients
+            .iter()
+            .filter(|c| {
+                c.remote_addr
+                    .parse::<SocketAddr>()
+                    .is_ok_and(|a| a.ip() == ip)
+            })
+            .count()
+    })
+}
+
 pub async fn stream_channel(
     State(state): State<Arc<AppState>>,
     Path(channel_id): Path<String>,
+    uri: Uri,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Query(params): Query<StreamParams>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
 ) -> Response {
-    // Get or start the channel
-    let active = if let Some(existing) = state.active_channels.get(&channel_id) {
-        existing.value().clone()
-    } else {
-        // Select a stream + account
-        let (stream_id, account_id, url) = match state.select_stream(&channel_id) {
-            Some(s) => s,
-            None => {
-                return (StatusCode::SERVICE_UNAVAILABLE, "No streams available").into_response();
+    let Some(channel_id) = tenant::channel_id(&state.config, &uri, &headers, &channel_id) else {
+        return (StatusCode::NOT_FOUND, "Unknown channel").into_response();
+    };
+    if state
+        .channel_routes
+        .load()
+        .get(&channel_id)
+        .is_some_and(|r| !r.enabled)
+    {
+        return (StatusCode::FORBIDDEN, "Channel is off-air").into_response();
+    }
+
+    // One URL for every device: HLS players are sent to the channel's HLS
+    // output, everything else gets the TS stream
+    match OutputFormat::negotiate(params.format.as_deref(), &headers) {
+        Ok(OutputFormat::Ts) => {}
+        Ok(OutputFormat::Hls) => {
+            let query: Vec<&str> = query
+                .as_deref()
+                .unwrap_or_default()
+                .split('&')
+                .filter(|p| !p.is_empty() && !p.starts_with("format="))
+                .collect();
+            let mut location = format!("/stream/{}/index.m3u8", channel_id);
+            if !query.is_empty() {
+                location = format!("{}?{}", location, query.join("&"));
             }
-        };
+            return Redirect::temporary(&location).into_response();
+        }
+        Ok(OutputFormat::Mp4) => {
+            return (StatusCode::NOT_ACCEPTABLE, "fMP4 output is not available").into_response();
+        }
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    }
+
+    if let Err(denied) =
+        auth::authorize(&state, &channel_id, addr, &headers, params.token.as_deref()).await
+    {
+        return denied.into_response();
+    }
+
+    // File-backed channels are served per client so Range/seek works
+    let (is_vod, is_hls) = state
+        .channel_routes
+        .load()
+        .get(&channel_id)
+        .map_or((false, false), |r| (r.vod, r.hls_passthrough));
+    if is_hls {
+        return hls::serve_playlist(state, channel_id, params.token).await;
+    }
+
+    // A connected session may only be taken over with the secret issued to
+    // it; the proxy's own client ids can't be taken at all
+    let ip = addr.ip();
+    let session = params.session.as_deref().filter(|s| !s.is_empty());
+    let mut session_secret = None;
+    let mut resuming = false;
+    if let Some(session) = session {
+        if ClientState::is_internal_id(session) {
+            return (StatusCode::FORBIDDEN, "Reserved session id").into_response();
+        }
+        if let Some(holder) = session_holder(&state, session) {
+            let presented = params.session_secret.as_deref().or_else(|| {
+                headers
+                    .get(SESSION_SECRET_HEADER)
+                    .and_then(|v| v.to_str().ok())
+            });
+            let secret = holder.secret.filter(|secret| {
+                presented.is_some_and(|presented| auth::tokens_match(presented, secret))
+            });
+            let Some(secret) = secret else {
+                return (StatusCode::FORBIDDEN, "Session is held by another client")
+                    .into_response();
+            };
+            resuming = holder.channel_id == channel_id && holder.ip == Some(ip);
+            session_secret = Some(secret);
+        }
+    }
+
+    // Per-IP connection limits; a session resumed from the same address
+    // replaces its own connection rather than adding one
+    let channel_limit = state
+        .channel_routes
+        .load()
+        .get(&channel_id)
+        .and_then(|r| r.max_clients_per_ip);
+    let ip_slot = match channel_limit {
+        Some(limit) if !resuming && channel_clients_from(&state, &channel_id, ip) >= limit => None,
+        _ => state.acquire_ip_slot(ip, !resuming),
+    };
+    let Some(ip_slot) = ip_slot else {
+        tracing::warn!(
+            "Channel {}: rejected client from {}, too many connections from that address",
+            channel_id,
+            ip
+        );
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many connections from this address",
+        )
+            .into_response();
+    };
+
+    if is_vod {
+        return vod::serve(state, channel_id, headers, addr, ip_slot).await;
+    }
+
+    // Spread out join storms (everyone coming back at half-time)
+    if !state.pace_join(&channel_id).await {
+        tracing::debug!(
+            "Channel {}: join from {} turned away, queue full",
+            channel_id,
+            ip
+        );
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, "1")],
+            "Too many clients joining, retry shortly",
+        )
+            .into_response();
+    }
 
-        upstream::start_channel(state.clone(), channel_id.clone(), stream_id, account_id, url)
+    // Get or start the channel
+    let request_id = headers.get(REQUEST_ID_HEADER).and_then(|v| v.to_str().ok());
+    let active = match upstream::get_or_start_channel(&state, &channel_id, request_id) {
+        Some(active) => active,
+        None => {
+            let reason = if state.quota_exceeded(&channel_id).is_some() {
+                "Upstream quota exceeded"
+            } else if state.group_full(&channel_id) {
+                "Channel group limit reached"
+            } else {
+                "No streams available"
+            };
+            return (StatusCode::SERVICE_UNAVAILABLE, reason).into_response();
+        }
     };
 
-    // Subscribe to broadcast channel
-    let mut rx = active.sender.subscribe();
+    // Subscribe to broadcast channel, starting from the most recent keyframe
+    // (or the last few chunks if there is no cached GOP to start from).
+    // The GOP cache lock is held by the upstream while broadcasting, so the
+    // snapshot and the subscription line up exactly.
+    let (rx, mut join_chunks) = {
+        let gop = active.gop_cache.lock().unwrap();
+        let join_chunks = active.join_chunks(&gop, state.config.join_buffer_max_age);
+        (active.sender.subscribe(), join_chunks)
+    };
 
-    // Register client
-    let client_id = uuid::Uuid::new_v4().to_string();
+    // Register client (a session token keeps the same identity across reconnects)
+    let audio_only = params.audio_only();
+    let client_id = session
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let session_secret =
+        session_secret.unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+    let label = params
+        .label
+        .as_deref()
+        .or_else(|| {
+            headers
+                .get(CLIENT_LABEL_HEADER)
+                .and_then(|v| v.to_str().ok())
+        })
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(|l| l.chars().take(MAX_LABEL_CHARS).collect::<String>());
+    let conn_id = NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed);
     let client_bytes = Arc::new(AtomicU64::new(0));
-    active.clients.insert(
-        client_id.clone(),
-        ClientState {
-            id: client_id.clone(),
-            connected_since: Instant::now(),
-            bytes_sent: AtomicU64::new(0),
-            remote_addr: addr.to_string(),
-        },
-    );
+    let kick = Arc::new(Notify::new());
+    let queue_depth = Arc::new(AtomicUsize::new(0));
+    let mut feed = ClientFeed::new(rx, state.config.client_queue_chunks, queue_depth.clone());
+    let mut client = ClientState {
+        id: client_id.clone(),
+        conn_id,
+        connected_since: Instant::now(),
+        bytes_sent: AtomicU64::new(0),
+        remote_addr: addr.to_string(),
+        label,
+        audio_only,
+        kick: kick.clone(),
+        lag_events: AtomicU64::new(0),
+        lag_bytes: AtomicU64::new(0),
+        lagging: AtomicBool::new(false),
+        queue_depth: queue_depth.clone(),
+        send_rate: Default::default(),
+        bandwidth: Default::default(),
+        playback: std::sync::Mutex::new(None),
+        session_secret: Some(session_secret.clone()),
+    };
 
-    tracing::info!(
-        "Channel {}: client {} connected from {}",
-        channel_id,
-        client_id,
-        addr
+    match active.clients.entry(client_id.clone()) {
+        Entry::Occupied(mut previous) => {
+            // Session resumed: carry over its history and retire the old
+            // connection (unless someone else took the id since the check)
+            let old = previous.get();
+            if old.session_secret.as_deref() != Some(session_secret.as_str()) {
+                return (StatusCode::FORBIDDEN, "Session is held by another client")
+                    .into_response();
+            }
+            client.connected_since = old.connected_since;
+            if client.label.is_none() {
+                client.label = old.label.clone();
+            }
+            client
+                .bytes_sent
+                .store(old.bytes_sent.load(Ordering::Relaxed), Ordering::Relaxed);
+            let old_ip = old.remote_addr.parse::<SocketAddr>().ok().map(|a| a.ip());
+            if old_ip != Some(addr.ip()) {
+                tracing::info!(
+                    "Channel {}: client {} moved from {} to {}",
+                    channel_id,
+                    client_id,
+                    old.remote_addr,
+                    addr
+                );
+            } else {
+                tracing::info!(
+                    "Channel {}: client {} reconnected from {}",
+                    channel_id,
+                    client_id,
+                    addr
+                );
+            }
+            previous.insert(client).kick.notify_one();
+        }
+        Entry::Vacant(vacant) => {
+            tracing::info!(
+                "Channel {}: client {} connected from {}",
+                channel_id,
+                client_id,
+                addr
+            );
+            vacant.insert(client);
+        }
+    }
+    state
+        .client_channels
+        .insert(client_id.clone(), channel_id.clone());
+    state.events.record(
+        EventKind::ClientConnected,
+        &channel_id,
+        Some(&client_id),
+        request_id,
+        format!("from {}", addr),
     );
 
     // Create drop guard for cleanup on client disconnect
     let guard = ClientGuard {
         channel_id: channel_id.clone(),
         client_id: client_id.clone(),
+        conn_id,
+        request_id: request_id.map(str::to_string),
         active: active.clone(),
-        state: state.clone(),
         bytes_sent: client_bytes.clone(),
+        idle_grace: state.config.idle_grace,
+        state: state.clone(),
+        _ip_slot: ip_slot,
     };
 
+    // With nothing to send yet, wait for the first chunk so a source that
+    // never starts fails the request instead of feeding the player keepalives
+    let start_timeout = state.config.stream_start_timeout;
+    if join_chunks.is_empty() && !start_timeout.is_zero() {
+        let mut stopped = active.stop_tx.subscribe();
+        let first = tokio::time::timeout(start_timeout, async {
+            tokio::select! {
+                result = feed.recv() => result,
+                _ = stopped.wait_for(|stop| *stop) => Err(broadcast::error::RecvError::Closed),
+            }
+        });
+        match first.await {
+            Ok(Ok(chunk)) => {
+                join_chunks.extend(active.psi_chunk());
+                join_chunks.push(chunk);
+            }
+            Ok(Err(broadcast::error::RecvError::Lagged(_))) => {}
+            Ok(Err(broadcast::error::RecvError::Closed)) => {
+                return (StatusCode::BAD_GATEWAY, "Upstream failed").into_response();
+            }
+            Err(_) => {
+                tracing::warn!(
+                    "Channel {}: no data within {:?}, failing client {}",
+                    channel_id,
+                    start_timeout,
+                    client_id
+                );
+                return (
+                    StatusCode::GATEWAY_TIMEOUT,
+                    "Upstream did not start in time",
+                )
+                    .into_response();
+            }
+        }
+    }
+
     // Build streaming response body
     let client_bytes_clone = client_bytes.clone();
     let active_clone = active.clone();
     let client_id_clone = client_id.clone();
+    let mut audio_filter = audio_only.then(ts::AudioOnlyFilter::default);
+    let lag_limits = state.lag_limits(&channel_id);
+    let keepalive_after = state.keepalive_after(&channel_id);
+    let channel_id_clone = channel_id.clone();
 
     let body_stream = async_stream::stream! {
         // Hold the guard — it will run cleanup when this stream is dropped
         let _guard = guard;
         let keepalive = ts_null_packet();
-        let mut keepalive_interval = tokio::time::interval(std::time::Duration::from_millis(500));
+        // Keepalives only fill gaps: the deadline moves with every chunk
+        let mut keepalive_due = tokio::time::Instant::now() + keepalive_after;
+        let mut lag_events: u32 = 0;
+        // Totals for this connection, checked against the channel's lag limits
+        let mut total_lag_events: u64 = 0;
+        let mut lag_bytes: u64 = 0;
+        let mut lagging = false;
+        let mut caught_up: u32 = 0;
+        // After losing data, drop chunks until the next keyframe so the player
+        // resumes on a clean picture (only if this channel has detectable keyframes)
+        let mut resync = false;
+
+        for chunk in join_chunks {
+            let data = match &mut audio_filter {
+                Some(filter) => filter.filter(&chunk.data),
+                None => chunk.data,
+            };
+            let len = data.len() as u64;
+            client_bytes_clone.fetch_add(len, Ordering::Relaxed);
+            active_clone.counters.bytes_out.fetch_add(len, Ordering::Relaxed);
+            if let Some(client) = active_clone.clients.get(&client_id_clone) {
+                client.bytes_sent.fetch_add(len, Ordering::Relaxed);
+            }
+            yield Ok::<_, std::io::Error>(data);
+        }
 
         loop {
             tokio::select! {
-                result = rx.recv() => {
+                result = feed.recv() => {
+                    queue_depth.store(feed.len(), Ordering::Relaxed);
                     match result {
-                        Ok(chunk) => {
-                            let len = chunk.len() as u64;
+                        Ok(mut chunk) => {
+                            keepalive_due = tokio::time::Instant::now() + keepalive_after;
+                            if lagging {
+                                // Lagging tier: skip any backlog and jump to the newest chunk
+                                let mut skipped = 0u64;
+                                let mut skipped_bytes = 0u64;
+                                loop {
+                                    match feed.try_recv() {
+                                        Ok(next) => {
+                                            skipped_bytes += chunk.data.len() as u64;
+                                            chunk = next;
+                                            skipped += 1;
+                                        }
+                                        Err(broadcast::error::TryRecvError::Lagged(n)) => {
+                                            skipped_bytes += n * upstream::CHUNK_SIZE as u64;
+                                            skipped += n;
+                                        }
+                                        Err(_) => break,
+                                    }
+                                }
+                                active_clone.counters.lag_drops.fetch_add(skipped, Ordering::Relaxed);
+                                lag_bytes += skipped_bytes;
+                                if let Some(client) = active_clone.clients.get(&client_id_clone) {
+                                    client.lag_bytes.fetch_add(skipped_bytes, Ordering::Relaxed);
+                                }
+                                if lag_limit_exceeded(total_lag_events, lag_bytes, lag_limits) {
+                                    active_clone.counters.lag_disconnects.fetch_add(1, Ordering::Relaxed);
+                                    tracing::warn!(
+                                        "Channel {}: disconnecting client {}, fell behind {} times and missed {} bytes",
+                                        channel_id_clone,
+                                        client_id_clone,
+                                        total_lag_events,
+                                        lag_bytes
+                                    );
+                                    break;
+                                }
+                                if skipped > 0 && !chunk.keyframe {
+                                    resync = active_clone.keyframes_seen.load(Ordering::Relaxed);
+                                }
+                                caught_up = if skipped == 0 { caught_up + 1 } else { 0 };
+                                if caught_up >= LAG_RECOVERY_CHUNKS {
+                                    lagging = false;
+                                    lag_events = 0;
+                                    if let Some(client) = active_clone.clients.get(&client_id_clone) {
+                                        client.lagging.store(false, Ordering::Relaxed);
+                                    }
+                                    tracing::info!("Client {} caught up, leaving lagging tier", client_id_clone);
+                                }
+                            }
+
+                            if resync {
+                                if !chunk.keyframe {
+                                    continue;
+                                }
+                                resync = false;
+                            }
+
+                            let data = match &mut audio_filter {
+                                Some(filter) => filter.filter(&chunk.data),
+                                None => chunk.data,
+                            };
+                            if data.is_empty() {
+                                continue;
+                            }
+                            let len = data.len() as u64;
                             client_bytes_clone.fetch_add(len, Ordering::Relaxed);
+                            active_clone.counters.bytes_out.fetch_add(len, Ordering::Relaxed);
                             if let Some(client) = active_clone.clients.get(&client_id_clone) {
                                 client.bytes_sent.fetch_add(len, Ordering::Relaxed);
                             }
-                            yield Ok::<_, std::io::Error>(chunk);
+                            // With data waiting, the write pace is the client's
+                            let backlogged = lagging || feed.len() > 0;
+                            let write_started = Instant::now();
+                            yield Ok::<_, std::io::Error>(data);
+                            if backlogged {
+                                if let Some(client) = active_clone.clients.get(&client_id_clone) {
+                                    client.bandwidth.lock().unwrap().record(len, write_started.elapsed());
+                                }
+                            }
                         }
                         Err(broadcast::error::RecvError::Lagged(n)) => {
                             tracing::warn!("Client {} lagged {} messages", client_id_clone, n);
-                            // Continue — client will catch up
+                            active_clone.counters.lag_drops.fetch_add(n, Ordering::Relaxed);
+                            lag_events += 1;
+                            total_lag_events += 1;
+                            let missed = n * upstream::CHUNK_SIZE as u64;
+                            lag_bytes += missed;
+                            caught_up = 0;
+                            resync = active_clone.keyframes_seen.load(Ordering::Relaxed);
+                            let enter_tier = !lagging && lag_events >= LAG_TIER_THRESHOLD;
+                            if let Some(client) = active_clone.clients.get(&client_id_clone) {
+                                client.lag_events.fetch_add(1, Ordering::Relaxed);
+                                client.lag_bytes.fetch_add(missed, Ordering::Relaxed);
+                                if enter_tier {
+                                    client.lagging.store(true, Ordering::Relaxed);
+                                }
+                            }
+                            if lag_limit_exceeded(total_lag_events, lag_bytes, lag_limits) {
+                                active_clone.counters.lag_disconnects.fetch_add(1, Ordering::Relaxed);
+                                tracing::warn!(
+                                    "Channel {}: disconnecting client {}, fell behind {} times and missed {} bytes",
+                                    channel_id_clone,
+                                    client_id_clone,
+                                    total_lag_events,
+                                    lag_bytes
+                                );
+                                break;
+                            }
+                            if enter_tier {
+                                lagging = true;
+                                tracing::info!("Client {} moved to lagging tier", client_id_clone);
+                            }
                         }
                         Err(broadcast::error::RecvError::Closed) => {
                             tracing::info!("Broadcast closed for client {}", client_id_clone);
@@ -135,8 +638,13 @@ pub async fn stream_channel(
                         }
                     }
                 }
-                _ = keepalive_interval.tick() => {
-                    // Only send keepalive if no data recently
+                _ = kick.notified() => {
+                    tracing::info!("Client {} connection terminated", client_id_clone);
+                    break;
+                }
+                _ = tokio::time::sleep_until(keepalive_due), if !keepalive_after.is_zero() => {
+                    keepalive_due = tokio::time::Instant::now() + keepalive_after;
+                    active_clone.counters.keepalive_packets.fetch_add(1, Ordering::Relaxed);
                     yield Ok::<_, std::io::Error>(keepalive.clone());
                 }
             }
@@ -144,11 +652,135 @@ pub async fn stream_channel(
         // Guard is dropped here too (normal exit), running cleanup
     };
 
-    Response::builder()
+    let mut response = Response::builder()
         .status(StatusCode::OK)
         .header(header::CONTENT_TYPE, "video/mp2t")
         .header(header::CACHE_CONTROL, "no-cache")
-        .header(header::CONNECTION, "keep-alive")
-        .body(Body::from_stream(body_stream))
-        .unwrap()
+        .header(header::CONNECTION, "keep-alive");
+    // A session token may not be a valid header value; players that chose
+    // one already know it
+    if let Ok(value) = header::HeaderValue::from_str(&client_id) {
+        response = response.header(CLIENT_ID_HEADER, value);
+    }
+    response = response.header(SESSION_SECRET_HEADER, session_secret);
+    response.body(Body::from_stream(body_stream)).unwrap()
+}
+
+/// Output formats a `/stream` request can ask for
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Ts,
+    Hls,
+    /// Recognised so it is refused with 406 rather than 400; the proxy
+    /// relays TS as is and has no remuxer to produce fMP4
+    Mp4,
+}
+
+impl OutputFormat {
+    /// The format from `?format=`, else the most preferred supported type in
+    /// the Accept header, else TS (so players sending odd or no Accept
+    /// headers keep working)
+    fn negotiate(format: Option<&str>, headers: &HeaderMap) -> Result<Self, String> {
+        if let Some(format) = format {
+            return match format.to_ascii_lowercase().as_str() {
+                "ts" => Ok(Self::Ts),
+                "hls" | "m3u8" => Ok(Self::Hls),
+                "mp4" | "fmp4" => Ok(Self::Mp4),
+                other => Err(format!("Unknown format {:?}", other)),
+            };
+        }
+        let accept = headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let mut best: Option<(f32, Self)> = None;
+        for range in accept.split(',') {
+            let mut parts = range.split(';').map(str::trim);
+            let media = parts.next().unwrap_or_default().to_ascii_lowercase();
+            let q = parts
+                .find_map(|p| p.strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let format = match media.as_str() {
+                "video/mp2t" | "video/*" | "*/*" => Self::Ts,
+                "application/vnd.apple.mpegurl" | "application/x-mpegurl" | "audio/mpegurl" => {
+                    Self::Hls
+                }
+                _ => continue,
+            };
+            if q > 0.0 && best.is_none_or(|(best_q, _)| q > best_q) {
+                best = Some((q, format));
+            }
+        }
+        Ok(best.map_or(Self::Ts, |(_, format)| format))
+    }
+}
+
+/// Whether a client's lag on this connection reached either of the
+/// channel's `(events, bytes)` limits (0 = no limit)
+fn lag_limit_exceeded(events: u64, bytes: u64, (max_events, max_bytes): (u64, u64)) -> bool {
+    (max_events > 0 && events >= max_events) || (max_bytes > 0 && bytes >= max_bytes)
+}
+
+/// udpxy-style `/udp/{group}:{port}` (or `/rtp/...`) request, served from the
+/// multicast gateway channel configured for that group so set-top boxes set
+/// up for udpxy work unchanged. Groups without a gateway channel get 404.
+pub async fn udpxy(
+    State(state): State<Arc<AppState>>,
+    Path(group): Path<String>,
+    uri: Uri,
+    connect_info: ConnectInfo<SocketAddr>,
+    params: Query<StreamParams>,
+    query: RawQuery,
+    headers: HeaderMap,
+) -> Response {
+    let Ok(group) = group.parse::<SocketAddr>() else {
+        return (StatusCode::BAD_REQUEST, "Invalid group address").into_response();
+    };
+    let Some(channel_id) = state.channel_routes.load().multicast_channel(group) else {
+        return (StatusCode::NOT_FOUND, "No gateway channel for this group").into_response();
+    };
+    stream_channel(
+        State(state),
+        Path(channel_id),
+        uri,
+        connect_info,
+        params,
+        query,
+        headers,
+    )
+    .await
+}
+
+/// Playback stats from a player for its stream connection, identified by
+/// its `session` token or the `X-Client-Id` response header and
+/// authenticated with the `X-Session-Secret` it was given
+pub async fn heartbeat(
+    State(state): State<Arc<AppState>>,
+    Path(client_id): Path<String>,
+    headers: HeaderMap,
+    Json(report): Json<HeartbeatRequest>,
+) -> StatusCode {
+    let Some(channel_id) = state.client_channels.get(&client_id).map(|c| c.clone()) else {
+        return StatusCode::NOT_FOUND;
+    };
+    let Some(active) = state.active_channels.get(&channel_id).map(|a| a.clone()) else {
+        return StatusCode::NOT_FOUND;
+    };
+    let Some(client) = active.clients.get(&client_id) else {
+        return StatusCode::NOT_FOUND;
+    };
+    let presented = headers
+        .get(SESSION_SECRET_HEADER)
+        .and_then(|v| v.to_str().ok());
+    let authorized = presented.zip(client.session_secret.as_deref());
+    if !authorized.is_some_and(|(presented, secret)| auth::tokens_match(presented, secret)) {
+        return StatusCode::FORBIDDEN;
+    }
+    *client.playback.lock().unwrap() = Some(PlaybackReport {
+        buffer_seconds: report.buffer_seconds,
+        dropped_frames: report.dropped_frames,
+        at: Instant::now(),
+    });
+    StatusCode::NO_CONTENT
 }
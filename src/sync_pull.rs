@@ -0,0 +1,41 @@
+use crate::control;
+use crate::models::SyncRequest;
+use crate::state::AppState;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Timeout for fetching the sync payload
+const PULL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fetch the full sync payload from `url` and apply it as a pushed sync
+/// would be.
+pub async fn pull(state: &AppState, client: &reqwest::Client, url: &str) -> Result<(), String> {
+    let mut request = client.get(url).timeout(PULL_TIMEOUT);
+    if let Some(token) = &state.config.sync_token {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await.map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("HTTP {}", response.status()));
+    }
+    let payload: SyncRequest = response.json().await.map_err(|e| e.to_string())?;
+    control::replace_routing(state, "Pull sync", payload.channels, payload.accounts);
+    state.routing_changed.notify_one();
+    Ok(())
+}
+
+/// Spawn the task that pulls the sync payload every SYNC_INTERVAL_SECS.
+pub fn spawn_puller(state: Arc<AppState>, url: String) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        let mut interval = tokio::time::interval(state.config.sync_interval);
+        // The startup pull has just run
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = pull(&state, &client, &url).await {
+                tracing::warn!("Sync pull from {} failed: {}", url, e);
+            }
+        }
+    })
+}
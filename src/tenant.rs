@@ -0,0 +1,60 @@
+use crate::config::{Config, TenantConfig};
+use axum::http::uri::Authority;
+use axum::http::{header, HeaderMap, Uri};
+
+/// Separates a tenant's name from its own id in a channel id
+const SEPARATOR: char = ':';
+
+/// The channel a viewer request for `channel_id` addresses. On a tenant's
+/// hostname that is the channel in the tenant's namespace (ids already
+/// qualified with it, as in rewritten playlist URLs, are kept); elsewhere
+/// only channels outside every namespace can be reached. None if the
+/// request reaches into another tenant's namespace.
+pub fn channel_id(
+    config: &Config,
+    uri: &Uri,
+    headers: &HeaderMap,
+    channel_id: &str,
+) -> Option<String> {
+    let tenant = request_host(uri, headers)
+        .and_then(|host| config.tenants.iter().find(|t| t.serves_host(&host)));
+    match tenant {
+        Some(tenant) if local_id(tenant, channel_id).is_some() => Some(channel_id.to_string()),
+        Some(tenant) => Some(format!("{}{}{}", tenant.name, SEPARATOR, channel_id)),
+        None => for_channel(config, channel_id)
+            .is_none()
+            .then(|| channel_id.to_string()),
+    }
+}
+
+/// The tenant owning `channel_id` and the channel's id within its namespace
+pub fn for_channel<'a, 'c>(
+    config: &'c Config,
+    channel_id: &'a str,
+) -> Option<(&'c TenantConfig, &'a str)> {
+    config
+        .tenants
+        .iter()
+        .find_map(|tenant| Some((tenant, local_id(tenant, channel_id)?)))
+}
+
+fn local_id<'a>(tenant: &TenantConfig, channel_id: &'a str) -> Option<&'a str> {
+    channel_id
+        .strip_prefix(tenant.name.as_str())?
+        .strip_prefix(SEPARATOR)
+}
+
+/// Hostname the request was made to, from the URI authority (HTTP/2) or the
+/// Host header
+fn request_host(uri: &Uri, headers: &HeaderMap) -> Option<String> {
+    let authority = match uri.authority() {
+        Some(authority) => authority.clone(),
+        None => headers
+            .get(header::HOST)?
+            .to_str()
+            .ok()?
+            .parse::<Authority>()
+            .ok()?,
+    };
+    Some(authority.host().to_ascii_lowercase())
+}
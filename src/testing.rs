@@ -0,0 +1,1128 @@
+use crate::config::{Config, ListenerConfig, RouteGroup};
+use crate::server::{ProxyServer, RunningServer};
+use crate::ts::TS_PACKET_SIZE;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+
+/// Packets between keyframe (random access) markers
+const KEYFRAME_INTERVAL: u64 = 500;
+
+/// Behaviour of a mock upstream, adjustable while it runs
+pub struct MockBehavior {
+    /// Bytes per second delivered to each connection
+    pub bitrate: AtomicU64,
+    /// Answer new requests with this HTTP status (0 = 200 and stream)
+    pub fail_status: AtomicU16,
+    /// Milliseconds to wait before answering a stream request
+    pub response_delay_ms: AtomicU64,
+    /// Close each connection after this many bytes (0 = never)
+    pub drop_after_bytes: AtomicU64,
+    /// Stop sending data on open connections without closing them
+    pub stalled: AtomicBool,
+    /// Flag random access points (false = no detectable keyframes)
+    pub keyframes: AtomicBool,
+    /// Flag only every Nth random access point (0 or 1 = every one)
+    pub keyframes_every: AtomicU64,
+    /// Junk bytes sent ahead of the first packet, misaligning the stream
+    pub leading_junk: AtomicU64,
+    /// Skip a continuity counter value on every video packet
+    pub continuity_skips: AtomicBool,
+    /// Answer Range requests for the file with all of it (200), though it
+    /// advertises `Accept-Ranges: bytes`
+    pub ranges_ignored: AtomicBool,
+    /// Close the next file connection once it has sent the file up to this
+    /// byte (0 = never); cleared by that connection
+    pub file_drop_at: AtomicU64,
+    /// Close every file connection once it has sent this many bytes (0 = never)
+    pub file_drop_every: AtomicU64,
+    /// Answer requests without this Authorization header with 401
+    pub required_authorization: std::sync::Mutex<Option<String>>,
+    /// Headers of the latest stream request
+    pub last_request_headers: std::sync::Mutex<HeaderMap>,
+    /// URI of the latest stream request
+    pub last_request_uri: std::sync::Mutex<Option<Uri>>,
+    /// Requests received so far
+    pub connections: AtomicU32,
+    /// Connections currently streaming
+    pub open_connections: AtomicU32,
+}
+
+/// A local HTTP server producing an endless MPEG-TS stream at a fixed rate
+pub struct MockUpstream {
+    addr: SocketAddr,
+    behavior: Arc<MockBehavior>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl MockUpstream {
+    /// Start a mock upstream delivering `bitrate` bytes per second.
+    pub async fn start(bitrate: u64) -> Self {
+        let behavior = Arc::new(MockBehavior {
+            bitrate: AtomicU64::new(bitrate),
+            fail_status: AtomicU16::new(0),
+            response_delay_ms: AtomicU64::new(0),
+            drop_after_bytes: AtomicU64::new(0),
+            stalled: AtomicBool::new(false),
+            keyframes: AtomicBool::new(true),
+            keyframes_every: AtomicU64::new(0),
+            leading_junk: AtomicU64::new(0),
+            continuity_skips: AtomicBool::new(false),
+            ranges_ignored: AtomicBool::new(false),
+            file_drop_at: AtomicU64::new(0),
+            file_drop_every: AtomicU64::new(0),
+            required_authorization: std::sync::Mutex::new(None),
+            last_request_headers: std::sync::Mutex::new(HeaderMap::new()),
+            last_request_uri: std::sync::Mutex::new(None),
+            connections: AtomicU32::new(0),
+            open_connections: AtomicU32::new(0),
+        });
+        let app = Router::new()
+            .route("/stream.ts", get(mock_stream))
+            .route("/file.ts", get(mock_file_request))
+            .route("/hls/index.m3u8", get(mock_playlist))
+            .route("/hls/segment/{index}", get(mock_segment))
+            .with_state(behavior.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+        Self {
+            addr,
+            behavior,
+            task,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/stream.ts", self.addr)
+    }
+
+    /// URL of [`mock_file`], served with Range support
+    pub fn file_url(&self) -> String {
+        format!("http://{}/file.ts", self.addr)
+    }
+
+    /// URL of a live HLS media playlist with relative segment URIs
+    pub fn hls_url(&self) -> String {
+        format!("http://{}/hls/index.m3u8", self.addr)
+    }
+
+    pub fn behavior(&self) -> &MockBehavior {
+        &self.behavior
+    }
+
+    /// Make new requests fail with `status` (None restores normal streaming)
+    pub fn fail_with(&self, status: Option<StatusCode>) {
+        let code = status.map_or(0, |s| s.as_u16());
+        self.behavior.fail_status.store(code, Ordering::Relaxed);
+    }
+
+    pub fn connections(&self) -> u32 {
+        self.behavior.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn open_connections(&self) -> u32 {
+        self.behavior.open_connections.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for MockUpstream {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Sends the mock stream as UDP datagrams of seven TS packets each, the way a
+/// headend multicasts, optionally wrapped in RTP. Stops when dropped.
+pub struct MockMulticast {
+    port: u16,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl MockMulticast {
+    /// Start sending `bitrate` bytes per second to a free local port.
+    pub async fn start(bitrate: u64, rtp: bool) -> Self {
+        let socket = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // Reserve a free port for the receiver by binding and releasing it
+        let port = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let task = tokio::spawn(async move {
+            let tick = Duration::from_millis(20);
+            let mut interval = tokio::time::interval(tick);
+            let mut packet_index: u64 = 0;
+            let mut sequence: u16 = 0;
+            loop {
+                interval.tick().await;
+                let per_tick = bitrate * tick.as_millis() as u64 / 1000;
+                let datagrams = (per_tick / (7 * TS_PACKET_SIZE) as u64).max(1);
+                for _ in 0..datagrams {
+                    let mut datagram = Vec::with_capacity(12 + 7 * TS_PACKET_SIZE);
+                    if rtp {
+                        // Version 2, payload type 33 (MP2T), no CSRCs
+                        datagram.extend_from_slice(&[0x80, 33]);
+                        datagram.extend_from_slice(&sequence.to_be_bytes());
+                        datagram.extend_from_slice(&[0; 8]);
+                        sequence = sequence.wrapping_add(1);
+                    }
+                    for _ in 0..7 {
+                        datagram.extend_from_slice(&ts_packet(packet_index));
+                        packet_index += 1;
+                    }
+                    let _ = socket.send_to(&datagram, ("127.0.0.1", port)).await;
+                }
+            }
+        });
+        Self { port, task }
+    }
+
+    pub fn url(&self) -> String {
+        format!("udp://127.0.0.1:{}", self.port)
+    }
+
+    pub fn rtp_url(&self) -> String {
+        format!("rtp://127.0.0.1:{}", self.port)
+    }
+
+    /// Stop sending, as if the feed went down
+    pub fn stop(&self) {
+        self.task.abort();
+    }
+}
+
+impl Drop for MockMulticast {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A delivery received by a `MockWebhook`
+#[derive(Clone)]
+pub struct WebhookDelivery {
+    /// Path and query the request was made to
+    pub uri: Uri,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+/// A local HTTP endpoint recording every request made to it
+pub struct MockWebhook {
+    addr: SocketAddr,
+    received: Arc<WebhookLog>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct WebhookLog {
+    deliveries: std::sync::Mutex<Vec<WebhookDelivery>>,
+    response: std::sync::Mutex<String>,
+    /// Status to answer with (0 = 200)
+    status: AtomicU16,
+}
+
+impl MockWebhook {
+    pub async fn start() -> Self {
+        let received = Arc::new(WebhookLog::default());
+        let app = Router::new()
+            .route(
+                "/hook",
+                get(mock_webhook).post(mock_webhook).put(mock_webhook),
+            )
+            .with_state(received.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+        Self {
+            addr,
+            received,
+            task,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/hook", self.addr)
+    }
+
+    /// Deliveries so far, oldest first
+    pub fn received(&self) -> Vec<WebhookDelivery> {
+        self.received.deliveries.lock().unwrap().clone()
+    }
+
+    /// Answer later requests with `body` (empty by default)
+    pub fn respond_with(&self, body: &str) {
+        *self.received.response.lock().unwrap() = body.to_string();
+    }
+
+    /// Answer later requests with `status` (None restores 200)
+    pub fn fail_with(&self, status: Option<StatusCode>) {
+        let code = status.map_or(0, |s| s.as_u16());
+        self.received.status.store(code, Ordering::Relaxed);
+    }
+}
+
+impl Drop for MockWebhook {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn mock_webhook(
+    State(received): State<Arc<WebhookLog>>,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, String) {
+    received
+        .deliveries
+        .lock()
+        .unwrap()
+        .push(WebhookDelivery { uri, headers, body });
+    let status =
+        StatusCode::from_u16(received.status.load(Ordering::Relaxed)).unwrap_or(StatusCode::OK);
+    (status, received.response.lock().unwrap().clone())
+}
+
+/// A key of a `MockRedis`
+enum RedisEntry {
+    Hash(HashMap<String, String>),
+    /// Members and their scores
+    SortedSet(HashMap<String, f64>),
+}
+
+#[derive(Default)]
+struct RedisData {
+    keys: HashMap<String, RedisEntry>,
+    /// Loaded scripts by SHA1
+    scripts: HashMap<String, String>,
+}
+
+/// A reply to a `MockRedis` command
+enum RedisReply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Nil,
+    Array(Vec<RedisReply>),
+}
+
+impl RedisReply {
+    fn encode(&self, out: &mut String) {
+        match self {
+            Self::Status(status) => out.push_str(&format!("+{}\r\n", status)),
+            Self::Error(message) => out.push_str(&format!("-{}\r\n", message)),
+            Self::Integer(n) => out.push_str(&format!(":{}\r\n", n)),
+            Self::Bulk(s) => out.push_str(&format!("${}\r\n{}\r\n", s.len(), s)),
+            Self::Nil => out.push_str("$-1\r\n"),
+            Self::Array(items) => {
+                out.push_str(&format!("*{}\r\n", items.len()));
+                for item in items {
+                    item.encode(out);
+                }
+            }
+        }
+    }
+
+    fn strings<'a>(items: impl IntoIterator<Item = &'a String>) -> Self {
+        Self::Array(items.into_iter().cloned().map(Self::Bulk).collect())
+    }
+}
+
+/// An in-memory Redis speaking just enough RESP for the cluster layer:
+/// hashes, sorted sets and Lua scripts (run by a real Lua interpreter, with
+/// `redis.call` bound to the mock's commands)
+pub struct MockRedis {
+    addr: SocketAddr,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl MockRedis {
+    pub async fn start() -> Self {
+        let data = Arc::new(std::sync::Mutex::new(RedisData::default()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(mock_redis_connection(socket, data.clone()));
+            }
+        });
+        Self { addr, task }
+    }
+
+    pub fn url(&self) -> String {
+        format!("redis://{}", self.addr)
+    }
+}
+
+impl Drop for MockRedis {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn mock_redis_connection(
+    socket: tokio::net::TcpStream,
+    data: Arc<std::sync::Mutex<RedisData>>,
+) {
+    let (read, mut write) = socket.into_split();
+    let mut read = tokio::io::BufReader::new(read);
+    while let Some(command) = read_resp_command(&mut read).await {
+        let mut reply = String::new();
+        mock_redis_command(&mut data.lock().unwrap(), &command).encode(&mut reply);
+        if write.write_all(reply.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// One command sent as an array of bulk strings (None at end of stream)
+async fn read_resp_command<R>(read: &mut R) -> Option<Vec<String>>
+where
+    R: tokio::io::AsyncBufRead + Unpin,
+{
+    let mut line = String::new();
+    read.read_line(&mut line).await.ok()?;
+    let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        line.clear();
+        read.read_line(&mut line).await.ok()?;
+        let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+        let mut data = vec![0; len + 2];
+        read.read_exact(&mut data).await.ok()?;
+        data.truncate(len);
+        args.push(String::from_utf8(data).ok()?);
+    }
+    (!args.is_empty()).then_some(args)
+}
+
+fn mock_redis_command(data: &mut RedisData, args: &[String]) -> RedisReply {
+    let arg = |i: usize| args.get(i).map(String::as_str).unwrap_or_default();
+    let wrong_type = || RedisReply::Error("WRONGTYPE wrong kind of value".to_string());
+    match arg(0).to_ascii_uppercase().as_str() {
+        "PING" => RedisReply::Status("PONG"),
+        "HGETALL" => match data.keys.get(arg(1)) {
+            Some(RedisEntry::Hash(hash)) => {
+                RedisReply::strings(hash.iter().flat_map(|(field, value)| [field, value]))
+            }
+            Some(_) => wrong_type(),
+            None => RedisReply::Array(Vec::new()),
+        },
+        "HSET" | "HDEL" | "HINCRBY" => {
+            let entry = data
+                .keys
+                .entry(arg(1).to_string())
+                .or_insert_with(|| RedisEntry::Hash(HashMap::new()));
+            let RedisEntry::Hash(hash) = entry else {
+                return wrong_type();
+            };
+            let reply = match arg(0).to_ascii_uppercase().as_str() {
+                "HSET" => {
+                    let added = args[2..]
+                        .chunks(2)
+                        .filter(|pair| hash.insert(pair[0].clone(), pair[1].clone()).is_none())
+                        .count();
+                    RedisReply::Integer(added as i64)
+                }
+                "HDEL" => {
+                    let removed = args[2..]
+                        .iter()
+                        .filter(|field| hash.remove(*field).is_some())
+                        .count();
+                    RedisReply::Integer(removed as i64)
+                }
+                _ => {
+                    let current: i64 = hash.get(arg(2)).map_or(0, |v| v.parse().unwrap());
+                    let value = current + arg(3).parse::<i64>().unwrap();
+                    hash.insert(arg(2).to_string(), value.to_string());
+                    RedisReply::Integer(value)
+                }
+            };
+            if hash.is_empty() {
+                data.keys.remove(arg(1));
+            }
+            reply
+        }
+        "ZADD" => {
+            let entry = data
+                .keys
+                .entry(arg(1).to_string())
+                .or_insert_with(|| RedisEntry::SortedSet(HashMap::new()));
+            let RedisEntry::SortedSet(set) = entry else {
+                return wrong_type();
+            };
+            let added = args[2..]
+                .chunks(2)
+                .filter(|pair| {
+                    set.insert(pair[1].clone(), pair[0].parse().unwrap())
+                        .is_none()
+                })
+                .count();
+            RedisReply::Integer(added as i64)
+        }
+        "ZRANGEBYSCORE" | "ZREMRANGEBYSCORE" => {
+            let Some(RedisEntry::SortedSet(set)) = data.keys.get_mut(arg(1)) else {
+                return RedisReply::Array(Vec::new());
+            };
+            let (min, max) = (score_bound(arg(2)), score_bound(arg(3)));
+            let in_range = |score: f64| {
+                (score > min.0 || (score == min.0 && !min.1))
+                    && (score < max.0 || (score == max.0 && !max.1))
+            };
+            if arg(0).eq_ignore_ascii_case("ZRANGEBYSCORE") {
+                let mut members: Vec<(&String, f64)> = set
+                    .iter()
+                    .filter(|(_, score)| in_range(**score))
+                    .map(|(member, score)| (member, *score))
+                    .collect();
+                members.sort_by(|a, b| a.1.total_cmp(&b.1));
+                RedisReply::strings(members.into_iter().map(|(member, _)| member))
+            } else {
+                let before = set.len();
+                set.retain(|_, score| !in_range(*score));
+                RedisReply::Integer((before - set.len()) as i64)
+            }
+        }
+        "SCRIPT" if arg(1).eq_ignore_ascii_case("LOAD") => {
+            let sha = redis::Script::new(arg(2)).get_hash().to_string();
+            data.scripts.insert(sha.clone(), arg(2).to_string());
+            RedisReply::Bulk(sha)
+        }
+        "EVAL" | "EVALSHA" => {
+            let body = if arg(0).eq_ignore_ascii_case("EVAL") {
+                arg(1).to_string()
+            } else {
+                match data.scripts.get(arg(1)) {
+                    Some(body) => body.clone(),
+                    None => {
+                        return RedisReply::Error(
+                            "NOSCRIPT No matching script. Please use EVAL.".to_string(),
+                        )
+                    }
+                }
+            };
+            let key_count: usize = arg(2).parse().unwrap();
+            let (keys, argv) = args[3..].split_at(key_count);
+            run_mock_redis_script(data, &body, keys, argv)
+        }
+        // CLIENT SETINFO and other handshake commands
+        _ => RedisReply::Status("OK"),
+    }
+}
+
+/// A ZRANGEBYSCORE bound: the score and whether it is exclusive
+fn score_bound(bound: &str) -> (f64, bool) {
+    let (exclusive, score) = match bound.strip_prefix('(') {
+        Some(score) => (true, score),
+        None => (false, bound),
+    };
+    let score = match score {
+        "-inf" => f64::NEG_INFINITY,
+        "+inf" | "inf" => f64::INFINITY,
+        score => score.parse().unwrap(),
+    };
+    (score, exclusive)
+}
+
+fn run_mock_redis_script(
+    data: &mut RedisData,
+    body: &str,
+    keys: &[String],
+    argv: &[String],
+) -> RedisReply {
+    let lua = mlua::Lua::new();
+    let result = lua.scope(|scope| {
+        let call = scope.create_function_mut(|lua, args: mlua::Variadic<mlua::Value>| {
+            let args = args
+                .iter()
+                .map(|arg| match arg {
+                    mlua::Value::String(s) => Ok(s.to_str()?.to_string()),
+                    mlua::Value::Integer(n) => Ok(n.to_string()),
+                    mlua::Value::Number(n) => Ok(n.to_string()),
+                    other => Err(mlua::Error::runtime(format!(
+                        "bad redis.call argument {:?}",
+                        other
+                    ))),
+                })
+                .collect::<mlua::Result<Vec<String>>>()?;
+            redis_reply_to_lua(lua, mock_redis_command(data, &args))
+        })?;
+        let redis = lua.create_table()?;
+        redis.set("call", call)?;
+        lua.globals().set("redis", redis)?;
+        lua.globals().set("KEYS", keys.to_vec())?;
+        lua.globals().set("ARGV", argv.to_vec())?;
+        let value: mlua::Value = lua.load(body).eval()?;
+        Ok(lua_to_redis_reply(value))
+    });
+    result.unwrap_or_else(|e| RedisReply::Error(format!("ERR {}", e)))
+}
+
+fn redis_reply_to_lua(lua: &mlua::Lua, reply: RedisReply) -> mlua::Result<mlua::Value<'_>> {
+    Ok(match reply {
+        RedisReply::Status(status) => {
+            let table = lua.create_table()?;
+            table.set("ok", status)?;
+            mlua::Value::Table(table)
+        }
+        RedisReply::Error(message) => return Err(mlua::Error::runtime(message)),
+        RedisReply::Integer(n) => mlua::Value::Integer(n),
+        RedisReply::Bulk(s) => mlua::Value::String(lua.create_string(&s)?),
+        RedisReply::Nil => mlua::Value::Boolean(false),
+        RedisReply::Array(items) => {
+            let table = lua.create_table()?;
+            for item in items {
+                table.push(redis_reply_to_lua(lua, item)?)?;
+            }
+            mlua::Value::Table(table)
+        }
+    })
+}
+
+fn lua_to_redis_reply(value: mlua::Value) -> RedisReply {
+    match value {
+        mlua::Value::Integer(n) => RedisReply::Integer(n),
+        mlua::Value::Number(n) => RedisReply::Integer(n as i64),
+        mlua::Value::String(s) => RedisReply::Bulk(s.to_string_lossy().into_owned()),
+        mlua::Value::Boolean(true) => RedisReply::Integer(1),
+        mlua::Value::Table(table) => RedisReply::Array(
+            table
+                .sequence_values::<mlua::Value>()
+                .filter_map(Result::ok)
+                .map(lua_to_redis_reply)
+                .collect(),
+        ),
+        _ => RedisReply::Nil,
+    }
+}
+
+/// Decrements the open-connection gauge when the response body is dropped
+struct OpenGuard(Arc<MockBehavior>);
+
+impl Drop for OpenGuard {
+    fn drop(&mut self) {
+        self.0.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+async fn mock_stream(
+    State(behavior): State<Arc<MockBehavior>>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response {
+    behavior.connections.fetch_add(1, Ordering::Relaxed);
+    *behavior.last_request_headers.lock().unwrap() = headers.clone();
+    *behavior.last_request_uri.lock().unwrap() = Some(uri);
+    let delay = behavior.response_delay_ms.load(Ordering::Relaxed);
+    tokio::time::sleep(Duration::from_millis(delay)).await;
+    let fail = behavior.fail_status.load(Ordering::Relaxed);
+    if fail != 0 {
+        let status = StatusCode::from_u16(fail).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        return (status, "mock failure").into_response();
+    }
+    if let Some(required) = behavior.required_authorization.lock().unwrap().as_deref() {
+        let given = headers.get("authorization").and_then(|v| v.to_str().ok());
+        if given != Some(required) {
+            return (StatusCode::UNAUTHORIZED, "mock auth required").into_response();
+        }
+    }
+
+    behavior.open_connections.fetch_add(1, Ordering::Relaxed);
+    let guard = OpenGuard(behavior.clone());
+    let body = async_stream::stream! {
+        let _guard = guard;
+        let tick = Duration::from_millis(20);
+        let mut interval = tokio::time::interval(tick);
+        let mut packet_index: u64 = 0;
+        let mut sent: u64 = 0;
+        let mut counters: HashMap<u16, u8> = HashMap::new();
+        let junk = behavior.leading_junk.load(Ordering::Relaxed) as usize;
+        if junk > 0 {
+            yield Ok::<_, std::io::Error>(Bytes::from(vec![0xAB; junk]));
+        }
+        loop {
+            interval.tick().await;
+            if behavior.stalled.load(Ordering::Relaxed) {
+                continue;
+            }
+            let per_tick = behavior.bitrate.load(Ordering::Relaxed) * tick.as_millis() as u64 / 1000;
+            let packets = (per_tick / TS_PACKET_SIZE as u64).max(1);
+            let mut data = Vec::with_capacity(packets as usize * TS_PACKET_SIZE);
+            let keyframes = behavior.keyframes.load(Ordering::Relaxed);
+            let every = behavior.keyframes_every.load(Ordering::Relaxed).max(1) * KEYFRAME_INTERVAL;
+            for _ in 0..packets {
+                let mut pkt = ts_packet(packet_index);
+                if packet_index.is_multiple_of(KEYFRAME_INTERVAL)
+                    && (!keyframes || !packet_index.is_multiple_of(every))
+                {
+                    pkt[5] = 0x00; // clear random_access_indicator
+                }
+                // Continuity counters run per PID
+                let pid = (((pkt[1] & 0x1F) as u16) << 8) | pkt[2] as u16;
+                let counter = counters.entry(pid).or_insert(0);
+                if pid == MOCK_VIDEO_PID && behavior.continuity_skips.load(Ordering::Relaxed) {
+                    *counter = (*counter + 1) & 0x0F;
+                }
+                pkt[3] = (pkt[3] & 0xF0) | *counter;
+                *counter = (*counter + 1) & 0x0F;
+                data.extend_from_slice(&pkt);
+                packet_index += 1;
+            }
+            sent += data.len() as u64;
+            yield Ok::<_, std::io::Error>(Bytes::from(data));
+
+            let limit = behavior.drop_after_bytes.load(Ordering::Relaxed);
+            if limit > 0 && sent >= limit {
+                yield Err(std::io::Error::other("mock connection drop"));
+                break;
+            }
+        }
+    };
+    Response::builder()
+        .header("content-type", "video/mp2t")
+        .body(Body::from_stream(body))
+        .unwrap()
+}
+
+async fn mock_file_request(
+    State(behavior): State<Arc<MockBehavior>>,
+    headers: HeaderMap,
+) -> Response {
+    behavior.connections.fetch_add(1, Ordering::Relaxed);
+    *behavior.last_request_headers.lock().unwrap() = headers.clone();
+    let file = mock_file();
+    let total = file.len() as u64;
+    let start = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("bytes="))
+        .and_then(|v| v.strip_suffix('-'))
+        .and_then(|v| v.parse::<u64>().ok())
+        .filter(|_| !behavior.ranges_ignored.load(Ordering::Relaxed));
+    if start.is_some_and(|start| start >= total) {
+        return StatusCode::RANGE_NOT_SATISFIABLE.into_response();
+    }
+    let from = start.unwrap_or(0);
+    let drop_at = match behavior.file_drop_at.swap(0, Ordering::Relaxed) {
+        0 => match behavior.file_drop_every.load(Ordering::Relaxed) {
+            0 => 0,
+            every => from + every,
+        },
+        drop_at => drop_at,
+    };
+
+    behavior.open_connections.fetch_add(1, Ordering::Relaxed);
+    let guard = OpenGuard(behavior.clone());
+    let body = async_stream::stream! {
+        let _guard = guard;
+        let tick = Duration::from_millis(20);
+        let mut interval = tokio::time::interval(tick);
+        let mut position = from;
+        while position < total {
+            interval.tick().await;
+            let per_tick = behavior.bitrate.load(Ordering::Relaxed) * tick.as_millis() as u64 / 1000;
+            // Whole packets, as the live stream sends
+            let packets = (per_tick / TS_PACKET_SIZE as u64).max(1);
+            let mut end = (position + packets * TS_PACKET_SIZE as u64).min(total);
+            if drop_at > position {
+                end = end.min(drop_at);
+            }
+            yield Ok::<_, std::io::Error>(Bytes::copy_from_slice(&file[position as usize..end as usize]));
+            position = end;
+            if position == drop_at {
+                // Give the data sent so far time to arrive first
+                interval.tick().await;
+                yield Err(std::io::Error::other("mock connection drop"));
+                break;
+            }
+        }
+    };
+    let response = Response::builder()
+        .header(header::CONTENT_TYPE, "video/mp2t")
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_LENGTH, total - from);
+    let response = match start {
+        Some(start) => response.status(StatusCode::PARTIAL_CONTENT).header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, total - 1, total),
+        ),
+        None => response,
+    };
+    response.body(Body::from_stream(body)).unwrap()
+}
+
+async fn mock_playlist() -> Response {
+    let playlist = "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:2\n\
+        #EXT-X-MEDIA-SEQUENCE:0\n#EXTINF:2.0,\nsegment/0\n#EXTINF:2.0,\nsegment/1\n";
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "application/vnd.apple.mpegurl",
+        )],
+        playlist,
+    )
+        .into_response()
+}
+
+/// One HLS segment: 100 TS packets starting at a keyframe
+async fn mock_segment(axum::extract::Path(index): axum::extract::Path<u64>) -> Response {
+    let mut data = Vec::with_capacity(100 * TS_PACKET_SIZE);
+    for i in 0..100 {
+        data.extend_from_slice(&ts_packet(index * KEYFRAME_INTERVAL + i));
+    }
+    ([(axum::http::header::CONTENT_TYPE, "video/mp2t")], data).into_response()
+}
+
+/// The mock stream's TS packet at `index`: one H.264 video PID flagged as a
+/// random access point every `KEYFRAME_INTERVAL` packets, each keyframe
+/// followed by a PAT and PMT, every fourth packet on the audio PID and an
+/// occasional DVB subtitle packet.
+pub fn ts_packet(index: u64) -> [u8; TS_PACKET_SIZE] {
+    match index % KEYFRAME_INTERVAL {
+        1 => return psi_packet(0x0000, &pat_section()),
+        2 => return psi_packet(MOCK_PMT_PID, &pmt_section()),
+        _ => {}
+    }
+    let keyframe = index.is_multiple_of(KEYFRAME_INTERVAL);
+    let pid = if index % 4 == 3 {
+        MOCK_AUDIO_PID
+    } else if index % 20 == 5 {
+        MOCK_SUBTITLE_PID
+    } else {
+        MOCK_VIDEO_PID
+    };
+    let mut pkt = [0xFFu8; TS_PACKET_SIZE];
+    pkt[0] = 0x47;
+    pkt[1] = if keyframe { 0x40 } else { 0x00 } | ((pid >> 8) as u8 & 0x1F);
+    pkt[2] = pid as u8;
+    let counter = (index % 16) as u8;
+    if keyframe {
+        // Adaptation field + payload, random_access_indicator set
+        pkt[3] = 0x30 | counter;
+        pkt[4] = 1;
+        pkt[5] = 0x40;
+    } else {
+        pkt[3] = 0x10 | counter;
+    }
+    pkt
+}
+
+/// Packets in [`mock_file`]
+pub const MOCK_FILE_PACKETS: u64 = 2000;
+
+/// The finite file the mock upstream serves: the mock stream's first
+/// `MOCK_FILE_PACKETS` packets
+pub fn mock_file() -> Vec<u8> {
+    (0..MOCK_FILE_PACKETS).flat_map(ts_packet).collect()
+}
+
+/// Packet ids of the mock stream's program
+pub const MOCK_VIDEO_PID: u16 = 0x100;
+pub const MOCK_AUDIO_PID: u16 = 0x101;
+pub const MOCK_SUBTITLE_PID: u16 = 0x102;
+pub const MOCK_AUDIO2_PID: u16 = 0x103;
+pub const MOCK_PMT_PID: u16 = 0x1000;
+
+fn pat_section() -> Vec<u8> {
+    // program 1 -> PMT PID
+    let program = [
+        0x00,
+        0x01,
+        0xE0 | (MOCK_PMT_PID >> 8) as u8,
+        MOCK_PMT_PID as u8,
+    ];
+    psi_section(0x00, &program)
+}
+
+fn pmt_section() -> Vec<u8> {
+    let mut body = vec![
+        0xE0 | (MOCK_AUDIO_PID >> 8) as u8, // PCR on the audio PID
+        MOCK_AUDIO_PID as u8,
+        0xF0,
+        0x00, // no program descriptors
+    ];
+    for (stream_type, pid) in [(0x1B, MOCK_VIDEO_PID), (0x0F, MOCK_AUDIO_PID)] {
+        body.extend_from_slice(&[stream_type, 0xE0 | (pid >> 8) as u8, pid as u8, 0xF0, 0x00]);
+    }
+    // Private PES with a DVB subtitling descriptor ("eng", composition page 1)
+    body.extend_from_slice(&[
+        0x06,
+        0xE0 | (MOCK_SUBTITLE_PID >> 8) as u8,
+        MOCK_SUBTITLE_PID as u8,
+        0xF0,
+        10,
+        0x59,
+        8,
+        b'e',
+        b'n',
+        b'g',
+        0x10,
+        0x00,
+        0x01,
+        0x00,
+        0x01,
+    ]);
+    // Second audio track (no packets) tagged English; the first has no language
+    body.extend_from_slice(&[
+        0x03,
+        0xE0 | (MOCK_AUDIO2_PID >> 8) as u8,
+        MOCK_AUDIO2_PID as u8,
+        0xF0,
+        6,
+        0x0A,
+        4,
+        b'e',
+        b'n',
+        b'g',
+        0x00,
+    ]);
+    psi_section(0x02, &body)
+}
+
+/// A complete PSI section (table id 1 / version 0) around `body`, with CRC
+fn psi_section(table_id: u8, body: &[u8]) -> Vec<u8> {
+    let length = 5 + body.len() + 4;
+    let mut section = vec![
+        table_id,
+        0xB0 | (length >> 8) as u8,
+        length as u8,
+        0x00,
+        0x01,
+        0xC1,
+        0x00,
+        0x00,
+    ];
+    section.extend_from_slice(body);
+    let crc = crate::ts::crc32_mpeg2(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+fn psi_packet(pid: u16, section: &[u8]) -> [u8; TS_PACKET_SIZE] {
+    let mut pkt = [0xFFu8; TS_PACKET_SIZE];
+    pkt[0] = 0x47;
+    pkt[1] = 0x40 | (pid >> 8) as u8;
+    pkt[2] = pid as u8;
+    pkt[3] = 0x10;
+    pkt[4] = 0; // pointer field
+    pkt[5..5 + section.len()].copy_from_slice(section);
+    pkt
+}
+
+/// A proxy instance on an ephemeral port with helpers for the HTTP APIs
+pub struct TestProxy {
+    server: RunningServer,
+    http: reqwest::Client,
+}
+
+impl TestProxy {
+    /// Start with default config
+    pub async fn start() -> Self {
+        Self::start_with(Config::default()).await
+    }
+
+    /// Start with `config`; its listeners are replaced by one ephemeral
+    /// listener serving every route group.
+    pub async fn start_with(mut config: Config) -> Self {
+        config.listeners = vec![ListenerConfig {
+            addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            groups: RouteGroup::ALL.to_vec(),
+            tls: false,
+            cert_file: None,
+            key_file: None,
+        }];
+        let server = ProxyServer::builder()
+            .config(config)
+            .build()
+            .start()
+            .await
+            .expect("failed to start test proxy");
+        Self {
+            server,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn state(&self) -> &Arc<crate::state::AppState> {
+        self.server.state()
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.server.local_addrs()[0], path)
+    }
+
+    /// Address of the gRPC control service, for a config with `grpc_listen` set
+    pub fn grpc_url(&self) -> String {
+        let addr = self.server.grpc_addr().expect("gRPC service not enabled");
+        format!("http://{}", addr)
+    }
+
+    /// `relay://` URL of a channel, for a config with `relay_listen` set
+    pub fn relay_url(&self, channel_id: &str) -> String {
+        let addr = self
+            .server
+            .relay_addr()
+            .expect("relay listener not enabled");
+        format!("relay://{}/{}", addr, channel_id)
+    }
+
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    pub async fn put_channel(&self, channel_id: &str, config: serde_json::Value) -> StatusCode {
+        self.send(
+            self.http
+                .put(self.url(&format!("/control/v1/channels/{}", channel_id)))
+                .json(&config),
+        )
+        .await
+    }
+
+    pub async fn delete_channel(&self, channel_id: &str) -> StatusCode {
+        self.send(
+            self.http
+                .delete(self.url(&format!("/control/v1/channels/{}", channel_id))),
+        )
+        .await
+    }
+
+    pub async fn put_account(&self, account_id: u64, max_connections: u32) -> StatusCode {
+        self.send(
+            self.http
+                .put(self.url(&format!("/control/v1/accounts/{}", account_id)))
+                .json(&serde_json::json!({ "max_connections": max_connections })),
+        )
+        .await
+    }
+
+    pub async fn put_group(&self, group: &str, max_active_channels: u32) -> StatusCode {
+        self.send(
+            self.http
+                .put(self.url(&format!("/control/v1/groups/{}", group)))
+                .json(&serde_json::json!({ "max_active_channels": max_active_channels })),
+        )
+        .await
+    }
+
+    pub async fn sync(&self, payload: serde_json::Value) -> StatusCode {
+        self.send(self.http.post(self.url("/control/v1/sync")).json(&payload))
+            .await
+    }
+
+    /// POST `body` to a control endpoint; returns the status and decoded JSON
+    /// response (null if the body isn't JSON)
+    pub async fn post_json(
+        &self,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> (StatusCode, serde_json::Value) {
+        let response = self
+            .http
+            .post(self.url(path))
+            .json(body)
+            .send()
+            .await
+            .unwrap();
+        let status = response.status();
+        (status, response.json().await.unwrap_or_default())
+    }
+
+    /// GET a status endpoint and decode its JSON body
+    pub async fn get_json(&self, path: &str) -> serde_json::Value {
+        self.http
+            .get(self.url(path))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap()
+    }
+
+    /// Open a stream request for a channel
+    pub async fn stream(&self, channel_id: &str) -> reqwest::Response {
+        self.http
+            .get(self.url(&format!("/stream/{}", channel_id)))
+            .send()
+            .await
+            .unwrap()
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> StatusCode {
+        request.send().await.unwrap().status()
+    }
+}
+
+/// A channel config with one stream per (account_id, url) pair
+pub fn channel_config(urls: &[(u64, &str)]) -> serde_json::Value {
+    let streams: Vec<_> = urls
+        .iter()
+        .enumerate()
+        .map(|(i, (account_id, url))| {
+            serde_json::json!({
+                "id": i as u64 + 1,
+                "urls": [{ "account_id": account_id, "url": url }],
+            })
+        })
+        .collect();
+    serde_json::json!({ "streams": streams })
+}
+
+/// Read from a stream response until `bytes` of non-keepalive data arrived
+/// or `timeout` elapsed; returns the number of bytes read.
+pub async fn read_stream(
+    response: &mut reqwest::Response,
+    bytes: usize,
+    timeout: Duration,
+) -> usize {
+    let mut total = 0;
+    let _ = tokio::time::timeout(timeout, async {
+        while total < bytes {
+            match response.chunk().await {
+                Ok(Some(chunk)) => {
+                    // Ignore keepalive null packets
+                    if !(chunk.len() == TS_PACKET_SIZE
+                        && chunk[1] & 0x1F == 0x1F
+                        && chunk[2] == 0xFF)
+                    {
+                        total += chunk.len();
+                    }
+                }
+                _ => break,
+            }
+        }
+    })
+    .await;
+    total
+}
+
+/// Poll `check` until it returns true or `timeout` elapses.
+pub async fn wait_until<F: FnMut() -> bool>(timeout: Duration, mut check: F) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    while tokio::time::Instant::now() < deadline {
+        if check() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    check()
+}
@@ -0,0 +1,232 @@
+use crate::config::TenantConfig;
+use arc_swap::ArcSwap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// Longest a client may take to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Handshaken connections waiting for the server to pick them up
+const ACCEPT_QUEUE: usize = 64;
+
+/// The certificate served on TLS listeners, swapped in place when its files
+/// change so rotated certs apply to new connections without a restart
+#[derive(Debug)]
+pub struct CertStore {
+    cert_file: String,
+    key_file: String,
+    current: ArcSwap<CertifiedKey>,
+    /// Modification times of the files the current certificate came from
+    loaded_from: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl CertStore {
+    pub fn load(cert_file: &str, key_file: &str) -> Result<Arc<Self>, String> {
+        let modified = (modified(cert_file), modified(key_file));
+        let current = read(cert_file, key_file)?;
+        Ok(Arc::new(Self {
+            cert_file: cert_file.to_string(),
+            key_file: key_file.to_string(),
+            current: ArcSwap::from_pointee(current),
+            loaded_from: Mutex::new(modified),
+        }))
+    }
+
+    /// Load the files again if either changed since the last load. Returns
+    /// whether the certificate was replaced; on error the current one stays.
+    pub fn reload_if_changed(&self) -> Result<bool, String> {
+        let modified = (modified(&self.cert_file), modified(&self.key_file));
+        let mut loaded_from = self.loaded_from.lock().unwrap();
+        if *loaded_from == modified {
+            return Ok(false);
+        }
+        // Only recorded once the pair loads, so a half-written rotation is
+        // retried on the next check
+        self.current
+            .store(Arc::new(read(&self.cert_file, &self.key_file)?));
+        *loaded_from = modified;
+        Ok(true)
+    }
+}
+
+/// Picks the certificate for a TLS connection by its SNI name: a tenant's
+/// when the name is one of the tenant's hosts, the default one otherwise
+#[derive(Debug)]
+pub struct SniResolver {
+    default: Option<Arc<CertStore>>,
+    tenants: Vec<(TenantConfig, Arc<CertStore>)>,
+}
+
+impl SniResolver {
+    /// Load the default certificate (if given) and those of the tenants
+    /// that bring their own
+    pub fn load(
+        default: Option<(&str, &str)>,
+        tenants: &[TenantConfig],
+    ) -> Result<Arc<Self>, String> {
+        let default = match default {
+            Some((cert_file, key_file)) => Some(CertStore::load(cert_file, key_file)?),
+            None => None,
+        };
+        let mut stores = Vec::new();
+        for tenant in tenants {
+            match (&tenant.cert_file, &tenant.key_file) {
+                (Some(cert_file), Some(key_file)) => {
+                    stores.push((tenant.clone(), CertStore::load(cert_file, key_file)?))
+                }
+                (None, None) => {}
+                _ => return Err(format!("tenant {}: cert_file needs key_file", tenant.name)),
+            }
+        }
+        Ok(Arc::new(Self {
+            default,
+            tenants: stores,
+        }))
+    }
+
+    /// Every certificate served, for reloading
+    pub fn stores(&self) -> impl Iterator<Item = &Arc<CertStore>> {
+        self.default
+            .iter()
+            .chain(self.tenants.iter().map(|(_, store)| store))
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let tenant = client_hello.server_name().and_then(|name| {
+            self.tenants
+                .iter()
+                .find(|(tenant, _)| tenant.serves_host(name))
+        });
+        tenant
+            .map(|(_, store)| store)
+            .or(self.default.as_ref())
+            .map(|store| store.current.load_full())
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn read(cert_file: &str, key_file: &str) -> Result<CertifiedKey, String> {
+    let certs = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {}", cert_file, e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates", cert_file));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_file).map_err(|e| format!("{}: {}", key_file, e))?;
+    let key = ring::sign::any_supported_type(&key).map_err(|e| format!("{}: {}", key_file, e))?;
+    let certified = CertifiedKey::new(certs, key);
+    certified
+        .keys_match()
+        .map_err(|e| format!("{} does not match {}: {}", key_file, cert_file, e))?;
+    Ok(certified)
+}
+
+/// TLS settings for the listeners, serving whatever certificates
+/// `resolver`'s stores currently hold
+pub fn server_config(resolver: Arc<SniResolver>) -> Arc<ServerConfig> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("default TLS versions are supported")
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Arc::new(config)
+}
+
+/// Spawn the task that picks up rotated certificate files.
+pub fn spawn_reloader(store: Arc<CertStore>, interval: Duration) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            match store.reload_if_changed() {
+                Ok(true) => tracing::info!("TLS certificate reloaded from {}", store.cert_file),
+                Ok(false) => {}
+                Err(e) => tracing::warn!("TLS certificate reload failed, keeping current: {}", e),
+            }
+        }
+    })
+}
+
+/// A TCP listener that hands out connections once their TLS handshake has
+/// completed. Handshakes run in their own tasks, so a slow or stalled client
+/// doesn't hold up anyone else's accept.
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    accepted: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl TlsListener {
+    pub fn new(tcp: TcpListener, config: Arc<ServerConfig>) -> std::io::Result<Self> {
+        let local_addr = tcp.local_addr()?;
+        let acceptor = TlsAcceptor::from(config);
+        let (tx, accepted) = mpsc::channel(ACCEPT_QUEUE);
+        let task = tokio::spawn(async move {
+            loop {
+                let (stream, addr) = match tcp.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        tracing::debug!("TLS listener {}: accept failed: {}", local_addr, e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let _ = tx.send((stream, addr)).await;
+                        }
+                        Ok(Err(e)) => tracing::debug!("TLS handshake with {} failed: {}", addr, e),
+                        Err(_) => tracing::debug!("TLS handshake with {} timed out", addr),
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            local_addr,
+            accepted,
+            task,
+        })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.accepted.recv().await {
+            Some(accepted) => accepted,
+            // The accept loop only ends when this listener is dropped
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Drop for TlsListener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
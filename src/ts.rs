@@ -0,0 +1,1057 @@
+use crate::models::{SubtitleAction, SubtitleConfig};
+
+pub const TS_PACKET_SIZE: usize = 188;
+pub const SYNC_BYTE: u8 = 0x47;
+
+/// Offset of the first packet boundary in `data` (a sync byte followed by
+/// another one packet later), or None if the data doesn't look like TS.
+pub fn find_sync(data: &[u8]) -> Option<usize> {
+    (0..TS_PACKET_SIZE.min(data.len())).find(|&i| {
+        data[i] == SYNC_BYTE && data.get(i + TS_PACKET_SIZE).is_none_or(|&b| b == SYNC_BYTE)
+    })
+}
+
+/// Whether any TS packet in `data` starts a video keyframe.
+///
+/// A packet counts if its adaptation field sets random_access_indicator, or
+/// if it starts a video PES whose payload carries an H.264 IDR/SPS or HEVC
+/// IRAP/parameter-set NAL unit.
+pub fn contains_keyframe(data: &[u8]) -> bool {
+    let Some(start) = find_sync(data) else {
+        return false;
+    };
+    data[start..]
+        .chunks_exact(TS_PACKET_SIZE)
+        .take_while(|pkt| pkt[0] == SYNC_BYTE)
+        .any(packet_has_keyframe)
+}
+
+/// Byte offset of the first packet in `data` that starts a video keyframe
+pub fn keyframe_offset(data: &[u8]) -> Option<usize> {
+    let start = find_sync(data)?;
+    data[start..]
+        .chunks_exact(TS_PACKET_SIZE)
+        .take_while(|pkt| pkt[0] == SYNC_BYTE)
+        .position(packet_has_keyframe)
+        .map(|i| start + i * TS_PACKET_SIZE)
+}
+
+/// Program clock reference of a packet, in seconds (None if it carries none)
+fn packet_pcr(pkt: &[u8]) -> Option<f64> {
+    let adaptation = (pkt[3] >> 4) & 0x3;
+    // PCR_flag in an adaptation field long enough to hold the PCR
+    if adaptation & 0x2 == 0 || pkt[4] < 7 || pkt[5] & 0x10 == 0 {
+        return None;
+    }
+    let b = &pkt[6..12];
+    let base = ((b[0] as u64) << 25)
+        | ((b[1] as u64) << 17)
+        | ((b[2] as u64) << 9)
+        | ((b[3] as u64) << 1)
+        | ((b[4] as u64) >> 7);
+    let extension = (((b[4] & 0x01) as u64) << 8) | b[5] as u64;
+    Some((base * 300 + extension) as f64 / 27_000_000.0)
+}
+
+/// First and last PCR found in `data`, in seconds
+pub fn pcr_range(data: &[u8]) -> Option<(f64, f64)> {
+    let start = find_sync(data)?;
+    let mut pcrs = data[start..]
+        .chunks_exact(TS_PACKET_SIZE)
+        .take_while(|pkt| pkt[0] == SYNC_BYTE)
+        .filter_map(packet_pcr);
+    let first = pcrs.next()?;
+    Some((first, pcrs.last().unwrap_or(first)))
+}
+
+fn packet_has_keyframe(pkt: &[u8]) -> bool {
+    let payload_unit_start = pkt[1] & 0x40 != 0;
+    let adaptation = (pkt[3] >> 4) & 0x3;
+    let mut payload_offset = 4;
+
+    if adaptation & 0x2 != 0 {
+        let af_len = pkt[4] as usize;
+        // random_access_indicator
+        if af_len > 0 && pkt[5] & 0x40 != 0 {
+            return true;
+        }
+        payload_offset += 1 + af_len;
+    }
+    if adaptation & 0x1 == 0 || !payload_unit_start || payload_offset >= pkt.len() {
+        return false;
+    }
+
+    let pes = &pkt[payload_offset..];
+    // PES start code + video stream_id (0xE0..=0xEF)
+    if pes.len() < 9 || pes[..3] != [0, 0, 1] || pes[3] & 0xF0 != 0xE0 {
+        return false;
+    }
+    let es_start = 9 + pes[8] as usize;
+    pes.get(es_start..).is_some_and(has_keyframe_nal)
+}
+
+/// Scan elementary stream bytes for a keyframe NAL unit header.
+fn has_keyframe_nal(es: &[u8]) -> bool {
+    es.windows(5).any(|w| {
+        if w[..3] != [0, 0, 1] || w[3] & 0x80 != 0 {
+            return false;
+        }
+        let h264_type = w[3] & 0x1F;
+        let hevc_type = (w[3] >> 1) & 0x3F;
+        // HEVC headers are two bytes: base layer (nuh_layer_id 0) and a
+        // nonzero temporal id. This keeps H.264 slices such as 0x41 from
+        // reading as HEVC parameter sets.
+        let hevc_header = w[3] & 0x01 == 0 && w[4] >> 3 == 0 && w[4] & 0x07 != 0;
+        // H.264: IDR slice (5) or SPS (7); HEVC: IRAP (16-21) or VPS/SPS (32-33)
+        matches!(h264_type, 5 | 7) || (hevc_header && matches!(hevc_type, 16..=21 | 32 | 33))
+    })
+}
+
+/// Re-frames an upstream byte stream into whole TS packets, so whatever is
+/// broadcast (or inserted between broadcasts) falls on a packet boundary.
+/// Bytes outside packets, such as a partial packet at the start or garbage
+/// after a loss of sync, are dropped. A stream that shows no TS framing
+/// from its start passes through untouched.
+#[derive(Default)]
+pub struct PacketAligner {
+    /// Bytes of an incomplete packet left over from the previous read
+    partial: Vec<u8>,
+    synced: bool,
+    passthrough: bool,
+    /// Sync was lost and has not been found again yet
+    resyncing: bool,
+    sync_losses: u64,
+}
+
+impl PacketAligner {
+    pub fn align(&mut self, data: &[u8]) -> Vec<u8> {
+        if self.passthrough {
+            return data.to_vec();
+        }
+        let mut input = std::mem::take(&mut self.partial);
+        input.extend_from_slice(data);
+        let mut out = Vec::with_capacity(input.len());
+        let mut pos = 0;
+        while input.len() - pos >= TS_PACKET_SIZE {
+            let rest = &input[pos..];
+            if self.synced && rest[0] == SYNC_BYTE {
+                out.extend_from_slice(&rest[..TS_PACKET_SIZE]);
+                pos += TS_PACKET_SIZE;
+                continue;
+            }
+            if self.synced && !self.resyncing {
+                self.resyncing = true;
+                self.sync_losses += 1;
+            }
+            // A boundary counts once the next packet's sync byte confirms it
+            if rest.len() < 2 * TS_PACKET_SIZE {
+                break;
+            }
+            match (0..TS_PACKET_SIZE)
+                .find(|&i| rest[i] == SYNC_BYTE && rest[i + TS_PACKET_SIZE] == SYNC_BYTE)
+            {
+                Some(offset) => {
+                    pos += offset;
+                    self.synced = true;
+                    self.resyncing = false;
+                }
+                None if self.synced => pos += TS_PACKET_SIZE,
+                None => {
+                    self.passthrough = true;
+                    return input;
+                }
+            }
+        }
+        self.partial = input[pos..].to_vec();
+        out
+    }
+
+    /// Times sync was lost since the last call
+    pub fn take_sync_losses(&mut self) -> u64 {
+        std::mem::take(&mut self.sync_losses)
+    }
+}
+
+/// The latest PAT and the PMTs it lists, as whole packets, replayed to
+/// players joining mid-stream so they can lock on without waiting for the
+/// next tables. Only single-packet sections are cached.
+#[derive(Default)]
+pub struct PsiCache {
+    pat: Option<[u8; TS_PACKET_SIZE]>,
+    pmt_pids: std::collections::HashSet<u16>,
+    pmts: std::collections::HashMap<u16, [u8; TS_PACKET_SIZE]>,
+}
+
+impl PsiCache {
+    /// Take in the PAT and PMT packets in `data`. Returns true when this
+    /// completes the tables, i.e. for the first full set from a new source.
+    pub fn update(&mut self, data: &[u8]) -> bool {
+        let was_complete = self.is_complete();
+        for pkt in data
+            .chunks_exact(TS_PACKET_SIZE)
+            .take_while(|pkt| pkt[0] == SYNC_BYTE)
+        {
+            let pid = packet_pid(pkt);
+            if pid == PAT_PID {
+                if let Some(pmt_pids) = parse_pat(pkt) {
+                    self.pmts.retain(|pid, _| pmt_pids.contains(pid));
+                    self.pmt_pids = pmt_pids;
+                    self.pat = Some(pkt.try_into().unwrap());
+                }
+            } else if self.pmt_pids.contains(&pid)
+                && psi_section(pkt).is_some_and(|(_, section)| section[0] == 0x02)
+            {
+                self.pmts.insert(pid, pkt.try_into().unwrap());
+            }
+        }
+        !was_complete && self.is_complete()
+    }
+
+    fn is_complete(&self) -> bool {
+        self.pat.is_some() && self.pmt_pids.iter().all(|pid| self.pmts.contains_key(pid))
+    }
+
+    /// The PAT followed by its PMTs, once all of them have been seen
+    pub fn tables(&self) -> Option<bytes::Bytes> {
+        if !self.is_complete() {
+            return None;
+        }
+        let mut pids: Vec<&u16> = self.pmts.keys().collect();
+        pids.sort();
+        let mut out = self.pat?.to_vec();
+        for pid in pids {
+            out.extend_from_slice(&self.pmts[pid]);
+        }
+        Some(out.into())
+    }
+
+    /// Forget the tables, e.g. when the channel changes source
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// User-private table_id of the source change sections
+pub const SOURCE_MARKER_TABLE_ID: u8 = 0xC0;
+
+/// In-band notice that the channel switched sources, as a single-packet
+/// private section (long form, CRC-protected) on a PID of its own. The
+/// section's version and the packet's continuity counter advance with each
+/// marker, so decoders filtering the PID see every one.
+#[derive(Default)]
+pub struct SourceMarker {
+    sent: u8,
+    pending: Option<[u8; TS_PACKET_SIZE]>,
+}
+
+impl SourceMarker {
+    /// Queue a marker on `pid` carrying `data` (truncated to fit one packet),
+    /// replacing any not yet sent
+    pub fn set(&mut self, pid: u16, data: &[u8]) {
+        // Pointer field, 8-byte section header and CRC around the data
+        let data = &data[..data.len().min(TS_PACKET_SIZE - 4 - 1 - 8 - 4)];
+        let section_length = 5 + data.len() + 4;
+        let mut section = vec![
+            SOURCE_MARKER_TABLE_ID,
+            0xB0 | (section_length >> 8) as u8,
+            section_length as u8,
+            0x00,
+            0x00,
+            0xC1 | ((self.sent & 0x1F) << 1),
+            0x00,
+            0x00,
+        ];
+        section.extend_from_slice(data);
+        section.extend_from_slice(&crc32_mpeg2(&section).to_be_bytes());
+
+        let mut pkt = [0xFF; TS_PACKET_SIZE];
+        pkt[..5].copy_from_slice(&[
+            SYNC_BYTE,
+            0x40 | ((pid >> 8) as u8 & 0x1F),
+            pid as u8,
+            0x10 | (self.sent & 0x0F),
+            0x00,
+        ]);
+        pkt[5..5 + section.len()].copy_from_slice(&section);
+        self.sent = self.sent.wrapping_add(1);
+        self.pending = Some(pkt);
+    }
+
+    /// The queued marker packet, if any
+    pub fn take(&mut self) -> Option<[u8; TS_PACKET_SIZE]> {
+        self.pending.take()
+    }
+}
+
+const PAT_PID: u16 = 0x0000;
+
+/// PMT stream_type values carrying video (MPEG-1/2, MPEG-4, H.264, HEVC, VC-1, AVS)
+fn is_video_stream_type(stream_type: u8) -> bool {
+    matches!(stream_type, 0x01 | 0x02 | 0x10 | 0x1B | 0x24 | 0x42 | 0xEA)
+}
+
+pub fn packet_pid(pkt: &[u8]) -> u16 {
+    (((pkt[1] & 0x1F) as u16) << 8) | pkt[2] as u16
+}
+
+/// Offset of the payload within a packet (None if it carries no payload)
+fn payload_offset(pkt: &[u8]) -> Option<usize> {
+    let adaptation = (pkt[3] >> 4) & 0x3;
+    if adaptation & 0x1 == 0 {
+        return None;
+    }
+    let offset = if adaptation & 0x2 != 0 {
+        5 + pkt[4] as usize
+    } else {
+        4
+    };
+    (offset < TS_PACKET_SIZE).then_some(offset)
+}
+
+/// The PSI section starting in this packet, bounded by its section_length
+/// (None unless the section starts here and fits in the packet).
+fn psi_section(pkt: &[u8]) -> Option<(usize, &[u8])> {
+    if pkt[1] & 0x40 == 0 {
+        return None;
+    }
+    let payload = payload_offset(pkt)?;
+    let start = payload + 1 + pkt[payload] as usize;
+    let header = pkt.get(start..start + 3)?;
+    let len = 3 + ((((header[1] & 0x0F) as usize) << 8) | header[2] as usize);
+    pkt.get(start..start + len).map(|section| (start, section))
+}
+
+/// MPEG-2 CRC32 (as used by PSI sections)
+pub fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04C1_1DB7
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Strips video from a TS stream for one client, leaving audio, subtitles
+/// and PSI. PMTs are rewritten without their video entries; if the PCR rides
+/// on a video PID, its PCR-bearing packets are kept with the payload removed.
+#[derive(Default)]
+pub struct AudioOnlyFilter {
+    pmt_pids: std::collections::HashSet<u16>,
+    video_pids: std::collections::HashSet<u16>,
+    pcr_pid: Option<u16>,
+    /// Trailing bytes of an incomplete packet from the previous chunk
+    partial: Vec<u8>,
+}
+
+impl AudioOnlyFilter {
+    pub fn filter(&mut self, data: &[u8]) -> bytes::Bytes {
+        let mut input = std::mem::take(&mut self.partial);
+        input.extend_from_slice(data);
+        let Some(start) = find_sync(&input) else {
+            return bytes::Bytes::new();
+        };
+
+        let mut out = Vec::with_capacity(input.len());
+        let mut packets = input[start..].chunks_exact(TS_PACKET_SIZE);
+        for pkt in packets.by_ref() {
+            if pkt[0] != SYNC_BYTE {
+                continue;
+            }
+            let pid = packet_pid(pkt);
+            if pid == PAT_PID {
+                if let Some(pmt_pids) = parse_pat(pkt) {
+                    self.pmt_pids = pmt_pids;
+                }
+                out.extend_from_slice(pkt);
+            } else if self.pmt_pids.contains(&pid) {
+                match self.rewrite_pmt(pkt) {
+                    Some(rewritten) => out.extend_from_slice(&rewritten),
+                    None => out.extend_from_slice(pkt),
+                }
+            } else if self.video_pids.contains(&pid) {
+                if self.pcr_pid == Some(pid) {
+                    if let Some(pcr_only) = strip_payload(pkt) {
+                        out.extend_from_slice(&pcr_only);
+                    }
+                }
+            } else if self.pcr_pid.is_some() {
+                // Until a PMT has been seen there is no telling what is video
+                out.extend_from_slice(pkt);
+            }
+        }
+        self.partial = packets.remainder().to_vec();
+        bytes::Bytes::from(out)
+    }
+
+    /// Record the PMT's video PIDs and return the packet with them removed
+    fn rewrite_pmt(&mut self, pkt: &[u8]) -> Option<[u8; TS_PACKET_SIZE]> {
+        let (start, section) = psi_section(pkt)?;
+        if section[0] != 0x02 || section.len() < 16 {
+            return None;
+        }
+        self.pcr_pid = Some((((section[8] & 0x1F) as u16) << 8) | section[9] as u16);
+        let info_len = (((section[10] & 0x0F) as usize) << 8) | section[11] as usize;
+        let es_start = 12 + info_len;
+        let es_end = section.len() - 4;
+        if es_start > es_end {
+            return None;
+        }
+
+        let mut rewritten = section[..es_start].to_vec();
+        let mut pos = es_start;
+        while pos + 5 <= es_end {
+            let stream_type = section[pos];
+            let pid = (((section[pos + 1] & 0x1F) as u16) << 8) | section[pos + 2] as u16;
+            let es_info_len =
+                (((section[pos + 3] & 0x0F) as usize) << 8) | section[pos + 4] as usize;
+            let entry_end = (pos + 5 + es_info_len).min(es_end);
+            if is_video_stream_type(stream_type) {
+                self.video_pids.insert(pid);
+            } else {
+                rewritten.extend_from_slice(&section[pos..entry_end]);
+            }
+            pos = entry_end;
+        }
+        Some(repack_section(pkt, start, rewritten))
+    }
+}
+
+/// Whether a PMT entry is a DVB subtitle or teletext stream (private PES
+/// with a subtitling, teletext or VBI teletext descriptor)
+fn is_subtitle_entry(stream_type: u8, es_info: &[u8]) -> bool {
+    stream_type == 0x06 && descriptors(es_info).any(|(tag, _)| matches!(tag, 0x46 | 0x56 | 0x59))
+}
+
+/// Whether a PMT entry carries audio (MPEG audio, AAC, AC-3/E-AC-3, or a
+/// private PES with an AC-3, E-AC-3, DTS or AAC descriptor)
+fn is_audio_entry(stream_type: u8, es_info: &[u8]) -> bool {
+    match stream_type {
+        0x03 | 0x04 | 0x0F | 0x11 | 0x81 | 0x87 => true,
+        0x06 => descriptors(es_info).any(|(tag, _)| matches!(tag, 0x6A | 0x7A | 0x7B | 0x7C)),
+        _ => false,
+    }
+}
+
+/// Language code of an ES_info's ISO 639 language descriptor, lowercased
+fn entry_language(es_info: &[u8]) -> Option<String> {
+    descriptors(es_info)
+        .find(|(tag, body)| *tag == 0x0A && body.len() >= 3)
+        .map(|(_, body)| String::from_utf8_lossy(&body[..3]).to_ascii_lowercase())
+}
+
+/// (tag, body) of each descriptor in a descriptor loop
+fn descriptors(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        let (&tag, rest) = data.split_first()?;
+        let (&len, rest) = rest.split_first()?;
+        let body = rest.get(..len as usize)?;
+        data = &rest[len as usize..];
+        Some((tag, body))
+    })
+}
+
+/// Channel-wide program rewriting applied before broadcast: subtitle and
+/// teletext rules (dropped streams lose their packets and PMT entry,
+/// remapped ones move to a new PID in both) and audio reordering, so the
+/// preferred languages are listed first for players that pick the first
+/// audio PID. Other packets pass through unchanged.
+pub struct ProgramFilter {
+    subtitles: SubtitleConfig,
+    /// Lowercase ISO 639-2 codes, most preferred first
+    audio_languages: Vec<String>,
+    pmt_pids: std::collections::HashSet<u16>,
+    /// Subtitle actions resolved from the latest PMT, by upstream PID
+    actions: std::collections::HashMap<u16, SubtitleAction>,
+    /// Trailing bytes of an incomplete packet from the previous chunk
+    partial: Vec<u8>,
+}
+
+impl ProgramFilter {
+    /// None if there is nothing to rewrite
+    pub fn new(subtitles: Option<SubtitleConfig>, audio_languages: &[String]) -> Option<Self> {
+        if subtitles.is_none() && audio_languages.is_empty() {
+            return None;
+        }
+        Some(Self {
+            subtitles: subtitles.unwrap_or_default(),
+            audio_languages: audio_languages
+                .iter()
+                .map(|l| l.trim().to_ascii_lowercase())
+                .collect(),
+            pmt_pids: Default::default(),
+            actions: Default::default(),
+            partial: Vec::new(),
+        })
+    }
+
+    pub fn filter(&mut self, data: &[u8]) -> bytes::Bytes {
+        let mut input = std::mem::take(&mut self.partial);
+        input.extend_from_slice(data);
+        let Some(start) = find_sync(&input) else {
+            return bytes::Bytes::new();
+        };
+
+        let mut out = Vec::with_capacity(input.len());
+        let mut packets = input[start..].chunks_exact(TS_PACKET_SIZE);
+        for pkt in packets.by_ref() {
+            if pkt[0] != SYNC_BYTE {
+                continue;
+            }
+            let pid = packet_pid(pkt);
+            if pid == PAT_PID {
+                if let Some(pmt_pids) = parse_pat(pkt) {
+                    self.pmt_pids = pmt_pids;
+                }
+                out.extend_from_slice(pkt);
+            } else if self.pmt_pids.contains(&pid) {
+                match self.rewrite_pmt(pkt) {
+                    Some(rewritten) => out.extend_from_slice(&rewritten),
+                    None => out.extend_from_slice(pkt),
+                }
+            } else {
+                match self.actions.get(&pid) {
+                    Some(SubtitleAction::Drop) => {}
+                    Some(SubtitleAction::Remap(to)) => {
+                        let start = out.len();
+                        out.extend_from_slice(pkt);
+                        out[start + 1] = (pkt[1] & 0xE0) | ((to >> 8) as u8 & 0x1F);
+                        out[start + 2] = *to as u8;
+                    }
+                    _ => out.extend_from_slice(pkt),
+                }
+            }
+        }
+        self.partial = packets.remainder().to_vec();
+        bytes::Bytes::from(out)
+    }
+
+    /// Resolve the PMT's subtitle actions and return the packet with those
+    /// applied and its audio reordered (None if nothing changes or the
+    /// section can't be handled)
+    fn rewrite_pmt(&mut self, pkt: &[u8]) -> Option<[u8; TS_PACKET_SIZE]> {
+        let (start, section) = psi_section(pkt)?;
+        if section[0] != 0x02 || section.len() < 16 {
+            return None;
+        }
+        let info_len = (((section[10] & 0x0F) as usize) << 8) | section[11] as usize;
+        let es_start = 12 + info_len;
+        let es_end = section.len() - 4;
+        if es_start > es_end {
+            return None;
+        }
+
+        self.actions.clear();
+        // Kept entries, with the audio ones' language rank (None = not audio)
+        let mut entries: Vec<(Vec<u8>, Option<usize>)> = Vec::new();
+        let mut pos = es_start;
+        while pos + 5 <= es_end {
+            let stream_type = section[pos];
+            let pid = (((section[pos + 1] & 0x1F) as u16) << 8) | section[pos + 2] as u16;
+            let es_info_len =
+                (((section[pos + 3] & 0x0F) as usize) << 8) | section[pos + 4] as usize;
+            let entry_end = (pos + 5 + es_info_len).min(es_end);
+            let es_info = &section[pos + 5..entry_end];
+            let mut entry = section[pos..entry_end].to_vec();
+            pos = entry_end;
+
+            if is_subtitle_entry(stream_type, es_info) {
+                let action = self
+                    .subtitles
+                    .pids
+                    .get(&pid)
+                    .copied()
+                    .unwrap_or(self.subtitles.default);
+                match action {
+                    SubtitleAction::Keep => {}
+                    SubtitleAction::Drop => {
+                        self.actions.insert(pid, action);
+                        continue;
+                    }
+                    SubtitleAction::Remap(to) => {
+                        self.actions.insert(pid, action);
+                        entry[1] = 0xE0 | ((to >> 8) as u8 & 0x1F);
+                        entry[2] = to as u8;
+                    }
+                }
+            }
+            let rank = is_audio_entry(stream_type, es_info).then(|| {
+                entry_language(es_info)
+                    .and_then(|lang| self.audio_languages.iter().position(|l| *l == lang))
+                    .unwrap_or(self.audio_languages.len())
+            });
+            entries.push((entry, rank));
+        }
+
+        // Audio entries keep their slots but are ordered by language
+        // preference (stable, so unlisted languages keep upstream order)
+        let mut audio: Vec<_> = entries
+            .iter()
+            .filter_map(|(e, rank)| rank.map(|r| (r, e.clone())))
+            .collect();
+        audio.sort_by_key(|(rank, _)| *rank);
+        let mut audio = audio.into_iter().map(|(_, e)| e);
+        let mut rewritten = section[..es_start].to_vec();
+        for (entry, rank) in &entries {
+            match rank {
+                Some(_) => rewritten.extend_from_slice(&audio.next().unwrap_or_default()),
+                None => rewritten.extend_from_slice(entry),
+            }
+        }
+        if rewritten[..] == section[..section.len() - 4] {
+            return None;
+        }
+        Some(repack_section(pkt, start, rewritten))
+    }
+}
+
+/// PMT PIDs listed in a PAT packet (None if it holds no complete PAT section)
+pub fn parse_pat(pkt: &[u8]) -> Option<std::collections::HashSet<u16>> {
+    let (_, section) = psi_section(pkt)?;
+    if section[0] != 0x00 || section.len() < 12 {
+        return None;
+    }
+    let pids = section[8..section.len() - 4]
+        .chunks_exact(4)
+        .filter(|p| p[0] != 0 || p[1] != 0) // program 0 is the NIT
+        .map(|p| (((p[2] & 0x1F) as u16) << 8) | p[3] as u16)
+        .collect();
+    Some(pids)
+}
+
+/// Write a rewritten PSI section (without CRC) back into its packet at
+/// `start`, fixing section_length and the CRC and padding with stuffing
+fn repack_section(pkt: &[u8], start: usize, mut section: Vec<u8>) -> [u8; TS_PACKET_SIZE] {
+    // section_length counts everything after the length field, CRC included
+    let section_length = section.len() + 4 - 3;
+    section[1] = (section[1] & 0xF0) | ((section_length >> 8) as u8 & 0x0F);
+    section[2] = section_length as u8;
+    let crc = crc32_mpeg2(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+
+    let mut out = [0xFFu8; TS_PACKET_SIZE];
+    out[..start].copy_from_slice(&pkt[..start]);
+    out[start..start + section.len()].copy_from_slice(&section);
+    out
+}
+
+/// Turn a packet into an adaptation-field-only packet, keeping its PCR
+/// (None if it carries no PCR)
+fn strip_payload(pkt: &[u8]) -> Option<[u8; TS_PACKET_SIZE]> {
+    let adaptation = (pkt[3] >> 4) & 0x3;
+    let af_len = pkt[4] as usize;
+    // PCR_flag
+    if adaptation & 0x2 == 0 || af_len == 0 || pkt[5] & 0x10 == 0 || 5 + af_len > TS_PACKET_SIZE {
+        return None;
+    }
+    let mut out = [0xFFu8; TS_PACKET_SIZE];
+    out[..4].copy_from_slice(&pkt[..4]);
+    out[1] &= !0x40; // no payload unit starts here any more
+    out[3] = (pkt[3] & 0xC0) | 0x20 | (pkt[3] & 0x0F);
+    out[4] = (TS_PACKET_SIZE - 5) as u8;
+    out[5..5 + af_len].copy_from_slice(&pkt[5..5 + af_len]);
+    out[5] &= !0x40; // clear random_access_indicator
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A packet on `pid` with an optional adaptation field and `payload`,
+    /// padded with stuffing bytes
+    fn packet(pid: u16, adaptation: Option<&[u8]>, payload: &[u8]) -> [u8; TS_PACKET_SIZE] {
+        let mut pkt = [0xFFu8; TS_PACKET_SIZE];
+        pkt[0] = SYNC_BYTE;
+        pkt[1] = if payload.is_empty() { 0 } else { 0x40 } | (pid >> 8) as u8;
+        pkt[2] = pid as u8;
+        let mut pos = 4;
+        pkt[3] = match adaptation {
+            Some(field) => {
+                pkt[4] = field.len() as u8;
+                pkt[5..5 + field.len()].copy_from_slice(field);
+                pos += 1 + field.len();
+                if payload.is_empty() {
+                    0x20
+                } else {
+                    0x30
+                }
+            }
+            None => 0x10,
+        };
+        pkt[pos..pos + payload.len()].copy_from_slice(payload);
+        pkt
+    }
+
+    /// A PES start with no optional header fields, stream `stream_id`
+    fn pes(stream_id: u8, es: &[u8]) -> Vec<u8> {
+        let mut data = vec![0, 0, 1, stream_id, 0, 0, 0x80, 0, 0];
+        data.extend_from_slice(es);
+        data
+    }
+
+    #[test]
+    fn find_sync_needs_a_confirming_sync_byte() {
+        let stream: Vec<u8> = [packet(0x100, None, &[]); 3].concat();
+        assert_eq!(find_sync(&stream), Some(0));
+
+        // A stray sync byte in leading junk isn't a packet boundary
+        let mut misaligned = vec![0x00, SYNC_BYTE, 0x00, 0x00, 0x00];
+        misaligned.extend_from_slice(&stream);
+        assert_eq!(find_sync(&misaligned), Some(5));
+
+        assert_eq!(find_sync(&[0u8; 2 * TS_PACKET_SIZE]), None);
+        assert_eq!(find_sync(&[]), None);
+    }
+
+    #[test]
+    fn random_access_indicator_marks_a_keyframe() {
+        assert!(contains_keyframe(&packet(0x100, Some(&[0x40]), &[])));
+        assert!(!contains_keyframe(&packet(0x100, Some(&[0x00]), &[])));
+        assert!(!contains_keyframe(&packet(0x100, None, &[0xAA; 16])));
+    }
+
+    #[test]
+    fn keyframe_nal_units_in_a_video_pes_mark_a_keyframe() {
+        let keyframe = |payload: &[u8]| contains_keyframe(&packet(0x100, None, payload));
+        // H.264 IDR slice and SPS
+        assert!(keyframe(&pes(0xE0, &[0, 0, 0, 1, 0x65])));
+        assert!(keyframe(&pes(0xE0, &[0, 0, 1, 0x67])));
+        // HEVC IDR_W_RADL and VPS
+        assert!(keyframe(&pes(0xE0, &[0, 0, 1, 19 << 1, 0x01])));
+        assert!(keyframe(&pes(0xE0, &[0, 0, 1, 32 << 1, 0x01])));
+
+        // Non-IDR slices, forbidden_zero_bit set, and non-video streams don't
+        assert!(!keyframe(&pes(0xE0, &[0, 0, 1, 0x41])));
+        assert!(!keyframe(&pes(0xE0, &[0, 0, 1, 0x85])));
+        assert!(!keyframe(&pes(0xC0, &[0, 0, 1, 0x65])));
+    }
+
+    #[test]
+    fn keyframes_are_found_past_the_first_packet_only_while_in_sync() {
+        let plain = packet(0x100, None, &[0xAA; 16]);
+        let keyframe = packet(0x100, Some(&[0x40]), &[]);
+        let mut data = [plain, plain, keyframe].concat();
+        assert!(contains_keyframe(&data));
+
+        data[TS_PACKET_SIZE] = 0x00;
+        assert!(!contains_keyframe(&data));
+    }
+
+    /// An adaptation field carrying a PCR of `base` (90 kHz) and `extension`
+    fn pcr_field(base: u64, extension: u16) -> [u8; 7] {
+        [
+            0x10,
+            (base >> 25) as u8,
+            (base >> 17) as u8,
+            (base >> 9) as u8,
+            (base >> 1) as u8,
+            ((base & 1) << 7) as u8 | 0x7E | (extension >> 8) as u8,
+            extension as u8,
+        ]
+    }
+
+    #[test]
+    fn keyframe_offset_points_at_the_keyframe_packet() {
+        let plain = packet(0x100, None, &[0xAA; 16]);
+        let keyframe = packet(0x100, Some(&[0x40]), &[]);
+        let mut data = vec![0x00; 7];
+        data.extend([plain, plain, keyframe, plain].concat());
+        assert_eq!(keyframe_offset(&data), Some(7 + 2 * TS_PACKET_SIZE));
+        assert_eq!(keyframe_offset(&[plain, plain].concat()), None);
+    }
+
+    #[test]
+    fn pcr_range_spans_the_first_and_last_pcr() {
+        let plain = packet(0x100, None, &[0xAA; 16]);
+        let data = [
+            plain,
+            packet(0x100, Some(&pcr_field(90_000, 0)), &[]),
+            plain,
+            packet(0x100, Some(&pcr_field(270_000, 150)), &[0xAA; 16]),
+            plain,
+        ]
+        .concat();
+        let (first, last) = pcr_range(&data).unwrap();
+        assert!((first - 1.0).abs() < 1e-9);
+        assert!((last - (3.0 + 150.0 / 27_000_000.0)).abs() < 1e-9);
+
+        // The 33-bit base keeps its top bit
+        let high = packet(0x100, Some(&pcr_field(1 << 32, 0)), &[]);
+        let (first, _) = pcr_range(&high).unwrap();
+        assert!((first - (1u64 << 32) as f64 / 90_000.0).abs() < 1e-9);
+        assert_eq!(pcr_range(&[plain, plain].concat()), None);
+    }
+
+    #[test]
+    fn aligner_reframes_reads_into_whole_packets() {
+        let stream: Vec<u8> = (0..6u16)
+            .flat_map(|i| packet(0x100 + i, None, &[i as u8; 8]))
+            .collect();
+        let mut aligner = PacketAligner::default();
+        // Leading junk is dropped and reads split mid-packet are rejoined
+        let mut input = vec![0xAB; 50];
+        input.extend_from_slice(&stream);
+        let mut out = Vec::new();
+        for read in input.chunks(100) {
+            let aligned = aligner.align(read);
+            assert_eq!(aligned.len() % TS_PACKET_SIZE, 0);
+            out.extend(aligned);
+        }
+        assert!(out == stream);
+        assert_eq!(aligner.take_sync_losses(), 0);
+    }
+
+    #[test]
+    fn aligner_counts_a_sync_loss_once_and_resyncs() {
+        let pkt = packet(0x100, None, &[0xAA; 16]);
+        let mut aligner = PacketAligner::default();
+        assert_eq!(aligner.align(&[pkt; 2].concat()).len(), 2 * TS_PACKET_SIZE);
+
+        // Garbage mid-stream: everything up to the next confirmed packet goes
+        let mut input = vec![0x00; 3 * TS_PACKET_SIZE + 10];
+        input.extend([pkt; 3].concat());
+        assert!(aligner.align(&input) == [pkt; 3].concat());
+        assert_eq!(aligner.take_sync_losses(), 1);
+        assert_eq!(aligner.take_sync_losses(), 0);
+    }
+
+    #[test]
+    fn aligner_passes_non_ts_data_through() {
+        let mut aligner = PacketAligner::default();
+        let data = vec![0x11; 3 * TS_PACKET_SIZE];
+        assert_eq!(aligner.align(&data), data);
+        assert_eq!(aligner.align(&[0x47, 0x00]), [0x47, 0x00]);
+    }
+
+    /// A long-form PSI section with its CRC
+    fn section(table_id: u8, id: u16, body: &[u8]) -> Vec<u8> {
+        let length = 5 + body.len() + 4;
+        let mut section = vec![
+            table_id,
+            0xB0 | (length >> 8) as u8,
+            length as u8,
+            (id >> 8) as u8,
+            id as u8,
+            0xC1,
+            0x00,
+            0x00,
+        ];
+        section.extend_from_slice(body);
+        section.extend_from_slice(&crc32_mpeg2(&section).to_be_bytes());
+        section
+    }
+
+    /// A packet starting `section` on `pid`
+    fn psi_packet(pid: u16, section: &[u8]) -> [u8; TS_PACKET_SIZE] {
+        packet(pid, None, &[&[0x00], section].concat())
+    }
+
+    /// A PAT listing program 1 on `pmt_pid` (and the NIT on 0x10)
+    fn pat(pmt_pid: u16) -> [u8; TS_PACKET_SIZE] {
+        let body = [
+            0,
+            0,
+            0xE0,
+            0x10,
+            0,
+            1,
+            0xE0 | (pmt_pid >> 8) as u8,
+            pmt_pid as u8,
+        ];
+        psi_packet(PAT_PID, &section(0x00, 1, &body))
+    }
+
+    /// A PMT on `pid` with `(stream_type, pid, ES_info)` entries
+    fn pmt(pid: u16, pcr_pid: u16, entries: &[(u8, u16, &[u8])]) -> [u8; TS_PACKET_SIZE] {
+        let mut body = vec![0xE0 | (pcr_pid >> 8) as u8, pcr_pid as u8, 0xF0, 0x00];
+        for (stream_type, es_pid, es_info) in entries {
+            body.extend_from_slice(&[
+                *stream_type,
+                0xE0 | (es_pid >> 8) as u8,
+                *es_pid as u8,
+                0xF0,
+                es_info.len() as u8,
+            ]);
+            body.extend_from_slice(es_info);
+        }
+        psi_packet(pid, &section(0x02, 1, &body))
+    }
+
+    /// (stream_type, pid) of each entry in a PMT packet, checking its CRC
+    fn pmt_entries(pkt: &[u8]) -> Vec<(u8, u16)> {
+        let (_, section) = psi_section(pkt).unwrap();
+        assert_eq!(crc32_mpeg2(section), 0, "bad CRC");
+        let mut entries = Vec::new();
+        let mut pos = 12;
+        while pos + 5 <= section.len() - 4 {
+            let pid = (((section[pos + 1] & 0x1F) as u16) << 8) | section[pos + 2] as u16;
+            entries.push((section[pos], pid));
+            pos += 5 + section[pos + 4] as usize;
+        }
+        entries
+    }
+
+    #[test]
+    fn crc32_mpeg2_matches_the_check_value() {
+        assert_eq!(crc32_mpeg2(b"123456789"), 0x0376_E6E7);
+        // A section followed by its CRC checks to zero
+        assert_eq!(crc32_mpeg2(&section(0x00, 1, &[0, 1, 0xE1, 0x00])), 0);
+    }
+
+    #[test]
+    fn audio_only_filter_strips_video_but_keeps_the_pcr() {
+        let video = packet(0x100, Some(&[0x10, 0, 0, 0, 0, 0x7E, 0]), &[0xAA; 16]);
+        let audio = packet(0x101, None, &[0xBB; 16]);
+        let stream = [
+            pat(0x1000),
+            pmt(0x1000, 0x100, &[(0x1B, 0x100, &[]), (0x0F, 0x101, &[])]),
+            video,
+            audio,
+            packet(0x100, None, &[0xAA; 16]),
+        ]
+        .concat();
+        let mut filter = AudioOnlyFilter::default();
+        let out = filter.filter(&stream);
+        let packets: Vec<&[u8]> = out.chunks(TS_PACKET_SIZE).collect();
+
+        assert_eq!(packets.len(), 4);
+        assert_eq!(packets[0], pat(0x1000));
+        assert_eq!(pmt_entries(packets[1]), [(0x0F, 0x101)]);
+        // The PCR-bearing video packet stays, without its payload
+        assert_eq!(packet_pid(packets[2]), 0x100);
+        assert_eq!(packet_pcr(packets[2]), packet_pcr(&video));
+        assert_eq!(packets[2][3] & 0x30, 0x20);
+        assert_eq!(packets[3], audio);
+    }
+
+    #[test]
+    fn program_filter_drops_and_remaps_subtitle_pids() {
+        let subtitling: &[u8] = &[0x59, 0x08, b'e', b'n', b'g', 0x10, 0, 1, 0, 1];
+        let teletext: &[u8] = &[0x56, 0x05, b'd', b'e', b'u', 0x09, 0x00];
+        let stream = [
+            pat(0x1000),
+            pmt(
+                0x1000,
+                0x100,
+                &[
+                    (0x1B, 0x100, &[]),
+                    (0x06, 0x102, subtitling),
+                    (0x06, 0x103, teletext),
+                ],
+            ),
+            packet(0x102, None, &[0xCC; 16]),
+            packet(0x103, None, &[0xDD; 16]),
+            packet(0x100, None, &[0xAA; 16]),
+        ]
+        .concat();
+        let rules = SubtitleConfig {
+            default: SubtitleAction::Drop,
+            pids: [(0x102, SubtitleAction::Remap(0x200))].into(),
+        };
+        let mut filter = ProgramFilter::new(Some(rules), &[]).unwrap();
+        let out = filter.filter(&stream);
+        let packets: Vec<&[u8]> = out.chunks(TS_PACKET_SIZE).collect();
+
+        assert_eq!(packets.len(), 4);
+        assert_eq!(pmt_entries(packets[1]), [(0x1B, 0x100), (0x06, 0x200)]);
+        assert_eq!(packet_pid(packets[2]), 0x200);
+        assert_eq!(packets[2][4..], packet(0x102, None, &[0xCC; 16])[4..]);
+        assert_eq!(packet_pid(packets[3]), 0x100);
+    }
+
+    #[test]
+    fn program_filter_lists_preferred_audio_languages_first() {
+        let lang = |code: &[u8; 3]| [&[0x0A, 0x04][..], code, &[0x00]].concat();
+        let (deu, eng, fra) = (lang(b"deu"), lang(b"ENG"), lang(b"fra"));
+        let upstream = pmt(
+            0x1000,
+            0x100,
+            &[
+                (0x1B, 0x100, &[]),
+                (0x0F, 0x101, &deu),
+                (0x06, 0x104, &[0x59, 0x00]),
+                (0x03, 0x102, &fra),
+                (0x81, 0x103, &eng),
+            ],
+        );
+        let languages = ["eng".to_string(), "fra".to_string()];
+        let mut filter = ProgramFilter::new(None, &languages).unwrap();
+        let out = filter.filter(&[pat(0x1000), upstream].concat());
+
+        // Audio takes the audio slots in preference order, the rest stays put
+        assert_eq!(
+            pmt_entries(&out[TS_PACKET_SIZE..]),
+            [
+                (0x1B, 0x100),
+                (0x81, 0x103),
+                (0x06, 0x104),
+                (0x03, 0x102),
+                (0x0F, 0x101)
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_pat_lists_pmt_pids_without_the_nit() {
+        assert_eq!(parse_pat(&pat(0x1000)), Some([0x1000].into()));
+        // Not a PAT, or a section that doesn't start in this packet
+        assert_eq!(parse_pat(&pmt(0x1000, 0x100, &[])), None);
+        let mut continuation = pat(0x1000);
+        continuation[1] &= !0x40;
+        assert_eq!(parse_pat(&continuation), None);
+    }
+
+    #[test]
+    fn psi_cache_completes_once_every_listed_pmt_is_seen() {
+        let mut cache = PsiCache::default();
+        let program = pmt(0x1000, 0x100, &[(0x1B, 0x100, &[])]);
+        assert!(!cache.update(&program));
+        assert!(!cache.update(&pat(0x1000)));
+        assert!(cache.tables().is_none());
+        assert!(cache.update(&[packet(0x100, None, &[0xAA; 16]), program].concat()));
+        assert_eq!(cache.tables().unwrap(), [pat(0x1000), program].concat());
+        // Further tables refresh the cache without completing it again
+        assert!(!cache.update(&[pat(0x1000), program].concat()));
+
+        // A PAT moving the program drops the old PMT until the new one arrives
+        let moved = pmt(0x1001, 0x100, &[(0x1B, 0x100, &[])]);
+        assert!(!cache.update(&pat(0x1001)));
+        assert!(cache.tables().is_none());
+        assert!(cache.update(&moved));
+        assert_eq!(cache.tables().unwrap(), [pat(0x1001), moved].concat());
+
+        cache.clear();
+        assert!(cache.tables().is_none());
+    }
+
+    #[test]
+    fn source_markers_are_crc_protected_and_versioned() {
+        let mut marker = SourceMarker::default();
+        assert!(marker.take().is_none());
+        marker.set(0x1FF0, b"first");
+        marker.set(0x1FF0, b"second");
+        let pkt = marker.take().unwrap();
+        assert!(marker.take().is_none());
+
+        assert_eq!(packet_pid(&pkt), 0x1FF0);
+        assert_eq!(pkt[3] & 0x0F, 1);
+        let (_, section) = psi_section(&pkt).unwrap();
+        assert_eq!(section[0], SOURCE_MARKER_TABLE_ID);
+        assert_eq!((section[5] >> 1) & 0x1F, 1);
+        assert_eq!(&section[8..section.len() - 4], b"second");
+        assert_eq!(crc32_mpeg2(section), 0);
+
+        // Oversized notices are cut to fit the packet
+        marker.set(0x1FF0, &[b'x'; 400]);
+        let pkt = marker.take().unwrap();
+        let (_, section) = psi_section(&pkt).unwrap();
+        assert_eq!(section.len(), TS_PACKET_SIZE - 5);
+        assert_eq!(crc32_mpeg2(section), 0);
+    }
+}
@@ -0,0 +1,167 @@
+use crate::models::{PidStats, TsHealth};
+use crate::ts::{self, SYNC_BYTE, TS_PACKET_SIZE};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tokio::time::Instant;
+
+const PAT_PID: u16 = 0x0000;
+const NULL_PID: u16 = 0x1FFF;
+
+/// Running health counters for a channel's transport stream, fed with the
+/// packet-aligned data read from upstream
+#[derive(Default)]
+pub struct TsAnalyzer {
+    /// Last continuity counter per PID on the current source
+    continuity: HashMap<u16, u8>,
+    /// PMT PIDs listed by the latest PAT
+    pmt_pids: HashSet<u16>,
+    packets: u64,
+    sync_losses: u64,
+    continuity_errors: u64,
+    scrambled_packets: u64,
+    last_pat: Option<Instant>,
+    last_pmt: Option<Instant>,
+    pids: BTreeMap<u16, PidStats>,
+}
+
+impl TsAnalyzer {
+    /// Forget continuity state on a new upstream connection, whose counters
+    /// need not follow on from the last one's
+    pub fn new_source(&mut self) {
+        self.continuity.clear();
+    }
+
+    /// Count `sync_losses` reported by the aligner and inspect each packet
+    /// in `data`
+    pub fn inspect(&mut self, data: &[u8], sync_losses: u64) {
+        self.sync_losses += sync_losses;
+        for pkt in data.chunks_exact(TS_PACKET_SIZE) {
+            // Only the case for a stream the aligner passes through untouched
+            if pkt[0] != SYNC_BYTE {
+                continue;
+            }
+            self.inspect_packet(pkt);
+        }
+    }
+
+    fn inspect_packet(&mut self, pkt: &[u8]) {
+        let pid = ts::packet_pid(pkt);
+        self.packets += 1;
+        let stats = self.pids.entry(pid).or_default();
+        stats.packets += 1;
+        if pid == NULL_PID {
+            return;
+        }
+        if pkt[3] & 0xC0 != 0 {
+            self.scrambled_packets += 1;
+            stats.scrambled_packets += 1;
+        }
+
+        let adaptation = (pkt[3] >> 4) & 0x3;
+        let has_payload = adaptation & 0x1 != 0;
+        let discontinuity = adaptation & 0x2 != 0 && pkt[4] > 0 && pkt[5] & 0x80 != 0;
+        let counter = pkt[3] & 0x0F;
+        if let Some(last) = self.continuity.insert(pid, counter) {
+            // The counter only advances with a payload; a payload packet may
+            // also be repeated once with the same counter
+            let expected = if has_payload { (last + 1) & 0x0F } else { last };
+            if counter != expected && counter != last && !discontinuity {
+                self.continuity_errors += 1;
+                stats.continuity_errors += 1;
+            }
+        }
+
+        if pid == PAT_PID {
+            if let Some(pmt_pids) = ts::parse_pat(pkt) {
+                self.pmt_pids = pmt_pids;
+                self.last_pat = Some(Instant::now());
+            }
+        } else if self.pmt_pids.contains(&pid) && pkt[1] & 0x40 != 0 {
+            self.last_pmt = Some(Instant::now());
+        }
+    }
+
+    pub fn health(&self) -> TsHealth {
+        let age = |at: Option<Instant>| at.map(|at| at.elapsed().as_secs_f64());
+        TsHealth {
+            packets: self.packets,
+            sync_losses: self.sync_losses,
+            continuity_errors: self.continuity_errors,
+            scrambled_packets: self.scrambled_packets,
+            seconds_since_pat: age(self.last_pat),
+            seconds_since_pmt: age(self.last_pmt),
+            pids: self.pids.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A payload packet on `pid` with continuity counter `counter`
+    fn packet(pid: u16, counter: u8) -> [u8; TS_PACKET_SIZE] {
+        let mut pkt = [0xFFu8; TS_PACKET_SIZE];
+        pkt[..4].copy_from_slice(&[SYNC_BYTE, (pid >> 8) as u8, pid as u8, 0x10 | counter]);
+        pkt
+    }
+
+    /// An adaptation-field-only packet, optionally flagging a discontinuity
+    fn adaptation_only(pid: u16, counter: u8, discontinuity: bool) -> [u8; TS_PACKET_SIZE] {
+        let mut pkt = packet(pid, counter);
+        pkt[3] = 0x20 | counter;
+        pkt[4] = 1;
+        pkt[5] = if discontinuity { 0x80 } else { 0x00 };
+        pkt
+    }
+
+    fn errors_after(packets: &[[u8; TS_PACKET_SIZE]]) -> u64 {
+        let mut analyzer = TsAnalyzer::default();
+        analyzer.inspect(&packets.concat(), 0);
+        analyzer.health().continuity_errors
+    }
+
+    #[test]
+    fn continuity_counters_advance_with_payloads() {
+        let wrapping: Vec<_> = (0..20).map(|i| packet(0x100, i % 16)).collect();
+        assert_eq!(errors_after(&wrapping), 0);
+        // One repeat of a payload packet is allowed, a skipped value isn't
+        assert_eq!(errors_after(&[packet(0x100, 3), packet(0x100, 3)]), 0);
+        assert_eq!(errors_after(&[packet(0x100, 3), packet(0x100, 5)]), 1);
+        // Counters run per PID
+        assert_eq!(errors_after(&[packet(0x100, 3), packet(0x101, 9)]), 0);
+    }
+
+    #[test]
+    fn adaptation_only_packets_and_discontinuities_keep_continuity() {
+        let packets = [
+            packet(0x100, 3),
+            adaptation_only(0x100, 3, false),
+            packet(0x100, 4),
+            adaptation_only(0x100, 9, true),
+            packet(0x100, 10),
+        ];
+        assert_eq!(errors_after(&packets), 0);
+        assert_eq!(
+            errors_after(&[packet(0x100, 3), adaptation_only(0x100, 4, false)]),
+            1
+        );
+    }
+
+    #[test]
+    fn a_new_source_restarts_continuity_but_not_the_counts() {
+        let mut analyzer = TsAnalyzer::default();
+        analyzer.inspect(&packet(0x100, 3), 1);
+        analyzer.new_source();
+        let mut scrambled = packet(0x100, 12);
+        scrambled[3] |= 0x80;
+        analyzer.inspect(&[scrambled, packet(NULL_PID, 0)].concat(), 0);
+
+        let health = analyzer.health();
+        assert_eq!(health.packets, 3);
+        assert_eq!(health.sync_losses, 1);
+        assert_eq!(health.continuity_errors, 0);
+        assert_eq!(health.scrambled_packets, 1);
+        assert_eq!(health.pids[&0x100].packets, 2);
+        assert!(health.seconds_since_pat.is_none());
+    }
+}
@@ -0,0 +1,18 @@
+use axum::http::header;
+use axum::response::IntoResponse;
+
+/// The dashboard page, embedded at build time. It polls the status API and
+/// calls the control API for its actions, sending the control token the
+/// operator enters (kept in the browser's local storage).
+const INDEX_HTML: &str = include_str!("ui/index.html");
+
+/// Serve the built-in operator dashboard.
+pub async fn dashboard() -> impl IntoResponse {
+    (
+        [
+            (header::CONTENT_TYPE, "text/html; charset=utf-8"),
+            (header::CACHE_CONTROL, "no-cache"),
+        ],
+        INDEX_HTML,
+    )
+}
@@ -1,62 +1,220 @@
-use crate::state::{ActiveChannel, AppState};
+use crate::certs::CertificateInfo;
+use crate::chaos;
+use crate::models::EventKind;
+use crate::multicast::{self, ByteStream, MulticastSource};
+use crate::relay::{self, RelaySource};
+use crate::session;
+use crate::state::{ActiveChannel, AppState, Chunk, UpstreamTarget};
+use crate::ts;
+use crate::REQUEST_ID_HEADER;
 use bytes::Bytes;
-use reqwest::Client;
+use dashmap::mapref::entry::Entry;
+use reqwest::{header, Client, StatusCode};
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use tokio::sync::{broadcast, watch};
 use tokio::time::Instant;
+use tracing::Instrument;
 
 const BROADCAST_CAPACITY: usize = 64;
-const CHUNK_SIZE: usize = 188 * 1024; // ~188 KB (aligned to TS packet size)
-const MAX_FAILOVERS: u32 = 10;
+pub const CHUNK_SIZE: usize = 188 * 1024; // ~188 KB (aligned to TS packet size)
+/// Give up caching a GOP for joiners if no keyframe shows up within this much
+/// data (in bytes rather than chunks, since timed flushes send short ones)
+const GOP_CACHE_MAX_BYTES: usize = 16 * CHUNK_SIZE;
+const MAX_RESUME_ATTEMPTS: u32 = 5;
+const RESUME_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+/// Connect error for an account whose limit is reached across the cluster
+const CLUSTER_FULL: &str = "account is at its cluster-wide connection limit";
+/// How often a paused upstream rechecks the broadcast queue depth
+const DRAIN_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// Why a fetch ended without an upstream error
+enum FetchOutcome {
+    Stopped,
+    /// Continue from this source (tier change, account migration, quota
+    /// fallback), using the connection if it was already opened (handover)
+    Switch(UpstreamTarget, Option<Connection>),
+    /// An upstream byte quota ("daily"/"monthly") is used up and there is no
+    /// reduced variant to fall back to
+    QuotaExceeded(&'static str),
+}
+
+/// An opened upstream that hasn't been read from yet
+enum Connection {
+    Http(reqwest::Response),
+    /// `udp://` / `rtp://` input
+    Multicast(MulticastSource),
+    /// `relay://` input from another proxy
+    Relay(RelaySource),
+}
+
+/// Connection to a switch target being opened while the old one keeps streaming
+type PendingConnect<'a> = Pin<Box<dyn Future<Output = Result<Connection, String>> + Send + 'a>>;
+
+/// Byte position within a finite, range-capable upstream (e.g. a VOD file),
+/// used to resume with a Range request after the connection drops.
+#[derive(Default)]
+struct ResumeState {
+    /// Bytes received from the current URL so far
+    offset: u64,
+    /// Total size reported by the upstream, if known
+    total: Option<u64>,
+    /// Upstream advertised `Accept-Ranges: bytes`
+    accepts_ranges: bool,
+    /// Resumes in a row that delivered no data
+    attempts: u32,
+}
+
+impl ResumeState {
+    fn can_resume(&self) -> bool {
+        self.accepts_ranges
+            && self.offset > 0
+            && self.total.is_some_and(|t| self.offset < t)
+            && self.attempts < MAX_RESUME_ATTEMPTS
+    }
+
+    fn is_complete(&self) -> bool {
+        self.total.is_some_and(|t| t > 0 && self.offset >= t)
+    }
+}
+
+/// Return the running channel, starting its upstream if needed. A channel
+/// that is stopping is replaced by a fresh start.
+///
+/// Holding the map entry while starting ensures concurrent callers for the
+/// same channel share a single upstream. Returns None if no stream is
+/// available, or the channel's group is at its active-channel limit.
+/// `request_id` identifies the request that triggered the start; it is
+/// attached to the upstream task's span and channel-start event for log
+/// correlation, and sent upstream on the channel's connects.
+pub fn get_or_start_channel(
+    state: &Arc<AppState>,
+    channel_id: &str,
+    request_id: Option<&str>,
+) -> Option<Arc<ActiveChannel>> {
+    let running = |state: &AppState| {
+        state
+            .active_channels
+            .get(channel_id)
+            .filter(|active| !active.stopping())
+            .map(|active| active.clone())
+    };
+    if let Some(active) = running(state) {
+        return Some(active);
+    }
+    // Count the group's channels and start this one under the same lock
+    // (counting iterates `active_channels`, so not under the entry below)
+    let _group_start = state
+        .group_limit(channel_id)
+        .map(|_| state.group_starts.lock().unwrap());
+    if running(state).is_none() && state.group_full(channel_id) {
+        return None;
+    }
+    match state.active_channels.entry(channel_id.to_string()) {
+        Entry::Occupied(existing) if !existing.get().stopping() => Some(existing.get().clone()),
+        entry => {
+            // Select a stream + account (start on the standard tier; the
+            // upstream task upgrades once enough viewers join)
+            let (stream_id, account_id, url) = state.reserve_stream(channel_id, false)?;
+            let persistent = state
+                .channel_routes
+                .load()
+                .get(channel_id)
+                .is_some_and(|r| r.persistent);
+
+            let target = UpstreamTarget {
+                stream_id,
+                account_id,
+                url,
+                premium: false,
+            };
+            let span = tracing::info_span!(
+                "upstream",
+                channel = %channel_id,
+                started_by = request_id.unwrap_or("-")
+            );
+            let active = start_channel(
+                state.clone(),
+                channel_id.to_string(),
+                target,
+                persistent,
+                request_id.map(str::to_string),
+                span,
+            );
+            entry.insert(active.clone());
+            Some(active)
+        }
+    }
+}
 
 /// Start streaming a channel. Spawns a background task that:
 /// - Opens upstream HTTP connection
 /// - Reads chunks and broadcasts them
 /// - On failure, tries next stream (failover)
 /// - Stops when stop signal received or all streams exhausted
-pub fn start_channel(
+///
+/// The caller is responsible for registering the returned channel in
+/// `active_channels`, and hands over a connection slot already reserved on
+/// the target's account.
+fn start_channel(
     state: Arc<AppState>,
     channel_id: String,
-    stream_id: u64,
-    account_id: u64,
-    url: String,
+    target: UpstreamTarget,
+    persistent: bool,
+    request_id: Option<String>,
+    span: tracing::Span,
 ) -> Arc<ActiveChannel> {
-    let (tx, _) = broadcast::channel::<Bytes>(BROADCAST_CAPACITY);
+    let (tx, _) = broadcast::channel::<Chunk>(BROADCAST_CAPACITY);
     let (stop_tx, stop_rx) = watch::channel(false);
 
     let active = Arc::new(ActiveChannel {
-        stream_id,
-        account_id,
-        url: url.clone(),
+        channel_id: channel_id.clone(),
+        upstream: std::sync::Mutex::new(target.clone()),
+        persistent,
+        started_by: request_id.clone(),
         connected_since: Instant::now(),
         bytes_transferred: std::sync::atomic::AtomicU64::new(0),
         sender: tx.clone(),
+        gop_cache: std::sync::Mutex::new(Vec::new()),
+        recent_chunks: std::sync::Mutex::new(std::collections::VecDeque::new()),
+        keyframes_seen: std::sync::atomic::AtomicBool::new(false),
+        last_data_ms: std::sync::atomic::AtomicU64::new(0),
+        task_running: std::sync::atomic::AtomicBool::new(true),
+        backpressure_paused: std::sync::atomic::AtomicBool::new(false),
+        backpressure_pauses: std::sync::atomic::AtomicU64::new(0),
+        pending_switch: std::sync::Mutex::new(None),
+        idle_generation: std::sync::atomic::AtomicU64::new(0),
+        counters: state.channel_counters(&channel_id),
+        quality: std::sync::Mutex::new(Default::default()),
+        input_rate: std::sync::Mutex::new(Default::default()),
+        output_rate: std::sync::Mutex::new(Default::default()),
+        ts_health: std::sync::Mutex::new(Default::default()),
+        psi_tables: std::sync::Mutex::new(Default::default()),
+        source_marker: std::sync::Mutex::new(Default::default()),
+        receive_stats: std::sync::Mutex::new(Default::default()),
         clients: dashmap::DashMap::new(),
         stop_tx,
     });
 
-    state.increment_connections(account_id);
-    state
-        .active_channels
-        .insert(channel_id.clone(), active.clone());
+    state.events.record(
+        EventKind::ChannelStarted,
+        &channel_id,
+        None,
+        request_id.as_deref(),
+        format!("stream={}, account={}", target.stream_id, target.account_id),
+    );
 
     // Spawn the upstream reader task
     let state_clone = state.clone();
     let active_clone = active.clone();
-    tokio::spawn(async move {
-        upstream_loop(
-            state_clone,
-            channel_id,
-            stream_id,
-            account_id,
-            url,
-            tx,
-            stop_rx,
-            active_clone,
-        )
-        .await;
-    });
+    state.spawn_upstream(
+        async move {
+            upstream_loop(state_clone, channel_id, target, tx, stop_rx, active_clone).await;
+        }
+        .instrument(span),
+    );
 
     active
 }
@@ -64,26 +222,63 @@ pub fn start_channel(
 async fn upstream_loop(
     state: Arc<AppState>,
     channel_id: String,
-    mut stream_id: u64,
-    mut account_id: u64,
-    mut url: String,
-    tx: broadcast::Sender<Bytes>,
+    mut target: UpstreamTarget,
+    tx: broadcast::Sender<Chunk>,
     mut stop_rx: watch::Receiver<bool>,
     active: Arc<ActiveChannel>,
 ) {
-    let client = Client::new();
+    let client = state
+        .config
+        .upstream_client_builder()
+        .build()
+        .expect("failed to build upstream HTTP client");
     let mut failover_count: u32 = 0;
+    // Failures since the upstream last delivered data, for retry backoff
+    let mut failures_in_row: u32 = 0;
+    let mut same_url_retries: u32 = 0;
+    let mut resume = ResumeState::default();
+    let mut handover = None;
+    let mut first_connect = true;
+    // Cleared once a failed target's slot is released with nothing to fail
+    // over to
+    let mut holds_slot = true;
 
     loop {
+        if !std::mem::take(&mut first_connect) {
+            active.counters.reconnects.fetch_add(1, Ordering::Relaxed);
+            active.receive_stats.lock().unwrap().reconnects += 1;
+        }
         tracing::info!(
             "Channel {}: connecting to upstream {} (stream={}, account={})",
             channel_id,
-            url,
-            stream_id,
-            account_id
+            target.url,
+            target.stream_id,
+            target.account_id
         );
 
-        let result = fetch_upstream(&client, &url, &tx, &mut stop_rx, &active).await;
+        let bytes_before = active.bytes_transferred.load(Ordering::Relaxed);
+        let fetch_started = Instant::now();
+        let result = fetch_upstream(
+            &state,
+            &client,
+            &target,
+            &tx,
+            &mut stop_rx,
+            &active,
+            &mut resume,
+            handover.take(),
+        )
+        .await;
+        let delivered = active
+            .bytes_transferred
+            .load(Ordering::Relaxed)
+            .saturating_sub(bytes_before);
+        state.record_url_throughput(&target.url, delivered, fetch_started.elapsed());
+        if delivered > 0 {
+            failures_in_row = 0;
+            same_url_retries = 0;
+            resume.attempts = 0;
+        }
 
         // Check if we were told to stop
         if *stop_rx.borrow() {
@@ -91,96 +286,728 @@ async fn upstream_loop(
             break;
         }
 
-        // Upstream failed — try failover
-        if let Err(e) = result {
-            tracing::warn!("Channel {}: upstream error: {}", channel_id, e);
-            failover_count += 1;
-
-            if failover_count >= MAX_FAILOVERS {
-                tracing::error!("Channel {}: max failovers reached", channel_id);
-                break;
+        match result {
+            Ok(FetchOutcome::Stopped) => {}
+            Ok(FetchOutcome::Switch(next, response)) => {
+                // A target on another account comes with its slot reserved
+                if next.account_id != target.account_id {
+                    state.decrement_connections(target.account_id);
+                }
+                target = next;
+                resume = ResumeState::default();
+                same_url_retries = 0;
+                handover = response;
+                *active.upstream.lock().unwrap() = target.clone();
+                mark_source_change(&state, &active, "switch", &target);
             }
-
-            state.decrement_connections(account_id);
-
-            if let Some((next_sid, next_aid, next_url)) =
-                state.select_next_stream(&channel_id, stream_id, account_id)
-            {
-                tracing::info!(
-                    "Channel {}: failing over to stream={}, account={}",
+            Ok(FetchOutcome::QuotaExceeded(period)) => {
+                tracing::warn!(
+                    "Channel {}: {} upstream quota exceeded, stopping",
                     channel_id,
-                    next_sid,
-                    next_aid
+                    period
                 );
-                stream_id = next_sid;
-                account_id = next_aid;
-                url = next_url;
-                state.increment_connections(account_id);
-            } else {
-                tracing::error!("Channel {}: no more streams available", channel_id);
                 break;
             }
+            // Upstream failed — try failover
+            Err(e) => {
+                if resume.is_complete() {
+                    tracing::info!("Channel {}: upstream file fully delivered", channel_id);
+                    break;
+                }
+
+                // Finite range-capable source: pick up where we left off on the same URL
+                if resume.can_resume() {
+                    resume.attempts += 1;
+                    tracing::warn!(
+                        "Channel {}: upstream error: {}, resuming at byte {} (attempt {}/{})",
+                        channel_id,
+                        e,
+                        resume.offset,
+                        resume.attempts,
+                        MAX_RESUME_ATTEMPTS
+                    );
+                    tokio::select! {
+                        _ = stop_rx.changed() => break,
+                        _ = tokio::time::sleep(RESUME_DELAY) => continue,
+                    }
+                }
+
+                tracing::warn!("Channel {}: upstream error: {}", channel_id, e);
+                // Not the source's fault: move on without retrying or
+                // counting it against the account
+                let cluster_full = e == CLUSTER_FULL;
+                failover_count += 1;
+                failures_in_row += 1;
+
+                let policy = state
+                    .channel_routes
+                    .load()
+                    .get(&channel_id)
+                    .map(|r| r.failover.clone())
+                    .unwrap_or_default();
+                if failover_count >= policy.max_attempts {
+                    tracing::error!("Channel {}: max failovers reached", channel_id);
+                    state.events.record(
+                        EventKind::FailoversExhausted,
+                        &channel_id,
+                        None,
+                        None,
+                        format!("max failovers reached, last error: {}", e),
+                    );
+                    break;
+                }
+                let delay = policy.retry_delay(failures_in_row);
+                if !delay.is_zero() && !cluster_full {
+                    tracing::info!("Channel {}: retrying in {:?}", channel_id, delay);
+                    tokio::select! {
+                        _ = stop_rx.changed() => break,
+                        _ = tokio::time::sleep(delay) => {}
+                    }
+                }
+                if same_url_retries < policy.same_url_retries && !cluster_full {
+                    same_url_retries += 1;
+                    tracing::info!(
+                        "Channel {}: retrying same URL (attempt {}/{})",
+                        channel_id,
+                        same_url_retries,
+                        policy.same_url_retries
+                    );
+                    continue;
+                }
+                same_url_retries = 0;
+
+                state.decrement_connections(target.account_id);
+                if !cluster_full {
+                    if let Some(account) = state.accounts.load().get(&target.account_id) {
+                        account.failovers.fetch_add(1, Ordering::Relaxed);
+                    }
+                    state.record_account_failure(target.account_id);
+                }
+
+                if let Some((next_sid, next_aid, next_url)) = state.reserve_next_stream(
+                    &channel_id,
+                    target.premium,
+                    target.stream_id,
+                    target.account_id,
+                ) {
+                    tracing::info!(
+                        "Channel {}: failing over to stream={}, account={}",
+                        channel_id,
+                        next_sid,
+                        next_aid
+                    );
+                    state.events.record(
+                        EventKind::Failover,
+                        &channel_id,
+                        None,
+                        None,
+                        format!(
+                            "stream={}, account={} failed ({}), now stream={}, account={}",
+                            target.stream_id, target.account_id, e, next_sid, next_aid
+                        ),
+                    );
+                    target.stream_id = next_sid;
+                    target.account_id = next_aid;
+                    target.url = next_url;
+                    resume = ResumeState::default();
+                    active.counters.failovers.fetch_add(1, Ordering::Relaxed);
+                    *active.upstream.lock().unwrap() = target.clone();
+                    mark_source_change(&state, &active, "failover", &target);
+                } else {
+                    tracing::error!("Channel {}: no more streams available", channel_id);
+                    holds_slot = false;
+                    state.events.record(
+                        EventKind::FailoversExhausted,
+                        &channel_id,
+                        None,
+                        None,
+                        format!("no more streams available, last error: {}", e),
+                    );
+                    break;
+                }
+            }
         }
     }
 
-    // Cleanup
-    state.decrement_connections(account_id);
-    state.active_channels.remove(&channel_id);
+    // Cleanup; clients still waiting for a first chunk learn none is coming
+    active.task_running.store(false, Ordering::Relaxed);
+    active.stop_tx.send_replace(true);
+    if holds_slot {
+        state.decrement_connections(target.account_id);
+    }
+    // A viewer may already have started the channel afresh
+    state
+        .active_channels
+        .remove_if(&channel_id, |_, a| Arc::ptr_eq(a, &active));
     tracing::info!("Channel {}: upstream task exited", channel_id);
+    state.events.record(
+        EventKind::ChannelStopped,
+        &channel_id,
+        None,
+        None,
+        format!(
+            "{} bytes delivered",
+            active.bytes_transferred.load(Ordering::Relaxed)
+        ),
+    );
 }
 
-async fn fetch_upstream(
+/// Queue the in-band notice of a source change, sent ahead of the new
+/// source's first chunk (when FAILOVER_MARKER_PID is set)
+fn mark_source_change(
+    state: &AppState,
+    active: &ActiveChannel,
+    event: &str,
+    target: &UpstreamTarget,
+) {
+    let pid = state.config.failover_marker_pid;
+    if pid == 0 {
+        return;
+    }
+    let notice = serde_json::json!({
+        "event": event,
+        "time": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+        "stream_id": target.stream_id,
+        "account_id": target.account_id,
+    });
+    active
+        .source_marker
+        .lock()
+        .unwrap()
+        .set(pid, notice.to_string().as_bytes());
+}
+
+/// Check whether the viewer count calls for the other tier, and if so pick
+/// an available source for it.
+fn tier_switch_target(
+    state: &AppState,
+    active: &ActiveChannel,
+    current: &UpstreamTarget,
+) -> Option<FetchOutcome> {
+    // Over quota, only the reduced variants are in play
+    if state.quota_exceeded(&active.channel_id).is_some() {
+        return None;
+    }
+    let premium = state
+        .channel_routes
+        .load()
+        .get(&active.channel_id)?
+        .wants_premium(active.clients.len());
+    if premium == current.premium {
+        return None;
+    }
+    let (stream_id, account_id, url) = reserve_switch(state, active, current, premium)?;
+    tracing::info!(
+        "Channel {}: switching to {} tier (stream={}, account={})",
+        active.channel_id,
+        if premium { "premium" } else { "standard" },
+        stream_id,
+        account_id
+    );
+    let next = UpstreamTarget {
+        stream_id,
+        account_id,
+        url,
+        premium,
+    };
+    Some(FetchOutcome::Switch(next, None))
+}
+
+/// If the current account is being drained for maintenance, pick a source
+/// on another account (None keeps the current one if nothing else is free).
+fn migration_target(
+    state: &AppState,
+    active: &ActiveChannel,
+    current: &UpstreamTarget,
+) -> Option<FetchOutcome> {
+    if !state
+        .accounts
+        .load()
+        .get(&current.account_id)
+        .is_some_and(|a| a.wants_migration())
+    {
+        return None;
+    }
+    let (stream_id, account_id, url) = reserve_switch(state, active, current, current.premium)?;
+    tracing::info!(
+        "Channel {}: account {} disabled, migrating to stream={}, account={}",
+        active.channel_id,
+        current.account_id,
+        stream_id,
+        account_id
+    );
+    let next = UpstreamTarget {
+        stream_id,
+        account_id,
+        url,
+        premium: current.premium,
+    };
+    Some(FetchOutcome::Switch(next, None))
+}
+
+/// Once a quota is used up, move to a reduced variant (or stop if there is
+/// none). None while within quota or already on a reduced variant.
+fn quota_outcome(
+    state: &AppState,
+    active: &ActiveChannel,
+    current: &UpstreamTarget,
+) -> Option<FetchOutcome> {
+    let period = state.quota_exceeded(&active.channel_id)?;
+    let on_reduced = state
+        .channel_routes
+        .load()
+        .get(&active.channel_id)?
+        .quota_streams
+        .iter()
+        .any(|s| s.id == current.stream_id && s.urls.iter().any(|u| u.url == current.url));
+    if on_reduced {
+        return None;
+    }
+    let Some((stream_id, account_id, url)) =
+        reserve_switch(state, active, current, current.premium)
+    else {
+        return Some(FetchOutcome::QuotaExceeded(period));
+    };
+    tracing::warn!(
+        "Channel {}: {} upstream quota exceeded, switching to reduced stream={}, account={}",
+        active.channel_id,
+        period,
+        stream_id,
+        account_id
+    );
+    let next = UpstreamTarget {
+        stream_id,
+        account_id,
+        url,
+        premium: current.premium,
+    };
+    Some(FetchOutcome::Switch(next, None))
+}
+
+/// Pick a source to switch to, reserving a slot on its account unless it is
+/// the current one, whose slot the channel keeps
+fn reserve_switch(
+    state: &AppState,
+    active: &ActiveChannel,
+    current: &UpstreamTarget,
+    premium: bool,
+) -> Option<(u64, u64, String)> {
+    let (stream_id, account_id, url) = state.reserve_stream(&active.channel_id, premium)?;
+    if account_id == current.account_id {
+        state.decrement_connections(account_id);
+    }
+    Some((stream_id, account_id, url))
+}
+
+/// A slot reserved on a pending switch target's account while it connects,
+/// released unless the switch goes through
+struct SwitchSlot<'a> {
+    state: &'a AppState,
+    account_id: Option<u64>,
+}
+
+impl SwitchSlot<'_> {
+    /// Reserve a slot for switching from `current` to `next`, or None if
+    /// `next`'s account is full. Staying on the same account needs none.
+    fn reserve<'a>(
+        state: &'a AppState,
+        current: &UpstreamTarget,
+        next: &UpstreamTarget,
+    ) -> Option<SwitchSlot<'a>> {
+        if next.account_id == current.account_id {
+            return Some(SwitchSlot {
+                state,
+                account_id: None,
+            });
+        }
+        state
+            .try_acquire_connection(next.account_id)
+            .then_some(SwitchSlot {
+                state,
+                account_id: Some(next.account_id),
+            })
+    }
+
+    /// Hand the slot over to the switched-to connection
+    fn commit(mut self) {
+        self.account_id = None;
+    }
+}
+
+impl Drop for SwitchSlot<'_> {
+    fn drop(&mut self) {
+        if let Some(account_id) = self.account_id {
+            self.state.decrement_connections(account_id);
+        }
+    }
+}
+
+/// Open an upstream request (from byte `offset` if non-zero), waiting for the
+/// host's pacing and a start slot first, and record the outcome in the URL's health. UDP/RTP
+/// URLs join their feed and relay URLs pull from another proxy instead; they don't count
+/// against provider slots.
+async fn connect_upstream(
+    state: &AppState,
     client: &Client,
+    channel_id: &str,
+    request_id: Option<&str>,
+    account_id: u64,
     url: &str,
-    tx: &broadcast::Sender<Bytes>,
-    stop_rx: &mut watch::Receiver<bool>,
-    active: &ActiveChannel,
-) -> Result<(), String> {
-    use futures_util::StreamExt;
+    offset: u64,
+) -> Result<Connection, String> {
+    if multicast::is_multicast_url(url) {
+        let started = Instant::now();
+        return match multicast::open(url, state.config.multicast_timeout).await {
+            Ok(source) => {
+                state.record_url_success(url, started.elapsed());
+                Ok(Connection::Multicast(source))
+            }
+            Err(e) => {
+                let e = format!("connect error: {}", e);
+                state.record_url_connect_failure(url, &e);
+                Err(e)
+            }
+        };
+    }
+    if relay::is_relay_url(url) {
+        let started = Instant::now();
+        return match relay::open(url, state.config.upstream_response_timeout).await {
+            Ok(source) => {
+                state.record_url_success(url, started.elapsed());
+                Ok(Connection::Relay(source))
+            }
+            Err(e) => {
+                let e = format!("connect error: {}", e);
+                state.record_url_connect_failure(url, &e);
+                Err(e)
+            }
+        };
+    }
 
-    let response = client
-        .get(url)
-        .send()
+    if !state.claim_cluster_slot(account_id).await {
+        return Err(CLUSTER_FULL.to_string());
+    }
+
+    // Space out connections per host first, so a paced host doesn't hold
+    // start slots other providers could use
+    state.pace_host_connect(url).await;
+
+    // Wait for start slots so bursts of new channels don't flood providers:
+    // the host's first, so a busy host doesn't hold global ones
+    let host_permit = state.host_start_permit(url).await;
+    if state.start_permits.available_permits() == 0 {
+        tracing::debug!("Upstream start queued, waiting for a free slot: {}", url);
+    }
+    let permit = state
+        .start_permits
+        .acquire()
         .await
-        .map_err(|e| format!("connect error: {}", e))?;
+        .map_err(|e| format!("start limiter closed: {}", e))?;
+
+    let mut request = session::get(state, client, channel_id, account_id, url).await?;
+    if offset > 0 {
+        request = request.header(header::RANGE, format!("bytes={}-", offset));
+    }
+    if let Some(request_id) = request_id {
+        request = request.header(REQUEST_ID_HEADER, request_id);
+    }
+    let started = Instant::now();
+    let response = match session::send(state, request).await {
+        Ok(response) => response,
+        Err(e) => {
+            let e = format!("connect error: {}", e);
+            state.record_url_connect_failure(url, &e);
+            return Err(e);
+        }
+    };
+    drop(permit);
+    drop(host_permit);
 
     if !response.status().is_success() {
-        return Err(format!("HTTP {}", response.status()));
+        let e = format!("HTTP {}", response.status());
+        if matches!(
+            response.status(),
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        ) {
+            session::invalidate(state, account_id);
+        }
+        state.record_url_connect_failure(url, &e);
+        return Err(e);
     }
+    state.record_url_success(url, started.elapsed());
+    if let Some(certificate) = CertificateInfo::from_response(&response) {
+        state.record_url_certificate(url, certificate);
+    }
+    Ok(Connection::Http(response))
+}
+
+/// Stream from `target` (or from `handover`, a response already opened for
+/// it) until stopped, switched away from, or the upstream fails.
+#[allow(clippy::too_many_arguments)]
+async fn fetch_upstream(
+    state: &AppState,
+    client: &Client,
+    target: &UpstreamTarget,
+    tx: &broadcast::Sender<Chunk>,
+    stop_rx: &mut watch::Receiver<bool>,
+    active: &ActiveChannel,
+    resume: &mut ResumeState,
+    handover: Option<Connection>,
+) -> Result<FetchOutcome, String> {
+    use futures_util::StreamExt;
+
+    let url = target.url.as_str();
+
+    let connection = match handover {
+        Some(connection) => connection,
+        None => tokio::select! {
+            _ = stop_rx.changed() => {
+                return Ok(FetchOutcome::Stopped);
+            }
+            connection = connect_upstream(
+                state,
+                client,
+                &active.channel_id,
+                active.started_by.as_deref(),
+                target.account_id,
+                url,
+                resume.offset,
+            ) => connection?,
+        },
+    };
+
+    // Bytes to drop from the start of a response that should have resumed
+    let mut skip = 0;
+    let mut byte_stream: ByteStream = match connection {
+        Connection::Http(response) => {
+            if response.status() == StatusCode::PARTIAL_CONTENT {
+                // Content-Range: bytes <start>-<end>/<total>
+                resume.total = response
+                    .headers()
+                    .get(header::CONTENT_RANGE)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.rsplit('/').next())
+                    .and_then(|v| v.parse().ok())
+                    .or(resume.total);
+            } else if resume.offset > 0 && resume.total.is_some() {
+                // Range ignored: the whole file again, minus what clients already have
+                if response.content_length() != resume.total {
+                    *resume = ResumeState::default();
+                    return Err("resume answered with a different file".to_string());
+                }
+                skip = resume.offset;
+            } else {
+                // Upstream wasn't asked for a range, or ignored it on a live stream — starting from byte zero
+                resume.offset = 0;
+                resume.total = response.content_length();
+            }
+            resume.accepts_ranges = response
+                .headers()
+                .get(header::ACCEPT_RANGES)
+                .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"bytes"))
+                || response.status() == StatusCode::PARTIAL_CONTENT;
+            Box::pin(
+                response
+                    .bytes_stream()
+                    .map(|r| r.map_err(|e| e.to_string())),
+            )
+        }
+        Connection::Multicast(source) => {
+            // A live feed has no byte positions to resume from
+            resume.offset = 0;
+            resume.total = None;
+            resume.accepts_ranges = false;
+            source.into_stream()
+        }
+        Connection::Relay(source) => {
+            resume.offset = 0;
+            resume.total = None;
+            resume.accepts_ranges = false;
+            source.into_stream(active.counters.clone())
+        }
+    };
 
-    let mut byte_stream = response.bytes_stream();
     let mut buffer = Vec::with_capacity(CHUNK_SIZE);
+    // Whole packets only, so flushes and client keepalives never split one
+    let mut aligner = ts::PacketAligner::default();
+    active.ts_health.lock().unwrap().new_source();
+    active.psi_tables.lock().unwrap().clear();
+    let mut program_filter = state
+        .channel_routes
+        .load()
+        .get(&active.channel_id)
+        .and_then(|r| ts::ProgramFilter::new(r.subtitles.clone(), &r.audio_language_priority));
+    let connected_at = Instant::now();
+    let mut switch: Option<(UpstreamTarget, PendingConnect, SwitchSlot)> = None;
+    let read_timeout = state.config.upstream_read_timeout;
+    let flush_interval = state.config.chunk_flush_interval;
+    let mut last_flush = Instant::now();
 
     loop {
         tokio::select! {
             _ = stop_rx.changed() => {
-                return Ok(());
+                return Ok(FetchOutcome::Stopped);
+            }
+            result = async { switch.as_mut().unwrap().1.as_mut().await }, if switch.is_some() => {
+                let (next, _, slot) = switch.take().unwrap();
+                match result {
+                    Ok(response) => {
+                        slot.commit();
+                        if !buffer.is_empty() {
+                            send_chunk(state, active, tx, Bytes::from(buffer));
+                        }
+                        tracing::info!(
+                            "Channel {}: switched from account {} to stream={}, account={}",
+                            active.channel_id,
+                            target.account_id,
+                            next.stream_id,
+                            next.account_id
+                        );
+                        return Ok(FetchOutcome::Switch(next, Some(response)));
+                    }
+                    Err(e) => tracing::warn!(
+                        "Channel {}: switch to account {} failed, staying on account {}: {}",
+                        active.channel_id,
+                        next.account_id,
+                        target.account_id,
+                        e
+                    ),
+                }
             }
-            chunk = byte_stream.next() => {
+            _ = tokio::time::sleep_until(last_flush + flush_interval), if !flush_interval.is_zero() && !buffer.is_empty() => {
+                // Upstream went quiet with data still buffered
+                let rest = std::mem::replace(&mut buffer, Vec::with_capacity(CHUNK_SIZE));
+                send_chunk(state, active, tx, Bytes::from(rest));
+                last_flush = Instant::now();
+            }
+            chunk = next_chunk(&mut byte_stream, read_timeout) => {
                 match chunk {
-                    Some(Ok(data)) => {
-                        buffer.extend_from_slice(&data);
+                    Some(Ok(mut data)) => {
+                        if skip > 0 {
+                            let n = skip.min(data.len() as u64);
+                            skip -= n;
+                            data = data.slice(n as usize..);
+                            if data.is_empty() {
+                                continue;
+                            }
+                        }
+                        let delay = match chaos::on_read(state, &active.channel_id) {
+                            Ok(delay) => delay,
+                            Err(e) => {
+                                if !buffer.is_empty() {
+                                    send_chunk(state, active, tx, Bytes::from(buffer));
+                                }
+                                state.record_url_drop(url, &e);
+                                return Err(e);
+                            }
+                        };
+                        if !delay.is_zero() {
+                            tokio::select! {
+                                _ = stop_rx.changed() => return Ok(FetchOutcome::Stopped),
+                                _ = tokio::time::sleep(delay) => {}
+                            }
+                        }
+                        active.mark_data();
+                        active.receive_stats.lock().unwrap().record_read(target, data.len());
+                        state.record_upstream_bytes(&active.channel_id, data.len() as u64);
+                        resume.offset += data.len() as u64;
+                        let data = aligner.align(&data);
+                        active
+                            .ts_health
+                            .lock()
+                            .unwrap()
+                            .inspect(&data, aligner.take_sync_losses());
+                        match &mut program_filter {
+                            Some(filter) => buffer.extend_from_slice(&filter.filter(&data)),
+                            None => buffer.extend_from_slice(&data),
+                        }
 
-                        // Flush when buffer is large enough
+                        // Flush when buffer is large enough, or has waited long enough
+                        let due = !flush_interval.is_zero()
+                            && !buffer.is_empty()
+                            && last_flush.elapsed() >= flush_interval;
+                        let flushed = due || buffer.len() >= CHUNK_SIZE;
                         while buffer.len() >= CHUNK_SIZE {
                             let chunk = Bytes::copy_from_slice(&buffer[..CHUNK_SIZE]);
                             buffer.drain(..CHUNK_SIZE);
-                            active.bytes_transferred.fetch_add(CHUNK_SIZE as u64, Ordering::Relaxed);
+                            send_chunk(state, active, tx, chunk);
+                        }
+                        if due && !buffer.is_empty() {
+                            let rest = std::mem::replace(&mut buffer, Vec::with_capacity(CHUNK_SIZE));
+                            send_chunk(state, active, tx, Bytes::from(rest));
+                        }
+                        if flushed {
+                            last_flush = Instant::now();
+                        }
+                        if flushed && wait_for_drain(state, active, tx, stop_rx).await {
+                            return Ok(FetchOutcome::Stopped);
+                        }
+
+                        // Open a requested switch target while this one keeps streaming
+                        if switch.is_none() {
+                            let pending = active.pending_switch.lock().unwrap().take();
+                            if let Some(next) = pending {
+                                match SwitchSlot::reserve(state, target, &next) {
+                                    Some(slot) => {
+                                        let next_url = next.url.clone();
+                                        let next_account = next.account_id;
+                                        let connect: PendingConnect = Box::pin(async move {
+                                            connect_upstream(
+                                                state,
+                                                client,
+                                                &active.channel_id,
+                                                active.started_by.as_deref(),
+                                                next_account,
+                                                &next_url,
+                                                0,
+                                            )
+                                            .await
+                                        });
+                                        switch = Some((next, connect, slot));
+                                    }
+                                    None => tracing::warn!(
+                                        "Channel {}: account {} has no free connection, not switching",
+                                        active.channel_id,
+                                        next.account_id
+                                    ),
+                                }
+                            }
+                        }
 
-                            // Send to all clients; if no receivers, that's fine
-                            let _ = tx.send(chunk);
+                        // Right after a flush is a safe point to change source
+                        let outcome = flushed
+                            .then(|| {
+                                quota_outcome(state, active, target)
+                                    .or_else(|| migration_target(state, active, target))
+                                    .or_else(|| {
+                                        (connected_at.elapsed() >= state.config.tier_switch_holdoff)
+                                            .then(|| tier_switch_target(state, active, target))
+                                            .flatten()
+                                    })
+                            })
+                            .flatten();
+                        if let Some(outcome) = outcome {
+                            if !buffer.is_empty() {
+                                send_chunk(state, active, tx, Bytes::from(buffer));
+                            }
+                            return Ok(outcome);
                         }
                     }
                     Some(Err(e)) => {
-                        return Err(format!("read error: {}", e));
+                        // Flush what we have so a resumed read continues seamlessly
+                        if !buffer.is_empty() {
+                            send_chunk(state, active, tx, Bytes::from(buffer));
+                        }
+                        let e = format!("read error: {}", e);
+                        state.record_url_drop(url, &e);
+                        return Err(e);
                     }
                     None => {
                         // Stream ended — flush remaining buffer
                         if !buffer.is_empty() {
-                            let chunk = Bytes::from(buffer);
-                            active.bytes_transferred.fetch_add(chunk.len() as u64, Ordering::Relaxed);
-                            let _ = tx.send(chunk);
+                            send_chunk(state, active, tx, Bytes::from(buffer));
                         }
+                        state.record_url_drop(url, "stream ended");
                         return Err("stream ended".to_string());
                     }
                 }
@@ -188,3 +1015,126 @@ async fn fetch_upstream(
         }
     }
 }
+
+/// The next read from the upstream, or an error if it stays silent for
+/// `timeout` while the connection remains open (zero = wait indefinitely)
+async fn next_chunk(
+    byte_stream: &mut ByteStream,
+    timeout: std::time::Duration,
+) -> Option<Result<Bytes, String>> {
+    use futures_util::StreamExt;
+
+    if timeout.is_zero() {
+        return byte_stream.next().await;
+    }
+    match tokio::time::timeout(timeout, byte_stream.next()).await {
+        Ok(chunk) => chunk,
+        Err(_) => Some(Err(format!("upstream stalled, no data for {:?}", timeout))),
+    }
+}
+
+/// Pause reading while the broadcast queue is above the channel's high
+/// watermark, so a briefly slow client exerts backpressure on the provider
+/// instead of losing data. Resumes once the queue drains to the low watermark,
+/// or after `upstream_max_pause`, at which point slow clients lag instead.
+/// Returns true if a stop signal arrived while paused.
+async fn wait_for_drain(
+    state: &AppState,
+    active: &ActiveChannel,
+    tx: &broadcast::Sender<Chunk>,
+    stop_rx: &mut watch::Receiver<bool>,
+) -> bool {
+    let (high, low) = state.watermarks(&active.channel_id);
+    // Clients reading through their own send queue don't hold up the
+    // broadcast, so count how far behind the slowest of them is too
+    let depth = || tx.len().max(active.deepest_client_queue());
+    if high == 0 || depth() < high {
+        return false;
+    }
+
+    active.backpressure_paused.store(true, Ordering::Relaxed);
+    active.backpressure_pauses.fetch_add(1, Ordering::Relaxed);
+    let paused_at = Instant::now();
+    let mut stopped = false;
+    while depth() > low {
+        if paused_at.elapsed() >= state.config.upstream_max_pause {
+            tracing::debug!(
+                "Channel {}: clients still {} chunks behind after {:?}, resuming upstream",
+                active.channel_id,
+                depth(),
+                state.config.upstream_max_pause
+            );
+            break;
+        }
+        tokio::select! {
+            _ = stop_rx.changed() => {
+                stopped = true;
+                break;
+            }
+            _ = tokio::time::sleep(DRAIN_POLL_INTERVAL) => {}
+        }
+    }
+    active.backpressure_paused.store(false, Ordering::Relaxed);
+    stopped
+}
+
+/// Account for and broadcast a chunk to all clients; if no receivers, that's fine
+fn send_chunk(
+    state: &AppState,
+    active: &ActiveChannel,
+    tx: &broadcast::Sender<Chunk>,
+    data: Bytes,
+) {
+    // Lead with the tables once a new source's are complete, so players
+    // lock on to it without waiting for their next repetition
+    let tables = {
+        let mut psi = active.psi_tables.lock().unwrap();
+        psi.update(&data).then(|| psi.tables()).flatten()
+    };
+    // and with a pending source change notice ahead of everything else
+    let marker = active.source_marker.lock().unwrap().take();
+    let data = match (marker, tables) {
+        (None, None) => data,
+        (marker, tables) => {
+            let mut out = Vec::new();
+            out.extend(marker.iter().flatten());
+            out.extend(tables.iter().flatten());
+            out.extend_from_slice(&data);
+            out.into()
+        }
+    };
+    active
+        .bytes_transferred
+        .fetch_add(data.len() as u64, Ordering::Relaxed);
+    active
+        .counters
+        .bytes_in
+        .fetch_add(data.len() as u64, Ordering::Relaxed);
+    let chunk = Chunk {
+        keyframe: ts::contains_keyframe(&data),
+        data,
+    };
+
+    let mut gop = active.gop_cache.lock().unwrap();
+    if chunk.keyframe {
+        gop.clear();
+        active.keyframes_seen.store(true, Ordering::Relaxed);
+    }
+    if !gop.is_empty() || chunk.keyframe {
+        let cached: usize = gop.iter().map(|c| c.data.len()).sum();
+        if cached + chunk.data.len() <= GOP_CACHE_MAX_BYTES {
+            gop.push(chunk.clone());
+        } else {
+            gop.clear();
+        }
+    }
+    let limit = state.config.join_buffer_chunks;
+    if limit > 0 {
+        let mut recent = active.recent_chunks.lock().unwrap();
+        if recent.len() >= limit {
+            recent.pop_front();
+        }
+        recent.push_back((Instant::now(), chunk.clone()));
+    }
+    let _ = tx.send(chunk);
+}
@@ -0,0 +1,151 @@
+use crate::session;
+use crate::state::{AppState, IpSlot};
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Releases the account connection when the client's body stream is dropped
+struct VodGuard {
+    channel_id: String,
+    session_id: String,
+    account_id: u64,
+    state: Arc<AppState>,
+    _ip_slot: IpSlot,
+}
+
+impl Drop for VodGuard {
+    fn drop(&mut self) {
+        self.state.vod_sessions.remove(&self.session_id);
+        self.state.decrement_connections(self.account_id);
+        tracing::info!("VOD {}: client session ended", self.channel_id);
+    }
+}
+
+/// Serve a file-backed channel with a dedicated upstream request per client.
+///
+/// The client's Range header is forwarded as-is and the upstream's partial
+/// content headers are passed back, so players can seek.
+pub async fn serve(
+    state: Arc<AppState>,
+    channel_id: String,
+    headers: HeaderMap,
+    addr: SocketAddr,
+    ip_slot: IpSlot,
+) -> Response {
+    let client = state.http_client.clone();
+    let range = headers.get(header::RANGE).cloned();
+
+    // Each candidate comes with its account's connection slot reserved
+    let mut candidate = state.reserve_stream(&channel_id, false);
+    let mut attempts = 0;
+    while let Some((stream_id, account_id, url)) = candidate {
+        attempts += 1;
+        let claimed = state.claim_cluster_slot(account_id).await;
+        let result = if claimed {
+            state.pace_host_connect(&url).await;
+            match session::get(&state, &client, &channel_id, account_id, &url).await {
+                Ok(mut request) => {
+                    if let Some(range) = &range {
+                        request = request.header(header::RANGE, range);
+                    }
+                    session::send(&state, request).await
+                }
+                Err(e) => Err(e),
+            }
+        } else {
+            Err("account is at its cluster-wide connection limit".to_string())
+        };
+
+        match result {
+            Ok(upstream) if upstream.status().is_success() => {
+                tracing::info!(
+                    "VOD {}: client {} via stream={}, account={} (range={:?})",
+                    channel_id,
+                    addr,
+                    stream_id,
+                    account_id,
+                    range
+                );
+                let session_id = uuid::Uuid::new_v4().to_string();
+                state.vod_sessions.insert(session_id.clone(), account_id);
+                let guard = VodGuard {
+                    channel_id,
+                    session_id,
+                    account_id,
+                    state: state.clone(),
+                    _ip_slot: ip_slot,
+                };
+                return relay(upstream, guard);
+            }
+            Ok(upstream) if upstream.status() == StatusCode::RANGE_NOT_SATISFIABLE => {
+                state.decrement_connections(account_id);
+                return relay_headers(&upstream, StatusCode::RANGE_NOT_SATISFIABLE)
+                    .body(Body::empty())
+                    .unwrap();
+            }
+            Ok(upstream) => {
+                tracing::warn!("VOD {}: upstream HTTP {}", channel_id, upstream.status());
+                state.record_account_failure(account_id);
+            }
+            Err(e) if !claimed => tracing::info!("VOD {}: {}", channel_id, e),
+            Err(e) => {
+                tracing::warn!("VOD {}: upstream connect error: {}", channel_id, e);
+                state.record_account_failure(account_id);
+            }
+        }
+
+        state.decrement_connections(account_id);
+        if attempts >= MAX_ATTEMPTS {
+            break;
+        }
+        candidate = state.reserve_next_stream(&channel_id, false, stream_id, account_id);
+    }
+
+    (StatusCode::SERVICE_UNAVAILABLE, "No streams available").into_response()
+}
+
+fn relay(upstream: reqwest::Response, guard: VodGuard) -> Response {
+    let builder = relay_headers(&upstream, upstream.status());
+
+    let body_stream = async_stream::stream! {
+        use futures_util::StreamExt;
+        // Hold the guard — the account connection is released when the client goes away
+        let _guard = guard;
+        let mut upstream_body = upstream.bytes_stream();
+        while let Some(chunk) = upstream_body.next().await {
+            yield chunk.map_err(std::io::Error::other);
+        }
+    };
+
+    builder.body(Body::from_stream(body_stream)).unwrap()
+}
+
+/// Copy the headers a player needs for seeking from the upstream response
+fn relay_headers(
+    upstream: &reqwest::Response,
+    status: StatusCode,
+) -> axum::http::response::Builder {
+    let mut builder = Response::builder()
+        .status(status)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CACHE_CONTROL, "no-cache");
+    for name in [
+        header::CONTENT_TYPE,
+        header::CONTENT_LENGTH,
+        header::CONTENT_RANGE,
+    ] {
+        if let Some(value) = upstream.headers().get(&name) {
+            builder = builder.header(name, value);
+        }
+    }
+    if !upstream.headers().contains_key(header::CONTENT_TYPE) {
+        builder = builder.header(header::CONTENT_TYPE, "video/mp2t");
+    }
+    builder
+}
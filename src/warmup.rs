@@ -0,0 +1,70 @@
+use crate::state::AppState;
+use crate::upstream;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Spawn the task that starts persistent channels after routing changes.
+///
+/// Channels are started in priority order and ramped at `warmup_rate` per
+/// second, so a fresh boot (or a sync adding many persistent channels) does
+/// not open every upstream at once.
+pub fn spawn_warmup(state: Arc<AppState>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            state.warmup.notify.notified().await;
+            run_pass(&state).await;
+        }
+    })
+}
+
+async fn run_pass(state: &Arc<AppState>) {
+    let mut pending: Vec<(i32, String)> = Vec::new();
+    state.channel_routes.load().scan(|id, e| {
+        if e.enabled
+            && e.persistent
+            && !e.vod
+            && !e.hls_passthrough
+            && !state.active_channels.contains_key(id)
+        {
+            pending.push((e.priority, id.to_string()));
+        }
+    });
+    if pending.is_empty() {
+        return;
+    }
+    pending.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+    let warmup = &state.warmup;
+    warmup.total.store(pending.len(), Ordering::Relaxed);
+    warmup.started.store(0, Ordering::Relaxed);
+    warmup.in_progress.store(true, Ordering::Relaxed);
+    tracing::info!("Warm-up: starting {} persistent channels", pending.len());
+
+    let ramp = match state.config.warmup_rate {
+        0 => None,
+        rate => Some(Duration::from_secs_f64(1.0 / rate as f64)),
+    };
+
+    for (i, (_, channel_id)) in pending.iter().enumerate() {
+        if i > 0 {
+            if let Some(delay) = ramp {
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        // Routing may have changed while we were ramping
+        let still_persistent = state
+            .channel_routes
+            .load()
+            .get(channel_id)
+            .is_some_and(|r| r.persistent);
+        if still_persistent && upstream::get_or_start_channel(state, channel_id, None).is_none() {
+            tracing::warn!("Warm-up: no stream available for channel {}", channel_id);
+        }
+        warmup.started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    warmup.in_progress.store(false, Ordering::Relaxed);
+    tracing::info!("Warm-up complete");
+}
@@ -0,0 +1,93 @@
+use crate::models::ProxyEvent;
+use crate::state::AppState;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+
+/// Unix timestamp (seconds) a webhook delivery was signed at
+pub const WEBHOOK_TIMESTAMP_HEADER: &str = "x-webhook-timestamp";
+/// `sha256=<hex HMAC>` over the timestamp and body
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "x-webhook-signature";
+/// Attempts per event before it is given up on
+const MAX_ATTEMPTS: u32 = 3;
+/// Wait before the first retry, doubled for each further one
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Spawn the task that POSTs each channel and client event to `url` as JSON,
+/// one event per request, in order. Events carry their sequence number, so
+/// a receiver that sees a gap (after an outage outlasting the retries) can
+/// fetch the missing ones from `/status/v1/events/export?since=`.
+///
+/// With WEBHOOK_SECRET set, each request is signed: `x-webhook-signature` is
+/// `sha256=` followed by the hex HMAC-SHA256 of `"{timestamp}\n{body}"`,
+/// with the timestamp sent in `x-webhook-timestamp`.
+pub fn spawn_notifier(state: Arc<AppState>, url: String) -> tokio::task::JoinHandle<()> {
+    let mut events = state.events.subscribe();
+    tokio::spawn(async move {
+        let client = reqwest::Client::builder()
+            .timeout(state.config.webhook_timeout)
+            .build()
+            .expect("failed to build webhook client");
+        let secret = state.config.webhook_secret.as_deref();
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    tracing::warn!("Webhook delivery fell behind, {} events skipped", n);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            deliver(&client, &url, secret, &event).await;
+        }
+    })
+}
+
+/// POST one event, retrying failures with backoff
+async fn deliver(client: &reqwest::Client, url: &str, secret: Option<&str>, event: &ProxyEvent) {
+    let body = serde_json::to_vec(event).expect("event serializes");
+    let mut delay = RETRY_DELAY;
+    for attempt in 1..=MAX_ATTEMPTS {
+        let mut request = client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(secret) = secret {
+            let timestamp = chrono::Utc::now().timestamp().to_string();
+            request = request
+                .header(WEBHOOK_SIGNATURE_HEADER, sign(secret, &timestamp, &body))
+                .header(WEBHOOK_TIMESTAMP_HEADER, timestamp);
+        }
+        let error = match request.body(body.clone()).send().await {
+            Ok(response) if response.status().is_success() => return,
+            Ok(response) => format!("HTTP {}", response.status()),
+            Err(e) => e.to_string(),
+        };
+        if attempt == MAX_ATTEMPTS {
+            tracing::warn!(
+                "Webhook: giving up on event {} after {} attempts: {}",
+                event.seq,
+                attempt,
+                error
+            );
+            return;
+        }
+        tracing::debug!(
+            "Webhook: event {} attempt {} failed: {}",
+            event.seq,
+            attempt,
+            error
+        );
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(format!("{}\n", timestamp).as_bytes());
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}